@@ -0,0 +1,350 @@
+#![cfg_attr(not(any(test, feature = "mock-flash")), no_std)]
+
+#[cfg(any(test, feature = "mock-flash"))]
+pub mod mock_flash;
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+use embedded_storage::nor_flash::NorFlash;
+use serde::{de::DeserializeOwned, Serialize};
+
+// Record layout, all the fields are little endian:
+//
+// | magic (4) | version (2) | len (2) | seq (4) | crc (4) | payload (len) | padding |
+//
+// * magic: marks the start of a record, erased flash (0xFF) means free space
+// * version: version of the payload layout, a record with different version is ignored
+// * len: length of postcard encoded payload
+// * seq: sequence number, the record with the largest number is the latest one
+// * crc: CRC32 of version, len, seq and payload
+//
+// Records are appended to one page until it is full. Then the other page is erased and
+// the new record is written to the beginning of it, so the previous record is kept until
+// the next page swap in case power is lost during erasing or writing.
+const RECORD_MAGIC: u32 = 0x4746_434D;
+const ERASED_MAGIC: u32 = 0xFFFF_FFFF;
+const HEADER_SIZE: usize = 16;
+const MAX_RECORD_SIZE: usize = 256;
+pub const MAX_PAYLOAD_SIZE: usize = MAX_RECORD_SIZE - HEADER_SIZE;
+
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+#[derive(Debug, PartialEq)]
+pub enum StoreError<E> {
+    Flash(E),
+    // The value can not be encoded or it is larger than `MAX_PAYLOAD_SIZE`
+    Serialize,
+    // The latest record is valid but it can not be decoded to the requested type
+    Deserialize,
+}
+
+#[derive(Clone, Copy)]
+struct RecordLocation {
+    page: u32,
+    offset: u32,
+    seq: u32,
+    version: u16,
+    len: u16,
+}
+
+pub struct ConfigStore<F: NorFlash> {
+    flash: F,
+    // Offset of the first page used by the store, two consecutive pages are used
+    base_offset: u32,
+    // The latest record and the first free offset of each page, they are updated by `scan`
+    latest: Option<RecordLocation>,
+    free_offset: [u32; 2],
+    scanned: bool,
+}
+
+impl<F: NorFlash> ConfigStore<F> {
+    pub fn new(flash: F, base_offset: u32) -> Self {
+        Self {
+            flash,
+            base_offset,
+            latest: None,
+            free_offset: [0; 2],
+            scanned: false,
+        }
+    }
+
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    pub fn load<T: DeserializeOwned>(
+        &mut self,
+        version: u16,
+    ) -> Result<Option<T>, StoreError<F::Error>> {
+        self.scan()?;
+
+        let Some(latest) = self.latest else {
+            return Ok(None);
+        };
+
+        if latest.version != version {
+            return Ok(None);
+        }
+
+        let mut buf = [0_u8; MAX_RECORD_SIZE];
+        let payload = &mut buf[..Self::aligned_read_len(latest.len as usize)];
+        self.flash
+            .read(
+                self.page_offset(latest.page) + latest.offset + HEADER_SIZE as u32,
+                payload,
+            )
+            .map_err(StoreError::Flash)?;
+
+        postcard::from_bytes::<T>(&payload[..latest.len as usize])
+            .map(Some)
+            .map_err(|_e| StoreError::Deserialize)
+    }
+
+    pub fn save<T: Serialize>(
+        &mut self,
+        version: u16,
+        value: &T,
+    ) -> Result<(), StoreError<F::Error>> {
+        self.scan()?;
+
+        let mut buf = [0xFF_u8; MAX_RECORD_SIZE];
+        let len = postcard::to_slice(value, &mut buf[HEADER_SIZE..])
+            .map_err(|_e| StoreError::Serialize)?
+            .len();
+        let seq = self.latest.map_or(0, |x| x.seq.wrapping_add(1));
+
+        buf[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        buf[4..6].copy_from_slice(&version.to_le_bytes());
+        buf[6..8].copy_from_slice(&(len as u16).to_le_bytes());
+        buf[8..12].copy_from_slice(&seq.to_le_bytes());
+        let crc = Self::record_crc(&buf[4..12], &buf[HEADER_SIZE..HEADER_SIZE + len]);
+        buf[12..16].copy_from_slice(&crc.to_le_bytes());
+
+        let record_len = Self::aligned_write_len(HEADER_SIZE + len) as u32;
+
+        // Append the record to the page holding the latest record, swap to the other page
+        // if there is not enough space
+        let active_page = self.latest.map_or(0, |x| x.page);
+        let (page, offset) =
+            if self.free_offset[active_page as usize] + record_len <= self.page_size() {
+                (active_page, self.free_offset[active_page as usize])
+            } else {
+                let page = if self.latest.is_some() {
+                    1 - active_page
+                } else {
+                    active_page
+                };
+                self.erase_page(page)?;
+                (page, 0)
+            };
+
+        self.flash
+            .write(self.page_offset(page) + offset, &buf[..record_len as usize])
+            .map_err(StoreError::Flash)?;
+
+        self.latest = Some(RecordLocation {
+            page,
+            offset,
+            seq,
+            version,
+            len: len as u16,
+        });
+        self.free_offset[page as usize] = offset + record_len;
+
+        Ok(())
+    }
+
+    pub fn erase(&mut self) -> Result<(), StoreError<F::Error>> {
+        self.erase_page(0)?;
+        self.erase_page(1)?;
+        self.latest = None;
+        self.scanned = true;
+
+        Ok(())
+    }
+
+    fn scan(&mut self) -> Result<(), StoreError<F::Error>> {
+        if self.scanned {
+            return Ok(());
+        }
+
+        let mut latest: Option<RecordLocation> = None;
+        for page in 0..2_u32 {
+            let mut offset = 0_u32;
+            while offset + HEADER_SIZE as u32 <= self.page_size() {
+                let mut header = [0_u8; HEADER_SIZE];
+                self.flash
+                    .read(self.page_offset(page) + offset, &mut header)
+                    .map_err(StoreError::Flash)?;
+
+                let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+                if magic == ERASED_MAGIC {
+                    break;
+                }
+
+                let len = u16::from_le_bytes(header[6..8].try_into().unwrap());
+                let record_len = Self::aligned_write_len(HEADER_SIZE + len as usize) as u32;
+                if magic != RECORD_MAGIC
+                    || len as usize > MAX_PAYLOAD_SIZE
+                    || offset + record_len > self.page_size()
+                {
+                    // The page is corrupted (ex: power loss during writing), treat it as full,
+                    // so it will be erased before the next write
+                    offset = self.page_size();
+                    break;
+                }
+
+                let mut payload = [0_u8; MAX_PAYLOAD_SIZE];
+                let payload = &mut payload[..Self::aligned_read_len(len as usize)];
+                self.flash
+                    .read(
+                        self.page_offset(page) + offset + HEADER_SIZE as u32,
+                        payload,
+                    )
+                    .map_err(StoreError::Flash)?;
+
+                let crc = u32::from_le_bytes(header[12..16].try_into().unwrap());
+                let seq = u32::from_le_bytes(header[8..12].try_into().unwrap());
+                if crc == Self::record_crc(&header[4..12], &payload[..len as usize])
+                    && latest.is_none_or(|x| seq.wrapping_sub(x.seq) as i32 > 0)
+                {
+                    latest = Some(RecordLocation {
+                        page,
+                        offset,
+                        seq,
+                        version: u16::from_le_bytes(header[4..6].try_into().unwrap()),
+                        len,
+                    });
+                }
+
+                offset += record_len;
+            }
+
+            self.free_offset[page as usize] = offset;
+        }
+
+        self.latest = latest;
+        self.scanned = true;
+
+        Ok(())
+    }
+
+    fn erase_page(&mut self, page: u32) -> Result<(), StoreError<F::Error>> {
+        let from = self.page_offset(page);
+        self.flash
+            .erase(from, from + self.page_size())
+            .map_err(StoreError::Flash)?;
+        self.free_offset[page as usize] = 0;
+
+        Ok(())
+    }
+
+    fn page_size(&self) -> u32 {
+        F::ERASE_SIZE as u32
+    }
+
+    fn page_offset(&self, page: u32) -> u32 {
+        self.base_offset + page * self.page_size()
+    }
+
+    fn record_crc(header: &[u8], payload: &[u8]) -> u32 {
+        let mut digest = CRC.digest();
+        digest.update(header);
+        digest.update(payload);
+        digest.finalize()
+    }
+
+    fn aligned_write_len(len: usize) -> usize {
+        // Keep records aligned to 4 bytes, so the magic number of the next record can be
+        // read and written with the write granularity of most flash
+        let align = F::WRITE_SIZE.max(4);
+        len.div_ceil(align) * align
+    }
+
+    fn aligned_read_len(len: usize) -> usize {
+        len.div_ceil(F::READ_SIZE) * F::READ_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock_flash::MockFlash;
+    use super::*;
+    use serde::Deserialize;
+
+    const PAGE_SIZE: usize = 256;
+    const VERSION: u16 = 1;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+    struct TestConfig {
+        gain: f32,
+        limit: f32,
+        offsets: (i16, i16, i16),
+    }
+
+    fn test_config(i: u32) -> TestConfig {
+        TestConfig {
+            gain: i as f32 * 0.5,
+            limit: 4000.0,
+            offsets: (-2453, i as i16, -1793),
+        }
+    }
+
+    #[test]
+    fn test_load_should_return_none_on_erased_flash() {
+        let mut store = ConfigStore::new(MockFlash::<PAGE_SIZE, 2>::new(2), 0);
+
+        assert_eq!(store.load::<TestConfig>(VERSION), Ok(None));
+    }
+
+    #[test]
+    fn test_load_should_return_latest_record_after_page_swaps_and_reboot() {
+        let mut store = ConfigStore::new(MockFlash::<PAGE_SIZE, 2>::new(2), 0);
+
+        // Each record takes 32 bytes, so the records will be swapped between pages
+        for i in 0..20 {
+            store.save(VERSION, &test_config(i)).unwrap();
+            assert_eq!(store.load::<TestConfig>(VERSION), Ok(Some(test_config(i))));
+        }
+
+        // Simulate reboot, the latest record should be found by scanning flash
+        let mut store = ConfigStore::new(store.release(), 0);
+        assert_eq!(store.load::<TestConfig>(VERSION), Ok(Some(test_config(19))));
+    }
+
+    #[test]
+    fn test_load_should_ignore_record_with_different_version() {
+        let mut store = ConfigStore::new(MockFlash::<PAGE_SIZE, 2>::new(2), 0);
+        store.save(VERSION, &test_config(1)).unwrap();
+
+        assert_eq!(store.load::<TestConfig>(VERSION + 1), Ok(None));
+    }
+
+    #[test]
+    fn test_load_should_fall_back_to_previous_record_when_latest_is_corrupted() {
+        let mut store = ConfigStore::new(MockFlash::<PAGE_SIZE, 2>::new(2), 0);
+        store.save(VERSION, &test_config(1)).unwrap();
+        store.save(VERSION, &test_config(2)).unwrap();
+
+        // Flip one bit in the payload of the second record
+        let mut flash = store.release();
+        flash.corrupt(32 + HEADER_SIZE);
+
+        let mut store = ConfigStore::new(flash, 0);
+        assert_eq!(store.load::<TestConfig>(VERSION), Ok(Some(test_config(1))));
+
+        // The next record should still be written and loaded after the corrupted one
+        store.save(VERSION, &test_config(3)).unwrap();
+        let mut store = ConfigStore::new(store.release(), 0);
+        assert_eq!(store.load::<TestConfig>(VERSION), Ok(Some(test_config(3))));
+    }
+
+    #[test]
+    fn test_erase_should_remove_all_records() {
+        let mut store = ConfigStore::new(MockFlash::<PAGE_SIZE, 2>::new(2), 0);
+        store.save(VERSION, &test_config(1)).unwrap();
+        store.erase().unwrap();
+
+        let mut store = ConfigStore::new(store.release(), 0);
+        assert_eq!(store.load::<TestConfig>(VERSION), Ok(None));
+    }
+}
@@ -0,0 +1,74 @@
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash,
+};
+
+// In-memory NOR flash, it behaves like the real one:
+// * erased bytes are 0xFF
+// * writing can only clear bits, so a byte has to be erased before it is written again
+pub struct MockFlash<const ERASE_SIZE: usize, const WRITE_SIZE: usize> {
+    data: Vec<u8>,
+}
+
+impl<const ERASE_SIZE: usize, const WRITE_SIZE: usize> MockFlash<ERASE_SIZE, WRITE_SIZE> {
+    pub fn new(pages: usize) -> Self {
+        Self {
+            data: vec![0xFF; ERASE_SIZE * pages],
+        }
+    }
+
+    // Flip the lowest bit of a byte to simulate data corruption
+    pub fn corrupt(&mut self, offset: usize) {
+        self.data[offset] ^= 0x01;
+    }
+}
+
+impl<const ERASE_SIZE: usize, const WRITE_SIZE: usize> ErrorType
+    for MockFlash<ERASE_SIZE, WRITE_SIZE>
+{
+    type Error = NorFlashErrorKind;
+}
+
+impl<const ERASE_SIZE: usize, const WRITE_SIZE: usize> ReadNorFlash
+    for MockFlash<ERASE_SIZE, WRITE_SIZE>
+{
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl<const ERASE_SIZE: usize, const WRITE_SIZE: usize> NorFlash
+    for MockFlash<ERASE_SIZE, WRITE_SIZE>
+{
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+
+        self.data[from as usize..to as usize].fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+
+        let offset = offset as usize;
+        for (dst, src) in self.data[offset..offset + bytes.len()]
+            .iter_mut()
+            .zip(bytes)
+        {
+            *dst &= *src;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,513 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::{interval, MissedTickBehavior};
+
+use motion_core::balance::BalanceController;
+use motion_core::config::default_config;
+use motion_core::hal::MotorDriver;
+use motion_core::heading::HeadingController;
+use motion_core::imu::{
+    is_at_rest, is_dmp_enabled, orientation_from_quaternion, quaternion_from_euler,
+};
+use motion_core::kinematics::{is_valid_twist, twist_to_wheel_cmds};
+use motion_core::motion::{is_valid_cmd, sync_axes};
+use motion_core::odometry::WheelOdometry;
+use motion_core::pid::{AutoTuneResult, Pid};
+use motion_core::program::ProgramExecutor;
+use motion_core::rpm_to_rad_s;
+use plant_sim::pendulum::{Pendulum, PendulumParams};
+use plant_sim::plant::PlantParams;
+use plant_sim::{Simulator, PERIOD_S};
+use protocol::*;
+use s_curve::SCurveInterpolator;
+
+// Events that are not published yet, the oldest one is dropped when it is full
+const EVENT_QUEUE_SIZE: usize = 64;
+// The commands of the base are applied to both motors, the error is reported for both
+const BASE_MOTOR_ID: u8 = MotorId::Left as u8 | MotorId::Right as u8;
+
+pub type MotorData = [(MotorId, MotorProcessData); 2];
+pub type SharedDevice = Arc<Mutex<Device>>;
+
+// Emulated board: two wheels driven by the motion logic and the simulated plant
+pub struct Device {
+    left: Simulator,
+    right: Simulator,
+    left_program: ProgramExecutor,
+    right_program: ProgramExecutor,
+    odometry: WheelOdometry,
+    heading: HeadingController,
+    balance: BalanceController,
+    // Chassis of the balancing robot, the base is on a flat floor when it is `None`
+    pendulum: Option<Pendulum>,
+    config: DeviceConfig,
+    saved_config: DeviceConfig,
+    events: VecDeque<DeviceEvent>,
+    // Same as firmware, the overflow is reported once until a command is accepted
+    overflow_motor_id: u8,
+    // Counter of the control cycle, the timestamp of the samples is taken from it
+    cycle: u32,
+}
+
+impl Device {
+    pub fn new(config: DeviceConfig) -> Self {
+        let mut device = Self {
+            left: create_simulator(&config.left.pid, &config),
+            right: create_simulator(&config.right.pid, &config),
+            left_program: ProgramExecutor::new(),
+            right_program: ProgramExecutor::new(),
+            odometry: WheelOdometry::new(PERIOD_S),
+            heading: HeadingController::new(PERIOD_S),
+            balance: BalanceController::new(PERIOD_S),
+            pendulum: None,
+            config,
+            saved_config: config,
+            events: VecDeque::new(),
+            overflow_motor_id: 0,
+            cycle: 0,
+        };
+        device.set_config(config);
+
+        device
+    }
+
+    // The chassis is balanced on two wheels, it starts upright and lies on the floor
+    // after it falls over
+    pub fn with_pendulum(config: DeviceConfig, params: PendulumParams) -> Self {
+        let mut device = Self::new(config);
+        device.pendulum = Some(Pendulum::new(params));
+
+        device
+    }
+
+    pub fn step(&mut self) {
+        self.cycle = self.cycle.wrapping_add(1);
+        self.left_program.run(self.left.motion_mut());
+        self.right_program.run(self.right.motion_mut());
+        sync_axes(self.left.motion_mut(), self.right.motion_mut());
+        self.heading.run(
+            &self.odometry.odometry(),
+            &self.config,
+            self.left.motion_mut(),
+            self.right.motion_mut(),
+        );
+        // The pitch of the previous cycle is used, same as the odometry
+        if let Some(event) = self.balance.run(
+            &self.config,
+            self.left.motion_mut(),
+            self.right.motion_mut(),
+        ) {
+            self.push_event(None, event);
+        }
+        self.left.step();
+        self.right.step();
+
+        if let Some(pendulum) = self.pendulum.as_mut() {
+            let base_vel = (self.left.plant().velocity() + self.right.plant().velocity()) / 2.0
+                * self.config.base.wheel_radius;
+            pendulum.step(base_vel, PERIOD_S);
+        }
+
+        // The simulated MPU6050 is read in every cycle, the gyro doesn't have bias
+        let imu = self.mpu6050_data();
+        self.odometry.set_gyro_rate(imu.g_z.to_radians());
+        self.balance.set_imu_data(imu);
+        let motors = [self.left.motion(), self.right.motion()].map(|x| &x.motor);
+        self.odometry.update(
+            motors.map(|x| x.get_act_position_in_rad()),
+            motors.map(|x| x.get_act_velocity_in_rpm()),
+            &self.config,
+        );
+
+        for id in [MotorId::Left, MotorId::Right] {
+            let mut events = Vec::new();
+            match self.simulator_mut(id).motion_mut().take_autotune_result() {
+                Some(AutoTuneResult::Applied(kp, ki, kd)) => {
+                    println!("{id:?} auto-tune finished, kp: {kp}, ki: {ki}, kd: {kd}");
+                    let gains = PidGains { kp, ki, kd };
+                    // Same as firmware, the gains are kept in the configuration, so they are
+                    // saved and not restored by the next change of configuration
+                    match id {
+                        MotorId::Left => self.config.left.pid = gains,
+                        MotorId::Right => self.config.right.pid = gains,
+                    }
+                    events.push(DeviceEventKind::AutoTuneFinished(Some(gains)));
+                }
+                Some(AutoTuneResult::Rejected) => {
+                    println!("{id:?} auto-tune rejected, oscillation is not consistent");
+                    events.push(DeviceEventKind::AutoTuneFinished(None));
+                }
+                None => (),
+            }
+            let motion = self.simulator_mut(id).motion_mut();
+            while let Some(event) = motion.take_event() {
+                events.push(event);
+            }
+
+            for event in events {
+                self.push_event(Some(id), event);
+            }
+        }
+    }
+
+    pub fn set_motor_cmd(&mut self, id: MotorId, cmd: SequencedCommand) -> CommandSetResult {
+        if !is_valid_cmd(&cmd.cmd) {
+            return Err(CommandError::InvalidCommand(id as u8));
+        }
+
+        // Same as firmware, `Halt` from host stops the program as well
+        if cmd.cmd == MotorCommand::Halt {
+            self.program_mut(id).cancel();
+        }
+
+        // `Halt` clears the queue in motion struct, so it is always accepted
+        let motion = self.simulator_mut(id).motion_mut();
+        let result = motion.push_cmd(cmd).map_err(|_cmd| {
+            if motion.fault().is_some() {
+                CommandError::Fault(id as u8)
+            } else {
+                CommandError::BufferFull(id as u8)
+            }
+        });
+
+        match result {
+            Ok(()) => self.overflow_motor_id &= !(id as u8),
+            Err(CommandError::BufferFull(_)) if self.overflow_motor_id & id as u8 == 0 => {
+                self.overflow_motor_id |= id as u8;
+                self.push_event(Some(id), DeviceEventKind::QueueOverflow);
+            }
+            Err(_) => (),
+        }
+        result
+    }
+
+    pub fn set_motor_cmds(&mut self, cmds: [(MotorId, SequencedCommand); 2]) -> CommandSetResult {
+        let mut full_motor_id = 0_u8;
+        let mut fault_motor_id = 0_u8;
+        let mut invalid_motor_id = 0_u8;
+        for (id, cmd) in cmds {
+            match self.set_motor_cmd(id, cmd) {
+                Err(CommandError::BufferFull(id)) => full_motor_id |= id,
+                Err(CommandError::Fault(id)) => fault_motor_id |= id,
+                Err(CommandError::InvalidCommand(id)) => invalid_motor_id |= id,
+                Ok(()) => (),
+            }
+        }
+
+        // Same as firmware, fault is reported first
+        if fault_motor_id != 0 {
+            Err(CommandError::Fault(fault_motor_id))
+        } else if invalid_motor_id != 0 {
+            Err(CommandError::InvalidCommand(invalid_motor_id))
+        } else if full_motor_id != 0 {
+            Err(CommandError::BufferFull(full_motor_id))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn set_base_twist(&mut self, twist: BaseTwistCommand) -> CommandSetResult {
+        if !is_valid_twist(&twist) {
+            return Err(CommandError::InvalidCommand(BASE_MOTOR_ID));
+        }
+        let cmds = twist_to_wheel_cmds(&twist, &self.config).map(|(id, cmd)| (id, cmd.into()));
+        self.set_motor_cmds(cmds)
+    }
+
+    pub fn set_heading_cmd(&mut self, cmd: HeadingCommand) -> CommandSetResult {
+        self.check_base_fault()?;
+        self.balance.cancel();
+        self.heading
+            .start(cmd, &self.odometry.odometry(), &self.config);
+        Ok(())
+    }
+
+    pub fn set_balance_cmd(&mut self, twist: BaseTwistCommand) -> CommandSetResult {
+        if !is_valid_twist(&twist) {
+            return Err(CommandError::InvalidCommand(BASE_MOTOR_ID));
+        }
+        self.check_base_fault()?;
+        self.heading.cancel();
+        self.balance.start(twist, &self.odometry.odometry());
+        Ok(())
+    }
+
+    // Same as firmware, the commands of a chunk are appended as long as there is space in
+    // the queue, and nothing is appended if one of the motors is in fault state
+    pub fn append_positions(&mut self, chunks: [(MotorId, PositionChunk); 2]) -> AppendResult {
+        let fault_motor_id = chunks
+            .iter()
+            .filter(|(id, chunk)| chunk.len > 0 && self.simulator(*id).motion().fault().is_some())
+            .fold(0_u8, |acc, (id, _chunk)| acc | *id as u8);
+        if fault_motor_id != 0 {
+            return Err(CommandError::Fault(fault_motor_id));
+        }
+
+        let mut accepted = [0_u8; 2];
+        for ((id, chunk), accepted) in chunks.into_iter().zip(accepted.iter_mut()) {
+            let motion = self.simulator_mut(id).motion_mut();
+            let free = motion.queue_free();
+            for cmd in chunk.sequenced_cmds().take(free) {
+                let _ = motion.push_cmd(cmd);
+                *accepted += 1;
+            }
+        }
+        Ok(accepted)
+    }
+
+    pub fn upload_program(&mut self, id: MotorId, program: MotionProgram) -> ProgramResult {
+        self.program_mut(id).load(program)
+    }
+
+    pub fn control_program(&mut self, id: MotorId, control: ProgramControl) -> ProgramResult {
+        let (program, simulator) = match id {
+            MotorId::Left => (&mut self.left_program, &mut self.left),
+            MotorId::Right => (&mut self.right_program, &mut self.right),
+        };
+
+        match control {
+            ProgramControl::Start => program.start(simulator.motion(), false),
+            ProgramControl::Loop => program.start(simulator.motion(), true),
+            ProgramControl::Stop => {
+                program.stop(simulator.motion_mut());
+                Ok(())
+            }
+        }
+    }
+
+    pub fn halt(&mut self) {
+        let _ = self.set_motor_cmd(MotorId::Left, MotorCommand::Halt.into());
+        let _ = self.set_motor_cmd(MotorId::Right, MotorCommand::Halt.into());
+    }
+
+    // The program stored in the device keeps running without the host
+    pub fn connection_lost(&mut self) {
+        for id in [MotorId::Left, MotorId::Right] {
+            if !self.program_mut(id).is_running() {
+                let _ = self.set_motor_cmd(id, MotorCommand::Halt.into());
+            }
+        }
+        self.push_event(None, DeviceEventKind::ConnectionLost);
+    }
+
+    pub fn take_event(&mut self) -> Option<DeviceEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn motor_data(&self) -> MotorData {
+        [
+            (
+                MotorId::Left,
+                self.process_data(&self.left, &self.left_program),
+            ),
+            (
+                MotorId::Right,
+                self.process_data(&self.right, &self.right_program),
+            ),
+        ]
+    }
+
+    // Simulated time of the control cycle, unit: us. The time of the plant is f32, and it
+    // loses the precision of us when the emulator runs for a while
+    pub fn timestamp_us(&self) -> u64 {
+        self.cycle as u64 * (PERIOD_S as f64 * 1e6).round() as u64
+    }
+
+    fn process_data(&self, simulator: &Simulator, program: &ProgramExecutor) -> MotorProcessData {
+        MotorProcessData {
+            program: program.status(simulator.motion()),
+            timestamp_us: self.timestamp_us(),
+            cycle: self.cycle,
+            ..simulator.motion().get_motor_process_data()
+        }
+    }
+
+    pub fn odometry(&self) -> Odometry {
+        Odometry {
+            heading_error: self.heading.heading_error(),
+            pitch: self.balance.pitch().unwrap_or(0.0),
+            ..self.odometry.odometry()
+        }
+    }
+
+    pub fn reset_pose(&mut self, pose: Pose) {
+        self.odometry.reset_pose(pose);
+    }
+
+    // The robot is on a flat floor, so the accelerometer only measures gravity, and the
+    // gyro measures the yaw rate caused by the difference of wheel velocities. The wheel
+    // velocities are treated as the velocities along forward direction. The pendulum
+    // model gives the pitch and the acceleration of the balancing robot, and the DMP
+    // orientation follows the pitch and the heading of odometry
+    pub fn mpu6050_data(&self) -> Mpu6050MotionData {
+        let base = &self.config.base;
+        let left_vel = self.left.plant().velocity() * base.wheel_radius;
+        let right_vel = self.right.plant().velocity() * base.wheel_radius;
+        let yaw_rate = (right_vel - left_vel) / base.track_width;
+
+        let (data, pitch) = match &self.pendulum {
+            Some(pendulum) => (pendulum.imu_data(yaw_rate), pendulum.pitch()),
+            None => {
+                let data = Mpu6050MotionData {
+                    acc_z: 1.0,
+                    g_z: yaw_rate.to_degrees(),
+                    ..Default::default()
+                };
+                (data, 0.0)
+            }
+        };
+        // Same as firmware, the DMP is not used when MPU6050 samples faster than it
+        let yaw = self.odometry.odometry().pose.theta;
+        let orientation = if is_dmp_enabled(&self.config.imu) {
+            orientation_from_quaternion(quaternion_from_euler(0.0, pitch, yaw))
+        } else {
+            None
+        };
+        Mpu6050MotionData {
+            orientation,
+            timestamp_us: self.timestamp_us(),
+            ..data
+        }
+    }
+
+    pub fn config(&self) -> DeviceConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: DeviceConfig) {
+        self.config = config;
+        self.left.motion_mut().apply_config(
+            &config.left,
+            config.vel_limit,
+            config.acc_limit,
+            config.jerk_limit,
+        );
+        self.right.motion_mut().apply_config(
+            &config.right,
+            config.vel_limit,
+            config.acc_limit,
+            config.jerk_limit,
+        );
+    }
+
+    // There is no flash in emulator, the saved configuration is kept until the emulator
+    // is closed. Same as firmware, it is saved only when motors are at rest
+    pub fn save_config(&mut self) -> ConfigSetResult {
+        if !self.is_at_rest() {
+            return Err(ConfigError::NotAtRest);
+        }
+
+        self.saved_config = self.config;
+        Ok(())
+    }
+
+    pub fn reset_config(&mut self) -> ConfigSetResult {
+        if !self.is_at_rest() {
+            return Err(ConfigError::NotAtRest);
+        }
+
+        self.set_config(default_config());
+        self.saved_config = self.config;
+        Ok(())
+    }
+
+    // The simulated MPU6050 has no bias, so the offsets found by the calibration are 0.
+    // Same as firmware, the offsets are kept in the configuration
+    pub fn calibrate_imu(&mut self, rqst: CalibrateImuRequest) -> CalibrationResult {
+        if !self.is_at_rest() {
+            return Err(CalibrationError::NotAtRest);
+        }
+
+        let calibration = ImuCalibration::default();
+        self.config.accel_calibration = calibration.accel;
+        self.config.gyro_calibration = calibration.gyro;
+        if rqst.persist {
+            self.save_config()
+                .map_err(|_e| CalibrationError::FlashError)?;
+        }
+        Ok(calibration)
+    }
+
+    fn is_at_rest(&self) -> bool {
+        self.motor_data().iter().all(|(_, x)| is_at_rest(x))
+    }
+
+    // The timestamp is the simulated time since the emulator starts
+    fn push_event(&mut self, motor: Option<MotorId>, kind: DeviceEventKind) {
+        if self.events.len() == EVENT_QUEUE_SIZE {
+            self.events.pop_front();
+        }
+        self.events.push_back(DeviceEvent {
+            timestamp_ms: (self.left.time_s() * 1000.0) as u32,
+            motor,
+            kind,
+        });
+    }
+
+    // Same as firmware, the controllers of the base are rejected when any axis is in fault
+    fn check_base_fault(&self) -> CommandSetResult {
+        let fault_motor_id = [MotorId::Left, MotorId::Right]
+            .into_iter()
+            .filter(|id| self.simulator(*id).motion().fault().is_some())
+            .fold(0_u8, |acc, id| acc | id as u8);
+        if fault_motor_id != 0 {
+            return Err(CommandError::Fault(fault_motor_id));
+        }
+        Ok(())
+    }
+
+    fn program_mut(&mut self, id: MotorId) -> &mut ProgramExecutor {
+        match id {
+            MotorId::Left => &mut self.left_program,
+            MotorId::Right => &mut self.right_program,
+        }
+    }
+
+    fn simulator(&self, id: MotorId) -> &Simulator {
+        match id {
+            MotorId::Left => &self.left,
+            MotorId::Right => &self.right,
+        }
+    }
+
+    fn simulator_mut(&mut self, id: MotorId) -> &mut Simulator {
+        match id {
+            MotorId::Left => &mut self.left,
+            MotorId::Right => &mut self.right,
+        }
+    }
+}
+
+fn create_simulator(pid_gains: &PidGains, config: &DeviceConfig) -> Simulator {
+    Simulator::new(
+        PlantParams::default(),
+        Pid::new(pid_gains.kp, pid_gains.ki, pid_gains.kd, 1.0),
+        SCurveInterpolator::new(
+            rpm_to_rad_s(config.vel_limit),
+            config.acc_limit,
+            config.jerk_limit,
+            PERIOD_S,
+        ),
+    )
+}
+
+// Same as the motion task in firmware, run control loop every `PERIOD_S` and notify the
+// publisher with the latest motor data
+pub async fn motion_task(device: SharedDevice, data_send: watch::Sender<MotorData>) {
+    let mut ticker = interval(Duration::from_secs_f32(PERIOD_S));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Burst);
+
+    loop {
+        ticker.tick().await;
+
+        let data = {
+            let mut device = device.lock().unwrap();
+            device.step();
+            device.motor_data()
+        };
+        data_send.send_replace(data);
+    }
+}
@@ -0,0 +1,32 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+use motion_core::config::default_config;
+
+use crate::device::{motion_task, Device};
+use crate::server::serve_connection;
+
+pub mod device;
+pub mod server;
+
+// Run the emulator, the clients are served one by one like the USB connection of the board
+pub async fn run(listener: TcpListener) -> io::Result<()> {
+    run_device(listener, Device::new(default_config())).await
+}
+
+// Run the emulator with the given device, ex: the balancing robot with the pendulum model
+pub async fn run_device(listener: TcpListener, device: Device) -> io::Result<()> {
+    let device = Arc::new(Mutex::new(device));
+    let (data_send, data_recv) = watch::channel(device.lock().unwrap().motor_data());
+    tokio::spawn(motion_task(device.clone(), data_send));
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        println!("client is connected: {addr}");
+
+        serve_connection(stream, device.clone(), data_recv.clone()).await;
+    }
+}
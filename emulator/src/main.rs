@@ -0,0 +1,32 @@
+use tokio::net::TcpListener;
+
+use emulator::device::Device;
+use host::tcp::DEFAULT_EMULATOR_ADDR;
+use motion_core::config::default_config;
+use plant_sim::pendulum::PendulumParams;
+
+// Usage: emulator [address] [--pendulum], the default address is `DEFAULT_EMULATOR_ADDR`.
+// The chassis is simulated as an inverted pendulum with `--pendulum`
+#[tokio::main]
+pub async fn main() {
+    let (flags, args): (Vec<_>, Vec<_>) =
+        std::env::args().skip(1).partition(|x| x.starts_with("--"));
+    let addr = args
+        .into_iter()
+        .next()
+        .unwrap_or(DEFAULT_EMULATOR_ADDR.to_string());
+    let device = if flags.iter().any(|x| x == "--pendulum") {
+        Device::with_pendulum(default_config(), PendulumParams::default())
+    } else {
+        Device::new(default_config())
+    };
+
+    let listener = TcpListener::bind(&addr)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to listen on {addr}, {e}"));
+    println!("Emulator is listening on {addr}");
+
+    if let Err(e) = emulator::run_device(listener, device).await {
+        println!("Emulator is stopped, {e}");
+    }
+}
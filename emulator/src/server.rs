@@ -0,0 +1,354 @@
+use std::time::Duration;
+
+use postcard_rpc::{
+    define_dispatch,
+    header::VarHeader,
+    server::{
+        impls::test_channels::{
+            dispatch_impl::{new_server, Settings, WireSpawnImpl, WireTxImpl},
+            ChannelWireRx, ChannelWireSpawn, ChannelWireTx,
+        },
+        Dispatch, Sender,
+    },
+};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio::time::interval;
+
+use host::tcp::{read_frame, write_frame, MAX_FRAME_SIZE};
+use motion_core::config::is_valid_config;
+use motion_core::imu::is_valid_imu_config;
+use protocol::*;
+
+use crate::device::{MotorData, SharedDevice};
+
+define_dispatch! {
+    app: EmulatorApp;
+    spawn_fn: spawn_fn;
+    tx_impl: WireTxImpl;
+    spawn_impl: WireSpawnImpl;
+    context: Context;
+
+    endpoints: {
+        list: ENDPOINT_LIST;
+
+        | EndpointTy                    | kind      | handler                       |
+        | ----------                    | ----      | -------                       |
+        | SetMotorCommandEndPoint       | blocking  | set_motor_cmd_handler         |
+        | SetMotorCommandsEndPoint      | blocking  | set_motor_cmds_handler        |
+        | AppendPositionsEndPoint       | blocking  | append_positions_handler      |
+        | UploadProgramEndPoint         | blocking  | upload_program_handler        |
+        | ControlProgramEndPoint        | blocking  | control_program_handler       |
+        | SetBaseTwistEndPoint          | blocking  | set_base_twist_handler        |
+        | SetHeadingCommandEndPoint     | blocking  | set_heading_cmd_handler       |
+        | SetBalanceCommandEndPoint     | blocking  | set_balance_cmd_handler       |
+        | ResetPoseEndPoint             | blocking  | reset_pose_handler            |
+        | GetConfigEndPoint             | blocking  | get_config_handler            |
+        | SetConfigEndPoint             | blocking  | set_config_handler            |
+        | SaveConfigEndPoint            | blocking  | save_config_handler           |
+        | ResetConfigEndPoint           | blocking  | reset_config_handler          |
+        | CalibrateImuEndPoint          | blocking  | calibrate_imu_handler         |
+        | SetImuConfigEndPoint          | blocking  | set_imu_config_handler        |
+    };
+    topics_in: {
+        list: TOPICS_IN_LIST;
+
+        | TopicTy                       | kind      | handler                       |
+        | ----------                    | ----      | -------                       |
+    };
+    topics_out: {
+        list: TOPICS_OUT_LIST;
+    };
+}
+
+const FRAME_QUEUE_SIZE: usize = 64;
+const EVENT_POLL_PERIOD: Duration = Duration::from_millis(10);
+// The odometry is integrated in every control cycle, it is published at a lower rate than
+// the firmware to keep the TCP traffic low
+const ODOMETRY_PUBLISH_PERIOD: Duration = Duration::from_millis(20);
+
+pub struct Context {
+    pub device: SharedDevice,
+}
+
+fn set_motor_cmd_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: (MotorId, SequencedCommand),
+) -> CommandSetResult {
+    context.device.lock().unwrap().set_motor_cmd(rqst.0, rqst.1)
+}
+
+fn set_motor_cmds_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: [(MotorId, SequencedCommand); 2],
+) -> CommandSetResult {
+    context.device.lock().unwrap().set_motor_cmds(rqst)
+}
+
+fn set_base_twist_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: BaseTwistCommand,
+) -> CommandSetResult {
+    context.device.lock().unwrap().set_base_twist(rqst)
+}
+
+fn set_heading_cmd_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: HeadingCommand,
+) -> CommandSetResult {
+    context.device.lock().unwrap().set_heading_cmd(rqst)
+}
+
+fn set_balance_cmd_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: BaseTwistCommand,
+) -> CommandSetResult {
+    context.device.lock().unwrap().set_balance_cmd(rqst)
+}
+
+fn reset_pose_handler(context: &mut Context, _header: VarHeader, rqst: Pose) {
+    context.device.lock().unwrap().reset_pose(rqst);
+}
+
+fn append_positions_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: [(MotorId, PositionChunk); 2],
+) -> AppendResult {
+    context.device.lock().unwrap().append_positions(rqst)
+}
+
+fn upload_program_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: (MotorId, MotionProgram),
+) -> ProgramResult {
+    context
+        .device
+        .lock()
+        .unwrap()
+        .upload_program(rqst.0, rqst.1)
+}
+
+fn control_program_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: (MotorId, ProgramControl),
+) -> ProgramResult {
+    context
+        .device
+        .lock()
+        .unwrap()
+        .control_program(rqst.0, rqst.1)
+}
+
+fn get_config_handler(context: &mut Context, _header: VarHeader, _rqst: ()) -> DeviceConfig {
+    context.device.lock().unwrap().config()
+}
+
+fn set_config_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: DeviceConfig,
+) -> ConfigSetResult {
+    if !is_valid_config(&rqst) {
+        return Err(ConfigError::InvalidConfig);
+    }
+
+    context.device.lock().unwrap().set_config(rqst);
+    Ok(())
+}
+
+fn save_config_handler(context: &mut Context, _header: VarHeader, _rqst: ()) -> ConfigSetResult {
+    context.device.lock().unwrap().save_config()
+}
+
+fn reset_config_handler(context: &mut Context, _header: VarHeader, _rqst: ()) -> ConfigSetResult {
+    context.device.lock().unwrap().reset_config()
+}
+
+fn calibrate_imu_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: CalibrateImuRequest,
+) -> CalibrationResult {
+    context.device.lock().unwrap().calibrate_imu(rqst)
+}
+
+fn set_imu_config_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: ImuConfig,
+) -> ConfigSetResult {
+    if !is_valid_imu_config(&rqst) {
+        return Err(ConfigError::InvalidConfig);
+    }
+
+    let mut device = context.device.lock().unwrap();
+    let config = DeviceConfig {
+        imu: rqst,
+        ..device.config()
+    };
+    device.set_config(config);
+    Ok(())
+}
+
+// Serve one client until the connection is closed. The postcard-rpc server runs on
+// channels, and the frames in channels are forwarded from/to the TCP stream
+pub async fn serve_connection(
+    stream: TcpStream,
+    device: SharedDevice,
+    data_recv: watch::Receiver<MotorData>,
+) {
+    let _ = stream.set_nodelay(true);
+    let (mut reader, mut writer) = stream.into_split();
+    let (client_to_server_send, client_to_server_recv) = mpsc::channel(FRAME_QUEUE_SIZE);
+    let (server_to_client_send, mut server_to_client_recv) =
+        mpsc::channel::<Vec<u8>>(FRAME_QUEUE_SIZE);
+
+    let read_task = tokio::spawn(async move {
+        while let Ok(frame) = read_frame(&mut reader).await {
+            if client_to_server_send.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+    let write_task = tokio::spawn(async move {
+        while let Some(frame) = server_to_client_recv.recv().await {
+            if write_frame(&mut writer, &frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let app = EmulatorApp::new(
+        Context {
+            device: device.clone(),
+        },
+        ChannelWireSpawn {},
+    );
+    let kkind = app.min_key_len();
+    let mut server = new_server(
+        app,
+        Settings {
+            tx: ChannelWireTx::new(server_to_client_send),
+            rx: ChannelWireRx::new(client_to_server_recv),
+            buf: MAX_FRAME_SIZE,
+            kkind,
+        },
+    );
+
+    let motor_data_task = tokio::spawn(motor_data_publish_task(server.sender(), data_recv));
+    let mpu6050_task = tokio::spawn(mpu6050_data_publish_task(server.sender(), device.clone()));
+    let event_task = tokio::spawn(device_event_publish_task(server.sender(), device.clone()));
+    let odometry_task = tokio::spawn(odometry_publish_task(server.sender(), device.clone()));
+
+    // The server stops when the client is disconnected
+    let _ = server.run().await;
+
+    // Same as the firmware, halt motors when connection is lost. The event is published
+    // to the next client
+    device.lock().unwrap().connection_lost();
+    println!("connection is lost, halt motors");
+
+    for task in [
+        read_task,
+        write_task,
+        motor_data_task,
+        mpu6050_task,
+        event_task,
+        odometry_task,
+    ] {
+        task.abort();
+    }
+}
+
+async fn motor_data_publish_task(
+    app_sender: Sender<WireTxImpl>,
+    mut data_recv: watch::Receiver<MotorData>,
+) {
+    let mut motor_topic_seq = 0_u8;
+    while data_recv.changed().await.is_ok() {
+        let data = *data_recv.borrow_and_update();
+        let _ = app_sender
+            .publish::<MotorProcessDataTopic>(motor_topic_seq.into(), &data)
+            .await;
+
+        motor_topic_seq = motor_topic_seq.wrapping_add(1);
+    }
+}
+
+async fn mpu6050_data_publish_task(app_sender: Sender<WireTxImpl>, device: SharedDevice) {
+    let mut mpu6050_topic_seq = 0_u8;
+    let mut sample = 0_u32;
+    let mut sample_rate_hz = device.lock().unwrap().config().imu.sample_rate_hz;
+    let mut ticker = interval(mpu6050_sample_period(sample_rate_hz));
+
+    loop {
+        ticker.tick().await;
+
+        // The publish rate follows `ImuConfig::sample_rate_hz`
+        let rate_hz = device.lock().unwrap().config().imu.sample_rate_hz;
+        if rate_hz != sample_rate_hz {
+            sample_rate_hz = rate_hz;
+            ticker = interval(mpu6050_sample_period(sample_rate_hz));
+        }
+
+        // The simulated MPU6050 is read at the time of the latest control cycle
+        sample = sample.wrapping_add(1);
+        let data = Mpu6050MotionData {
+            sample,
+            ..device.lock().unwrap().mpu6050_data()
+        };
+        let _ = app_sender
+            .publish::<Mpu6050MotionDataTopic>(mpu6050_topic_seq.into(), &data)
+            .await;
+
+        mpu6050_topic_seq = mpu6050_topic_seq.wrapping_add(1);
+    }
+}
+
+fn mpu6050_sample_period(rate_hz: u16) -> Duration {
+    Duration::from_secs_f64(1.0 / rate_hz as f64)
+}
+
+async fn odometry_publish_task(app_sender: Sender<WireTxImpl>, device: SharedDevice) {
+    let mut odometry_topic_seq = 0_u8;
+    let mut ticker = interval(ODOMETRY_PUBLISH_PERIOD);
+
+    loop {
+        ticker.tick().await;
+
+        let odometry = device.lock().unwrap().odometry();
+        let _ = app_sender
+            .publish::<OdometryTopic>(odometry_topic_seq.into(), &odometry)
+            .await;
+
+        odometry_topic_seq = odometry_topic_seq.wrapping_add(1);
+    }
+}
+
+async fn device_event_publish_task(app_sender: Sender<WireTxImpl>, device: SharedDevice) {
+    let mut event_topic_seq = 0_u8;
+    let mut ticker = interval(EVENT_POLL_PERIOD);
+
+    loop {
+        ticker.tick().await;
+
+        // The lock can't be held across `await`
+        let events: Vec<DeviceEvent> =
+            std::iter::from_fn(|| device.lock().unwrap().take_event()).collect();
+        for event in events {
+            let _ = app_sender
+                .publish::<DeviceEventTopic>(event_topic_seq.into(), &event)
+                .await;
+
+            event_topic_seq = event_topic_seq.wrapping_add(1);
+        }
+    }
+}
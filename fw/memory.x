@@ -0,0 +1,7 @@
+MEMORY
+{
+    /* The last 2 pages (2 * 2K) of the 256K flash are reserved for the configuration, the
+       length must match `FIRMWARE_FLASH_SIZE` in src/config.rs */
+    FLASH : ORIGIN = 0x08000000, LENGTH = 252K
+    RAM   : ORIGIN = 0x20000000, LENGTH =  40K
+}
@@ -1,22 +1,32 @@
 use embassy_stm32::peripherals::USB;
 use embassy_stm32::usb;
 use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex};
+use embassy_sync::channel;
 use embassy_sync::pubsub::Publisher;
-use embassy_sync::watch::Receiver;
+use embassy_sync::signal::Signal;
+use embassy_sync::watch::{Receiver, Sender};
+use embassy_time::Instant;
 
 use postcard_rpc::{
     define_dispatch,
     header::VarHeader,
     server::{
         impls::embassy_usb_v0_4::{
-            dispatch_impl::{WireRxBuf, WireRxImpl, WireSpawnImpl, WireStorage, WireTxImpl},
+            dispatch_impl::{
+                spawn_fn, WireRxBuf, WireRxImpl, WireSpawnImpl, WireStorage, WireTxImpl,
+            },
             PacketBuffers,
         },
-        Server,
+        Sender as AppSender, Server, SpawnContext,
     },
 };
 use static_cell::ConstStaticCell;
 
+use crate::config::{default_config, is_valid_config, SharedConfig};
+use motion_core::imu::{is_at_rest, is_valid_imu_config};
+use motion_core::kinematics::{is_valid_twist, twist_to_wheel_cmds};
+use motion_core::motion::is_valid_cmd;
+use motion_core::program::is_valid_program;
 use protocol::*;
 
 define_dispatch! {
@@ -33,6 +43,19 @@ define_dispatch! {
         | ----------                    | ----      | -------                       |
         | SetMotorCommandEndPoint       | async     | set_motor_cmd_handler         |
         | SetMotorCommandsEndPoint      | async     | set_motor_cmds_handler        |
+        | AppendPositionsEndPoint       | blocking  | append_positions_handler      |
+        | UploadProgramEndPoint         | async     | upload_program_handler        |
+        | ControlProgramEndPoint        | async     | control_program_handler       |
+        | SetBaseTwistEndPoint          | blocking  | set_base_twist_handler        |
+        | SetHeadingCommandEndPoint     | blocking  | set_heading_cmd_handler       |
+        | SetBalanceCommandEndPoint     | blocking  | set_balance_cmd_handler       |
+        | ResetPoseEndPoint             | blocking  | reset_pose_handler            |
+        | GetConfigEndPoint             | blocking  | get_config_handler            |
+        | SetConfigEndPoint             | blocking  | set_config_handler            |
+        | SaveConfigEndPoint            | blocking  | save_config_handler           |
+        | ResetConfigEndPoint           | blocking  | reset_config_handler          |
+        | CalibrateImuEndPoint          | spawn     | calibrate_imu_handler         |
+        | SetImuConfigEndPoint          | blocking  | set_imu_config_handler        |
     };
     topics_in: {
         list: TOPICS_IN_LIST;
@@ -46,6 +69,17 @@ define_dispatch! {
 }
 
 pub const CHANNEL_SIZE: usize = 48;
+// Every command dropped by halt or fault is reported, so it is larger than the command
+// queue in motion struct
+pub const EVENT_CHANNEL_SIZE: usize = 48;
+// The handler waits until the motion controller task takes the previous request
+pub const PROGRAM_CHANNEL_SIZE: usize = 1;
+// The commands of the base are applied to both motors, the error is reported for both
+const BASE_MOTOR_ID: u8 = MotorId::Left as u8 | MotorId::Right as u8;
+// The handler waits for the result before sending the next calibration request
+pub const CALIBRATION_CHANNEL_SIZE: usize = 1;
+// Both motors can finish auto-tune in the same control cycle
+pub const TUNED_GAINS_CHANNEL_SIZE: usize = 2;
 
 pub static PBUFS: ConstStaticCell<BufStorage> = ConstStaticCell::new(BufStorage::new());
 pub static STORAGE: AppStorage = AppStorage::new();
@@ -56,6 +90,83 @@ pub type BufStorage = PacketBuffers<1024, 1024>;
 pub type AppTx = WireTxImpl<ThreadModeRawMutex, AppDriver>;
 pub type AppRx = WireRxImpl<AppDriver>;
 pub type AppServer = Server<AppTx, AppRx, WireRxBuf, MyApp>;
+pub type EventSender =
+    channel::Sender<'static, CriticalSectionRawMutex, DeviceEvent, EVENT_CHANNEL_SIZE>;
+pub type EventReceiver =
+    channel::Receiver<'static, CriticalSectionRawMutex, DeviceEvent, EVENT_CHANNEL_SIZE>;
+pub type ProgramControlSender = channel::Sender<
+    'static,
+    CriticalSectionRawMutex,
+    (MotorId, ProgramControl),
+    PROGRAM_CHANNEL_SIZE,
+>;
+pub type ProgramControlReceiver = channel::Receiver<
+    'static,
+    CriticalSectionRawMutex,
+    (MotorId, ProgramControl),
+    PROGRAM_CHANNEL_SIZE,
+>;
+pub type ProgramSignal = Signal<CriticalSectionRawMutex, MotionProgram>;
+pub type CalibrationSender =
+    channel::Sender<'static, CriticalSectionRawMutex, ReferenceGravity, CALIBRATION_CHANNEL_SIZE>;
+pub type CalibrationReceiver =
+    channel::Receiver<'static, CriticalSectionRawMutex, ReferenceGravity, CALIBRATION_CHANNEL_SIZE>;
+pub type CalibrationResultSender =
+    channel::Sender<'static, CriticalSectionRawMutex, CalibrationResult, CALIBRATION_CHANNEL_SIZE>;
+pub type CalibrationResultReceiver = channel::Receiver<
+    'static,
+    CriticalSectionRawMutex,
+    CalibrationResult,
+    CALIBRATION_CHANNEL_SIZE,
+>;
+pub type TunedGainsSender = channel::Sender<
+    'static,
+    CriticalSectionRawMutex,
+    (MotorId, PidGains),
+    TUNED_GAINS_CHANNEL_SIZE,
+>;
+pub type TunedGainsReceiver = channel::Receiver<
+    'static,
+    CriticalSectionRawMutex,
+    (MotorId, PidGains),
+    TUNED_GAINS_CHANNEL_SIZE,
+>;
+
+// MPU6050 is owned by its publisher task, the calibration is run there
+pub struct CalibrationChannels {
+    pub request: CalibrationReceiver,
+    pub result: CalibrationResultSender,
+}
+
+// The program is large, so it is not sent through the channel of program controls. The
+// motion controller task takes it from the slot of the motor, and a program that is not
+// taken yet is replaced by the new one
+pub struct ProgramSlots {
+    left: ProgramSignal,
+    right: ProgramSignal,
+}
+
+impl ProgramSlots {
+    pub const fn new() -> Self {
+        Self {
+            left: Signal::new(),
+            right: Signal::new(),
+        }
+    }
+
+    pub fn slot(&self, id: MotorId) -> &ProgramSignal {
+        match id {
+            MotorId::Left => &self.left,
+            MotorId::Right => &self.right,
+        }
+    }
+}
+
+impl Default for ProgramSlots {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 #[derive(Clone, Copy)]
 pub struct MotorStatus {
@@ -66,17 +177,72 @@ pub struct MotorStatus {
 
 pub struct Context {
     pub left_motor_cmd_pub:
-        Publisher<'static, CriticalSectionRawMutex, MotorCommand, CHANNEL_SIZE, 1, 2>,
+        Publisher<'static, CriticalSectionRawMutex, SequencedCommand, CHANNEL_SIZE, 1, 2>,
     pub right_motor_cmd_pub:
-        Publisher<'static, CriticalSectionRawMutex, MotorCommand, CHANNEL_SIZE, 1, 2>,
+        Publisher<'static, CriticalSectionRawMutex, SequencedCommand, CHANNEL_SIZE, 1, 2>,
     pub left_motor_status: Receiver<'static, CriticalSectionRawMutex, MotorStatus, 2>,
     pub right_motor_status: Receiver<'static, CriticalSectionRawMutex, MotorStatus, 2>,
+    pub config: &'static SharedConfig,
+    pub config_sender: Sender<'static, CriticalSectionRawMutex, DeviceConfig, 2>,
+    pub event_sender: EventSender,
+    // Motor ids (as bits) whose overflow is reported, the host retries the rejected
+    // command, so the event is only sent again after a command is accepted
+    pub overflow_motor_id: u8,
+    pub program_slots: &'static ProgramSlots,
+    pub program_control_sender: ProgramControlSender,
+    // Motor ids (as bits) that have a program, the program is kept in RAM
+    pub program_motor_id: u8,
+    // The pose of odometry is reset by motion task in the next control cycle
+    pub pose_sender: Sender<'static, CriticalSectionRawMutex, Pose, 1>,
+    // The heading controller is started by motion task in the next control cycle
+    pub heading_sender: Sender<'static, CriticalSectionRawMutex, HeadingCommand, 1>,
+    // The balance controller is started by motion task in the next control cycle
+    pub balance_sender: Sender<'static, CriticalSectionRawMutex, BaseTwistCommand, 1>,
+    // MPU6050 is owned by its publisher task, the calibration is run there
+    pub calibration_sender: CalibrationSender,
+    pub calibration_result: CalibrationResultReceiver,
+}
+
+// The calibration takes a few seconds, so its handler is spawned and the other requests
+// are handled in the meantime. Only one calibration runs at a time, the spawn fails when
+// it is requested again before the previous one is done
+pub struct CalibrationContext {
+    // The motors are checked when the request is received
+    is_at_rest: bool,
+    config: &'static SharedConfig,
+    config_sender: Sender<'static, CriticalSectionRawMutex, DeviceConfig, 2>,
+    calibration_sender: CalibrationSender,
+    calibration_result: CalibrationResultReceiver,
+}
+
+impl SpawnContext for Context {
+    type SpawnCtxt = CalibrationContext;
+
+    fn spawn_ctxt(&mut self) -> Self::SpawnCtxt {
+        CalibrationContext {
+            is_at_rest: are_motors_at_rest(self),
+            config: self.config,
+            config_sender: self.config_sender.clone(),
+            calibration_sender: self.calibration_sender,
+            calibration_result: self.calibration_result,
+        }
+    }
+}
+
+// Events are sent from every task and published by `device_event_publish_task`, the
+// event is dropped if the channel is full, ex: the host is disconnected for a long time
+pub fn send_event(sender: &EventSender, motor: Option<MotorId>, kind: DeviceEventKind) {
+    let _ = sender.try_send(DeviceEvent {
+        timestamp_ms: Instant::now().as_millis() as u32,
+        motor,
+        kind,
+    });
 }
 
 async fn set_motor_cmd_helper(
     context: &mut Context,
     id: MotorId,
-    cmd: MotorCommand,
+    seq_cmd: SequencedCommand,
 ) -> CommandSetResult {
     // Indicates the internal buffer in motion controller is full or not, need to check
     // this flag before sending commands to motion controller task
@@ -93,6 +259,11 @@ async fn set_motor_cmd_helper(
     // Currently, the size of PubSubChannel is more than Deque, so if user pushes too
     // many position commands, the Dequeu will be full first, and the further commands
     // will not be pushed to PubSubChannel
+    //
+    // The host uses the queue credits in `MotorProcessData` to send commands only when
+    // there is space in the Deque, so `BufferFull` is only returned when the credits
+    // are not followed, ex: several hosts send commands to the same motor
+    let cmd = seq_cmd.cmd;
     let (queue_status, channel_pub) = match id {
         MotorId::Left => (&mut context.left_motor_status, &context.left_motor_cmd_pub),
         MotorId::Right => (
@@ -101,28 +272,60 @@ async fn set_motor_cmd_helper(
         ),
     };
 
+    // Motion commands are rejected when the axis is in fault state, only `Halt` and
+    // `ResetFault` are passed to the motion controller task
+    let is_motion_cmd = !matches!(cmd, MotorCommand::Halt | MotorCommand::ResetFault);
+    if is_motion_cmd
+        && queue_status
+            .try_get()
+            .is_some_and(|x| x.process_data.fault.is_some())
+    {
+        return Err(CommandError::Fault(id as u8));
+    }
+    if !is_valid_cmd(&cmd) {
+        return Err(CommandError::InvalidCommand(id as u8));
+    }
+
     // The `Halt` command has the highest priority, so it can be sent when the queue in motion
     // struct is full.
     let can_push = match cmd {
-        MotorCommand::VelocityCommand(_) | MotorCommand::Halt => true,
-        MotorCommand::PositionCommand(_) | MotorCommand::AutoTuneCommand(_) => {
-            !queue_status.changed().await.is_queue_full
-        }
+        MotorCommand::VelocityCommand(_) | MotorCommand::Halt | MotorCommand::ResetFault => true,
+        MotorCommand::PositionCommand(_)
+        | MotorCommand::AutoTuneCommand(_)
+        | MotorCommand::Dwell(_)
+        | MotorCommand::WaitForOtherAxis
+        | MotorCommand::Marker(_) => !queue_status.changed().await.is_queue_full,
     };
 
-    if can_push {
+    let result = if can_push {
         channel_pub
-            .try_publish(cmd)
+            .try_publish(seq_cmd)
             .map_err(|_e| CommandError::BufferFull(id as u8))
     } else {
         Err(CommandError::BufferFull(id as u8))
+    };
+
+    report_overflow(context, id, result.is_err());
+    result
+}
+
+fn report_overflow(context: &mut Context, id: MotorId, is_overflow: bool) {
+    if !is_overflow {
+        context.overflow_motor_id &= !(id as u8);
+    } else if context.overflow_motor_id & id as u8 == 0 {
+        context.overflow_motor_id |= id as u8;
+        send_event(
+            &context.event_sender,
+            Some(id),
+            DeviceEventKind::QueueOverflow,
+        );
     }
 }
 
 async fn set_motor_cmd_handler(
     context: &mut Context,
     _header: VarHeader,
-    rqst: (MotorId, MotorCommand),
+    rqst: (MotorId, SequencedCommand),
 ) -> CommandSetResult {
     set_motor_cmd_helper(context, rqst.0, rqst.1).await
 }
@@ -130,20 +333,348 @@ async fn set_motor_cmd_handler(
 async fn set_motor_cmds_handler(
     context: &mut Context,
     _header: VarHeader,
-    rqst: [(MotorId, MotorCommand); 2],
+    rqst: [(MotorId, SequencedCommand); 2],
+) -> CommandSetResult {
+    set_motor_cmds_helper(context, rqst).await
+}
+
+fn set_base_twist_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: BaseTwistCommand,
 ) -> CommandSetResult {
-    let mut err_motor_id = 0_u8;
-    for (id, cmd) in rqst {
+    if !is_valid_twist(&rqst) {
+        return Err(CommandError::InvalidCommand(BASE_MOTOR_ID));
+    }
+    check_base_fault(context)?;
+
+    // The motion task runs on the interrupt executor, so it can take the command of the
+    // left wheel before the right one is published. Both are published in one critical
+    // section, so both wheels change velocity in the same control cycle
+    let config = context.config.lock(|x| x.borrow().config);
+    let [(_, left), (_, right)] = twist_to_wheel_cmds(&rqst, &config);
+    let full_motor_id = cortex_m::interrupt::free(|_| {
+        let mut full_motor_id = 0_u8;
+        for (id, channel_pub) in [
+            (MotorId::Left, &context.left_motor_cmd_pub),
+            (MotorId::Right, &context.right_motor_cmd_pub),
+        ] {
+            if channel_pub.is_full() {
+                full_motor_id |= id as u8;
+            }
+        }
+        if full_motor_id == 0 {
+            // The space is checked in the same critical section, it doesn't fail
+            let _ = context.left_motor_cmd_pub.try_publish(left.into());
+            let _ = context.right_motor_cmd_pub.try_publish(right.into());
+        }
+        full_motor_id
+    });
+
+    report_overflow(context, MotorId::Left, full_motor_id != 0);
+    report_overflow(context, MotorId::Right, full_motor_id != 0);
+    if full_motor_id != 0 {
+        Err(CommandError::BufferFull(full_motor_id))
+    } else {
+        Ok(())
+    }
+}
+
+async fn set_motor_cmds_helper(
+    context: &mut Context,
+    cmds: [(MotorId, SequencedCommand); 2],
+) -> CommandSetResult {
+    let mut full_motor_id = 0_u8;
+    let mut fault_motor_id = 0_u8;
+    let mut invalid_motor_id = 0_u8;
+    for (id, cmd) in cmds {
         if let Err(e) = set_motor_cmd_helper(context, id, cmd).await {
             match e {
-                CommandError::BufferFull(id) => err_motor_id |= id,
+                CommandError::BufferFull(id) => full_motor_id |= id,
+                CommandError::Fault(id) => fault_motor_id |= id,
+                CommandError::InvalidCommand(id) => invalid_motor_id |= id,
             }
         }
     }
 
-    if err_motor_id == 0 {
-        Ok(())
+    // Fault and invalid command are reported first, the command will not be accepted by
+    // retrying
+    if fault_motor_id != 0 {
+        Err(CommandError::Fault(fault_motor_id))
+    } else if invalid_motor_id != 0 {
+        Err(CommandError::InvalidCommand(invalid_motor_id))
+    } else if full_motor_id != 0 {
+        Err(CommandError::BufferFull(full_motor_id))
     } else {
-        Err(CommandError::BufferFull(err_motor_id))
+        Ok(())
+    }
+}
+
+fn set_heading_cmd_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: HeadingCommand,
+) -> CommandSetResult {
+    check_base_fault(context)?;
+    context.heading_sender.send(rqst);
+    Ok(())
+}
+
+fn set_balance_cmd_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: BaseTwistCommand,
+) -> CommandSetResult {
+    if !is_valid_twist(&rqst) {
+        return Err(CommandError::InvalidCommand(BASE_MOTOR_ID));
+    }
+    check_base_fault(context)?;
+    context.balance_sender.send(rqst);
+    Ok(())
+}
+
+// The controllers of the base drive both wheels, so they are rejected when any axis is
+// in fault
+fn check_base_fault(context: &mut Context) -> CommandSetResult {
+    let mut fault_motor_id = 0_u8;
+    for (id, status) in [
+        (MotorId::Left, &mut context.left_motor_status),
+        (MotorId::Right, &mut context.right_motor_status),
+    ] {
+        if status
+            .try_get()
+            .is_some_and(|x| x.process_data.fault.is_some())
+        {
+            fault_motor_id |= id as u8;
+        }
+    }
+    if fault_motor_id != 0 {
+        return Err(CommandError::Fault(fault_motor_id));
     }
+    Ok(())
+}
+
+fn reset_pose_handler(context: &mut Context, _header: VarHeader, rqst: Pose) {
+    context.pose_sender.send(rqst);
+}
+
+// The position commands are published to the motion controller task without waiting, so
+// the other handlers can't put commands between them. The number of commands is limited by
+// the free entries of the Deque in motion controller, the commands that are already in the
+// PubSubChannel take the entries as well
+fn append_positions_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: [(MotorId, PositionChunk); 2],
+) -> AppendResult {
+    let mut fault_motor_id = 0_u8;
+    for (id, chunk) in rqst.iter() {
+        let queue_status = match id {
+            MotorId::Left => &mut context.left_motor_status,
+            MotorId::Right => &mut context.right_motor_status,
+        };
+        if chunk.len > 0
+            && queue_status
+                .try_get()
+                .is_some_and(|x| x.process_data.fault.is_some())
+        {
+            fault_motor_id |= *id as u8;
+        }
+    }
+    if fault_motor_id != 0 {
+        return Err(CommandError::Fault(fault_motor_id));
+    }
+
+    let mut accepted = [0_u8; 2];
+    for ((id, chunk), accepted) in rqst.into_iter().zip(accepted.iter_mut()) {
+        let (queue_status, channel_pub) = match id {
+            MotorId::Left => (&mut context.left_motor_status, &context.left_motor_cmd_pub),
+            MotorId::Right => (
+                &mut context.right_motor_status,
+                &context.right_motor_cmd_pub,
+            ),
+        };
+
+        // Nothing is appended before the motion controller task reports its status
+        let queue_free = queue_status
+            .try_get()
+            .map_or(0, |x| x.process_data.queue_free as usize);
+        let in_channel = channel_pub.len();
+        let free = queue_free.saturating_sub(in_channel);
+
+        for cmd in chunk.sequenced_cmds().take(free) {
+            if channel_pub.try_publish(cmd).is_err() {
+                break;
+            }
+            *accepted += 1;
+        }
+
+        if *accepted > 0 {
+            context.overflow_motor_id &= !(id as u8);
+        }
+    }
+    Ok(accepted)
+}
+
+async fn upload_program_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: (MotorId, MotionProgram),
+) -> ProgramResult {
+    let (id, program) = rqst;
+    if !is_valid_program(&program) {
+        return Err(ProgramError::InvalidProgram);
+    }
+    if motor_status(context, id).is_some_and(|x| x.process_data.program.is_some()) {
+        return Err(ProgramError::Running);
+    }
+
+    context.program_slots.slot(id).signal(program);
+    context.program_motor_id |= id as u8;
+    Ok(())
+}
+
+async fn control_program_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: (MotorId, ProgramControl),
+) -> ProgramResult {
+    let (id, control) = rqst;
+    if control != ProgramControl::Stop {
+        if context.program_motor_id & id as u8 == 0 {
+            return Err(ProgramError::NoProgram);
+        }
+        if motor_status(context, id).is_some_and(|x| x.process_data.fault.is_some()) {
+            return Err(ProgramError::Fault);
+        }
+    }
+
+    context.program_control_sender.send((id, control)).await;
+    Ok(())
+}
+
+#[embassy_executor::task]
+async fn calibrate_imu_handler(
+    context: CalibrationContext,
+    header: VarHeader,
+    rqst: CalibrateImuRequest,
+    sender: AppSender<AppTx>,
+) {
+    let result = calibrate_imu(&context, rqst).await;
+    // The offsets are kept in the configuration even if the host is disconnected
+    let _ = sender
+        .reply::<CalibrateImuEndPoint>(header.seq_no, &result)
+        .await;
+}
+
+async fn calibrate_imu(
+    context: &CalibrationContext,
+    rqst: CalibrateImuRequest,
+) -> CalibrationResult {
+    if !context.is_at_rest {
+        return Err(CalibrationError::NotAtRest);
+    }
+
+    context.calibration_sender.send(rqst.gravity).await;
+    let calibration = context.calibration_result.receive().await?;
+
+    // The offsets are applied to MPU6050 already, they are kept in the configuration so
+    // `SaveConfigEndPoint` doesn't overwrite them with the old ones
+    context.config.lock(|x| {
+        let state = &mut *x.borrow_mut();
+        state.config.accel_calibration = calibration.accel;
+        state.config.gyro_calibration = calibration.gyro;
+        context.config_sender.send(state.config);
+        if rqst.persist {
+            state
+                .store
+                .save(DEVICE_CONFIG_VERSION, &state.config)
+                .map_err(|_e| CalibrationError::FlashError)?;
+        }
+        Ok(calibration)
+    })
+}
+
+fn motor_status(context: &mut Context, id: MotorId) -> Option<MotorStatus> {
+    match id {
+        MotorId::Left => context.left_motor_status.try_get(),
+        MotorId::Right => context.right_motor_status.try_get(),
+    }
+}
+
+fn are_motors_at_rest(context: &mut Context) -> bool {
+    [MotorId::Left, MotorId::Right]
+        .into_iter()
+        .all(|id| motor_status(context, id).is_none_or(|x| is_at_rest(&x.process_data)))
+}
+
+fn get_config_handler(context: &mut Context, _header: VarHeader, _rqst: ()) -> DeviceConfig {
+    context.config.lock(|x| x.borrow().config)
+}
+
+fn set_config_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: DeviceConfig,
+) -> ConfigSetResult {
+    if !is_valid_config(&rqst) {
+        return Err(ConfigError::InvalidConfig);
+    }
+
+    // PID gains, interpolation limits and IMU configuration are applied right away, and IMU
+    // calibration is applied after reboot. The configuration is only kept in RAM until
+    // `SaveConfigEndPoint` is called
+    context.config.lock(|x| x.borrow_mut().config = rqst);
+    context.config_sender.send(rqst);
+    Ok(())
+}
+
+fn set_imu_config_handler(
+    context: &mut Context,
+    _header: VarHeader,
+    rqst: ImuConfig,
+) -> ConfigSetResult {
+    if !is_valid_imu_config(&rqst) {
+        return Err(ConfigError::InvalidConfig);
+    }
+
+    // The MPU6050 task applies it and changes the publish rate, it is kept in RAM in the
+    // same way as `SetConfigEndPoint`
+    let config = context.config.lock(|x| {
+        let mut state = x.borrow_mut();
+        state.config.imu = rqst;
+        state.config
+    });
+    context.config_sender.send(config);
+    Ok(())
+}
+
+fn save_config_handler(context: &mut Context, _header: VarHeader, _rqst: ()) -> ConfigSetResult {
+    // The CPU is stalled when flash is being erased or written, and this also delays the
+    // motion task, so the configuration is saved only when motors are not moving
+    if !are_motors_at_rest(context) {
+        return Err(ConfigError::NotAtRest);
+    }
+
+    context.config.lock(|x| {
+        let state = &mut *x.borrow_mut();
+        state
+            .store
+            .save(DEVICE_CONFIG_VERSION, &state.config)
+            .map_err(|_e| ConfigError::FlashError)
+    })
+}
+
+fn reset_config_handler(context: &mut Context, _header: VarHeader, _rqst: ()) -> ConfigSetResult {
+    // Same as saving, flash is erased only when motors are not moving
+    if !are_motors_at_rest(context) {
+        return Err(ConfigError::NotAtRest);
+    }
+
+    context.config_sender.send(default_config());
+    context.config.lock(|x| {
+        let mut state = x.borrow_mut();
+        state.config = default_config();
+        state.store.erase().map_err(|_e| ConfigError::FlashError)
+    })
 }
@@ -0,0 +1,59 @@
+use core::cell::RefCell;
+
+use config_store::ConfigStore;
+use defmt::{info, warn};
+use embassy_stm32::flash::{Blocking, Flash, FLASH_SIZE, MAX_ERASE_SIZE};
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+
+use protocol::{DeviceConfig, VelocityEstimator, DEVICE_CONFIG_VERSION};
+
+pub use motion_core::config::default_config;
+
+// The configuration is stored in the last 2 pages of flash, the firmware must not grow
+// into this region
+pub const CONFIG_FLASH_OFFSET: u32 = (FLASH_SIZE - 2 * MAX_ERASE_SIZE) as u32;
+
+// Length of FLASH region in memory.x, the linker fails if the firmware is larger
+const FIRMWARE_FLASH_SIZE: u32 = 252 * 1024;
+const _: () = assert!(
+    FIRMWARE_FLASH_SIZE <= CONFIG_FLASH_OFFSET,
+    "memory.x must not give the config pages to the firmware"
+);
+
+pub type AppFlash = Flash<'static, Blocking>;
+pub type AppConfigStore = ConfigStore<AppFlash>;
+// The configuration is changed by the handlers and the spawned IMU calibration handler, all
+// of them run in thread mode
+pub type SharedConfig = Mutex<ThreadModeRawMutex, RefCell<ConfigState>>;
+
+// The configuration in RAM and the flash it is saved to
+pub struct ConfigState {
+    pub config: DeviceConfig,
+    pub store: AppConfigStore,
+}
+
+// The timers of the encoders run in QEI mode, they don't capture the time of the edges, so
+// M/T estimator can't be used on the board
+pub fn is_valid_config(config: &DeviceConfig) -> bool {
+    motion_core::config::is_valid_config(config)
+        && [&config.left, &config.right]
+            .iter()
+            .all(|x| x.vel_estimator != VelocityEstimator::MT)
+}
+
+pub fn load_config(store: &mut AppConfigStore) -> DeviceConfig {
+    match store.load::<DeviceConfig>(DEVICE_CONFIG_VERSION) {
+        Ok(Some(config)) if is_valid_config(&config) => {
+            info!("config is loaded from flash");
+            config
+        }
+        Ok(_) => {
+            info!("no valid config in flash, use default config");
+            default_config()
+        }
+        Err(_) => {
+            warn!("failed to load config from flash, use default config");
+            default_config()
+        }
+    }
+}
@@ -1,78 +1,110 @@
 #![no_std]
 #![no_main]
 
+use core::cell::RefCell;
+
 use embassy_executor::{InterruptExecutor, Spawner};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
 use embassy_sync::pubsub::PubSubChannel;
 use embassy_sync::watch::Watch;
 use embassy_usb::{Config, UsbDevice};
 
 use embassy_stm32::bind_interrupts;
+use embassy_stm32::flash::Flash;
 use embassy_stm32::gpio::{Level, Output, OutputType, Speed};
-use embassy_stm32::i2c::{self};
 use embassy_stm32::interrupt;
 use embassy_stm32::interrupt::{InterruptExt, Priority};
 use embassy_stm32::pac;
-use embassy_stm32::peripherals::{self, TIM2, TIM3, TIM8};
+use embassy_stm32::peripherals;
 use embassy_stm32::time::Hertz;
 use embassy_stm32::timer::low_level::{CountingMode, Timer as LLTimer};
 use embassy_stm32::timer::simple_pwm::{PwmPin, SimplePwm};
 use embassy_stm32::usb;
 
 use defmt::info;
+use static_cell::StaticCell;
 use {defmt_rtt as _, panic_probe as _};
 
 use postcard_rpc::server::{Dispatch, Server};
 
+use config_store::ConfigStore;
 use fw::{
     communication::communication::*,
-    motion::{
-        encoder::Encoder,
-        motion::{Motion, MOTION_CMD_QUEUE_SIZE},
-        motor::BldcMotor24H,
-        pid::Pid,
-    },
+    config::{load_config, ConfigState, SharedConfig, CONFIG_FLASH_OFFSET},
+    motion::hal::{EmbassyDelay, GpioOutput, PwmChannelOutput, QeiEncoderInput},
     rpm_to_rad_s,
     task::{
-        motion_controller::{motion_task, TIMER_SIGNAL},
+        device_event_publisher::device_event_publish_task,
+        motion_controller::{motion_task, BaseChannels, MotorChannels, TIMER_SIGNAL},
         motion_data_publisher::motor_data_publish_task,
-        mpu6050_data_publisher::mpu6050_data_publish_task,
+        mpu6050_data_publisher::{mpu6050_data_publish_task, Mpu6050Bus},
+        odometry_publisher::odometry_publish_task,
+        tuned_gains_writer::tuned_gains_write_task,
     },
 };
+use motion_core::{
+    config::motor_counts_per_rev, encoder::Encoder, motion::Motion, motor::BldcMotor24H, pid::Pid,
+};
 use protocol::*;
 use s_curve::*;
 
 const PERIOD_S: f32 = 0.005;
 const PWM_HZ: u32 = 20_000;
-const VEL_LIMIT_RPM: f32 = 4000.0;
 
 static EXECUTOR_TIMER: InterruptExecutor = InterruptExecutor::new();
+static SHARED_CONFIG: StaticCell<SharedConfig> = StaticCell::new();
 static LEFT_MOTOR_CMD_CHANNEL: PubSubChannel<
     CriticalSectionRawMutex,
-    MotorCommand,
+    SequencedCommand,
     CHANNEL_SIZE,
     1,
     2,
 > = PubSubChannel::new();
 static RIGHT_MOTOR_CMD_CHANNEL: PubSubChannel<
     CriticalSectionRawMutex,
-    MotorCommand,
+    SequencedCommand,
     CHANNEL_SIZE,
     1,
     2,
 > = PubSubChannel::new();
 static LEFT_MOTOR_STATUS_WATCH: Watch<CriticalSectionRawMutex, MotorStatus, 2> = Watch::new();
 static RIGHT_MOTOR_STATUS_WATCH: Watch<CriticalSectionRawMutex, MotorStatus, 2> = Watch::new();
+static CONFIG_WATCH: Watch<CriticalSectionRawMutex, DeviceConfig, 2> = Watch::new();
+static POSE_WATCH: Watch<CriticalSectionRawMutex, Pose, 1> = Watch::new();
+static ODOMETRY_WATCH: Watch<CriticalSectionRawMutex, Odometry, 1> = Watch::new();
+static IMU_WATCH: Watch<CriticalSectionRawMutex, Mpu6050MotionData, 1> = Watch::new();
+static HEADING_WATCH: Watch<CriticalSectionRawMutex, HeadingCommand, 1> = Watch::new();
+static BALANCE_WATCH: Watch<CriticalSectionRawMutex, BaseTwistCommand, 1> = Watch::new();
+static EVENT_CHANNEL: Channel<CriticalSectionRawMutex, DeviceEvent, EVENT_CHANNEL_SIZE> =
+    Channel::new();
+static PROGRAM_CHANNEL: Channel<
+    CriticalSectionRawMutex,
+    (MotorId, ProgramControl),
+    PROGRAM_CHANNEL_SIZE,
+> = Channel::new();
+static PROGRAM_SLOTS: ProgramSlots = ProgramSlots::new();
+static CALIBRATION_CHANNEL: Channel<
+    CriticalSectionRawMutex,
+    ReferenceGravity,
+    CALIBRATION_CHANNEL_SIZE,
+> = Channel::new();
+static CALIBRATION_RESULT_CHANNEL: Channel<
+    CriticalSectionRawMutex,
+    CalibrationResult,
+    CALIBRATION_CHANNEL_SIZE,
+> = Channel::new();
+static TUNED_GAINS_CHANNEL: Channel<
+    CriticalSectionRawMutex,
+    (MotorId, PidGains),
+    TUNED_GAINS_CHANNEL_SIZE,
+> = Channel::new();
 
 bind_interrupts!(struct UsbIrqs {
     USB_LP_CAN_RX0 => usb::InterruptHandler<peripherals::USB>;
 });
 
-bind_interrupts!(struct I2cIrqs {
-    I2C1_EV => i2c::EventInterruptHandler<peripherals::I2C1>;
-    I2C1_ER => i2c::ErrorInterruptHandler<peripherals::I2C1>;
-});
-
 #[interrupt]
 unsafe fn TIM1_BRK_TIM15() {
     let sr = pac::TIM15.sr().read();
@@ -128,17 +160,37 @@ async fn main(spawner: Spawner) {
     }
     let p = embassy_stm32::init(config);
 
-    let left_wheel_enc: Encoder<'_, TIM2, 400> = Encoder::new(p.TIM2, p.PD3, p.PD4);
+    // Load configuration from flash, default configuration is used if it is not found
+    let mut config_store = ConfigStore::new(Flash::new_blocking(p.FLASH), CONFIG_FLASH_OFFSET);
+    let device_config = load_config(&mut config_store);
+
+    let left_wheel_enc = Encoder::new(
+        QeiEncoderInput::new(p.TIM2, p.PD3, p.PD4),
+        motor_counts_per_rev(&device_config.left.mechanical),
+    );
     let left_wheel_pwm_pin = PwmPin::new_ch3(p.PB0, OutputType::PushPull);
-    let left_wheel_dir_pin = Output::new(p.PA4, Level::High, Speed::Low);
-    let left_wheel_break_pin = Output::new(p.PC1, Level::High, Speed::Low);
-    let left_wheel_pid = Pid::new(0.00006, 0.00124, 0.000000728, 1.0);
+    let left_wheel_dir_pin = GpioOutput::new(Output::new(p.PA4, Level::High, Speed::Low));
+    let left_wheel_break_pin = GpioOutput::new(Output::new(p.PC1, Level::High, Speed::Low));
+    let left_wheel_pid = Pid::new(
+        device_config.left.pid.kp,
+        device_config.left.pid.ki,
+        device_config.left.pid.kd,
+        1.0,
+    );
 
-    let right_wheel_enc: Encoder<'_, TIM8, 400> = Encoder::new(p.TIM8, p.PC6, p.PC7);
+    let right_wheel_enc = Encoder::new(
+        QeiEncoderInput::new(p.TIM8, p.PC6, p.PC7),
+        motor_counts_per_rev(&device_config.right.mechanical),
+    );
     let right_wheel_pwm_pin = PwmPin::new_ch1(p.PB4, OutputType::PushPull);
-    let right_wheel_dir_pin = Output::new(p.PB5, Level::High, Speed::Low);
-    let right_wheel_break_pin = Output::new(p.PB3, Level::High, Speed::Low);
-    let right_wheel_pid = Pid::new(0.00006, 0.00124, 0.000000728, 1.0);
+    let right_wheel_dir_pin = GpioOutput::new(Output::new(p.PB5, Level::High, Speed::Low));
+    let right_wheel_break_pin = GpioOutput::new(Output::new(p.PB3, Level::High, Speed::Low));
+    let right_wheel_pid = Pid::new(
+        device_config.right.pid.kp,
+        device_config.right.pid.ki,
+        device_config.right.pid.kd,
+        1.0,
+    );
 
     let pwm = SimplePwm::new(
         p.TIM3,
@@ -151,8 +203,8 @@ async fn main(spawner: Spawner) {
     );
 
     let pwm_channels = pwm.split();
-    let left_wheel_pwm_ch = pwm_channels.ch3;
-    let right_wheel_pwm_ch = pwm_channels.ch1;
+    let left_wheel_pwm_ch = PwmChannelOutput::new(pwm_channels.ch3);
+    let right_wheel_pwm_ch = PwmChannelOutput::new(pwm_channels.ch1);
 
     // Create motors
     let left_wheel = BldcMotor24H::new(
@@ -160,6 +212,7 @@ async fn main(spawner: Spawner) {
         left_wheel_pwm_ch,
         left_wheel_dir_pin,
         left_wheel_break_pin,
+        EmbassyDelay,
         left_wheel_pid,
         PERIOD_S,
     );
@@ -169,33 +222,23 @@ async fn main(spawner: Spawner) {
         right_wheel_pwm_ch,
         right_wheel_dir_pin,
         right_wheel_break_pin,
+        EmbassyDelay,
         right_wheel_pid,
         PERIOD_S,
     );
 
     // Create s_curve interpolator for left, right wheel
-    let vel_limit_rad_s = rpm_to_rad_s(VEL_LIMIT_RPM);
     let left_s_curve_intper = SCurveInterpolator::new(
-        vel_limit_rad_s,
-        vel_limit_rad_s * 10.0,
-        vel_limit_rad_s * 100.0,
+        rpm_to_rad_s(device_config.vel_limit),
+        device_config.acc_limit,
+        device_config.jerk_limit,
         PERIOD_S,
     );
     let right_s_curve_intper = left_s_curve_intper.clone();
 
     // Create motion controller for left, right wheel
-    let left_motion_controller =
-        Motion::<CriticalSectionRawMutex, TIM2, TIM3, CHANNEL_SIZE, MOTION_CMD_QUEUE_SIZE>::new(
-            left_s_curve_intper,
-            left_wheel,
-            LEFT_MOTOR_CMD_CHANNEL.subscriber().unwrap(),
-        );
-    let right_motion_controller =
-        Motion::<CriticalSectionRawMutex, TIM8, TIM3, CHANNEL_SIZE, MOTION_CMD_QUEUE_SIZE>::new(
-            right_s_curve_intper,
-            right_wheel,
-            RIGHT_MOTOR_CMD_CHANNEL.subscriber().unwrap(),
-        );
+    let left_motion_controller = Motion::new(left_s_curve_intper, left_wheel);
+    let right_motion_controller = Motion::new(right_s_curve_intper, right_wheel);
 
     // Create timer
     let low_level_timer = LLTimer::new(p.TIM15);
@@ -205,28 +248,42 @@ async fn main(spawner: Spawner) {
     low_level_timer.enable_update_interrupt(true);
     low_level_timer.start();
 
-    // Create I2C
-    let i2c = i2c::I2c::new(
-        p.I2C1,
-        p.PB6,
-        p.PB7,
-        I2cIrqs,
-        p.DMA1_CH6,
-        p.DMA1_CH7,
-        Hertz(400_000),
-        Default::default(),
-    );
+    // I2C bus of MPU6050, the driver is created in MPU6050 task
+    let mpu6050_bus = Mpu6050Bus {
+        i2c: p.I2C1,
+        scl: p.PB6,
+        sda: p.PB7,
+        tx_dma: p.DMA1_CH6,
+        rx_dma: p.DMA1_CH7,
+    };
 
     // USB/RPC init
     let driver = usb::Driver::new(p.USB, UsbIrqs, p.PA12, p.PA11);
     let pbufs = PBUFS.take();
     let config = usb_config();
 
+    let shared_config: &'static SharedConfig =
+        SHARED_CONFIG.init(Mutex::new(RefCell::new(ConfigState {
+            config: device_config,
+            store: config_store,
+        })));
     let context = Context {
         left_motor_cmd_pub: LEFT_MOTOR_CMD_CHANNEL.publisher().unwrap(),
         right_motor_cmd_pub: RIGHT_MOTOR_CMD_CHANNEL.publisher().unwrap(),
         left_motor_status: LEFT_MOTOR_STATUS_WATCH.receiver().unwrap(),
         right_motor_status: RIGHT_MOTOR_STATUS_WATCH.receiver().unwrap(),
+        config: shared_config,
+        config_sender: CONFIG_WATCH.sender(),
+        event_sender: EVENT_CHANNEL.sender(),
+        overflow_motor_id: 0,
+        program_slots: &PROGRAM_SLOTS,
+        program_control_sender: PROGRAM_CHANNEL.sender(),
+        program_motor_id: 0,
+        pose_sender: POSE_WATCH.sender(),
+        heading_sender: HEADING_WATCH.sender(),
+        balance_sender: BALANCE_WATCH.sender(),
+        calibration_sender: CALIBRATION_CHANNEL.sender(),
+        calibration_result: CALIBRATION_RESULT_CHANNEL.receiver(),
     };
     let (device, tx_impl, rx_impl) = STORAGE.init(driver, config, pbufs.tx_buf.as_mut_slice());
 
@@ -250,8 +307,25 @@ async fn main(spawner: Spawner) {
         .spawn(motion_task(
             left_motion_controller,
             right_motion_controller,
-            LEFT_MOTOR_STATUS_WATCH.sender(),
-            RIGHT_MOTOR_STATUS_WATCH.sender(),
+            MotorChannels {
+                left_cmd_sub: LEFT_MOTOR_CMD_CHANNEL.subscriber().unwrap(),
+                right_cmd_sub: RIGHT_MOTOR_CMD_CHANNEL.subscriber().unwrap(),
+                left_motor_status: LEFT_MOTOR_STATUS_WATCH.sender(),
+                right_motor_status: RIGHT_MOTOR_STATUS_WATCH.sender(),
+                program_slots: &PROGRAM_SLOTS,
+                program_control_recv: PROGRAM_CHANNEL.receiver(),
+                tuned_gains_sender: TUNED_GAINS_CHANNEL.sender(),
+            },
+            BaseChannels {
+                pose_recv: POSE_WATCH.receiver().unwrap(),
+                odometry_sender: ODOMETRY_WATCH.sender(),
+                imu_recv: IMU_WATCH.receiver().unwrap(),
+                heading_recv: HEADING_WATCH.receiver().unwrap(),
+                balance_recv: BALANCE_WATCH.receiver().unwrap(),
+            },
+            device_config,
+            CONFIG_WATCH.receiver().unwrap(),
+            EVENT_CHANNEL.sender(),
         ))
         .unwrap();
 
@@ -261,9 +335,37 @@ async fn main(spawner: Spawner) {
         server.sender(),
         LEFT_MOTOR_CMD_CHANNEL.publisher().unwrap(),
         RIGHT_MOTOR_CMD_CHANNEL.publisher().unwrap(),
+        EVENT_CHANNEL.sender(),
     ));
 
-    spawner.must_spawn(mpu6050_data_publish_task(server.sender(), i2c));
+    spawner.must_spawn(mpu6050_data_publish_task(
+        server.sender(),
+        mpu6050_bus,
+        device_config,
+        CONFIG_WATCH.receiver().unwrap(),
+        EVENT_CHANNEL.sender(),
+        IMU_WATCH.sender(),
+        CalibrationChannels {
+            request: CALIBRATION_CHANNEL.receiver(),
+            result: CALIBRATION_RESULT_CHANNEL.sender(),
+        },
+    ));
+
+    spawner.must_spawn(odometry_publish_task(
+        ODOMETRY_WATCH.receiver().unwrap(),
+        server.sender(),
+    ));
+
+    spawner.must_spawn(device_event_publish_task(
+        EVENT_CHANNEL.receiver(),
+        server.sender(),
+    ));
+
+    spawner.must_spawn(tuned_gains_write_task(
+        shared_config,
+        TUNED_GAINS_CHANNEL.receiver(),
+        CONFIG_WATCH.sender(),
+    ));
 
     loop {
         let _ = server.run().await;
@@ -0,0 +1,84 @@
+use embassy_stm32::gpio::Output;
+use embassy_stm32::timer::low_level::OutputPolarity;
+use embassy_stm32::timer::qei::*;
+use embassy_stm32::timer::simple_pwm::SimplePwmChannel;
+use embassy_stm32::timer::GeneralInstance4Channel;
+use embassy_stm32::timer::{Channel1Pin, Channel2Pin};
+use embassy_stm32::Peripheral;
+use embassy_time::{block_for, Duration};
+
+use motion_core::hal::{BlockingDelay, DigitalOutput, EncoderInput, PwmOutput};
+
+// Implementations of `motion_core::hal` traits for the peripherals of STM32
+
+pub struct QeiEncoderInput<'a, T: GeneralInstance4Channel> {
+    qei: Qei<'a, T>,
+}
+
+impl<'a, T: GeneralInstance4Channel> QeiEncoderInput<'a, T> {
+    pub fn new(
+        tim: impl Peripheral<P = T> + 'a,
+        enc_a_pin: impl Peripheral<P = impl Channel1Pin<T>> + 'a,
+        enc_b_pin: impl Peripheral<P = impl Channel2Pin<T>> + 'a,
+    ) -> Self {
+        let enc_a_pin = QeiPin::new_ch1(enc_a_pin);
+        let enc_b_pin = QeiPin::new_ch2(enc_b_pin);
+        Self {
+            qei: Qei::new(tim, enc_a_pin, enc_b_pin),
+        }
+    }
+}
+
+impl<T: GeneralInstance4Channel> EncoderInput for QeiEncoderInput<'_, T> {
+    fn count(&mut self) -> u16 {
+        self.qei.count()
+    }
+}
+
+pub struct PwmChannelOutput<'a, T: GeneralInstance4Channel> {
+    channel: SimplePwmChannel<'a, T>,
+}
+
+impl<'a, T: GeneralInstance4Channel> PwmChannelOutput<'a, T> {
+    pub fn new(mut channel: SimplePwmChannel<'a, T>) -> Self {
+        // 24H motor, 0% duty: full speed, 100% duty: 0 speed
+        channel.set_polarity(OutputPolarity::ActiveLow);
+        channel.enable();
+
+        Self { channel }
+    }
+}
+
+impl<T: GeneralInstance4Channel> PwmOutput for PwmChannelOutput<'_, T> {
+    fn set_duty_cycle_percent(&mut self, percent: u8) {
+        self.channel.set_duty_cycle_percent(percent);
+    }
+}
+
+pub struct GpioOutput<'a> {
+    pin: Output<'a>,
+}
+
+impl<'a> GpioOutput<'a> {
+    pub fn new(pin: Output<'a>) -> Self {
+        Self { pin }
+    }
+}
+
+impl DigitalOutput for GpioOutput<'_> {
+    fn set_high(&mut self) {
+        self.pin.set_high();
+    }
+
+    fn set_low(&mut self) {
+        self.pin.set_low();
+    }
+}
+
+pub struct EmbassyDelay;
+
+impl BlockingDelay for EmbassyDelay {
+    fn delay_us(&mut self, us: u32) {
+        block_for(Duration::from_micros(us as u64));
+    }
+}
@@ -1,4 +1,17 @@
-pub mod encoder;
-pub mod motion;
-pub mod motor;
-pub mod pid;
+pub mod hal;
+
+use motion_core::motion::Motion;
+use motion_core::motor::BldcMotor24H;
+
+use crate::motion::hal::{EmbassyDelay, GpioOutput, PwmChannelOutput, QeiEncoderInput};
+
+// The `CHANNEL_SIZE` is used in `PubSubChannel` and `MOTION_CMD_QUEUE_SIZE` is used
+// in motion struct. If the queue in motion struct is full, I want to make sure there
+// are spaces in `PubSubChannel`, so `Halt` command can be sent to motion struct.
+// And for the other commands, since they don't have the same priority as `Halt`, the
+// sender needs to wait until there are spaces in the queue.
+pub const MOTION_CMD_QUEUE_SIZE: usize = 32;
+
+pub type AppMotor<'a, T1, T2> =
+    BldcMotor24H<QeiEncoderInput<'a, T1>, PwmChannelOutput<'a, T2>, GpioOutput<'a>, EmbassyDelay>;
+pub type AppMotion<'a, T1, T2> = Motion<AppMotor<'a, T1, T2>, MOTION_CMD_QUEUE_SIZE>;
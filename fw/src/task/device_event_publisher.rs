@@ -0,0 +1,31 @@
+use embassy_time::Timer;
+
+use postcard_rpc::server::Sender;
+
+use crate::communication::communication::{AppTx, EventReceiver};
+use protocol::*;
+
+// Time to wait before publishing the same event again when the host is not connected
+const RETRY_PERIOD_MS: u64 = 100;
+
+#[embassy_executor::task]
+pub async fn device_event_publish_task(event_recv: EventReceiver, app_sender: Sender<AppTx>) {
+    let mut event_topic_seq = 0_u8;
+
+    loop {
+        let event = event_recv.receive().await;
+
+        // The event is kept until it is published, so the events that happen when the
+        // connection is broken (ex: `ConnectionLost`) are received after the host is
+        // connected again. The newer events are kept in the channel in the meantime
+        while app_sender
+            .publish::<DeviceEventTopic>(event_topic_seq.into(), &event)
+            .await
+            .is_err()
+        {
+            Timer::after_millis(RETRY_PERIOD_MS).await;
+        }
+
+        event_topic_seq = event_topic_seq.wrapping_add(1);
+    }
+}
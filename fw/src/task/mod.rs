@@ -1,3 +1,6 @@
+pub mod device_event_publisher;
 pub mod motion_controller;
 pub mod motion_data_publisher;
 pub mod mpu6050_data_publisher;
+pub mod odometry_publisher;
+pub mod tuned_gains_writer;
@@ -1,54 +1,313 @@
+use defmt::{info, warn, Debug2Format};
+
 use embassy_stm32::peripherals::{TIM2, TIM3, TIM8};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::{Subscriber, WaitResult};
 use embassy_sync::signal::Signal;
-use embassy_sync::watch::Sender as WatchSender;
+use embassy_sync::watch::{Receiver as WatchReceiver, Sender as WatchSender};
+use embassy_time::Instant;
 
-use crate::communication::communication::{MotorStatus, CHANNEL_SIZE};
-use crate::motion::motion::{Motion, MOTION_CMD_QUEUE_SIZE};
+use crate::communication::communication::{
+    send_event, EventSender, MotorStatus, ProgramControlReceiver, ProgramSlots, TunedGainsSender,
+    CHANNEL_SIZE,
+};
+use crate::motion::AppMotion;
+use motion_core::balance::BalanceController;
+use motion_core::hal::MotorDriver;
+use motion_core::heading::HeadingController;
+use motion_core::motion::{sync_axes, Motion};
+use motion_core::odometry::WheelOdometry;
+use motion_core::pid::AutoTuneResult;
+use motion_core::program::ProgramExecutor;
 use protocol::*;
 
 pub static TIMER_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
+pub type MotorCommandSubscriber =
+    Subscriber<'static, CriticalSectionRawMutex, SequencedCommand, CHANNEL_SIZE, 1, 2>;
+
+// Commands, programs and status of the motors
+pub struct MotorChannels {
+    pub left_cmd_sub: MotorCommandSubscriber,
+    pub right_cmd_sub: MotorCommandSubscriber,
+    pub left_motor_status: WatchSender<'static, CriticalSectionRawMutex, MotorStatus, 2>,
+    pub right_motor_status: WatchSender<'static, CriticalSectionRawMutex, MotorStatus, 2>,
+    pub program_slots: &'static ProgramSlots,
+    pub program_control_recv: ProgramControlReceiver,
+    // The gains found by auto-tune are written to the configuration by another task
+    pub tuned_gains_sender: TunedGainsSender,
+}
+
+// Odometry, IMU data and the controllers of the base, they drive both motors
+pub struct BaseChannels {
+    pub pose_recv: WatchReceiver<'static, CriticalSectionRawMutex, Pose, 1>,
+    pub odometry_sender: WatchSender<'static, CriticalSectionRawMutex, Odometry, 1>,
+    pub imu_recv: WatchReceiver<'static, CriticalSectionRawMutex, Mpu6050MotionData, 1>,
+    pub heading_recv: WatchReceiver<'static, CriticalSectionRawMutex, HeadingCommand, 1>,
+    pub balance_recv: WatchReceiver<'static, CriticalSectionRawMutex, BaseTwistCommand, 1>,
+}
+
 #[embassy_executor::task]
 pub async fn motion_task(
-    mut left_motion_controller: Motion<
-        'static,
-        CriticalSectionRawMutex,
-        TIM2,
-        TIM3,
-        CHANNEL_SIZE,
-        MOTION_CMD_QUEUE_SIZE,
-    >,
-    mut right_motion_controller: Motion<
-        'static,
-        CriticalSectionRawMutex,
-        TIM8,
-        TIM3,
-        CHANNEL_SIZE,
-        MOTION_CMD_QUEUE_SIZE,
-    >,
-    left_motor_status: WatchSender<'static, CriticalSectionRawMutex, MotorStatus, 2>,
-    right_motor_status: WatchSender<'static, CriticalSectionRawMutex, MotorStatus, 2>,
+    mut left_motion_controller: AppMotion<'static, TIM2, TIM3>,
+    mut right_motion_controller: AppMotion<'static, TIM8, TIM3>,
+    motors: MotorChannels,
+    base: BaseChannels,
+    mut device_config: DeviceConfig,
+    mut config: WatchReceiver<'static, CriticalSectionRawMutex, DeviceConfig, 2>,
+    event_sender: EventSender,
 ) {
+    let MotorChannels {
+        mut left_cmd_sub,
+        mut right_cmd_sub,
+        left_motor_status,
+        right_motor_status,
+        program_slots,
+        program_control_recv,
+        tuned_gains_sender,
+    } = motors;
+    let BaseChannels {
+        mut pose_recv,
+        odometry_sender,
+        mut imu_recv,
+        mut heading_recv,
+        mut balance_recv,
+    } = base;
+    let mut left_program = ProgramExecutor::new();
+    let mut right_program = ProgramExecutor::new();
+    let mut odometry = WheelOdometry::new(left_motion_controller.motor.get_period_s());
+    let mut heading = HeadingController::new(left_motion_controller.motor.get_period_s());
+    let mut balance = BalanceController::new(left_motion_controller.motor.get_period_s());
+    let mut cycle = 0_u32;
+
     loop {
         TIMER_SIGNAL.wait().await;
+        // Both motors are sampled at the start of the cycle
+        let timestamp_us = Instant::now().as_micros();
+        cycle = cycle.wrapping_add(1);
+
+        if let Some(config) = config.try_changed() {
+            device_config = config;
+            left_motion_controller.apply_config(
+                &config.left,
+                config.vel_limit,
+                config.acc_limit,
+                config.jerk_limit,
+            );
+            right_motion_controller.apply_config(
+                &config.right,
+                config.vel_limit,
+                config.acc_limit,
+                config.jerk_limit,
+            );
+        }
 
-        left_motion_controller.read_cmd_from_queue();
-        right_motion_controller.read_cmd_from_queue();
+        // The program is uploaded before it is started, so it is loaded first. It is
+        // validated by the handler, the errors are already reported to the host
+        if let Some(x) = program_slots.slot(MotorId::Left).try_take() {
+            let _ = left_program.load(x);
+        }
+        if let Some(x) = program_slots.slot(MotorId::Right).try_take() {
+            let _ = right_program.load(x);
+        }
+        if let Ok((id, control)) = program_control_recv.try_receive() {
+            match id {
+                MotorId::Left => {
+                    handle_program_control(&mut left_program, &mut left_motion_controller, control)
+                }
+                MotorId::Right => handle_program_control(
+                    &mut right_program,
+                    &mut right_motion_controller,
+                    control,
+                ),
+            }
+        }
+
+        read_cmd_from_channel(
+            &mut left_motion_controller,
+            &mut left_program,
+            &mut left_cmd_sub,
+        );
+        read_cmd_from_channel(
+            &mut right_motion_controller,
+            &mut right_program,
+            &mut right_cmd_sub,
+        );
+
+        left_program.run(&mut left_motion_controller);
+        right_program.run(&mut right_motion_controller);
+
+        sync_axes(&mut left_motion_controller, &mut right_motion_controller);
+
+        // The odometry of the previous cycle is used, it is updated after the motors run.
+        // Only one controller of the base drives the wheels, the new command takes over
+        if let Some(cmd) = heading_recv.try_changed() {
+            balance.cancel();
+            heading.start(cmd, &odometry.odometry(), &device_config);
+        }
+        if let Some(twist) = balance_recv.try_changed() {
+            heading.cancel();
+            balance.start(twist, &odometry.odometry());
+        }
+        heading.run(
+            &odometry.odometry(),
+            &device_config,
+            &mut left_motion_controller,
+            &mut right_motion_controller,
+        );
+        if let Some(imu) = imu_recv.try_changed() {
+            odometry.set_gyro_rate(imu.g_z.to_radians());
+            balance.set_imu_data(imu);
+        }
+        if let Some(event) = balance.run(
+            &device_config,
+            &mut left_motion_controller,
+            &mut right_motion_controller,
+        ) {
+            warn!("balance cutoff, wheels halted");
+            send_event(&event_sender, None, event);
+        }
 
         left_motion_controller.run();
         right_motion_controller.run();
 
+        if let Some(pose) = pose_recv.try_changed() {
+            odometry.reset_pose(pose);
+        }
+        odometry.update(
+            [
+                left_motion_controller.motor.get_act_position_in_rad(),
+                right_motion_controller.motor.get_act_position_in_rad(),
+            ],
+            [
+                left_motion_controller.motor.get_act_velocity_in_rpm(),
+                right_motion_controller.motor.get_act_velocity_in_rpm(),
+            ],
+            &device_config,
+        );
+        odometry_sender.send(Odometry {
+            heading_error: heading.heading_error(),
+            pitch: balance.pitch().unwrap_or(0.0),
+            ..odometry.odometry()
+        });
+
+        report_events(
+            MotorId::Left,
+            &mut left_motion_controller,
+            &event_sender,
+            &tuned_gains_sender,
+        );
+        report_events(
+            MotorId::Right,
+            &mut right_motion_controller,
+            &event_sender,
+            &tuned_gains_sender,
+        );
+
         left_motor_status.send(MotorStatus {
             id: MotorId::Left,
             is_queue_full: left_motion_controller.is_queue_full(),
-            process_data: left_motion_controller.get_motor_process_data(),
+            process_data: MotorProcessData {
+                program: left_program.status(&left_motion_controller),
+                timestamp_us,
+                cycle,
+                ..left_motion_controller.get_motor_process_data()
+            },
         });
 
         right_motor_status.send(MotorStatus {
             id: MotorId::Right,
             is_queue_full: right_motion_controller.is_queue_full(),
-            process_data: right_motion_controller.get_motor_process_data(),
+            process_data: MotorProcessData {
+                program: right_program.status(&right_motion_controller),
+                timestamp_us,
+                cycle,
+                ..right_motion_controller.get_motor_process_data()
+            },
         });
     }
 }
+
+// The control is checked by the handler, the errors are already reported to the host
+fn handle_program_control<D: MotorDriver, const N: usize>(
+    program: &mut ProgramExecutor,
+    motion_controller: &mut Motion<D, N>,
+    control: ProgramControl,
+) {
+    let _ = match control {
+        ProgramControl::Start => program.start(motion_controller, false),
+        ProgramControl::Loop => program.start(motion_controller, true),
+        ProgramControl::Stop => {
+            program.stop(motion_controller);
+            Ok(())
+        }
+    };
+}
+
+fn read_cmd_from_channel<D: MotorDriver, const N: usize>(
+    motion_controller: &mut Motion<D, N>,
+    program: &mut ProgramExecutor,
+    cmd_sub: &mut MotorCommandSubscriber,
+) {
+    // Commands are kept in the channel until there are spaces in the queue of motion
+    if motion_controller.is_queue_full() {
+        return;
+    }
+
+    if let Some(WaitResult::Message(cmd)) = cmd_sub.try_next_message() {
+        // `Halt` from host stops the program as well
+        if cmd.cmd == MotorCommand::Halt {
+            program.cancel();
+        }
+        // A command rejected by a fault is reported as aborted by the motion itself
+        let _ = motion_controller.push_cmd(cmd);
+    }
+}
+
+// Report the auto-tuning result and the events of motion to the host, the gains are kept
+// if the auto-tuning run is rejected. The applied gains are also written to the
+// configuration
+fn report_events<D: MotorDriver, const N: usize>(
+    id: MotorId,
+    motion_controller: &mut Motion<D, N>,
+    event_sender: &EventSender,
+    tuned_gains_sender: &TunedGainsSender,
+) {
+    let gains = match motion_controller.take_autotune_result() {
+        Some(AutoTuneResult::Applied(kp, ki, kd)) => {
+            info!(
+                "{:?} auto-tune finished, kp: {}, ki: {}, kd: {}",
+                Debug2Format(&id),
+                kp,
+                ki,
+                kd
+            );
+            let gains = PidGains { kp, ki, kd };
+            // The channel holds the results of both motors, and a run takes many cycles
+            let _ = tuned_gains_sender.try_send((id, gains));
+            Some(Some(gains))
+        }
+        Some(AutoTuneResult::Rejected) => {
+            warn!(
+                "{:?} auto-tune rejected, oscillation is not consistent",
+                Debug2Format(&id)
+            );
+            Some(None)
+        }
+        None => None,
+    };
+    if let Some(gains) = gains {
+        send_event(
+            event_sender,
+            Some(id),
+            DeviceEventKind::AutoTuneFinished(gains),
+        );
+    }
+
+    while let Some(event) = motion_controller.take_event() {
+        if let DeviceEventKind::FaultRaised(reason) = event {
+            warn!("{:?} fault: {:?}", Debug2Format(&id), Debug2Format(&reason));
+        }
+        send_event(event_sender, Some(id), event);
+    }
+}
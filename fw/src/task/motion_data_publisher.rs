@@ -6,7 +6,9 @@ use embassy_time::Timer;
 use defmt::warn;
 use postcard_rpc::server::{Sender, WireTxErrorKind};
 
-use crate::communication::communication::{AppTx, MotorStatus, CHANNEL_SIZE};
+use crate::communication::communication::{
+    send_event, AppTx, EventSender, MotorStatus, CHANNEL_SIZE,
+};
 use protocol::*;
 
 #[embassy_executor::task]
@@ -14,15 +16,23 @@ pub async fn motor_data_publish_task(
     mut left_motor_status: Receiver<'static, CriticalSectionRawMutex, MotorStatus, 2>,
     mut right_motor_status: Receiver<'static, CriticalSectionRawMutex, MotorStatus, 2>,
     app_sender: Sender<AppTx>,
-    left_command_pub: Publisher<'static, CriticalSectionRawMutex, MotorCommand, CHANNEL_SIZE, 1, 2>,
+    left_command_pub: Publisher<
+        'static,
+        CriticalSectionRawMutex,
+        SequencedCommand,
+        CHANNEL_SIZE,
+        1,
+        2,
+    >,
     right_command_pub: Publisher<
         'static,
         CriticalSectionRawMutex,
-        MotorCommand,
+        SequencedCommand,
         CHANNEL_SIZE,
         1,
         2,
     >,
+    event_sender: EventSender,
 ) {
     let mut left_motor_topic_seq = 0_u8;
     let mut right_motor_topic_seq = 0_u8;
@@ -53,8 +63,14 @@ pub async fn motor_data_publish_task(
                 WireTxErrorKind::Timeout => {
                     if connected {
                         connected = false;
-                        let _ = left_command_pub.try_publish(MotorCommand::Halt);
-                        let _ = right_command_pub.try_publish(MotorCommand::Halt);
+                        // The program stored in the board keeps running without the host
+                        if left_motor_status.process_data.program.is_none() {
+                            let _ = left_command_pub.try_publish(MotorCommand::Halt.into());
+                        }
+                        if right_motor_status.process_data.program.is_none() {
+                            let _ = right_command_pub.try_publish(MotorCommand::Halt.into());
+                        }
+                        send_event(&event_sender, None, DeviceEventKind::ConnectionLost);
                         warn!("connection is lost, halt motors");
                     }
                 }
@@ -1,98 +1,433 @@
-use embassy_stm32::i2c::I2c;
+use defmt::{info, warn};
+use embassy_stm32::bind_interrupts;
+use embassy_stm32::gpio::{Level, OutputOpenDrain, Speed};
+use embassy_stm32::i2c::{self, I2c};
 use embassy_stm32::mode::Async;
+use embassy_stm32::peripherals;
 use embassy_stm32::time::Hertz;
-use embassy_time::Timer;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::watch::{Receiver as WatchReceiver, Sender as WatchSender};
+use embassy_time::{with_timeout, Duration, Instant, Ticker, Timer};
 
 use mpu6050_dmp::{
     accel::{Accel, AccelFullScale},
     address::Address,
-    calibration::ReferenceGravity,
+    calibration::{CalibrationParameters, ReferenceGravity as DmpReferenceGravity},
+    config::DigitalLowPassFilter,
     gyro::{Gyro, GyroFullScale},
+    quaternion::Quaternion as DmpQuaternion,
     sensor_async::Mpu6050,
 };
 use postcard_rpc::server::Sender;
 
-use crate::communication::communication::AppTx;
+use crate::communication::communication::{send_event, AppTx, CalibrationChannels, EventSender};
+use motion_core::imu::{
+    is_dmp_enabled, orientation_from_quaternion, sample_rate_divider, ImuMonitor,
+};
 use protocol::*;
 
 // mpu6050
-const ACCEL_SCALE: AccelFullScale = AccelFullScale::G2;
-const GYRO_SCALE: GyroFullScale = GyroFullScale::Deg2000;
-const REF_GRAVITY: ReferenceGravity = ReferenceGravity::ZN;
-const MPU_6050_SAMPLE_PERIOD: f32 = 0.05;
+// DMP packet in FIFO: quaternion (16 bytes), accel (6 bytes) and gyro (6 bytes)
+const DMP_PACKET_SIZE: usize = 28;
+const DMP_QUATERNION_SIZE: usize = 16;
+// The FIFO of MPU6050 is 1024 bytes, the packets are not aligned after it overflows
+const FIFO_SIZE: usize = 1024;
+const I2C_FREQ: Hertz = Hertz(400_000);
+// The I2C transfer doesn't finish when the bus is stuck, ex: SDA is held low by MPU6050
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+const INIT_TIMEOUT: Duration = Duration::from_secs(2);
+const CALIBRATION_TIMEOUT: Duration = Duration::from_secs(30);
+// Half period of SCL when the bus is recovered, about 100 kHz
+const RECOVERY_HALF_PERIOD_US: u64 = 5;
+
+bind_interrupts!(pub struct I2cIrqs {
+    I2C1_EV => i2c::EventInterruptHandler<peripherals::I2C1>;
+    I2C1_ER => i2c::ErrorInterruptHandler<peripherals::I2C1>;
+});
+
+type Imu<'d> = Mpu6050<I2c<'d, Async>>;
+
+// Peripherals of the I2C bus of MPU6050, the pins are driven as GPIO to recover the bus,
+// and the I2C driver is created again after that
+pub struct Mpu6050Bus {
+    pub i2c: peripherals::I2C1,
+    pub scl: peripherals::PB6,
+    pub sda: peripherals::PB7,
+    pub tx_dma: peripherals::DMA1_CH6,
+    pub rx_dma: peripherals::DMA1_CH7,
+}
+
+struct Mpu6050Publisher {
+    app_sender: Sender<AppTx>,
+    topic_seq: u8,
+    sample: u32,
+    event_sender: EventSender,
+    imu_sender: WatchSender<'static, CriticalSectionRawMutex, Mpu6050MotionData, 1>,
+    monitor: ImuMonitor,
+}
+
+impl Mpu6050Publisher {
+    fn report(&self, health: Option<ImuHealth>) {
+        if let Some(health) = health {
+            warn!("mpu6050 health is changed to {}", health as u8);
+            send_event(
+                &self.event_sender,
+                None,
+                DeviceEventKind::ImuHealthChanged(health),
+            );
+        }
+    }
 
-// These values are obtained from the calibration process
-const ACCEL_CALIBRATION: (i16, i16, i16) = (-2453, -3243, -1793);
-const GYRO_CALIBRATION: (i16, i16, i16) = (133, 32, -59);
+    // The motion task only takes the data when MPU6050 is healthy, and the host gets the
+    // health in every sample period
+    async fn publish(&mut self, data: &Mpu6050MotionData, timestamp_us: u64) {
+        self.sample = self.sample.wrapping_add(1);
+        let health = self.monitor.health();
+        let data = if health == ImuHealth::Ok {
+            let data = Mpu6050MotionData {
+                timestamp_us,
+                sample: self.sample,
+                ..*data
+            };
+            // The yaw rate is fused with wheel odometry and the pitch is estimated for
+            // the balance controller in motion task
+            self.imu_sender.send(data);
+            data
+        } else {
+            Mpu6050MotionData {
+                health,
+                timestamp_us,
+                sample: self.sample,
+                ..Default::default()
+            }
+        };
 
+        let _ = self
+            .app_sender
+            .publish::<Mpu6050MotionDataTopic>(self.topic_seq.into(), &data)
+            .await;
+        self.topic_seq = self.topic_seq.wrapping_add(1);
+    }
+}
+
+// MPU6050 can be unplugged or glitching, the task doesn't panic, so the motors are
+// controlled regardless of it. The initialization is retried with backoff when MPU6050
+// doesn't respond, and the bus is recovered before MPU6050 is initialized again
 #[embassy_executor::task]
-pub async fn mpu6050_data_publish_task(app_sender: Sender<AppTx>, i2c: I2c<'static, Async>) {
-    let mut mpu6050_topic_seq = 0_u8;
+pub async fn mpu6050_data_publish_task(
+    app_sender: Sender<AppTx>,
+    mut bus: Mpu6050Bus,
+    mut device_config: DeviceConfig,
+    mut config: WatchReceiver<'static, CriticalSectionRawMutex, DeviceConfig, 2>,
+    event_sender: EventSender,
+    imu_sender: WatchSender<'static, CriticalSectionRawMutex, Mpu6050MotionData, 1>,
+    calibration: CalibrationChannels,
+) {
+    let mut publisher = Mpu6050Publisher {
+        app_sender,
+        topic_seq: 0,
+        sample: 0,
+        event_sender,
+        imu_sender,
+        monitor: ImuMonitor::new(),
+    };
+
+    loop {
+        // The offsets found by the calibration are kept in the configuration, so they are
+        // applied again
+        if let Some(new_config) = config.try_changed() {
+            device_config = new_config;
+        }
+
+        let i2c = I2c::new(
+            &mut bus.i2c,
+            &mut bus.scl,
+            &mut bus.sda,
+            I2cIrqs,
+            &mut bus.tx_dma,
+            &mut bus.rx_dma,
+            I2C_FREQ,
+            Default::default(),
+        );
+        match with_timeout(INIT_TIMEOUT, init_mpu6050(i2c, &device_config)).await {
+            Ok(Ok(mut mpu6050)) => {
+                info!("mpu6050 is initialized");
+                publisher.monitor.init_done();
+                run_mpu6050(
+                    &mut mpu6050,
+                    &mut publisher,
+                    &mut device_config,
+                    &mut config,
+                    &calibration,
+                )
+                .await;
+            }
+            _ => {
+                let health = publisher.monitor.init_failed();
+                publisher.report(health);
+                publisher
+                    .publish(&Mpu6050MotionData::default(), Instant::now().as_micros())
+                    .await;
+
+                // The handler waits for the result, so the request is rejected here
+                if calibration.request.try_receive().is_ok() {
+                    calibration
+                        .result
+                        .send(Err(CalibrationError::I2cError))
+                        .await;
+                }
+                Timer::after_millis(publisher.monitor.retry_delay_ms() as u64).await;
+            }
+        }
+
+        recover_bus(&mut bus).await;
+    }
+}
+
+// Read and publish the data until the reads keep failing
+async fn run_mpu6050(
+    mpu6050: &mut Imu<'_>,
+    publisher: &mut Mpu6050Publisher,
+    device_config: &mut DeviceConfig,
+    config: &mut WatchReceiver<'static, CriticalSectionRawMutex, DeviceConfig, 2>,
+    calibration: &CalibrationChannels,
+) {
+    let mut ticker = Ticker::every(sample_period(&device_config.imu));
     let mut mpu6050_motion_data = Mpu6050MotionData::default();
 
-    // MPU6050 init
-    let mut mpu6050 = Mpu6050::new(i2c, Address::default()).await.unwrap();
+    while !publisher.monitor.needs_init() {
+        // The configuration is validated by the handler, and the I2C errors show up in the
+        // following reads
+        if let Some(new_config) = config.try_changed() {
+            if new_config.imu != device_config.imu {
+                let _ = apply_imu_config(mpu6050, &new_config.imu).await;
+                ticker = Ticker::every(sample_period(&new_config.imu));
+            }
+            *device_config = new_config;
+        }
+        let imu_config = device_config.imu;
+
+        if let Ok(gravity) = calibration.request.try_receive() {
+            let result = with_timeout(
+                CALIBRATION_TIMEOUT,
+                calibrate(mpu6050, &imu_config, gravity),
+            )
+            .await
+            .unwrap_or(Err(CalibrationError::I2cError));
+            calibration.result.send(result).await;
+        }
+
+        let timestamp_us = Instant::now().as_micros();
+        let result = with_timeout(
+            READ_TIMEOUT,
+            read_motion_data(
+                mpu6050,
+                is_dmp_enabled(&imu_config),
+                &mut mpu6050_motion_data,
+            ),
+        )
+        .await;
+        let health = match result {
+            Ok(Ok((accel, gyro))) => {
+                let accel = accel.scaled(accel_scale(imu_config.accel_range));
+                let gyro = gyro.scaled(gyro_scale(imu_config.gyro_range));
+                mpu6050_motion_data.acc_x = accel.x();
+                mpu6050_motion_data.acc_y = accel.y();
+                mpu6050_motion_data.acc_z = accel.z();
+                mpu6050_motion_data.g_x = gyro.x();
+                mpu6050_motion_data.g_y = gyro.y();
+                mpu6050_motion_data.g_z = gyro.z();
+                publisher.monitor.read_done()
+            }
+            _ => publisher.monitor.read_failed(),
+        };
+        publisher.report(health);
+        publisher.publish(&mpu6050_motion_data, timestamp_us).await;
+
+        ticker.next().await;
+    }
+}
+
+// The offsets and the IMU configuration are applied after the DMP is loaded
+async fn init_mpu6050<'d>(i2c: I2c<'d, Async>, config: &DeviceConfig) -> Result<Imu<'d>, ()> {
+    let mut mpu6050 = Mpu6050::new(i2c, Address::default())
+        .await
+        .map_err(|_e| ())?;
 
     let mut delay = embassy_time::Delay;
-    mpu6050.initialize_dmp(&mut delay).await.unwrap();
+    mpu6050.initialize_dmp(&mut delay).await.map_err(|_e| ())?;
 
-    mpu6050.set_accel_full_scale(ACCEL_SCALE).await.unwrap();
-    mpu6050.set_gyro_full_scale(GYRO_SCALE).await.unwrap();
+    let (x, y, z) = config.accel_calibration;
     mpu6050
-        .set_accel_calibration(&Accel::new(
-            ACCEL_CALIBRATION.0,
-            ACCEL_CALIBRATION.1,
-            ACCEL_CALIBRATION.2,
-        ))
+        .set_accel_calibration(&Accel::new(x, y, z))
         .await
-        .unwrap();
+        .map_err(|_e| ())?;
+    let (x, y, z) = config.gyro_calibration;
     mpu6050
-        .set_gyro_calibration(&Gyro::new(
-            GYRO_CALIBRATION.0,
-            GYRO_CALIBRATION.1,
-            GYRO_CALIBRATION.2,
-        ))
+        .set_gyro_calibration(&Gyro::new(x, y, z))
         .await
-        .unwrap();
+        .map_err(|_e| ())?;
 
-    #[cfg(feature = "calibrate-mpu")]
-    {
-        use defmt::info;
-        use mpu6050_dmp::calibration::CalibrationParameters;
+    apply_imu_config(&mut mpu6050, &config.imu).await?;
+    Ok(mpu6050)
+}
 
-        info!("Calibrating mpu");
+// The DMP output rate and the sample rate are set by the sample rate divider, and FIFO
+// is reset as the packets are not aligned after the rate is changed
+async fn apply_imu_config(mpu6050: &mut Imu<'_>, config: &ImuConfig) -> Result<(), ()> {
+    mpu6050
+        .set_accel_full_scale(accel_scale(config.accel_range))
+        .await
+        .map_err(|_e| ())?;
+    mpu6050
+        .set_gyro_full_scale(gyro_scale(config.gyro_range))
+        .await
+        .map_err(|_e| ())?;
+    mpu6050
+        .set_digital_lowpass_filter(dlpf(config.dlpf))
+        .await
+        .map_err(|_e| ())?;
+    mpu6050
+        .set_sample_rate_divider(sample_rate_divider(config))
+        .await
+        .map_err(|_e| ())?;
+    mpu6050.reset_fifo().await.map_err(|_e| ())
+}
 
-        let calibration_params = CalibrationParameters::new(ACCEL_SCALE, GYRO_SCALE, REF_GRAVITY);
-        let _data = mpu6050
-            .calibrate(&mut delay, &calibration_params)
-            .await
-            .unwrap();
+fn sample_period(config: &ImuConfig) -> Duration {
+    Duration::from_hz(config.sample_rate_hz as u64)
+}
 
-        // If new calibration process is run, the `data` can be printed and update `ACCEL_CALIBRATION`
-        // and `GYRO_CALIBRATION` constants.
+fn accel_scale(range: AccelRange) -> AccelFullScale {
+    match range {
+        AccelRange::G2 => AccelFullScale::G2,
+        AccelRange::G4 => AccelFullScale::G4,
+        AccelRange::G8 => AccelFullScale::G8,
+        AccelRange::G16 => AccelFullScale::G16,
     }
+}
 
-    let sample_rate = Hertz((1.0 / MPU_6050_SAMPLE_PERIOD) as u32); // 100Hz (1000Hz / (1 + 9))
-    let divider = (1000 / sample_rate.0 - 1) as u8;
-    mpu6050.set_sample_rate_divider(divider).await.unwrap();
+fn gyro_scale(range: GyroRange) -> GyroFullScale {
+    match range {
+        GyroRange::Deg250 => GyroFullScale::Deg250,
+        GyroRange::Deg500 => GyroFullScale::Deg500,
+        GyroRange::Deg1000 => GyroFullScale::Deg1000,
+        GyroRange::Deg2000 => GyroFullScale::Deg2000,
+    }
+}
 
-    let _ = REF_GRAVITY;
-    loop {
-        let (accel, gyro) = mpu6050.motion6().await.unwrap();
-        let accel = accel.scaled(ACCEL_SCALE);
-        let gyro = gyro.scaled(GYRO_SCALE);
-        mpu6050_motion_data.acc_x = accel.x();
-        mpu6050_motion_data.acc_y = accel.y();
-        mpu6050_motion_data.acc_z = accel.z();
-        mpu6050_motion_data.g_x = gyro.x();
-        mpu6050_motion_data.g_y = gyro.y();
-        mpu6050_motion_data.g_z = gyro.z();
-
-        let _ = app_sender
-            .publish::<Mpu6050MotionDataTopic>(mpu6050_topic_seq.into(), &mpu6050_motion_data)
-            .await;
+fn dlpf(bandwidth: DlpfBandwidth) -> DigitalLowPassFilter {
+    match bandwidth {
+        DlpfBandwidth::Hz184 => DigitalLowPassFilter::Filter1,
+        DlpfBandwidth::Hz94 => DigitalLowPassFilter::Filter2,
+        DlpfBandwidth::Hz44 => DigitalLowPassFilter::Filter3,
+        DlpfBandwidth::Hz21 => DigitalLowPassFilter::Filter4,
+        DlpfBandwidth::Hz10 => DigitalLowPassFilter::Filter5,
+        DlpfBandwidth::Hz5 => DigitalLowPassFilter::Filter6,
+    }
+}
+
+// A slave holds SDA low when the transfer is interrupted, ex: the board is reset or there
+// is noise on the bus. SCL is clocked until SDA is released, and then STOP is sent
+async fn recover_bus(bus: &mut Mpu6050Bus) {
+    let mut scl = OutputOpenDrain::new(&mut bus.scl, Level::High, Speed::Low);
+    let mut sda = OutputOpenDrain::new(&mut bus.sda, Level::High, Speed::Low);
+
+    for _ in 0..9 {
+        if sda.is_high() {
+            break;
+        }
+        scl.set_low();
+        Timer::after_micros(RECOVERY_HALF_PERIOD_US).await;
+        scl.set_high();
+        Timer::after_micros(RECOVERY_HALF_PERIOD_US).await;
+    }
+
+    sda.set_low();
+    Timer::after_micros(RECOVERY_HALF_PERIOD_US).await;
+    sda.set_high();
+    Timer::after_micros(RECOVERY_HALF_PERIOD_US).await;
+}
+
+// Find the offsets with the board at rest, `mpu6050-dmp` writes them to MPU6050 when the
+// calibration is done
+async fn calibrate(
+    mpu6050: &mut Imu<'_>,
+    config: &ImuConfig,
+    gravity: ReferenceGravity,
+) -> CalibrationResult {
+    let gravity = match gravity {
+        ReferenceGravity::Zero => DmpReferenceGravity::Zero,
+        ReferenceGravity::XN => DmpReferenceGravity::XN,
+        ReferenceGravity::XP => DmpReferenceGravity::XP,
+        ReferenceGravity::YN => DmpReferenceGravity::YN,
+        ReferenceGravity::YP => DmpReferenceGravity::YP,
+        ReferenceGravity::ZN => DmpReferenceGravity::ZN,
+        ReferenceGravity::ZP => DmpReferenceGravity::ZP,
+    };
+    info!("calibrating mpu6050");
+
+    let mut delay = embassy_time::Delay;
+    let calibration_params = CalibrationParameters::new(
+        accel_scale(config.accel_range),
+        gyro_scale(config.gyro_range),
+        gravity,
+    );
+    let (accel, gyro) = mpu6050
+        .calibrate(&mut delay, &calibration_params)
+        .await
+        .map_err(|_e| CalibrationError::I2cError)?;
+    // The packets in FIFO are piled up during the calibration
+    mpu6050
+        .reset_fifo()
+        .await
+        .map_err(|_e| CalibrationError::I2cError)?;
+
+    info!(
+        "mpu6050 offsets, accel: {}, gyro: {}",
+        (accel.x(), accel.y(), accel.z()),
+        (gyro.x(), gyro.y(), gyro.z())
+    );
+    Ok(ImuCalibration {
+        accel: (accel.x(), accel.y(), accel.z()),
+        gyro: (gyro.x(), gyro.y(), gyro.z()),
+    })
+}
+
+// Read the raw accel and gyro, and the orientation of the latest DMP packet in FIFO. The
+// orientation is kept when there is no new packet, and the FIFO is reset when it
+// overflows
+async fn read_motion_data(
+    mpu6050: &mut Imu<'_>,
+    dmp_enabled: bool,
+    data: &mut Mpu6050MotionData,
+) -> Result<(Accel, Gyro), ()> {
+    let motion = mpu6050.motion6().await.map_err(|_e| ())?;
+    if !dmp_enabled {
+        data.orientation = None;
+        return Ok(motion);
+    }
+
+    let count = mpu6050.get_fifo_count().await.map_err(|_e| ())?;
+    if count >= FIFO_SIZE || count % DMP_PACKET_SIZE != 0 {
+        mpu6050.reset_fifo().await.map_err(|_e| ())?;
+        return Ok(motion);
+    }
 
-        mpu6050_topic_seq = mpu6050_topic_seq.wrapping_add(1);
-        Timer::after_millis(sample_rate.0 as u64).await;
+    let mut buf = [0_u8; DMP_PACKET_SIZE];
+    for _ in 0..count / DMP_PACKET_SIZE {
+        let packet = mpu6050.read_fifo(&mut buf).await.map_err(|_e| ())?;
+        let orientation = DmpQuaternion::from_bytes(&packet[..DMP_QUATERNION_SIZE]).and_then(|q| {
+            orientation_from_quaternion(Quaternion {
+                w: q.w,
+                x: q.x,
+                y: q.y,
+                z: q.z,
+            })
+        });
+        if orientation.is_some() {
+            data.orientation = orientation;
+        }
     }
+    Ok(motion)
 }
@@ -0,0 +1,26 @@
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::watch::Receiver;
+
+use postcard_rpc::server::Sender;
+
+use crate::communication::communication::AppTx;
+use protocol::*;
+
+// The odometry is integrated by motion task in every control cycle, only the latest value
+// is published if the USB is busy
+#[embassy_executor::task]
+pub async fn odometry_publish_task(
+    mut odometry_recv: Receiver<'static, CriticalSectionRawMutex, Odometry, 1>,
+    app_sender: Sender<AppTx>,
+) {
+    let mut odometry_topic_seq = 0_u8;
+
+    loop {
+        let odometry = odometry_recv.changed().await;
+        let _ = app_sender
+            .publish::<OdometryTopic>(odometry_topic_seq.into(), &odometry)
+            .await;
+
+        odometry_topic_seq = odometry_topic_seq.wrapping_add(1);
+    }
+}
@@ -0,0 +1,33 @@
+use defmt::{info, Debug2Format};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::watch::Sender as WatchSender;
+
+use crate::communication::communication::TunedGainsReceiver;
+use crate::config::SharedConfig;
+use protocol::*;
+
+// The motion task runs in the interrupt executor and can't lock the configuration, so the
+// gains found by auto-tune are written here. They are broadcast with the configuration,
+// otherwise the next change of configuration restores the old gains, and
+// `SaveConfigEndPoint` persists them
+#[embassy_executor::task]
+pub async fn tuned_gains_write_task(
+    config: &'static SharedConfig,
+    tuned_gains_recv: TunedGainsReceiver,
+    config_sender: WatchSender<'static, CriticalSectionRawMutex, DeviceConfig, 2>,
+) {
+    loop {
+        let (id, gains) = tuned_gains_recv.receive().await;
+        let new_config = config.lock(|x| {
+            let mut state = x.borrow_mut();
+            match id {
+                MotorId::Left => state.config.left.pid = gains,
+                MotorId::Right => state.config.right.pid = gains,
+            }
+            state.config
+        });
+        config_sender.send(new_config);
+        info!("{:?} tuned gains written to config", Debug2Format(&id));
+    }
+}
@@ -1,15 +1,33 @@
+use std::collections::VecDeque;
 use std::convert::Infallible;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use postcard_rpc::{
     header::VarSeqKind,
-    host_client::{HostClient, HostErr},
+    host_client::{HostClient, HostErr, MultiSubRxError, MultiSubscription},
     standard_icd::{ERROR_PATH, PingEndpoint, WireError},
 };
+use tokio::net::TcpStream;
+use tokio::sync::{OnceCell, watch};
 
+use crate::tcp::{TcpWireRx, TcpWireTx, TokioSpawn};
 use protocol::*;
 
+// Number of finished commands that are kept for `wait_motion_done`
+const MAX_MOTION_OUTCOMES: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MotionOutcome {
+    Completed,
+    Aborted,
+}
+
+type MotionOutcomes = VecDeque<(MotorId, u32, MotionOutcome)>;
+
 pub struct Client {
     pub client: HostClient<WireError>,
+    next_cmd_id: AtomicU32,
+    motion_outcomes: OnceCell<watch::Receiver<MotionOutcomes>>,
 }
 
 #[derive(Debug)]
@@ -49,7 +67,40 @@ impl Client {
             8,
             VarSeqKind::Seq2,
         )?;
-        Ok(Self { client })
+        Ok(Self::from_host_client(client))
+    }
+
+    // Connect to the emulator through TCP, the tokio runtime must be entered when this
+    // function is called
+    pub fn new_tcp(addr: &str) -> Result<Self, String> {
+        let stream = std::net::TcpStream::connect(addr)
+            .and_then(|stream| {
+                stream.set_nodelay(true)?;
+                stream.set_nonblocking(true)?;
+                Ok(stream)
+            })
+            .and_then(TcpStream::from_std)
+            .map_err(|e| format!("Failed to connect to {addr}, {e}"))?;
+
+        let (reader, writer) = stream.into_split();
+        let client = HostClient::new_with_wire(
+            TcpWireTx::new(writer),
+            TcpWireRx::new(reader),
+            TokioSpawn,
+            VarSeqKind::Seq2,
+            ERROR_PATH,
+            8,
+        );
+        Ok(Self::from_host_client(client))
+    }
+
+    fn from_host_client(client: HostClient<WireError>) -> Self {
+        Self {
+            client,
+            // 0 is used by the commands that are not followed
+            next_cmd_id: AtomicU32::new(1),
+            motion_outcomes: OnceCell::new(),
+        }
     }
 
     pub async fn wait_closed(&self) {
@@ -61,24 +112,316 @@ impl Client {
         Ok(val)
     }
 
+    // Stream of fault, motion and connection events of the device, the events published
+    // before subscribing are not received
+    pub async fn subscribe_events(
+        &self,
+        depth: usize,
+    ) -> Result<MultiSubscription<DeviceEvent>, ClientError<Infallible>> {
+        self.client
+            .subscribe_multi::<DeviceEventTopic>(depth)
+            .await
+            .map_err(|_e| ClientError::Comms(HostErr::Closed))
+    }
+
+    pub async fn subscribe_odometry(
+        &self,
+        depth: usize,
+    ) -> Result<MultiSubscription<Odometry>, ClientError<Infallible>> {
+        self.client
+            .subscribe_multi::<OdometryTopic>(depth)
+            .await
+            .map_err(|_e| ClientError::Comms(HostErr::Closed))
+    }
+
+    // Raw accel and gyro, and the orientation from the DMP of MPU6050
+    pub async fn subscribe_mpu6050(
+        &self,
+        depth: usize,
+    ) -> Result<MultiSubscription<Mpu6050MotionData>, ClientError<Infallible>> {
+        self.client
+            .subscribe_multi::<Mpu6050MotionDataTopic>(depth)
+            .await
+            .map_err(|_e| ClientError::Comms(HostErr::Closed))
+    }
+
+    // The odometry keeps integrating from the given pose
+    pub async fn reset_pose(&self, pose: Pose) -> Result<(), ClientError<Infallible>> {
+        self.client.send_resp::<ResetPoseEndPoint>(&pose).await?;
+        Ok(())
+    }
+
+    // The command is sent with a new sequence id, it is returned and can be passed to
+    // `wait_motion_done`
     pub async fn set_motor_cmd(
         &self,
         id: MotorId,
         cmd: MotorCommand,
-    ) -> Result<(), ClientError<CommandError>> {
+    ) -> Result<u32, ClientError<CommandError>> {
+        let cmd = self.sequenced_cmd(cmd).await?;
         self.client
             .send_resp::<SetMotorCommandEndPoint>(&(id, cmd))
             .await?
-            .flatten()
+            .flatten()?;
+        Ok(cmd.id)
     }
 
     pub async fn set_motor_cmds(
         &self,
         cmds: [(MotorId, MotorCommand); 2],
-    ) -> Result<(), ClientError<CommandError>> {
+    ) -> Result<[u32; 2], ClientError<CommandError>> {
+        let cmds = [
+            (cmds[0].0, self.sequenced_cmd(cmds[0].1).await?),
+            (cmds[1].0, self.sequenced_cmd(cmds[1].1).await?),
+        ];
         self.client
             .send_resp::<SetMotorCommandsEndPoint>(&cmds)
             .await?
+            .flatten()?;
+        Ok([cmds[0].1.id, cmds[1].1.id])
+    }
+
+    // The wheel velocities are calculated by the board, they are not followed by sequence
+    // ids like the velocity commands
+    pub async fn set_base_twist(
+        &self,
+        twist: BaseTwistCommand,
+    ) -> Result<(), ClientError<CommandError>> {
+        self.client
+            .send_resp::<SetBaseTwistEndPoint>(&twist)
+            .await?
+            .flatten()?;
+        Ok(())
+    }
+
+    // The heading controller runs until other commands are sent to the motors, ex: `Halt`
+    pub async fn set_heading_cmd(
+        &self,
+        cmd: HeadingCommand,
+    ) -> Result<(), ClientError<CommandError>> {
+        self.client
+            .send_resp::<SetHeadingCommandEndPoint>(&cmd)
+            .await?
+            .flatten()?;
+        Ok(())
+    }
+
+    // The balance controller keeps the chassis upright and moves the base with the body
+    // velocities, it runs until other commands are sent to the motors or the chassis falls
+    pub async fn set_balance_cmd(
+        &self,
+        twist: BaseTwistCommand,
+    ) -> Result<(), ClientError<CommandError>> {
+        self.client
+            .send_resp::<SetBalanceCommandEndPoint>(&twist)
+            .await?
+            .flatten()?;
+        Ok(())
+    }
+
+    // Position programs of both motors (a program can be empty) are split into chunks. The
+    // part of a chunk that doesn't fit in the queue of the board is sent again after the
+    // board starts a queued command. The sequence ids of the commands are returned
+    pub async fn upload_positions(
+        &self,
+        left: &[PositionCommand],
+        right: &[PositionCommand],
+    ) -> Result<[Vec<u32>; 2], ClientError<CommandError>> {
+        let mut outcomes = self.motion_outcomes().await?;
+        let programs = [(MotorId::Left, left), (MotorId::Right, right)];
+        let first_ids = programs.map(|(_id, program)| {
+            self.next_cmd_id
+                .fetch_add(program.len() as u32, Ordering::Relaxed)
+        });
+
+        let mut sent = [0_usize; 2];
+        while programs
+            .iter()
+            .zip(sent)
+            .any(|((_id, program), sent)| sent < program.len())
+        {
+            let chunks = [0, 1].map(|i| {
+                let (id, program) = programs[i];
+                let first_id = first_ids[i].wrapping_add(sent[i] as u32);
+                (id, PositionChunk::new(first_id, &program[sent[i]..]))
+            });
+
+            // Changes before sending are marked as seen, so the wait below is only woken up
+            // by the commands started after the chunks are rejected
+            outcomes.borrow_and_update();
+            let accepted = self
+                .client
+                .send_resp::<AppendPositionsEndPoint>(&chunks)
+                .await?
+                .flatten()?;
+
+            if accepted == [0, 0] {
+                outcomes
+                    .changed()
+                    .await
+                    .map_err(|_e| ClientError::Comms(HostErr::Closed))?;
+            }
+            for (sent, accepted) in sent.iter_mut().zip(accepted) {
+                *sent += accepted as usize;
+            }
+        }
+
+        Ok([0, 1].map(|i| {
+            (0..programs[i].1.len() as u32)
+                .map(|x| first_ids[i].wrapping_add(x))
+                .collect()
+        }))
+    }
+
+    // Wait until the command with the sequence id is done or aborted on the motor
+    pub async fn wait_motion_done(
+        &self,
+        id: MotorId,
+        cmd_id: u32,
+    ) -> Result<MotionOutcome, ClientError<Infallible>> {
+        let mut outcomes = self.motion_outcomes().await?;
+        let find = |outcomes: &MotionOutcomes| {
+            outcomes
+                .iter()
+                .find(|(motor, x, _)| *motor == id && *x == cmd_id)
+                .map(|(_, _, outcome)| *outcome)
+        };
+
+        let outcomes = outcomes
+            .wait_for(|x| find(x).is_some())
+            .await
+            .map_err(|_e| ClientError::Comms(HostErr::Closed))?;
+        Ok(find(&outcomes).unwrap())
+    }
+
+    async fn sequenced_cmd(
+        &self,
+        cmd: MotorCommand,
+    ) -> Result<SequencedCommand, HostErr<WireError>> {
+        // The events are followed before the first command is sent, so the outcome of a
+        // short command is not missed
+        self.motion_outcomes().await?;
+        let id = self.next_cmd_id.fetch_add(1, Ordering::Relaxed);
+        Ok(SequencedCommand { id, cmd })
+    }
+
+    // Outcomes of the commands are collected from the event stream by a background task,
+    // the receivers are also notified when a command is started
+    async fn motion_outcomes(&self) -> Result<watch::Receiver<MotionOutcomes>, HostErr<WireError>> {
+        self.motion_outcomes
+            .get_or_try_init(|| async {
+                let Ok(mut sub) = self.client.subscribe_multi::<DeviceEventTopic>(32).await else {
+                    return Err(HostErr::Closed);
+                };
+                let (outcomes_send, outcomes_recv) = watch::channel(MotionOutcomes::new());
+
+                tokio::spawn(async move {
+                    loop {
+                        let event = match sub.recv().await {
+                            Ok(event) => event,
+                            Err(MultiSubRxError::Lagged(_)) => continue,
+                            Err(MultiSubRxError::IoClosed) => break,
+                        };
+                        let outcome = match event.kind {
+                            DeviceEventKind::MotionComplete(x) => (x, MotionOutcome::Completed),
+                            DeviceEventKind::MotionAborted(x) => (x, MotionOutcome::Aborted),
+                            // The started command leaves the queue in the board, the receivers
+                            // are woken up, so `upload_positions` can send the rest of a program
+                            DeviceEventKind::MotionStarted(_) => {
+                                outcomes_send.send_modify(|_outcomes| ());
+                                continue;
+                            }
+                            _ => continue,
+                        };
+                        let Some(motor) = event.motor else {
+                            continue;
+                        };
+
+                        outcomes_send.send_modify(|outcomes| {
+                            if outcomes.len() == MAX_MOTION_OUTCOMES {
+                                outcomes.pop_front();
+                            }
+                            outcomes.push_back((motor, outcome.0, outcome.1));
+                        });
+                    }
+                });
+
+                Ok(outcomes_recv)
+            })
+            .await
+            .cloned()
+    }
+
+    // The program is kept in the RAM of the board, it is lost when the board is reset
+    pub async fn upload_program(
+        &self,
+        id: MotorId,
+        program: MotionProgram,
+    ) -> Result<(), ClientError<ProgramError>> {
+        self.client
+            .send_resp::<UploadProgramEndPoint>(&(id, program))
+            .await?
+            .flatten()
+    }
+
+    pub async fn control_program(
+        &self,
+        id: MotorId,
+        control: ProgramControl,
+    ) -> Result<(), ClientError<ProgramError>> {
+        self.client
+            .send_resp::<ControlProgramEndPoint>(&(id, control))
+            .await?
+            .flatten()
+    }
+
+    pub async fn get_config(&self) -> Result<DeviceConfig, ClientError<Infallible>> {
+        let config = self.client.send_resp::<GetConfigEndPoint>(&()).await?;
+        Ok(config)
+    }
+
+    pub async fn set_config(&self, config: DeviceConfig) -> Result<(), ClientError<ConfigError>> {
+        self.client
+            .send_resp::<SetConfigEndPoint>(&config)
+            .await?
+            .flatten()
+    }
+
+    // Both axes need to be at rest, otherwise `ConfigError::NotAtRest` is returned
+    pub async fn save_config(&self) -> Result<(), ClientError<ConfigError>> {
+        self.client
+            .send_resp::<SaveConfigEndPoint>(&())
+            .await?
+            .flatten()
+    }
+
+    // Both axes need to be at rest, otherwise `ConfigError::NotAtRest` is returned
+    pub async fn reset_config(&self) -> Result<(), ClientError<ConfigError>> {
+        self.client
+            .send_resp::<ResetConfigEndPoint>(&())
+            .await?
+            .flatten()
+    }
+
+    // The board needs to stay still, the response is received after the calibration is
+    // done, it takes a few seconds
+    pub async fn calibrate_imu(
+        &self,
+        gravity: ReferenceGravity,
+        persist: bool,
+    ) -> Result<ImuCalibration, ClientError<CalibrationError>> {
+        self.client
+            .send_resp::<CalibrateImuEndPoint>(&CalibrateImuRequest { gravity, persist })
+            .await?
+            .flatten()
+    }
+
+    // The sample rate, full-scale ranges and the low-pass filter of MPU6050 are applied
+    // right away, the orientation is `None` when it samples faster than the DMP
+    pub async fn set_imu_config(&self, config: ImuConfig) -> Result<(), ClientError<ConfigError>> {
+        self.client
+            .send_resp::<SetImuConfigEndPoint>(&config)
+            .await?
             .flatten()
     }
 }
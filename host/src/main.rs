@@ -12,6 +12,7 @@ pub async fn main() {
     tokio::join!(
         ping(client.clone()),
         subscribe(client.clone()),
+        subscribe_events(client.clone()),
         send_vel_cmd(client.clone()),
         send_pos_cmd(client.clone())
     );
@@ -53,10 +54,19 @@ async fn subscribe(client: Arc<Client>) {
     }
 }
 
+async fn subscribe_events(client: Arc<Client>) {
+    println!("Check events");
+
+    let mut sub = client.subscribe_events(8).await.unwrap();
+    while let Ok(event) = sub.recv().await {
+        println!("Got event: {event:?}");
+    }
+}
+
 async fn send_vel_cmd(client: Arc<Client>) {
     println!("Check vel cmd");
 
-    let vel = 500.0_f32;
+    let vel = 50.0_f32;
     let mut ticker = interval(Duration::from_millis(50));
 
     for i in 0..10 {
@@ -64,7 +74,7 @@ async fn send_vel_cmd(client: Arc<Client>) {
         let res = client
             .set_motor_cmd(
                 protocol::MotorId::Left,
-                protocol::MotorCommand::VelocityCommand(vel + i as f32 * 5.0),
+                protocol::MotorCommand::VelocityCommand(vel + i as f32 * 0.5),
             )
             .await;
         println!("send_vel_cmd got {res:?}!");
@@ -77,6 +87,7 @@ async fn send_pos_cmd(client: Arc<Client>) {
     let dummy_val = 500.0_f32;
     let mut ticker = interval(Duration::from_millis(50));
 
+    let mut last_cmd_id = None;
     for i in 0..10 {
         ticker.tick().await;
 
@@ -92,5 +103,11 @@ async fn send_pos_cmd(client: Arc<Client>) {
             )
             .await;
         println!("send_pos_cmd got {res:?}!");
+        last_cmd_id = res.ok().or(last_cmd_id);
+    }
+
+    if let Some(cmd_id) = last_cmd_id {
+        let res = client.wait_motion_done(protocol::MotorId::Left, cmd_id).await;
+        println!("send_pos_cmd, motion {cmd_id} done: {res:?}");
     }
 }
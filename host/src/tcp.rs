@@ -0,0 +1,78 @@
+use std::future::Future;
+use std::io;
+
+use postcard_rpc::host_client::{WireRx, WireSpawn, WireTx};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+// TCP transport used to talk to the emulator. TCP is a byte stream, so each postcard-rpc
+// frame is sent with its length (u32, little endian) in front of it
+
+pub const DEFAULT_EMULATOR_ADDR: &str = "127.0.0.1:7878";
+
+// Same as the size of the receive buffer in firmware
+pub const MAX_FRAME_SIZE: usize = 1024;
+
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = reader.read_u32_le().await? as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame is too large: {len}"),
+        ));
+    }
+
+    let mut frame = vec![0_u8; len];
+    reader.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &[u8]) -> io::Result<()> {
+    writer.write_u32_le(frame.len() as u32).await?;
+    writer.write_all(frame).await?;
+    writer.flush().await
+}
+
+pub struct TcpWireTx {
+    writer: OwnedWriteHalf,
+}
+
+impl TcpWireTx {
+    pub fn new(writer: OwnedWriteHalf) -> Self {
+        Self { writer }
+    }
+}
+
+impl WireTx for TcpWireTx {
+    type Error = io::Error;
+
+    async fn send(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        write_frame(&mut self.writer, &data).await
+    }
+}
+
+pub struct TcpWireRx {
+    reader: OwnedReadHalf,
+}
+
+impl TcpWireRx {
+    pub fn new(reader: OwnedReadHalf) -> Self {
+        Self { reader }
+    }
+}
+
+impl WireRx for TcpWireRx {
+    type Error = io::Error;
+
+    async fn receive(&mut self) -> Result<Vec<u8>, Self::Error> {
+        read_frame(&mut self.reader).await
+    }
+}
+
+pub struct TokioSpawn;
+
+impl WireSpawn for TokioSpawn {
+    fn spawn(&mut self, fut: impl Future<Output = ()> + Send + 'static) {
+        tokio::spawn(fut);
+    }
+}
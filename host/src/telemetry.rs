@@ -0,0 +1,109 @@
+use std::collections::VecDeque;
+
+use protocol::Mpu6050MotionData;
+
+// Follow the counter of the samples published by the board, ex: `MotorProcessData::cycle`
+// and `Mpu6050MotionData::sample`. The board publishes the latest data, so a sample can
+// be received twice or skipped when the link is busy
+#[derive(Default)]
+pub struct SampleCounter {
+    last: Option<u32>,
+    dropped: u64,
+}
+
+impl SampleCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Number of samples dropped before this one, `None` is returned when the sample is
+    // already received. The counter is followed again when it goes back, ex: the board
+    // is reset
+    pub fn update(&mut self, counter: u32) -> Option<u32> {
+        let dropped = match self.last {
+            Some(last) if counter == last => return None,
+            Some(last) if (counter.wrapping_sub(last) as i32) > 0 => counter.wrapping_sub(last) - 1,
+            _ => 0,
+        };
+        self.last = Some(counter);
+        self.dropped += dropped as u64;
+
+        Some(dropped)
+    }
+
+    // Total number of dropped samples
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+// Recent MPU6050 samples, the motor data is aligned with the sample that is read closest
+// to the control cycle. Both are timestamped by the clock of the board
+pub struct ImuHistory {
+    samples: VecDeque<Mpu6050MotionData>,
+    size: usize,
+}
+
+impl ImuHistory {
+    pub fn new(size: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(size),
+            size,
+        }
+    }
+
+    pub fn push(&mut self, data: Mpu6050MotionData) {
+        if self.samples.len() == self.size {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(data);
+    }
+
+    pub fn nearest(&self, timestamp_us: u64) -> Option<&Mpu6050MotionData> {
+        self.samples
+            .iter()
+            .min_by_key(|x| x.timestamp_us.abs_diff(timestamp_us))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_counter_should_count_dropped_and_skip_repeated_samples() {
+        let mut counter = SampleCounter::new();
+        assert_eq!(counter.update(10), Some(0));
+        assert_eq!(counter.update(11), Some(0));
+        assert_eq!(counter.update(11), None);
+        assert_eq!(counter.update(15), Some(3));
+        assert_eq!(counter.dropped(), 3);
+
+        // Wrapping counter
+        let mut counter = SampleCounter::new();
+        counter.update(u32::MAX - 1);
+        assert_eq!(counter.update(1), Some(2));
+
+        // The board is reset
+        assert_eq!(counter.update(0), Some(0));
+        assert_eq!(counter.update(1), Some(0));
+        assert_eq!(counter.dropped(), 2);
+    }
+
+    #[test]
+    fn test_imu_history_should_give_nearest_sample() {
+        let mut history = ImuHistory::new(3);
+        assert_eq!(history.nearest(0), None);
+        for (sample, timestamp_us) in [(1, 1000), (2, 11000), (3, 21000), (4, 31000)] {
+            history.push(Mpu6050MotionData {
+                sample,
+                timestamp_us,
+                ..Default::default()
+            });
+        }
+        assert_eq!(history.nearest(15000).unwrap().sample, 2);
+        assert_eq!(history.nearest(17000).unwrap().sample, 3);
+        // The oldest sample is dropped
+        assert_eq!(history.nearest(0).unwrap().sample, 2);
+    }
+}
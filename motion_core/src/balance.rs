@@ -0,0 +1,297 @@
+use num_traits::Float;
+use protocol::{
+    BaseTwistCommand, ControlMode, DeviceConfig, DeviceEventKind, Mpu6050MotionData, Odometry,
+};
+
+use crate::hal::MotorDriver;
+use crate::kinematics::twist_to_wheel_vels;
+use crate::motion::{halt_base, set_base_velocities, Motion};
+use crate::rpm_to_rad_s;
+
+// The latest MPU6050 data is used until it is older than the timeout
+const IMU_TIMEOUT_S: f32 = 0.1;
+
+struct ImuSample {
+    data: Mpu6050MotionData,
+    age_s: f32,
+}
+
+// Pitch of the chassis from MPU6050, the gyro Y rate is integrated and its drift is
+// corrected by the pitch of gravity measured by the accelerometer (complementary filter).
+// The pitch is positive when the chassis leans forward (+x of MPU6050)
+pub struct PitchEstimator {
+    period_s: f32,
+    imu: Option<ImuSample>,
+    pitch: Option<f32>,
+    pitch_rate: f32,
+}
+
+impl PitchEstimator {
+    pub fn new(period_s: f32) -> Self {
+        Self {
+            period_s,
+            imu: None,
+            pitch: None,
+            pitch_rate: 0.0,
+        }
+    }
+
+    // Acceleration in g and angular rate in deg/s, the same as `Mpu6050MotionDataTopic`
+    pub fn set_imu_data(&mut self, data: Mpu6050MotionData) {
+        self.imu = Some(ImuSample { data, age_s: 0.0 });
+    }
+
+    // It is `None` when MPU6050 data is not received, unit: rad
+    pub fn pitch(&self) -> Option<f32> {
+        self.pitch
+    }
+
+    // unit: rad/s
+    pub fn pitch_rate(&self) -> f32 {
+        self.pitch_rate
+    }
+
+    pub fn update(&mut self, time_constant_s: f32) {
+        let Some(imu) = self.imu.as_mut() else {
+            return;
+        };
+        imu.age_s += self.period_s;
+        if imu.age_s > IMU_TIMEOUT_S {
+            self.imu = None;
+            self.pitch = None;
+            self.pitch_rate = 0.0;
+            return;
+        }
+
+        let acc_pitch = Float::atan2(-imu.data.acc_x, imu.data.acc_z);
+        self.pitch_rate = imu.data.g_y.to_radians();
+        self.pitch = Some(match self.pitch {
+            // The first sample only uses the accelerometer
+            None => acc_pitch,
+            Some(pitch) => {
+                let alpha = time_constant_s / (time_constant_s + self.period_s);
+                alpha * (pitch + self.pitch_rate * self.period_s) + (1.0 - alpha) * acc_pitch
+            }
+        });
+    }
+}
+
+// Balance controller of the two-wheel base (inverted pendulum). The wheels are velocity
+// controlled, so the controller sets the acceleration of the base from the pitch, pitch
+// rate and velocity error, and the integrated velocity is set to both wheels. It is run
+// before `Motion::run` of the axes and the axes are in `ControlMode::Balance` until the
+// controller is stopped by other commands or the tilt cutoff
+pub struct BalanceController {
+    period_s: f32,
+    estimator: PitchEstimator,
+    target: Option<BaseTwistCommand>,
+    // The axes follow the controller, it is cleared when the controller is cancelled
+    engaged: bool,
+    // Velocity of the base set to the wheels, unit: m/s
+    vel_cmd: f32,
+}
+
+impl BalanceController {
+    pub fn new(period_s: f32) -> Self {
+        Self {
+            period_s,
+            estimator: PitchEstimator::new(period_s),
+            target: None,
+            engaged: false,
+            vel_cmd: 0.0,
+        }
+    }
+
+    pub fn set_imu_data(&mut self, data: Mpu6050MotionData) {
+        self.estimator.set_imu_data(data);
+    }
+
+    pub fn pitch(&self) -> Option<f32> {
+        self.estimator.pitch()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.target.is_some()
+    }
+
+    // The body velocities are changed while balancing, the base starts from its velocity
+    pub fn start(&mut self, twist: BaseTwistCommand, odometry: &Odometry) {
+        if !twist.linear.is_finite() || !twist.angular.is_finite() {
+            return;
+        }
+
+        if self.target.is_none() {
+            self.vel_cmd = odometry.linear;
+        }
+        self.target = Some(twist);
+    }
+
+    // The axes are not halted, it is used when the axes are stopped by other commands
+    pub fn cancel(&mut self) {
+        self.target = None;
+        self.engaged = false;
+    }
+
+    // The pitch is estimated in every control cycle, so it is ready when the controller
+    // starts. `BalanceCutoff` is returned when the wheels are halted by the controller
+    pub fn run<D1: MotorDriver, D2: MotorDriver, const N1: usize, const N2: usize>(
+        &mut self,
+        config: &DeviceConfig,
+        left: &mut Motion<D1, N1>,
+        right: &mut Motion<D2, N2>,
+    ) -> Option<DeviceEventKind> {
+        let balance = &config.balance;
+        self.estimator.update(balance.filter_time_constant);
+        let target = self.target?;
+
+        // Other commands, ex: `Halt` or velocity commands from host, take over the axes
+        let is_balance_mode = |mode| mode == ControlMode::Balance;
+        if self.engaged
+            && !(is_balance_mode(left.control_mode()) && is_balance_mode(right.control_mode()))
+        {
+            halt_base(left, right, ControlMode::Balance);
+            self.cancel();
+            return None;
+        }
+
+        // The chassis falls over or it can't be measured
+        let tilt = self.estimator.pitch().map(|x| x - balance.pitch_offset);
+        let Some(tilt) = tilt.filter(|x| x.abs() <= balance.max_tilt) else {
+            halt_base(left, right, ControlMode::Balance);
+            self.cancel();
+            return Some(DeviceEventKind::BalanceCutoff);
+        };
+
+        // The wheels move under the center of mass, the velocity error is corrected by
+        // leaning the chassis to the direction of the target velocity
+        let acc = balance.kp_pitch * tilt
+            + balance.kd_pitch * self.estimator.pitch_rate()
+            + balance.kp_vel * (self.vel_cmd - target.linear);
+        let vel_limit = max_base_velocity(config);
+        self.vel_cmd = (self.vel_cmd + acc * self.period_s).clamp(-vel_limit, vel_limit);
+
+        let twist = BaseTwistCommand {
+            linear: self.vel_cmd,
+            angular: target.angular,
+        };
+        let vels = twist_to_wheel_vels(&twist, config);
+        if !set_base_velocities(left, right, ControlMode::Balance, vels) {
+            self.cancel();
+            return None;
+        }
+        self.engaged = true;
+        None
+    }
+}
+
+// The forward velocity (m/s) that both wheels can reach, it stops the integration of
+// the velocity when a wheel is at the velocity limit
+fn max_base_velocity(config: &DeviceConfig) -> f32 {
+    let gear_ratio = config
+        .left
+        .mechanical
+        .gear_ratio
+        .max(config.right.mechanical.gear_ratio);
+    rpm_to_rad_s(config.vel_limit / gear_ratio) * config.base.wheel_radius
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::default_config;
+    use crate::mock::MockMotor;
+    use s_curve::SCurveInterpolator;
+
+    const PERIOD_S: f32 = 0.005;
+
+    fn create_motion() -> Motion<MockMotor, 4> {
+        let s_curve_intper = SCurveInterpolator::new(rpm_to_rad_s(3000.0), 300.0, 3000.0, PERIOD_S);
+        Motion::new(s_curve_intper, MockMotor::new(PERIOD_S))
+    }
+
+    // The chassis is tilted by the pitch and turns at the pitch rate
+    fn imu_data(pitch: f32, pitch_rate: f32) -> Mpu6050MotionData {
+        Mpu6050MotionData {
+            acc_x: -Float::sin(pitch),
+            acc_z: Float::cos(pitch),
+            g_y: pitch_rate.to_degrees(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_estimator_should_follow_gyro_and_correct_drift() {
+        let mut estimator = PitchEstimator::new(PERIOD_S);
+        estimator.update(0.5);
+        assert_eq!(estimator.pitch(), None);
+
+        estimator.set_imu_data(imu_data(0.1, 0.0));
+        estimator.update(0.5);
+        assert!((estimator.pitch().unwrap() - 0.1).abs() < 1e-6);
+
+        // The gyro has 0.05 rad/s drift, the error is kept small by the accelerometer
+        for _ in 0..2000 {
+            let mut data = imu_data(0.1, 0.0);
+            data.g_y = 0.05_f32.to_degrees();
+            estimator.set_imu_data(data);
+            estimator.update(0.5);
+        }
+        assert!((estimator.pitch().unwrap() - 0.1).abs() < 0.05 * 0.5 * 1.1);
+
+        // The data is not received
+        for _ in 0..30 {
+            estimator.update(0.5);
+        }
+        assert_eq!(estimator.pitch(), None);
+    }
+
+    #[test]
+    fn test_balance_should_drive_wheels_under_the_chassis() {
+        let config = default_config();
+        let (mut left, mut right) = (create_motion(), create_motion());
+        let mut balance = BalanceController::new(PERIOD_S);
+        balance.set_imu_data(imu_data(0.05, 0.0));
+        balance.start(BaseTwistCommand::default(), &Odometry::default());
+
+        // The wheels accelerate forward when the chassis leans forward
+        for _ in 0..10 {
+            assert_eq!(balance.run(&config, &mut left, &mut right), None);
+            left.run();
+            right.run();
+        }
+        assert_eq!(left.control_mode(), ControlMode::Balance);
+        assert_eq!(right.control_mode(), ControlMode::Balance);
+        assert!(left.get_motor_process_data().actual_vel > 0.0);
+        assert!(right.get_motor_process_data().actual_vel > 0.0);
+    }
+
+    #[test]
+    fn test_balance_should_halt_wheels_when_chassis_falls_over() {
+        let config = default_config();
+        let (mut left, mut right) = (create_motion(), create_motion());
+        let mut balance = BalanceController::new(PERIOD_S);
+        balance.set_imu_data(imu_data(0.0, 0.0));
+        balance.start(BaseTwistCommand::default(), &Odometry::default());
+        assert_eq!(balance.run(&config, &mut left, &mut right), None);
+
+        // The pitch is filtered, the cutoff is reported after the estimate passes the limit
+        let mut event = None;
+        for _ in 0..400 {
+            balance.set_imu_data(imu_data(config.balance.max_tilt + 0.2, 0.0));
+            event = balance.run(&config, &mut left, &mut right);
+            if event.is_some() {
+                break;
+            }
+            left.run();
+            right.run();
+        }
+        assert_eq!(event, Some(DeviceEventKind::BalanceCutoff));
+        assert!(!balance.is_active());
+        for _ in 0..100 {
+            left.run();
+            right.run();
+        }
+        assert_eq!(left.control_mode(), ControlMode::StandStill);
+        assert_eq!(right.control_mode(), ControlMode::StandStill);
+    }
+}
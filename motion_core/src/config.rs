@@ -0,0 +1,223 @@
+use protocol::{
+    AccelRange, AxisConfig, BalanceConfig, BaseConfig, DeviceConfig, DlpfBandwidth,
+    DriveProtectionConfig, FollowingErrorConfig, GyroRange, ImuConfig, MechanicalConfig, PidGains,
+    VelocityEstimator,
+};
+
+use crate::encoder::{velocity_resolution_in_rpm, MAX_OBSERVER_BANDWIDTH_HZ, MAX_WINDOW_PERIODS};
+use crate::imu::is_valid_imu_config;
+use crate::rpm_to_rad_s;
+
+// Default values, they are used when there is no valid configuration in flash
+const DEFAULT_PID_GAINS: PidGains = PidGains {
+    kp: 0.00006,
+    ki: 0.00124,
+    kd: 0.000000728,
+};
+const DEFAULT_VEL_LIMIT_RPM: f32 = 4000.0;
+
+// The windows are wide enough for the lag of velocity loop when a velocity step is
+// commanded, a jammed wheel still trips the velocity window
+const DEFAULT_FOLLOWING_ERROR: FollowingErrorConfig = FollowingErrorConfig {
+    pos_window: 20.0,
+    pos_filter_ms: 200,
+    vel_window: 100.0,
+    vel_filter_ms: 500,
+};
+
+// A velocity step saturates the effort only for a short time after the motor starts, and
+// a velocity reversal passes the opposite direction within the runaway time
+const DEFAULT_DRIVE_PROTECTION: DriveProtectionConfig = DriveProtectionConfig {
+    stall_effort: 0.9,
+    stall_vel: 30.0,
+    stall_time_ms: 300,
+    runaway_margin: 300.0,
+    runaway_time_ms: 500,
+};
+
+// These values are obtained from the calibration process
+const DEFAULT_ACCEL_CALIBRATION: (i16, i16, i16) = (-2453, -3243, -1793);
+const DEFAULT_GYRO_CALIBRATION: (i16, i16, i16) = (133, 32, -59);
+
+const DEFAULT_BASE: BaseConfig = BaseConfig {
+    wheel_radius: 0.035,
+    track_width: 0.16,
+    gyro_weight: 0.98,
+    heading_kp: 4.0,
+    angular_acc_limit: 10.0,
+    angular_jerk_limit: 100.0,
+};
+
+// The gains are tuned with the pendulum model in `plant_sim`, the wheels need a stiffer
+// velocity loop than `DEFAULT_PID_GAINS` to balance. The tilt cutoff is about 30 degree
+const DEFAULT_BALANCE: BalanceConfig = BalanceConfig {
+    kp_pitch: 32.0,
+    kd_pitch: 5.5,
+    kp_vel: 3.8,
+    pitch_offset: 0.0,
+    max_tilt: 0.5,
+    filter_time_constant: 1.0,
+};
+
+const DEFAULT_IMU: ImuConfig = ImuConfig {
+    dmp_rate_hz: 100,
+    sample_rate_hz: 100,
+    accel_range: AccelRange::G2,
+    gyro_range: GyroRange::Deg2000,
+    dlpf: DlpfBandwidth::Hz44,
+};
+
+pub fn default_config() -> DeviceConfig {
+    let vel_limit_rad_s = rpm_to_rad_s(DEFAULT_VEL_LIMIT_RPM);
+    let axis = AxisConfig {
+        pid: DEFAULT_PID_GAINS,
+        vel_estimator: VelocityEstimator::Difference,
+        mechanical: MechanicalConfig::default(),
+        following_error: DEFAULT_FOLLOWING_ERROR,
+        drive_protection: DEFAULT_DRIVE_PROTECTION,
+    };
+    DeviceConfig {
+        left: axis,
+        right: axis,
+        vel_limit: DEFAULT_VEL_LIMIT_RPM,
+        acc_limit: vel_limit_rad_s * 10.0,
+        jerk_limit: vel_limit_rad_s * 100.0,
+        accel_calibration: DEFAULT_ACCEL_CALIBRATION,
+        gyro_calibration: DEFAULT_GYRO_CALIBRATION,
+        base: DEFAULT_BASE,
+        balance: DEFAULT_BALANCE,
+        imu: DEFAULT_IMU,
+    }
+}
+
+pub fn is_valid_config(config: &DeviceConfig) -> bool {
+    let is_valid_limit = |x: f32| x.is_finite() && x > 0.0;
+
+    is_valid_axis_config(&config.left)
+        && is_valid_axis_config(&config.right)
+        && is_valid_limit(config.vel_limit)
+        && is_valid_limit(config.acc_limit)
+        && is_valid_limit(config.jerk_limit)
+        && is_valid_limit(config.base.wheel_radius)
+        && is_valid_limit(config.base.track_width)
+        && (0.0..=1.0).contains(&config.base.gyro_weight)
+        && config.base.heading_kp.is_finite()
+        && config.base.heading_kp >= 0.0
+        && is_valid_limit(config.base.angular_acc_limit)
+        && is_valid_limit(config.base.angular_jerk_limit)
+        && is_valid_balance_config(&config.balance)
+        && is_valid_imu_config(&config.imu)
+}
+
+fn is_valid_balance_config(balance: &BalanceConfig) -> bool {
+    let is_valid_gain = |x: f32| x.is_finite() && x >= 0.0;
+
+    is_valid_gain(balance.kp_pitch)
+        && is_valid_gain(balance.kd_pitch)
+        && is_valid_gain(balance.kp_vel)
+        && balance.pitch_offset.is_finite()
+        && balance.max_tilt > 0.0
+        && balance.max_tilt <= core::f32::consts::FRAC_PI_2
+        && balance.filter_time_constant.is_finite()
+        && balance.filter_time_constant > 0.0
+}
+
+// Encoder counts per motor revolution after quadrature decoding
+pub fn motor_counts_per_rev(mechanical: &MechanicalConfig) -> u32 {
+    mechanical.counts_per_rev as u32 * mechanical.quadrature_multiplier as u32
+}
+
+// Motor-side rad per axis unit (and rad/s per axis unit/s), it is negative if the axis is
+// inverted
+pub fn motor_per_axis_unit(mechanical: &MechanicalConfig) -> f32 {
+    let scale = mechanical.gear_ratio / mechanical.linear_scale;
+    if mechanical.invert {
+        -scale
+    } else {
+        scale
+    }
+}
+
+// Smallest axis velocity that the velocity estimator of the axis can tell from
+// standstill, unit: axis unit/s
+pub fn axis_velocity_resolution(axis: &AxisConfig, period_s: f32) -> f32 {
+    let resolution = velocity_resolution_in_rpm(
+        axis.vel_estimator,
+        motor_counts_per_rev(&axis.mechanical),
+        period_s,
+    );
+
+    rpm_to_rad_s(resolution) / motor_per_axis_unit(&axis.mechanical).abs()
+}
+
+fn is_valid_axis_config(axis: &AxisConfig) -> bool {
+    let gains = &axis.pid;
+    let is_valid_gains = [gains.kp, gains.ki, gains.kd]
+        .iter()
+        .all(|x| x.is_finite() && *x >= 0.0);
+
+    let is_valid_estimator = match axis.vel_estimator {
+        VelocityEstimator::Difference | VelocityEstimator::MT => true,
+        VelocityEstimator::MovingWindow(periods) => (1..=MAX_WINDOW_PERIODS).contains(&periods),
+        VelocityEstimator::TrackingObserver(bandwidth_hz) => {
+            bandwidth_hz.is_finite()
+                && bandwidth_hz > 0.0
+                && bandwidth_hz <= MAX_OBSERVER_BANDWIDTH_HZ
+        }
+    };
+
+    let mechanical = &axis.mechanical;
+    let is_valid_mechanical = mechanical.counts_per_rev > 0
+        && matches!(mechanical.quadrature_multiplier, 1 | 2 | 4)
+        && mechanical.gear_ratio.is_finite()
+        && mechanical.gear_ratio > 0.0
+        && mechanical.linear_scale.is_finite()
+        && mechanical.linear_scale > 0.0;
+
+    let following_error = &axis.following_error;
+    let is_valid_following_error = [following_error.pos_window, following_error.vel_window]
+        .iter()
+        .all(|x| x.is_finite() && *x >= 0.0);
+
+    let protection = &axis.drive_protection;
+    let is_valid_stall_effort = protection.stall_effort > 0.0 && protection.stall_effort <= 1.0;
+    let is_valid_protection = (protection.stall_time_ms == 0 || is_valid_stall_effort)
+        && [protection.stall_vel, protection.runaway_margin]
+            .iter()
+            .all(|x| x.is_finite() && *x >= 0.0);
+
+    is_valid_gains
+        && is_valid_estimator
+        && is_valid_mechanical
+        && is_valid_following_error
+        && is_valid_protection
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config_store::MAX_PAYLOAD_SIZE;
+    use core::f32::consts::PI;
+
+    #[test]
+    fn test_default_config_should_fit_in_config_record() {
+        let mut buf = [0_u8; MAX_PAYLOAD_SIZE];
+        let encoded = postcard::to_slice(&default_config(), &mut buf).map(|x| x.len());
+        assert!(encoded.is_ok(), "{encoded:?}");
+    }
+
+    #[test]
+    fn test_axis_velocity_resolution_should_be_in_axis_units() {
+        // One count per period is 30 rpm at the motor with 400 counts/rev
+        let mut axis = default_config().left;
+        assert!((axis_velocity_resolution(&axis, 0.005) - PI).abs() < 1e-5);
+
+        axis.mechanical.gear_ratio = 10.0;
+        axis.mechanical.invert = true;
+        assert!((axis_velocity_resolution(&axis, 0.005) - PI / 10.0).abs() < 1e-5);
+
+        // Metres per second at the wheel with 0.05 m radius
+        axis.mechanical.linear_scale = 0.05;
+        assert!((axis_velocity_resolution(&axis, 0.005) - PI / 200.0).abs() < 1e-6);
+    }
+}
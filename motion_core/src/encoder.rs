@@ -0,0 +1,401 @@
+use core::f32::consts::PI;
+
+use heapless::HistoryBuffer;
+use protocol::VelocityEstimator;
+
+use crate::hal::{EdgeTiming, EncoderInput};
+
+// Maximum number of periods used by moving window estimation
+pub const MAX_WINDOW_PERIODS: u8 = 16;
+
+// The discrete observer becomes unstable when the bandwidth gets close to the control
+// frequency, this limit is chosen for 5 ms control period
+pub const MAX_OBSERVER_BANDWIDTH_HZ: f32 = 10.0;
+
+// Window used by M/T estimation when the encoder input can't capture edge timing
+const MT_FALLBACK_WINDOW_PERIODS: u8 = 4;
+
+// M/T estimation treats the encoder as stopped if there is no edge within this time,
+// it is about 0.75 rpm with 400 counts/rev
+const MT_STOP_TIMEOUT_US: u32 = 200_000;
+
+// Smallest speed that the estimator can tell from standstill, unit: rpm. It is one count
+// over the time the estimator looks at, the observer averages the counts over about its
+// time constant
+pub fn velocity_resolution_in_rpm(
+    estimator: VelocityEstimator,
+    counts_per_rev: u32,
+    period_s: f32,
+) -> f32 {
+    let window_s = match estimator {
+        VelocityEstimator::Difference => period_s,
+        VelocityEstimator::MovingWindow(periods) => {
+            periods.clamp(1, MAX_WINDOW_PERIODS) as f32 * period_s
+        }
+        VelocityEstimator::MT => MT_STOP_TIMEOUT_US as f32 * 1.0e-6,
+        VelocityEstimator::TrackingObserver(bandwidth_hz) => {
+            (1.0 / (2.0 * PI * bandwidth_hz)).max(period_s)
+        }
+    };
+
+    60.0 / (counts_per_rev as f32 * window_s)
+}
+
+pub struct Encoder<E: EncoderInput> {
+    input: E,
+    // Counts per motor revolution after quadrature decoding
+    counts_per_rev: u32,
+    estimator: VelocityEstimator,
+    act_vel: f32,
+    act_acc: f32,
+    act_pos: f32,
+    curr_enc_count: i32,
+    prev_enc_count: i32,
+    prev_qei_count: i16,
+    curr_qei_count: i16,
+    edge_timing: Option<EdgeTiming>,
+    // Counts of the latest periods, the newest one is the current count
+    count_history: HistoryBuffer<i32, { MAX_WINDOW_PERIODS as usize + 1 }>,
+    // Count and time of the edge used as the start of M/T measurement
+    mt_ref_edge: Option<(i32, u32)>,
+    // Predicted position (relative to the current count), velocity and acceleration of
+    // observer, unit: counts, counts/s, counts/s^2
+    obs_pos: f32,
+    obs_vel: f32,
+    obs_acc: f32,
+}
+
+impl<E: EncoderInput> Encoder<E> {
+    pub fn new(input: E, counts_per_rev: u32) -> Self {
+        Self {
+            input,
+            counts_per_rev,
+            estimator: VelocityEstimator::Difference,
+            act_vel: 0.0,
+            act_acc: 0.0,
+            act_pos: 0.0,
+            curr_enc_count: 0,
+            prev_enc_count: 0,
+            prev_qei_count: 0,
+            curr_qei_count: 0,
+            edge_timing: None,
+            count_history: HistoryBuffer::new(),
+            mt_ref_edge: None,
+            obs_pos: 0.0,
+            obs_vel: 0.0,
+            obs_acc: 0.0,
+        }
+    }
+
+    // Change the velocity estimator, the states of estimators are reset and the observer
+    // starts from the current velocity
+    pub fn set_velocity_estimator(&mut self, estimator: VelocityEstimator) {
+        if self.estimator == estimator {
+            return;
+        }
+
+        self.estimator = estimator;
+        self.reset_estimator();
+    }
+
+    // Change the resolution, the velocity is estimated with the new resolution from the
+    // next update
+    pub fn set_counts_per_rev(&mut self, counts_per_rev: u32) {
+        if self.counts_per_rev == counts_per_rev || counts_per_rev == 0 {
+            return;
+        }
+
+        self.counts_per_rev = counts_per_rev;
+        self.reset_estimator();
+    }
+
+    pub fn get_velocity_estimator(&self) -> VelocityEstimator {
+        self.estimator
+    }
+
+    pub fn get_counts_per_rev(&self) -> u32 {
+        self.counts_per_rev
+    }
+
+    pub fn get_enc_count(&self) -> i32 {
+        self.curr_enc_count
+    }
+
+    pub fn get_act_position_in_rad(&self) -> f32 {
+        self.act_pos
+    }
+
+    pub fn get_act_velocity_in_rpm(&self) -> f32 {
+        self.act_vel
+    }
+
+    // Acceleration is only estimated by the tracking observer, it is 0 for the other
+    // estimators
+    pub fn get_act_acceleration_in_rad_s2(&self) -> f32 {
+        self.act_acc
+    }
+
+    pub fn update_act_velocity_in_rpm(&mut self, period_s: f32) {
+        self.update_encoder_count();
+        self.count_history.write(self.curr_enc_count);
+
+        let diff_count = self.curr_enc_count - self.prev_enc_count;
+        let counts_per_s = match self.estimator {
+            VelocityEstimator::Difference => diff_count as f32 / period_s,
+            VelocityEstimator::MovingWindow(periods) => self.window_velocity(periods, period_s),
+            VelocityEstimator::MT => match self.edge_timing {
+                Some(edge_timing) => self.mt_velocity(edge_timing),
+                None => self.window_velocity(MT_FALLBACK_WINDOW_PERIODS, period_s),
+            },
+            VelocityEstimator::TrackingObserver(bandwidth_hz) => {
+                self.observer_velocity(bandwidth_hz, diff_count, period_s)
+            }
+        };
+
+        self.act_vel = 60.0 * counts_per_s / (self.counts_per_rev as f32);
+        self.act_pos += 2.0 * PI * diff_count as f32 / (self.counts_per_rev as f32);
+
+        self.prev_enc_count = self.curr_enc_count;
+    }
+
+    fn update_encoder_count(&mut self) {
+        self.curr_qei_count = self.input.count() as i16;
+        self.edge_timing = self.input.edge_timing();
+        self.curr_enc_count += self.curr_qei_count.wrapping_sub(self.prev_qei_count) as i32;
+        self.prev_qei_count = self.curr_qei_count;
+    }
+
+    fn reset_estimator(&mut self) {
+        self.act_acc = 0.0;
+        self.count_history.clear();
+        self.count_history.write(self.curr_enc_count);
+        self.mt_ref_edge = None;
+        self.obs_pos = 0.0;
+        self.obs_vel = self.rpm_to_counts_per_s(self.act_vel);
+        self.obs_acc = 0.0;
+    }
+
+    // Count difference over the last `periods` periods, fewer periods are used until
+    // the history is filled
+    fn window_velocity(&self, periods: u8, period_s: f32) -> f32 {
+        let periods = (periods.clamp(1, MAX_WINDOW_PERIODS) as usize)
+            .min(self.count_history.len().saturating_sub(1));
+        let Some(oldest_count) = self
+            .count_history
+            .oldest_ordered()
+            .nth(self.count_history.len() - 1 - periods)
+        else {
+            return 0.0;
+        };
+
+        (self.curr_enc_count - oldest_count) as f32 / (periods as f32 * period_s)
+    }
+
+    // M/T method: the counts between two edges are divided by the time between them, so
+    // the resolution is decided by the capture timer instead of the control period. If
+    // there is no new edge, the velocity can't be larger than one count over the time
+    // since the last edge
+    fn mt_velocity(&mut self, edge_timing: EdgeTiming) -> f32 {
+        let Some((ref_count, ref_us)) = self.mt_ref_edge else {
+            self.mt_ref_edge = Some((self.curr_enc_count, edge_timing.last_edge_us));
+            return 0.0;
+        };
+
+        if self.curr_enc_count != ref_count {
+            let elapsed_us = edge_timing.last_edge_us.wrapping_sub(ref_us);
+            self.mt_ref_edge = Some((self.curr_enc_count, edge_timing.last_edge_us));
+            if elapsed_us > 0 {
+                return (self.curr_enc_count - ref_count) as f32 * 1.0e6 / elapsed_us as f32;
+            }
+        }
+
+        let elapsed_us = edge_timing.sample_us.wrapping_sub(ref_us);
+        if elapsed_us > MT_STOP_TIMEOUT_US {
+            return 0.0;
+        }
+
+        let max_counts_per_s = 1.0e6 / elapsed_us.max(1) as f32;
+        self.rpm_to_counts_per_s(self.act_vel)
+            .clamp(-max_counts_per_s, max_counts_per_s)
+    }
+
+    // Third order tracking observer with all poles at `-2 pi bandwidth`, the position
+    // error corrects the estimation of position, velocity and acceleration
+    fn observer_velocity(&mut self, bandwidth_hz: f32, diff_count: i32, period_s: f32) -> f32 {
+        let omega = 2.0 * PI * bandwidth_hz;
+        let l1 = 3.0 * omega;
+        let l2 = 3.0 * omega * omega;
+        let l3 = omega * omega * omega;
+
+        // `obs_pos` is relative to the previous count, move it to the current count after
+        // the prediction of the next period
+        let pos_err = diff_count as f32 - self.obs_pos;
+        self.obs_acc += l3 * pos_err * period_s;
+        self.obs_vel += (self.obs_acc + l2 * pos_err) * period_s;
+        self.obs_pos += (self.obs_vel + l1 * pos_err) * period_s - diff_count as f32;
+        self.act_acc = 2.0 * PI * self.obs_acc / (self.counts_per_rev as f32);
+
+        self.obs_vel
+    }
+
+    fn rpm_to_counts_per_s(&self, rpm: f32) -> f32 {
+        rpm * (self.counts_per_rev as f32) / 60.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockEncoderInput;
+
+    const PERIOD_S: f32 = 0.005;
+    const PERIOD_US: u32 = 5_000;
+
+    // Edge stream generated from a position profile (unit: counts), edges are captured
+    // with 1 us resolution like a timer capture channel
+    struct EdgeStream {
+        input: MockEncoderInput,
+        time_us: u32,
+        count: i32,
+        last_edge_us: u32,
+    }
+
+    impl EdgeStream {
+        fn new(input: MockEncoderInput) -> Self {
+            Self {
+                input,
+                time_us: 0,
+                count: 0,
+                last_edge_us: 0,
+            }
+        }
+
+        fn advance(&mut self, pos: impl Fn(f64) -> f64) {
+            for _ in 0..PERIOD_US {
+                self.time_us += 1;
+                let count = pos(self.time_us as f64 * 1.0e-6).floor() as i32;
+                if count != self.count {
+                    self.input.add(count - self.count);
+                    self.count = count;
+                    self.last_edge_us = self.time_us;
+                }
+            }
+
+            self.input.set_edge_timing(EdgeTiming {
+                last_edge_us: self.last_edge_us,
+                sample_us: self.time_us,
+            });
+        }
+    }
+
+    // Run the encoder at constant velocity and return the largest error after the
+    // estimator settles
+    fn max_error_at_constant_velocity(estimator: VelocityEstimator, vel_rpm: f32) -> f32 {
+        let input = MockEncoderInput::new();
+        let mut stream = EdgeStream::new(input.clone());
+        let mut encoder = Encoder::new(input, 400);
+        encoder.set_velocity_estimator(estimator);
+
+        let counts_per_s = vel_rpm as f64 * 400.0 / 60.0;
+        let mut max_err = 0.0_f32;
+        for i in 0..400 {
+            stream.advance(|t| counts_per_s * t + 0.3);
+            encoder.update_act_velocity_in_rpm(PERIOD_S);
+            if i >= 200 {
+                max_err = max_err.max((encoder.get_act_velocity_in_rpm() - vel_rpm).abs());
+            }
+        }
+        assert!(encoder.get_act_velocity_in_rpm().is_finite());
+
+        max_err
+    }
+
+    #[test]
+    fn test_update_act_velocity_should_handle_counter_wrap_around() {
+        let input = MockEncoderInput::new();
+        let mut encoder = Encoder::new(input.clone(), 400);
+
+        // 20 counts per 5ms with 400 counts/rev = 600 rpm, run backward to pass 0
+        for _ in 0..100 {
+            input.add(-20);
+            encoder.update_act_velocity_in_rpm(0.005);
+            assert!((encoder.get_act_velocity_in_rpm() + 600.0).abs() < 1e-3);
+        }
+
+        assert_eq!(encoder.get_enc_count(), -2000);
+        assert!((encoder.get_act_position_in_rad() + 5.0 * 2.0 * PI).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_estimators_should_resolve_low_speed() {
+        // 10 rpm is a third of count per period, the difference only reads 0 or 30 rpm
+        let vel_rpm = 10.0;
+        assert!(max_error_at_constant_velocity(VelocityEstimator::Difference, vel_rpm) > 15.0);
+        assert!(max_error_at_constant_velocity(VelocityEstimator::MovingWindow(16), vel_rpm) < 2.0);
+        assert!(max_error_at_constant_velocity(VelocityEstimator::MT, vel_rpm) < 0.1);
+        assert!(
+            max_error_at_constant_velocity(VelocityEstimator::TrackingObserver(5.0), vel_rpm) < 2.0
+        );
+
+        // The estimators should still work at high speed
+        let vel_rpm = -2500.0;
+        assert!(max_error_at_constant_velocity(VelocityEstimator::MT, vel_rpm) < 1.0);
+        assert!(
+            max_error_at_constant_velocity(VelocityEstimator::TrackingObserver(10.0), vel_rpm)
+                < 10.0
+        );
+    }
+
+    #[test]
+    fn test_mt_should_fall_back_to_window_and_report_zero_after_stop() {
+        // Without edge timing, M/T uses the moving window
+        let input = MockEncoderInput::new();
+        let mut encoder = Encoder::new(input.clone(), 400);
+        encoder.set_velocity_estimator(VelocityEstimator::MT);
+        for i in 0..20 {
+            input.add(if i % 2 == 0 { 1 } else { 0 });
+            encoder.update_act_velocity_in_rpm(PERIOD_S);
+        }
+        assert!((encoder.get_act_velocity_in_rpm() - 15.0).abs() < 1e-3);
+
+        // With edge timing, the velocity decays after the last edge and becomes 0
+        let input = MockEncoderInput::new();
+        let mut stream = EdgeStream::new(input.clone());
+        let mut encoder = Encoder::new(input, 400);
+        encoder.set_velocity_estimator(VelocityEstimator::MT);
+        for _ in 0..100 {
+            stream.advance(|t| (t * 200.0).min(50.5));
+            encoder.update_act_velocity_in_rpm(PERIOD_S);
+        }
+        assert_eq!(encoder.get_act_velocity_in_rpm(), 0.0);
+    }
+
+    #[test]
+    fn test_velocity_resolution_should_depend_on_estimator() {
+        let resolution = |estimator| velocity_resolution_in_rpm(estimator, 400, PERIOD_S);
+        assert_eq!(resolution(VelocityEstimator::Difference), 30.0);
+        assert_eq!(resolution(VelocityEstimator::MovingWindow(4)), 7.5);
+        assert_eq!(resolution(VelocityEstimator::MT), 0.75);
+        assert!((resolution(VelocityEstimator::TrackingObserver(10.0)) - 3.0 * PI).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_tracking_observer_should_estimate_acceleration() {
+        let input = MockEncoderInput::new();
+        let mut stream = EdgeStream::new(input.clone());
+        let mut encoder = Encoder::new(input, 400);
+        encoder.set_velocity_estimator(VelocityEstimator::TrackingObserver(10.0));
+
+        // 20 rad/s^2 in counts/s^2
+        let acc_rad_s2 = 20.0;
+        let acc_counts_s2 = acc_rad_s2 * 400.0 / (2.0 * core::f64::consts::PI);
+        for _ in 0..200 {
+            stream.advance(|t| 0.5 * acc_counts_s2 * t * t);
+            encoder.update_act_velocity_in_rpm(PERIOD_S);
+        }
+
+        let vel_rpm = 60.0 * acc_rad_s2 * 1.0 / (2.0 * core::f64::consts::PI);
+        assert!((encoder.get_act_acceleration_in_rad_s2() - acc_rad_s2 as f32).abs() < 2.0);
+        assert!((encoder.get_act_velocity_in_rpm() - vel_rpm as f32).abs() < 5.0);
+    }
+}
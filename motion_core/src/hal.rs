@@ -0,0 +1,60 @@
+use protocol::{DriveProtectionConfig, FaultReason, VelocityEstimator};
+
+use crate::pid::Pid;
+
+// Hardware abstraction used by the motion control logic, the implementations for the
+// target board are in `fw`, and the mock implementations are used in host tests
+
+// Time of the latest count change and the time when the count is read, both are in
+// microseconds from a free running timer and wrap around at the boundary of u32
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EdgeTiming {
+    pub last_edge_us: u32,
+    pub sample_us: u32,
+}
+
+// Quadrature encoder counter, the raw count wraps around at the boundary of u16
+pub trait EncoderInput {
+    fn count(&mut self) -> u16;
+
+    // Edge timing captured together with the latest `count`, it is used by M/T velocity
+    // estimation. Inputs without timer capture return `None`
+    fn edge_timing(&mut self) -> Option<EdgeTiming> {
+        None
+    }
+}
+
+// PWM output that controls the speed of motor
+pub trait PwmOutput {
+    fn set_duty_cycle_percent(&mut self, percent: u8);
+}
+
+// Digital output, it is used to control direction and brake of motor
+pub trait DigitalOutput {
+    fn set_high(&mut self);
+    fn set_low(&mut self);
+}
+
+// Blocking delay, it is used when toggling pins that need a short pulse
+pub trait BlockingDelay {
+    fn delay_us(&mut self, us: u32);
+}
+
+// Motor driver that runs velocity control loop, it is used by `Motion`
+pub trait MotorDriver {
+    fn set_target_velocity(&mut self, target_velocity_rpm: f32);
+    fn set_velocity_estimator(&mut self, estimator: VelocityEstimator);
+    fn set_counts_per_rev(&mut self, counts_per_rev: u32);
+    fn set_drive_protection(&mut self, protection: DriveProtectionConfig, vel_limit_rpm: f32);
+    fn get_period_s(&self) -> f32;
+    fn run_pid_velocity_control(&mut self);
+    // Stall or runaway detected by the driver, the output stays cut until it is cleared
+    fn drive_fault(&self) -> Option<FaultReason>;
+    fn clear_drive_fault(&mut self);
+    fn get_act_position_in_rad(&self) -> f32;
+    fn get_act_velocity_in_rpm(&self) -> f32;
+    // It is 0 unless the velocity estimator also estimates acceleration
+    fn get_act_acceleration_in_rad_s2(&self) -> f32;
+    fn pid(&self) -> &Pid;
+    fn pid_mut(&mut self) -> &mut Pid;
+}
@@ -0,0 +1,265 @@
+use protocol::{BaseTwistCommand, ControlMode, DeviceConfig, HeadingCommand, Odometry};
+use s_curve::{InterpolationStatus, SCurveInterpolator};
+
+use crate::hal::MotorDriver;
+use crate::kinematics::twist_to_wheel_vels;
+use crate::motion::{halt_base, set_base_velocities, Motion};
+use crate::odometry::wrap_angle;
+
+#[derive(Clone, Copy)]
+enum Target {
+    Hold {
+        linear: f32,
+        heading: f32,
+    },
+    // The angular motion is interpolated from the start heading, the position of the
+    // interpolator is the rotated angle plus `start_pos`
+    Rotate {
+        heading: f32,
+        start_heading: f32,
+        start_pos: f32,
+    },
+}
+
+// Heading controller of the two-wheel base, it trims the velocity difference of the wheels
+// with the heading error of odometry. It is run before `Motion::run` of the axes and the
+// axes are in `ControlMode::Heading` until the controller is stopped by other commands
+pub struct HeadingController {
+    intper: SCurveInterpolator,
+    target: Option<Target>,
+    // The axes follow the controller, it is cleared when the controller is cancelled
+    engaged: bool,
+    heading_error: f32,
+}
+
+impl HeadingController {
+    pub fn new(period_s: f32) -> Self {
+        Self {
+            intper: SCurveInterpolator::new(0.0, 0.0, 0.0, period_s),
+            target: None,
+            engaged: false,
+            heading_error: 0.0,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.target.is_some()
+    }
+
+    pub fn heading_error(&self) -> Option<f32> {
+        self.target.map(|_| self.heading_error)
+    }
+
+    // The command replaces the running one, commands with invalid values are ignored
+    pub fn start(&mut self, cmd: HeadingCommand, odometry: &Odometry, config: &DeviceConfig) {
+        let theta = odometry.pose.theta;
+        match cmd {
+            HeadingCommand::Hold { linear, heading } => {
+                let heading = heading.unwrap_or(theta);
+                if !linear.is_finite() || !heading.is_finite() {
+                    return;
+                }
+
+                self.intper.abort();
+                self.target = Some(Target::Hold {
+                    linear,
+                    heading: wrap_angle(heading),
+                });
+            }
+            HeadingCommand::RotateTo { heading, vel_max } => {
+                if !heading.is_finite() || !vel_max.is_finite() || vel_max <= 0.0 {
+                    return;
+                }
+
+                let base = &config.base;
+                self.intper.abort();
+                self.intper.set_constraint(
+                    vel_max,
+                    base.angular_acc_limit,
+                    base.angular_jerk_limit,
+                );
+                let start_pos = self.intper.get_intp_data().pos;
+                // The shorter direction is used
+                let displacement = wrap_angle(heading - theta);
+                self.intper.set_target(0.0, displacement, 0.0, 0.0, vel_max);
+                self.target = Some(Target::Rotate {
+                    heading: wrap_angle(heading),
+                    start_heading: theta,
+                    start_pos,
+                });
+            }
+        }
+    }
+
+    // The axes are not halted, it is used when the axes are stopped by other commands
+    pub fn cancel(&mut self) {
+        self.intper.abort();
+        self.target = None;
+        self.engaged = false;
+    }
+
+    // It is called with the odometry of the previous cycle, the velocities of the wheels
+    // are set to the axes in every control cycle
+    pub fn run<D1: MotorDriver, D2: MotorDriver, const N1: usize, const N2: usize>(
+        &mut self,
+        odometry: &Odometry,
+        config: &DeviceConfig,
+        left: &mut Motion<D1, N1>,
+        right: &mut Motion<D2, N2>,
+    ) {
+        let Some(target) = self.target else {
+            return;
+        };
+
+        // Other commands, ex: `Halt` or velocity commands from host, take over the axes
+        let is_heading_mode = |mode| mode == ControlMode::Heading;
+        if self.engaged
+            && !(is_heading_mode(left.control_mode()) && is_heading_mode(right.control_mode()))
+        {
+            halt_base(left, right, ControlMode::Heading);
+            self.cancel();
+            return;
+        }
+
+        let (linear, target_heading, angular_ff) = match target {
+            Target::Hold { linear, heading } => (linear, heading, 0.0),
+            Target::Rotate {
+                heading,
+                start_heading,
+                start_pos,
+            } => {
+                self.intper.interpolate();
+                let data = self.intper.get_intp_data();
+                if self.intper.get_intp_status() == InterpolationStatus::Done {
+                    // The heading is held at the end of rotation
+                    self.target = Some(Target::Hold {
+                        linear: 0.0,
+                        heading,
+                    });
+                    (0.0, heading, 0.0)
+                } else {
+                    (0.0, start_heading + data.pos - start_pos, data.vel)
+                }
+            }
+        };
+
+        self.heading_error = wrap_angle(target_heading - odometry.pose.theta);
+        let twist = BaseTwistCommand {
+            linear,
+            angular: angular_ff + config.base.heading_kp * self.heading_error,
+        };
+        let vels = twist_to_wheel_vels(&twist, config);
+        if !set_base_velocities(left, right, ControlMode::Heading, vels) {
+            self.cancel();
+            return;
+        }
+        self.engaged = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::default_config;
+    use crate::mock::MockMotor;
+    use crate::odometry::WheelOdometry;
+    use crate::rpm_to_rad_s;
+    use core::f32::consts::PI;
+    use protocol::{MotorCommand, Pose};
+
+    const PERIOD_S: f32 = 0.005;
+
+    struct Base {
+        left: Motion<MockMotor, 4>,
+        right: Motion<MockMotor, 4>,
+        odometry: WheelOdometry,
+        heading: HeadingController,
+        config: DeviceConfig,
+    }
+
+    impl Base {
+        fn new() -> Self {
+            let config = default_config();
+            let create_motion = || {
+                let s_curve_intper =
+                    SCurveInterpolator::new(rpm_to_rad_s(3000.0), 300.0, 3000.0, PERIOD_S);
+                Motion::new(s_curve_intper, MockMotor::new(PERIOD_S))
+            };
+            Self {
+                left: create_motion(),
+                right: create_motion(),
+                odometry: WheelOdometry::new(PERIOD_S),
+                heading: HeadingController::new(PERIOD_S),
+                config,
+            }
+        }
+
+        fn run_cycles(&mut self, cycles: usize) {
+            for _ in 0..cycles {
+                let odometry = self.odometry.odometry();
+                self.heading
+                    .run(&odometry, &self.config, &mut self.left, &mut self.right);
+                self.left.run();
+                self.right.run();
+
+                let motors = [&self.left.motor, &self.right.motor];
+                self.odometry.update(
+                    motors.map(|x| x.get_act_position_in_rad()),
+                    motors.map(|x| x.get_act_velocity_in_rpm()),
+                    &self.config,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rotate_to_should_turn_in_place_and_hold_heading() {
+        let mut base = Base::new();
+        base.run_cycles(1);
+        let cmd = HeadingCommand::RotateTo {
+            heading: PI / 2.0,
+            vel_max: 2.0,
+        };
+        base.heading
+            .start(cmd, &base.odometry.odometry(), &base.config);
+
+        base.run_cycles(1);
+        assert_eq!(base.left.control_mode(), ControlMode::Heading);
+        assert_eq!(base.right.control_mode(), ControlMode::Heading);
+
+        base.run_cycles(1000);
+        let data = base.odometry.odometry();
+        assert!((data.pose.theta - PI / 2.0).abs() < 1e-2);
+        assert!(data.pose.x.abs() < 1e-3 && data.pose.y.abs() < 1e-3);
+        assert!(base.heading.heading_error().unwrap().abs() < 1e-2);
+    }
+
+    #[test]
+    fn test_hold_should_correct_heading_and_stop_by_halt() {
+        let mut base = Base::new();
+        base.run_cycles(1);
+        base.odometry.reset_pose(Pose {
+            theta: 0.2,
+            ..Default::default()
+        });
+        let cmd = HeadingCommand::Hold {
+            linear: 0.2,
+            heading: Some(0.0),
+        };
+        base.heading
+            .start(cmd, &base.odometry.odometry(), &base.config);
+
+        base.run_cycles(400);
+        let data = base.odometry.odometry();
+        assert!(data.pose.theta.abs() < 1e-2);
+        assert!((data.linear - 0.2).abs() < 1e-3);
+
+        // `Halt` from host takes over the axes
+        base.left.push_cmd(MotorCommand::Halt).unwrap();
+        base.run_cycles(100);
+        assert!(!base.heading.is_active());
+        assert_eq!(base.left.control_mode(), ControlMode::StandStill);
+        assert_eq!(base.right.control_mode(), ControlMode::StandStill);
+        assert_eq!(base.odometry.odometry().linear, 0.0);
+    }
+}
@@ -0,0 +1,262 @@
+use num_traits::Float;
+use protocol::{ControlMode, ImuConfig, ImuHealth, MotorProcessData, Orientation, Quaternion};
+
+// The DMP runs at 200 Hz, and the output rate is 1 kHz / (1 + divider) with the digital
+// low-pass filter of MPU6050
+pub const MAX_DMP_RATE_HZ: u16 = 200;
+pub const MIN_DMP_RATE_HZ: u16 = 4;
+// The sensors are sampled at 1 kHz with the digital low-pass filter, and the divider is
+// 8 bits
+pub const MAX_SAMPLE_RATE_HZ: u16 = 1000;
+pub const MIN_SAMPLE_RATE_HZ: u16 = 4;
+
+// The velocity estimated from encoder is not exactly 0 after the wheel stops, unit: axis
+// unit/s
+const REST_VEL_LIMIT: f32 = 0.1;
+
+// Number of failed reads in a row before MPU6050 is initialized again
+const MAX_READ_ERRORS: u8 = 5;
+// Backoff of the initialization when MPU6050 doesn't respond, unit: ms
+const MIN_RETRY_DELAY_MS: u32 = 100;
+const MAX_RETRY_DELAY_MS: u32 = 5000;
+
+pub fn is_valid_dmp_rate(rate_hz: u16) -> bool {
+    (MIN_DMP_RATE_HZ..=MAX_DMP_RATE_HZ).contains(&rate_hz)
+}
+
+pub fn is_valid_imu_config(config: &ImuConfig) -> bool {
+    is_valid_dmp_rate(config.dmp_rate_hz)
+        && (MIN_SAMPLE_RATE_HZ..=MAX_SAMPLE_RATE_HZ).contains(&config.sample_rate_hz)
+}
+
+// Sample rate divider of MPU6050, it samples at the faster of the publish rate and the
+// DMP output rate. The rate is clamped to the valid range
+pub fn sample_rate_divider(config: &ImuConfig) -> u8 {
+    let rate_hz = config
+        .sample_rate_hz
+        .max(config.dmp_rate_hz)
+        .clamp(MIN_SAMPLE_RATE_HZ, MAX_SAMPLE_RATE_HZ);
+    (1000 / rate_hz - 1) as u8
+}
+
+// The DMP doesn't work when MPU6050 samples faster than it, the orientation isn't read
+pub fn is_dmp_enabled(config: &ImuConfig) -> bool {
+    config.sample_rate_hz.max(config.dmp_rate_hz) <= MAX_DMP_RATE_HZ
+}
+
+// The board needs to stay still during the calibration of MPU6050, the axis is at rest
+// when it is not commanded to move and the wheel doesn't turn
+pub fn is_at_rest(data: &MotorProcessData) -> bool {
+    let is_driven = matches!(
+        data.control_mode_display,
+        ControlMode::Heading | ControlMode::Balance | ControlMode::Pid
+    );
+    !is_driven && data.intp_vel == 0.0 && data.actual_vel.abs() < REST_VEL_LIMIT
+}
+
+// Health of MPU6050 from the results of initialization and reads. The new health is
+// returned when it is changed, so it is reported once
+pub struct ImuMonitor {
+    health: ImuHealth,
+    read_errors: u8,
+    retry_delay_ms: u32,
+}
+
+impl ImuMonitor {
+    pub fn new() -> Self {
+        Self {
+            health: ImuHealth::Ok,
+            read_errors: 0,
+            retry_delay_ms: MIN_RETRY_DELAY_MS,
+        }
+    }
+
+    pub fn health(&self) -> ImuHealth {
+        self.health
+    }
+
+    // Delay before the next initialization, it is doubled after every failed one
+    pub fn retry_delay_ms(&self) -> u32 {
+        self.retry_delay_ms
+    }
+
+    // The I2C bus needs to be recovered and MPU6050 is initialized again when the reads
+    // keep failing
+    pub fn needs_init(&self) -> bool {
+        self.read_errors >= MAX_READ_ERRORS
+    }
+
+    // The health is changed after the data is read
+    pub fn init_done(&mut self) {
+        self.read_errors = 0;
+    }
+
+    pub fn init_failed(&mut self) -> Option<ImuHealth> {
+        self.read_errors = 0;
+        self.retry_delay_ms = if self.health == ImuHealth::Absent {
+            (self.retry_delay_ms * 2).min(MAX_RETRY_DELAY_MS)
+        } else {
+            MIN_RETRY_DELAY_MS
+        };
+        self.set_health(ImuHealth::Absent)
+    }
+
+    pub fn read_done(&mut self) -> Option<ImuHealth> {
+        self.read_errors = 0;
+        self.retry_delay_ms = MIN_RETRY_DELAY_MS;
+        self.set_health(ImuHealth::Ok)
+    }
+
+    pub fn read_failed(&mut self) -> Option<ImuHealth> {
+        self.read_errors = self.read_errors.saturating_add(1);
+        self.set_health(ImuHealth::Error)
+    }
+
+    fn set_health(&mut self, health: ImuHealth) -> Option<ImuHealth> {
+        if health == self.health {
+            return None;
+        }
+        self.health = health;
+        Some(health)
+    }
+}
+
+impl Default for ImuMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Roll, pitch and yaw of the quaternion, it is normalized first. `None` is returned when
+// the quaternion is not valid, ex: the packet is corrupted
+pub fn orientation_from_quaternion(q: Quaternion) -> Option<Orientation> {
+    let norm = Float::sqrt(q.w * q.w + q.x * q.x + q.y * q.y + q.z * q.z);
+    if !norm.is_finite() || norm < f32::EPSILON {
+        return None;
+    }
+
+    let Quaternion { w, x, y, z } = q;
+    let (w, x, y, z) = (w / norm, x / norm, y / norm, z / norm);
+    // The pitch is clamped at +-90 degree (gimbal lock)
+    let sin_pitch = (2.0 * (w * y - z * x)).clamp(-1.0, 1.0);
+    Some(Orientation {
+        quaternion: Quaternion { w, x, y, z },
+        roll: Float::atan2(2.0 * (w * x + y * z), 1.0 - 2.0 * (x * x + y * y)),
+        pitch: Float::asin(sin_pitch),
+        yaw: Float::atan2(2.0 * (w * z + x * y), 1.0 - 2.0 * (y * y + z * z)),
+    })
+}
+
+// Quaternion of the roll, pitch and yaw, it is used by the simulated MPU6050
+pub fn quaternion_from_euler(roll: f32, pitch: f32, yaw: f32) -> Quaternion {
+    let (sr, cr) = Float::sin_cos(roll / 2.0);
+    let (sp, cp) = Float::sin_cos(pitch / 2.0);
+    let (sy, cy) = Float::sin_cos(yaw / 2.0);
+    Quaternion {
+        w: cr * cp * cy + sr * sp * sy,
+        x: sr * cp * cy - cr * sp * sy,
+        y: cr * sp * cy + sr * cp * sy,
+        z: cr * cp * sy - sr * sp * cy,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::FRAC_PI_2;
+
+    #[test]
+    fn test_orientation_should_match_euler_angles() {
+        let q = quaternion_from_euler(0.1, -0.4, 2.5);
+        let data = orientation_from_quaternion(q).unwrap();
+        assert!((data.roll - 0.1).abs() < 1e-5);
+        assert!((data.pitch + 0.4).abs() < 1e-5);
+        assert!((data.yaw - 2.5).abs() < 1e-5);
+
+        // The quaternion of DMP is not exactly a unit quaternion
+        let scaled = Quaternion {
+            w: q.w * 2.0,
+            x: q.x * 2.0,
+            y: q.y * 2.0,
+            z: q.z * 2.0,
+        };
+        let data = orientation_from_quaternion(scaled).unwrap();
+        assert!((data.quaternion.w - q.w).abs() < 1e-6);
+        assert!((data.pitch + 0.4).abs() < 1e-5);
+
+        // Gimbal lock
+        let data = orientation_from_quaternion(quaternion_from_euler(0.0, FRAC_PI_2, 0.0));
+        assert!((data.unwrap().pitch - FRAC_PI_2).abs() < 1e-3);
+        assert_eq!(orientation_from_quaternion(Quaternion::default()), None);
+    }
+
+    #[test]
+    fn test_axis_should_be_at_rest_when_wheel_stops() {
+        let mut data = MotorProcessData {
+            control_mode_display: ControlMode::StandStill,
+            actual_vel: 0.03,
+            ..Default::default()
+        };
+        assert!(is_at_rest(&data));
+
+        data.actual_vel = 2.0;
+        assert!(!is_at_rest(&data));
+
+        // The balance controller keeps the wheels moving around 0
+        data.actual_vel = 0.0;
+        data.control_mode_display = ControlMode::Balance;
+        assert!(!is_at_rest(&data));
+    }
+
+    #[test]
+    fn test_imu_monitor_should_back_off_and_report_health_once() {
+        let mut monitor = ImuMonitor::new();
+        assert_eq!(monitor.init_failed(), Some(ImuHealth::Absent));
+        assert_eq!(monitor.retry_delay_ms(), MIN_RETRY_DELAY_MS);
+        assert_eq!(monitor.init_failed(), None);
+        assert_eq!(monitor.retry_delay_ms(), MIN_RETRY_DELAY_MS * 2);
+        for _ in 0..10 {
+            monitor.init_failed();
+        }
+        assert_eq!(monitor.retry_delay_ms(), MAX_RETRY_DELAY_MS);
+
+        assert_eq!(monitor.read_done(), Some(ImuHealth::Ok));
+        assert_eq!(monitor.retry_delay_ms(), MIN_RETRY_DELAY_MS);
+
+        // A glitch is reported, and MPU6050 is initialized again when it persists
+        assert_eq!(monitor.read_failed(), Some(ImuHealth::Error));
+        assert_eq!(monitor.read_done(), Some(ImuHealth::Ok));
+        for _ in 0..MAX_READ_ERRORS - 1 {
+            monitor.read_failed();
+        }
+        assert!(!monitor.needs_init());
+        assert_eq!(monitor.read_failed(), None);
+        assert!(monitor.needs_init());
+
+        monitor.init_done();
+        assert!(!monitor.needs_init());
+        assert_eq!(monitor.health(), ImuHealth::Error);
+    }
+
+    #[test]
+    fn test_sample_rate_divider_should_follow_faster_rate_and_be_clamped() {
+        let config = |dmp_rate_hz, sample_rate_hz| ImuConfig {
+            dmp_rate_hz,
+            sample_rate_hz,
+            ..Default::default()
+        };
+        assert_eq!(sample_rate_divider(&config(200, 20)), 4);
+        assert_eq!(sample_rate_divider(&config(100, 20)), 9);
+        assert_eq!(sample_rate_divider(&config(4, 4)), 249);
+        assert_eq!(sample_rate_divider(&config(0, 0)), 249);
+        assert!(!is_valid_dmp_rate(0) && is_valid_dmp_rate(50));
+
+        // Vibration is sampled faster than the DMP works
+        assert_eq!(sample_rate_divider(&config(100, 1000)), 0);
+        assert_eq!(sample_rate_divider(&config(100, 5000)), 0);
+        assert!(is_dmp_enabled(&config(100, 200)));
+        assert!(!is_dmp_enabled(&config(100, 500)));
+        assert!(is_valid_imu_config(&config(100, 1000)));
+        assert!(!is_valid_imu_config(&config(100, 2000)));
+    }
+}
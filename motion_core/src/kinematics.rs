@@ -0,0 +1,126 @@
+use protocol::{AxisConfig, BaseTwistCommand, DeviceConfig, MotorCommand, MotorId};
+
+use crate::rad_s_to_rpm;
+
+// A NaN or infinite velocity is passed through the scaling to the wheels, so the twist is
+// checked before it is converted
+pub fn is_valid_twist(twist: &BaseTwistCommand) -> bool {
+    twist.linear.is_finite() && twist.angular.is_finite()
+}
+
+// Convert the body velocity of the two-wheel base to velocity commands of the wheels. If
+// a wheel exceeds the velocity limit, both wheels are scaled by the same factor, so the
+// curvature of the path is kept
+pub fn twist_to_wheel_cmds(
+    twist: &BaseTwistCommand,
+    config: &DeviceConfig,
+) -> [(MotorId, MotorCommand); 2] {
+    let [left, right] = twist_to_wheel_vels(twist, config);
+    [
+        (MotorId::Left, MotorCommand::VelocityCommand(left)),
+        (MotorId::Right, MotorCommand::VelocityCommand(right)),
+    ]
+}
+
+// Velocities (axis unit/s) of the left and right wheels, it is used by the velocity commands
+// and the heading controller
+pub fn twist_to_wheel_vels(twist: &BaseTwistCommand, config: &DeviceConfig) -> [f32; 2] {
+    let half_track = config.base.track_width / 2.0;
+    let output_vel = |vel: f32| vel / config.base.wheel_radius;
+    let left_vel = output_vel(twist.linear - twist.angular * half_track);
+    let right_vel = output_vel(twist.linear + twist.angular * half_track);
+
+    // `vel_limit` is motor-side rpm, so the gear ratio of each wheel is applied
+    let max_motor_rpm = rad_s_to_rpm(
+        (left_vel * config.left.mechanical.gear_ratio)
+            .abs()
+            .max((right_vel * config.right.mechanical.gear_ratio).abs()),
+    );
+    let scale = if max_motor_rpm > config.vel_limit {
+        config.vel_limit / max_motor_rpm
+    } else {
+        1.0
+    };
+
+    // `invert` is applied by the axis, so the mounting of the wheels is not handled here
+    let axis_vel = |vel: f32, axis: &AxisConfig| vel * scale * axis.mechanical.linear_scale;
+    [
+        axis_vel(left_vel, &config.left),
+        axis_vel(right_vel, &config.right),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::default_config;
+    use crate::rpm_to_rad_s;
+
+    fn wheel_vels(cmds: &[(MotorId, MotorCommand); 2]) -> [f32; 2] {
+        cmds.map(|(_, cmd)| match cmd {
+            MotorCommand::VelocityCommand(x) => x,
+            _ => panic!("unexpected command: {cmd:?}"),
+        })
+    }
+
+    #[test]
+    fn test_twist_should_convert_to_wheel_velocities() {
+        let mut config = default_config();
+        config.base.wheel_radius = 0.05;
+        config.base.track_width = 0.2;
+        config.right.mechanical.linear_scale = config.base.wheel_radius;
+
+        // Left wheel: 0.1 m/s (2 rad/s), right wheel: 0.3 m/s
+        let twist = BaseTwistCommand {
+            linear: 0.2,
+            angular: 1.0,
+        };
+        let [left, right] = wheel_vels(&twist_to_wheel_cmds(&twist, &config));
+        assert!((left - 2.0).abs() < 1e-5);
+        assert!((right - 0.3).abs() < 1e-5);
+
+        let spin = BaseTwistCommand {
+            linear: 0.0,
+            angular: -1.0,
+        };
+        let [left, right] = wheel_vels(&twist_to_wheel_cmds(&spin, &config));
+        assert!((left - 2.0).abs() < 1e-5);
+        assert!((right + 0.1).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_twist_should_scale_both_wheels_to_velocity_limit() {
+        let mut config = default_config();
+        config.left.mechanical.gear_ratio = 2.0;
+
+        // The left wheel reaches the limit first because of the gear ratio
+        let out_vel_limit = rpm_to_rad_s(config.vel_limit / 2.0);
+        let vel = out_vel_limit * config.base.wheel_radius;
+        let twist = BaseTwistCommand {
+            linear: 2.0 * vel,
+            angular: 0.0,
+        };
+        let [left, right] = wheel_vels(&twist_to_wheel_cmds(&twist, &config));
+        assert!((left - out_vel_limit).abs() < 1e-3);
+        assert!((right - out_vel_limit).abs() < 1e-3);
+
+        let turn = BaseTwistCommand {
+            linear: 2.0 * vel,
+            angular: 2.0 * vel / config.base.track_width,
+        };
+        let [left, right] = wheel_vels(&twist_to_wheel_cmds(&turn, &config));
+        assert!((right - rpm_to_rad_s(config.vel_limit)).abs() < 1e-3);
+        assert!((left - rpm_to_rad_s(config.vel_limit) / 3.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_twist_should_be_finite() {
+        assert!(is_valid_twist(&BaseTwistCommand {
+            linear: 1.0,
+            angular: -2.0,
+        }));
+        for (linear, angular) in [(f32::NAN, 0.0), (0.0, f32::INFINITY)] {
+            assert!(!is_valid_twist(&BaseTwistCommand { linear, angular }));
+        }
+    }
+}
@@ -0,0 +1,27 @@
+#![cfg_attr(not(test), no_std)]
+
+pub mod balance;
+pub mod config;
+pub mod encoder;
+pub mod hal;
+pub mod heading;
+pub mod imu;
+pub mod kinematics;
+pub mod motion;
+pub mod motor;
+pub mod odometry;
+pub mod pid;
+pub mod program;
+
+#[cfg(test)]
+mod mock;
+
+use core::f32;
+
+pub fn rpm_to_rad_s(val: f32) -> f32 {
+    val * 2.0 * f32::consts::PI / 60.0
+}
+
+pub fn rad_s_to_rpm(val: f32) -> f32 {
+    val * 60.0 / (2.0 * f32::consts::PI)
+}
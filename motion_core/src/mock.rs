@@ -0,0 +1,191 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use protocol::{DriveProtectionConfig, FaultReason, VelocityEstimator};
+
+use crate::hal::{BlockingDelay, DigitalOutput, EdgeTiming, EncoderInput, MotorDriver, PwmOutput};
+use crate::pid::Pid;
+use crate::rpm_to_rad_s;
+
+// Mock hardware used in tests, the state is shared between clones, so tests can keep a
+// clone to drive or inspect the hardware after it is moved into the motor
+
+#[derive(Clone)]
+pub struct MockEncoderInput {
+    count: Rc<Cell<u16>>,
+    edge_timing: Rc<Cell<Option<EdgeTiming>>>,
+}
+
+impl MockEncoderInput {
+    pub fn new() -> Self {
+        Self {
+            count: Rc::new(Cell::new(0)),
+            edge_timing: Rc::new(Cell::new(None)),
+        }
+    }
+
+    pub fn add(&self, diff: i32) {
+        self.count
+            .set(self.count.get().wrapping_add(diff as i16 as u16));
+    }
+
+    pub fn set_edge_timing(&self, edge_timing: EdgeTiming) {
+        self.edge_timing.set(Some(edge_timing));
+    }
+}
+
+impl EncoderInput for MockEncoderInput {
+    fn count(&mut self) -> u16 {
+        self.count.get()
+    }
+
+    fn edge_timing(&mut self) -> Option<EdgeTiming> {
+        self.edge_timing.get()
+    }
+}
+
+#[derive(Clone)]
+pub struct MockPwm {
+    duty: Rc<Cell<u8>>,
+}
+
+impl MockPwm {
+    pub fn new() -> Self {
+        Self {
+            duty: Rc::new(Cell::new(0)),
+        }
+    }
+
+    pub fn duty(&self) -> u8 {
+        self.duty.get()
+    }
+}
+
+impl PwmOutput for MockPwm {
+    fn set_duty_cycle_percent(&mut self, percent: u8) {
+        self.duty.set(percent);
+    }
+}
+
+#[derive(Clone)]
+pub struct MockPin {
+    high: Rc<Cell<bool>>,
+    falling_edges: Rc<Cell<u32>>,
+}
+
+impl MockPin {
+    pub fn new() -> Self {
+        Self {
+            high: Rc::new(Cell::new(true)),
+            falling_edges: Rc::new(Cell::new(0)),
+        }
+    }
+
+    pub fn is_high(&self) -> bool {
+        self.high.get()
+    }
+
+    pub fn falling_edges(&self) -> u32 {
+        self.falling_edges.get()
+    }
+}
+
+impl DigitalOutput for MockPin {
+    fn set_high(&mut self) {
+        self.high.set(true);
+    }
+
+    fn set_low(&mut self) {
+        if self.high.get() {
+            self.falling_edges.set(self.falling_edges.get() + 1);
+        }
+        self.high.set(false);
+    }
+}
+
+pub struct MockDelay;
+
+impl BlockingDelay for MockDelay {
+    fn delay_us(&mut self, _us: u32) {}
+}
+
+// Ideal motor, the actual velocity follows the target velocity immediately unless the
+// motor is jammed
+pub struct MockMotor {
+    pub jammed: bool,
+    pub drive_fault: Option<FaultReason>,
+    pid: Pid,
+    period_s: f32,
+    target_velocity_rpm: f32,
+    act_velocity_rpm: f32,
+    act_position_rad: f32,
+}
+
+impl MockMotor {
+    pub fn new(period_s: f32) -> Self {
+        Self {
+            jammed: false,
+            drive_fault: None,
+            pid: Pid::new(0.0, 0.0, 0.0, 1.0),
+            period_s,
+            target_velocity_rpm: 0.0,
+            act_velocity_rpm: 0.0,
+            act_position_rad: 0.0,
+        }
+    }
+}
+
+impl MotorDriver for MockMotor {
+    fn set_target_velocity(&mut self, target_velocity_rpm: f32) {
+        self.target_velocity_rpm = target_velocity_rpm;
+        self.pid.set_target_velocity(target_velocity_rpm);
+    }
+
+    fn set_velocity_estimator(&mut self, _estimator: VelocityEstimator) {}
+
+    fn set_counts_per_rev(&mut self, _counts_per_rev: u32) {}
+
+    fn set_drive_protection(&mut self, _protection: DriveProtectionConfig, _vel_limit_rpm: f32) {}
+
+    fn get_period_s(&self) -> f32 {
+        self.period_s
+    }
+
+    fn run_pid_velocity_control(&mut self) {
+        self.act_velocity_rpm = if self.jammed {
+            0.0
+        } else {
+            self.target_velocity_rpm
+        };
+        self.act_position_rad += rpm_to_rad_s(self.act_velocity_rpm) * self.period_s;
+        self.pid.run(self.act_velocity_rpm, self.period_s);
+    }
+
+    fn drive_fault(&self) -> Option<FaultReason> {
+        self.drive_fault
+    }
+
+    fn clear_drive_fault(&mut self) {
+        self.drive_fault = None;
+    }
+
+    fn get_act_position_in_rad(&self) -> f32 {
+        self.act_position_rad
+    }
+
+    fn get_act_velocity_in_rpm(&self) -> f32 {
+        self.act_velocity_rpm
+    }
+
+    fn get_act_acceleration_in_rad_s2(&self) -> f32 {
+        0.0
+    }
+
+    fn pid(&self) -> &Pid {
+        &self.pid
+    }
+
+    fn pid_mut(&mut self) -> &mut Pid {
+        &mut self.pid
+    }
+}
@@ -0,0 +1,998 @@
+#[cfg(feature = "debug-motion")]
+use defmt::debug;
+
+use heapless::Deque;
+use protocol::{
+    AxisConfig, ControlMode, DeviceEventKind, FaultReason, FollowingErrorConfig, MotorCommand,
+    MotorProcessData, PositionCommand, SequencedCommand,
+};
+
+use crate::config::{motor_counts_per_rev, motor_per_axis_unit};
+use crate::hal::MotorDriver;
+use crate::pid::{is_valid_autotune_cycles, AutoTuneResult};
+use crate::{rad_s_to_rpm, rpm_to_rad_s};
+use s_curve::*;
+
+// Events that are not taken yet, the oldest one is dropped when it is full. It is larger
+// than the command queue in firmware, so every command dropped by halt or fault is
+// reported
+const EVENT_QUEUE_SIZE: usize = 40;
+
+// The queue is paused until the wait is over
+#[derive(PartialEq, Clone, Copy)]
+enum Wait {
+    // Remaining time of `Dwell`
+    Dwell(f32),
+    OtherAxis,
+}
+
+#[derive(PartialEq)]
+enum HaltProcessState {
+    Idle,
+    Ignite,
+    Running,
+    Finished,
+}
+
+pub struct Motion<D: MotorDriver, const MOTION_QUEUE_SIZE: usize> {
+    pub motor: D,
+    pub s_curve_intper: SCurveInterpolator,
+    halt_process_state: HaltProcessState,
+    cmd_queue: Deque<SequencedCommand, MOTION_QUEUE_SIZE>,
+    active_cmd_id: Option<u32>,
+    wait: Option<Wait>,
+    // Number of commands given to `push_cmd`, accepted or not, it is reported with the free
+    // entries of the queue for the flow control in host
+    received_cmds: u32,
+    control_mode: ControlMode,
+    // Motor-side rad per axis unit (and rad/s per axis unit/s), commands are converted to
+    // the motor side when they are pushed, and process data is converted back to axis units
+    motor_per_unit: f32,
+    following_error: FollowingErrorConfig,
+    // Time that the following errors stay outside the windows
+    pos_err_time_s: f32,
+    vel_err_time_s: f32,
+    fault: Option<FaultReason>,
+    events: Deque<DeviceEventKind, EVENT_QUEUE_SIZE>,
+}
+
+impl<D: MotorDriver, const MOTION_QUEUE_SIZE: usize> Motion<D, MOTION_QUEUE_SIZE> {
+    pub fn new(s_curve_intper: SCurveInterpolator, motor: D) -> Self {
+        Self {
+            motor,
+            s_curve_intper,
+            halt_process_state: HaltProcessState::Idle,
+            cmd_queue: Deque::new(),
+            active_cmd_id: None,
+            wait: None,
+            received_cmds: 0,
+            control_mode: ControlMode::Velocity,
+            motor_per_unit: 1.0,
+            following_error: FollowingErrorConfig::default(),
+            pos_err_time_s: 0.0,
+            vel_err_time_s: 0.0,
+            fault: None,
+            events: Deque::new(),
+        }
+    }
+
+    // Push command to the queue, the command is given back if the queue is full or the
+    // axis is in fault state. A `MotorCommand` is pushed with sequence id 0. The command
+    // rejected by the fault is reported as aborted, it is never started
+    pub fn push_cmd(&mut self, cmd: impl Into<SequencedCommand>) -> Result<(), MotorCommand> {
+        let SequencedCommand { id, cmd } = cmd.into();
+        self.received_cmds = self.received_cmds.wrapping_add(1);
+        if self.fault.is_some() {
+            return match cmd {
+                // The axis is already stopped by the fault
+                MotorCommand::Halt => {
+                    self.complete_immediately(id);
+                    Ok(())
+                }
+                MotorCommand::ResetFault => {
+                    self.reset_fault();
+                    self.complete_immediately(id);
+                    Ok(())
+                }
+                _ => {
+                    self.push_event(DeviceEventKind::MotionAborted(id));
+                    Err(cmd)
+                }
+            };
+        }
+
+        if cmd == MotorCommand::ResetFault {
+            self.complete_immediately(id);
+            return Ok(());
+        }
+
+        if cmd == MotorCommand::Halt {
+            self.clear_queue();
+        }
+
+        // cmd_queue is used as a cache to hold commands from host
+        let motor_cmd = SequencedCommand {
+            id,
+            cmd: self.to_motor_cmd(cmd),
+        };
+        self.cmd_queue.push_back(motor_cmd).map_err(|_| cmd)
+    }
+
+    // The commands that are already in the queue keep the units of the previous
+    // mechanical configuration
+    pub fn apply_config(
+        &mut self,
+        axis: &AxisConfig,
+        vel_limit: f32,
+        acc_limit: f32,
+        jerk_limit: f32,
+    ) {
+        self.motor
+            .pid_mut()
+            .set_gains(axis.pid.kp, axis.pid.ki, axis.pid.kd);
+        self.motor.set_velocity_estimator(axis.vel_estimator);
+        self.motor
+            .set_counts_per_rev(motor_counts_per_rev(&axis.mechanical));
+        self.motor_per_unit = motor_per_axis_unit(&axis.mechanical);
+        self.following_error = axis.following_error;
+        self.motor
+            .set_drive_protection(axis.drive_protection, vel_limit);
+        self.s_curve_intper
+            .set_constraint(rpm_to_rad_s(vel_limit), acc_limit, jerk_limit);
+    }
+
+    pub fn is_queue_full(&self) -> bool {
+        self.cmd_queue.is_full()
+    }
+
+    pub fn queue_free(&self) -> usize {
+        self.cmd_queue.capacity() - self.cmd_queue.len()
+    }
+
+    pub fn active_cmd_id(&self) -> Option<u32> {
+        self.active_cmd_id
+    }
+
+    pub fn is_waiting_for_other_axis(&self) -> bool {
+        self.wait == Some(Wait::OtherAxis)
+    }
+
+    // All the pushed commands are done, completed or aborted
+    pub fn is_done(&self) -> bool {
+        self.cmd_queue.is_empty() && self.active_cmd_id.is_none()
+    }
+
+    pub fn fault(&self) -> Option<FaultReason> {
+        self.fault
+    }
+
+    pub fn control_mode(&self) -> ControlMode {
+        self.control_mode
+    }
+
+    // Velocity set point (axis unit/s) from a controller of the base, ex: heading or balance,
+    // it is set in every control cycle and the axis enters the given mode. It is refused
+    // when the axis is in fault, halting, or a command from the queue is pending or still
+    // moving the axis
+    pub fn set_base_velocity(&mut self, mode: ControlMode, vel: f32) -> bool {
+        let can_set = match self.control_mode {
+            ControlMode::Heading
+            | ControlMode::Balance
+            | ControlMode::Velocity
+            | ControlMode::StandStill => true,
+            ControlMode::Position | ControlMode::Pid => self.ready(),
+            ControlMode::Fault => false,
+        };
+        if !can_set
+            || !self.cmd_queue.is_empty()
+            || self.wait.is_some()
+            || self.halt_process_state != HaltProcessState::Idle
+        {
+            return false;
+        }
+
+        // The running velocity command is replaced by the controller of the base
+        if self.control_mode != mode {
+            if let Some(id) = self.active_cmd_id.take() {
+                self.push_event(DeviceEventKind::MotionAborted(id));
+            }
+            self.control_mode = mode;
+        }
+        self.motor
+            .set_target_velocity(rad_s_to_rpm(vel * self.motor_per_unit));
+        true
+    }
+
+    // Fault and motion events of the axis, they are taken by the task that reports them
+    // to the host
+    pub fn take_event(&mut self) -> Option<DeviceEventKind> {
+        self.events.pop_front()
+    }
+
+    pub fn get_motor_process_data(&self) -> MotorProcessData {
+        let s_curve_intp_data = self.s_curve_intper.get_intp_data();
+        let to_unit = |x: f32| x / self.motor_per_unit;
+        MotorProcessData {
+            control_mode_display: self.control_mode,
+            actual_pos: to_unit(self.motor.get_act_position_in_rad()),
+            actual_vel: to_unit(rpm_to_rad_s(self.motor.get_act_velocity_in_rpm())),
+            actual_acc: to_unit(self.motor.get_act_acceleration_in_rad_s2()),
+            intp_pos: to_unit(s_curve_intp_data.pos),
+            intp_vel: to_unit(s_curve_intp_data.vel),
+            intp_acc: to_unit(s_curve_intp_data.acc),
+            intp_jerk: to_unit(s_curve_intp_data.jerk),
+            fault: self.fault,
+            active_cmd_id: self.active_cmd_id,
+            queue_free: self.queue_free().min(u8::MAX as usize) as u8,
+            received_cmds: self.received_cmds,
+            // It is filled by the program executor
+            program: None,
+            // They are filled by the task that runs the control cycle
+            timestamp_us: 0,
+            cycle: 0,
+        }
+    }
+
+    pub fn run(&mut self) {
+        if let Some(Wait::Dwell(time_left_s)) = self.wait.as_mut() {
+            *time_left_s -= self.motor.get_period_s();
+            if *time_left_s <= 0.0 {
+                self.finish_wait();
+            }
+        }
+
+        // Process that reads command from queue and set command if it is ok
+        if let Some(&SequencedCommand { id, cmd }) = self.cmd_queue.front() {
+            let mut ready_to_set = match cmd {
+                MotorCommand::VelocityCommand(_)
+                | MotorCommand::Halt
+                | MotorCommand::ResetFault => true,
+                MotorCommand::PositionCommand(_)
+                | MotorCommand::AutoTuneCommand(_)
+                | MotorCommand::Dwell(_)
+                | MotorCommand::WaitForOtherAxis
+                | MotorCommand::Marker(_) => self.ready(),
+            };
+
+            if self.halt_process_state != HaltProcessState::Idle {
+                // Halt process is running, do not set command
+                ready_to_set = false;
+            }
+
+            // Only `Halt` can be set when the queue is paused
+            if self.wait.is_some() && cmd != MotorCommand::Halt {
+                ready_to_set = false;
+            }
+
+            if ready_to_set {
+                self.start_cmd(id);
+                match cmd {
+                    MotorCommand::Halt => {
+                        self.wait = None;
+                        self.halt_process_state = HaltProcessState::Ignite;
+                        match self.control_mode {
+                            ControlMode::Position => self.s_curve_intper.stop(),
+                            ControlMode::Velocity
+                            | ControlMode::Heading
+                            | ControlMode::Balance => self.motor.set_target_velocity(0.0),
+                            ControlMode::Pid => self.motor.pid_mut().cancel_autotune(),
+                            _ => (),
+                        }
+                    }
+                    MotorCommand::PositionCommand(x) => {
+                        self.control_mode = ControlMode::Position;
+                        self.set_pos_command(x);
+                    }
+                    MotorCommand::VelocityCommand(x) => {
+                        self.control_mode = ControlMode::Velocity;
+                        self.motor.set_target_velocity(x);
+                    }
+                    MotorCommand::AutoTuneCommand(x) => {
+                        self.control_mode = ControlMode::Pid;
+                        if !x.start {
+                            self.motor.set_target_velocity(0.0);
+                            self.motor.pid_mut().cancel_autotune();
+                        } else {
+                            self.motor.set_target_velocity(x.set_point);
+                            // The cycles are checked by `is_valid_cmd` before the command
+                            // is queued
+                            let _ = self.motor.pid_mut().start_autotune(
+                                x.output_limit,
+                                -x.output_limit,
+                                x.hysteresis,
+                                x.cycles as usize,
+                                x.rule,
+                            );
+                        }
+                    }
+                    // It is handled in `push_cmd` and never queued
+                    MotorCommand::ResetFault => (),
+                    MotorCommand::Dwell(x) => self.wait = Some(Wait::Dwell(x as f32 / 1000.0)),
+                    MotorCommand::WaitForOtherAxis => self.wait = Some(Wait::OtherAxis),
+                    MotorCommand::Marker(x) => self.push_event(DeviceEventKind::MarkerReached(x)),
+                }
+
+                // Command is set, pop it from queue
+                self.cmd_queue.pop_front();
+            }
+        }
+
+        // Process halt if controller gets halt request
+        self.process_halt();
+
+        // Interpolate position command if current operation if IntpPos and update
+        // target velocity in pid velocity control loop
+        if self.control_mode == ControlMode::Position
+            && self.s_curve_intper.get_intp_status() != InterpolationStatus::Done
+        {
+            self.s_curve_intper.interpolate();
+
+            let intp_vel = rad_s_to_rpm(self.s_curve_intper.get_intp_data().vel);
+            self.motor.set_target_velocity(intp_vel);
+
+            #[cfg(feature = "debug-motion")]
+            debug!("run, intp pos, {}", intp_vel);
+        }
+
+        // The pid velocity control loop will always be run since we need to drive
+        // the motor with velocity command.
+        // If current operation == `IntPos`, the target velocity will be set by position interpolation
+        // If current operation != `IntPos`, the target velocity will be set by `set_command` function
+        // Note: only `IntpVel` is handled, and the other operation modes are currently listed as `todo!()`
+        self.motor.run_pid_velocity_control();
+
+        // The driver has already cut its output, the axis follows it into fault state
+        if let Some(reason) = self.motor.drive_fault() {
+            if self.fault.is_none() {
+                self.enter_fault(reason);
+            }
+        }
+
+        self.check_following_error();
+
+        // The command is done when the axis is ready for the next one, it is checked
+        // after the velocity loop runs with the latest set point
+        if self.halt_process_state == HaltProcessState::Idle && self.wait.is_none() && self.ready()
+        {
+            if let Some(id) = self.active_cmd_id.take() {
+                self.push_event(DeviceEventKind::MotionComplete(id));
+            }
+        }
+    }
+
+    // Result of the last finished auto-tuning run, the gains are kept if the run is rejected
+    pub fn take_autotune_result(&mut self) -> Option<AutoTuneResult> {
+        self.motor.pid_mut().take_autotune_result()
+    }
+
+    // The velocity command is converted to motor rpm for the velocity loop, and the
+    // velocities of position command to motor rad/s for the interpolation. Auto-tune works
+    // on the velocity loop of motor, so its command is not converted
+    fn to_motor_cmd(&self, cmd: MotorCommand) -> MotorCommand {
+        let scale = self.motor_per_unit;
+        match cmd {
+            MotorCommand::VelocityCommand(x) => {
+                MotorCommand::VelocityCommand(rad_s_to_rpm(x * scale))
+            }
+            MotorCommand::PositionCommand(x) => MotorCommand::PositionCommand(PositionCommand {
+                displacement: x.displacement * scale,
+                vel_max: x.vel_max * scale.abs(),
+                vel_end: x.vel_end * scale,
+            }),
+            MotorCommand::Halt
+            | MotorCommand::AutoTuneCommand(_)
+            | MotorCommand::ResetFault
+            | MotorCommand::Dwell(_)
+            | MotorCommand::WaitForOtherAxis
+            | MotorCommand::Marker(_) => cmd,
+        }
+    }
+
+    // Position is only checked in position mode, the velocity set point is decided by
+    // interpolation or velocity command. Auto-tune is not checked because the relay
+    // drives the velocity around the set point on purpose
+    fn check_following_error(&mut self) {
+        let (check_pos, check_vel) = match self.control_mode {
+            ControlMode::Position => (true, true),
+            ControlMode::Velocity | ControlMode::Heading | ControlMode::Balance => (false, true),
+            _ => (false, false),
+        };
+
+        let period_s = self.motor.get_period_s();
+        let scale = self.motor_per_unit.abs();
+        let config = self.following_error;
+        let exceeded_time = |time_s: f32, enabled: bool, err: f32, window: f32| {
+            if enabled && window > 0.0 && err > window {
+                time_s + period_s
+            } else {
+                0.0
+            }
+        };
+
+        let intp_pos = self.s_curve_intper.get_intp_data().pos;
+        let pos_err = (intp_pos - self.motor.get_act_position_in_rad()).abs() / scale;
+        self.pos_err_time_s =
+            exceeded_time(self.pos_err_time_s, check_pos, pos_err, config.pos_window);
+
+        let vel_err = rpm_to_rad_s(self.motor.pid().get_error()).abs() / scale;
+        self.vel_err_time_s =
+            exceeded_time(self.vel_err_time_s, check_vel, vel_err, config.vel_window);
+
+        if self.pos_err_time_s * 1000.0 > config.pos_filter_ms as f32 {
+            self.enter_fault(FaultReason::PositionFollowingError);
+        } else if self.vel_err_time_s * 1000.0 > config.vel_filter_ms as f32 {
+            self.enter_fault(FaultReason::VelocityFollowingError);
+        }
+    }
+
+    // Quick stop: drop pending commands, stop interpolation and auto-tune without
+    // deceleration and brake the motor
+    fn enter_fault(&mut self, reason: FaultReason) {
+        self.fault = Some(reason);
+        self.control_mode = ControlMode::Fault;
+        self.halt_process_state = HaltProcessState::Idle;
+        self.wait = None;
+        if let Some(id) = self.active_cmd_id.take() {
+            self.push_event(DeviceEventKind::MotionAborted(id));
+        }
+        self.clear_queue();
+        self.s_curve_intper.abort();
+        self.motor.pid_mut().cancel_autotune();
+        self.motor.set_target_velocity(0.0);
+        self.pos_err_time_s = 0.0;
+        self.vel_err_time_s = 0.0;
+        self.push_event(DeviceEventKind::FaultRaised(reason));
+    }
+
+    fn reset_fault(&mut self) {
+        self.motor.clear_drive_fault();
+        self.fault = None;
+        self.control_mode = ControlMode::StandStill;
+        self.push_event(DeviceEventKind::FaultCleared);
+    }
+
+    // The command that is still running is replaced by the new one
+    fn start_cmd(&mut self, id: u32) {
+        if let Some(prev_id) = self.active_cmd_id.replace(id) {
+            self.push_event(DeviceEventKind::MotionAborted(prev_id));
+        }
+        self.push_event(DeviceEventKind::MotionStarted(id));
+    }
+
+    // The waiting command is done before the next command is taken from the queue, so it
+    // is not reported as aborted
+    fn finish_wait(&mut self) {
+        self.wait = None;
+        if let Some(id) = self.active_cmd_id.take() {
+            self.push_event(DeviceEventKind::MotionComplete(id));
+        }
+    }
+
+    // `Halt` in fault state and `ResetFault` are done when they are pushed
+    fn complete_immediately(&mut self, id: u32) {
+        self.push_event(DeviceEventKind::MotionStarted(id));
+        self.push_event(DeviceEventKind::MotionComplete(id));
+    }
+
+    // The pending commands are dropped before they are started
+    fn clear_queue(&mut self) {
+        while let Some(cmd) = self.cmd_queue.pop_front() {
+            self.push_event(DeviceEventKind::MotionAborted(cmd.id));
+        }
+    }
+
+    fn push_event(&mut self, event: DeviceEventKind) {
+        if self.events.is_full() {
+            self.events.pop_front();
+        }
+        let _ = self.events.push_back(event);
+    }
+
+    fn process_halt(&mut self) {
+        match self.halt_process_state {
+            HaltProcessState::Ignite => self.halt_process_state = HaltProcessState::Running,
+            HaltProcessState::Running if self.ready() => {
+                self.halt_process_state = HaltProcessState::Finished;
+            }
+            HaltProcessState::Finished => {
+                // Standstill control mode will be set when halt process is finished
+                self.halt_process_state = HaltProcessState::Idle;
+                self.control_mode = ControlMode::StandStill;
+            }
+            _ => (),
+        }
+    }
+
+    fn set_pos_command(&mut self, cmd: PositionCommand) {
+        let vel_start = rpm_to_rad_s(self.motor.get_act_velocity_in_rpm());
+
+        let pos_offset =
+            self.motor.get_act_position_in_rad() - self.s_curve_intper.get_intp_data().pos;
+        self.s_curve_intper.set_target(
+            pos_offset,
+            cmd.displacement,
+            vel_start,
+            cmd.vel_end,
+            cmd.vel_max,
+        );
+
+        #[cfg(feature = "debug-motion")]
+        debug!(
+            "set_pos_command, {}, {}, {}, {}",
+            cmd.displacement,
+            self.motor.get_act_velocity_in_rpm(),
+            cmd.vel_end,
+            cmd.vel_max
+        );
+    }
+
+    fn ready(&self) -> bool {
+        let is_ready = match self.control_mode {
+            ControlMode::Position => {
+                #[cfg(feature = "debug-motion")]
+                debug!(
+                    "ready, pos, {}",
+                    self.s_curve_intper.get_intp_status() as u8
+                );
+
+                self.s_curve_intper.get_intp_status() == InterpolationStatus::Done
+            }
+            ControlMode::Velocity | ControlMode::Heading | ControlMode::Balance => {
+                #[cfg(feature = "debug-motion")]
+                debug!("ready, vel, {}", self.motor.pid().get_error());
+
+                self.motor.pid().get_error().abs() <= 60.0
+            }
+            ControlMode::StandStill => true,
+            ControlMode::Pid => !self.motor.pid().is_autotune_running(),
+            ControlMode::Fault => false,
+        };
+
+        is_ready
+    }
+}
+
+// The commands with values out of range are rejected before they are queued, so the host
+// gets the error instead of a command that does nothing
+pub fn is_valid_cmd(cmd: &MotorCommand) -> bool {
+    match cmd {
+        MotorCommand::AutoTuneCommand(x) => !x.start || is_valid_autotune_cycles(x.cycles as usize),
+        _ => true,
+    }
+}
+
+// Both axes that wait at `WaitForOtherAxis` are released in the same control cycle, it is
+// called before `Motion::run` of the axes
+pub fn sync_axes<D1: MotorDriver, D2: MotorDriver, const N1: usize, const N2: usize>(
+    left: &mut Motion<D1, N1>,
+    right: &mut Motion<D2, N2>,
+) {
+    if left.is_waiting_for_other_axis() && right.is_waiting_for_other_axis() {
+        left.finish_wait();
+        right.finish_wait();
+    }
+}
+
+// Both axes follow the velocities (axis unit) from a controller of the base in the same
+// control cycle. The base can't be steered by one wheel, so the other axis is halted when
+// one of them refuses the velocity, and false is returned
+pub fn set_base_velocities<D1: MotorDriver, D2: MotorDriver, const N1: usize, const N2: usize>(
+    left: &mut Motion<D1, N1>,
+    right: &mut Motion<D2, N2>,
+    mode: ControlMode,
+    vels: [f32; 2],
+) -> bool {
+    let is_left_set = left.set_base_velocity(mode, vels[0]);
+    let is_right_set = right.set_base_velocity(mode, vels[1]);
+    if is_left_set && is_right_set {
+        return true;
+    }
+
+    halt_base(left, right, mode);
+    false
+}
+
+// The controller of the base is stopped, the axes that still follow it are halted
+pub fn halt_base<D1: MotorDriver, D2: MotorDriver, const N1: usize, const N2: usize>(
+    left: &mut Motion<D1, N1>,
+    right: &mut Motion<D2, N2>,
+    mode: ControlMode,
+) {
+    if left.control_mode() == mode {
+        let _ = left.push_cmd(MotorCommand::Halt);
+    }
+    if right.control_mode() == mode {
+        let _ = right.push_cmd(MotorCommand::Halt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::default_config;
+    use crate::mock::MockMotor;
+    use protocol::MechanicalConfig;
+
+    const PERIOD_S: f32 = 0.005;
+
+    fn create_motion() -> Motion<MockMotor, 4> {
+        let s_curve_intper = SCurveInterpolator::new(rpm_to_rad_s(3000.0), 300.0, 3000.0, PERIOD_S);
+        Motion::new(s_curve_intper, MockMotor::new(PERIOD_S))
+    }
+
+    fn run_cycles(motion: &mut Motion<MockMotor, 4>, cycles: usize) {
+        for _ in 0..cycles {
+            motion.run();
+        }
+    }
+
+    fn pos_cmd(displacement: f32) -> MotorCommand {
+        MotorCommand::PositionCommand(PositionCommand {
+            displacement,
+            vel_max: 100.0,
+            vel_end: 0.0,
+        })
+    }
+
+    // The velocity is converted to motor rpm and back, so it is not exactly the commanded one
+    fn assert_actual_vel(motion: &Motion<MockMotor, 4>, vel: f32) {
+        let actual_vel = motion.get_motor_process_data().actual_vel;
+        assert!((actual_vel - vel).abs() < 1e-4, "{actual_vel}");
+    }
+
+    #[test]
+    fn test_run_should_enter_standstill_after_halt() {
+        let mut motion = create_motion();
+        motion
+            .push_cmd(MotorCommand::VelocityCommand(50.0))
+            .unwrap();
+        run_cycles(&mut motion, 10);
+        assert_actual_vel(&motion, 50.0);
+
+        motion.push_cmd(MotorCommand::Halt).unwrap();
+        run_cycles(&mut motion, 10);
+
+        let data = motion.get_motor_process_data();
+        assert_eq!(data.control_mode_display, ControlMode::StandStill);
+        assert_eq!(data.actual_vel, 0.0);
+    }
+
+    #[test]
+    fn test_run_should_start_next_position_command_after_previous_one_is_done() {
+        let mut motion = create_motion();
+        motion.push_cmd(pos_cmd(20.0)).unwrap();
+        motion.push_cmd(pos_cmd(-10.0)).unwrap();
+
+        // The second command is kept in queue until the first one is done
+        run_cycles(&mut motion, 10);
+        assert_eq!(motion.cmd_queue.len(), 1);
+
+        run_cycles(&mut motion, 2000);
+        let data = motion.get_motor_process_data();
+        assert!(motion.cmd_queue.is_empty());
+        assert_eq!(data.control_mode_display, ControlMode::Position);
+        assert!((data.intp_pos - 10.0).abs() < 0.2);
+        assert!((data.actual_pos - data.intp_pos).abs() < 1e-2);
+    }
+
+    #[test]
+    fn test_push_cmd_should_reject_command_when_queue_is_full_except_halt() {
+        let mut motion = create_motion();
+        for _ in 0..4 {
+            motion.push_cmd(pos_cmd(1.0)).unwrap();
+        }
+        assert!(motion.is_queue_full());
+        assert_eq!(motion.push_cmd(pos_cmd(2.0)), Err(pos_cmd(2.0)));
+
+        // Halt clears the pending commands, so it is always accepted
+        assert_eq!(motion.push_cmd(MotorCommand::Halt), Ok(()));
+        assert_eq!(motion.cmd_queue.len(), 1);
+    }
+
+    #[test]
+    fn test_process_data_should_report_queue_credits() {
+        let mut motion = create_motion();
+        motion.push_cmd(pos_cmd(1.0)).unwrap();
+        motion.push_cmd(pos_cmd(1.0)).unwrap();
+        let data = motion.get_motor_process_data();
+        assert_eq!((data.queue_free, data.received_cmds), (2, 2));
+
+        // The first command is taken from the queue
+        run_cycles(&mut motion, 1);
+        let data = motion.get_motor_process_data();
+        assert_eq!((data.queue_free, data.received_cmds), (3, 2));
+
+        // The rejected command is received as well, so it is not counted as in flight
+        for _ in 0..4 {
+            let _ = motion.push_cmd(pos_cmd(1.0));
+        }
+        let data = motion.get_motor_process_data();
+        assert_eq!((data.queue_free, data.received_cmds), (0, 6));
+    }
+
+    #[test]
+    fn test_mechanical_config_should_convert_commands_and_process_data() {
+        let mut motion = create_motion();
+
+        // Wheel with 0.05 m radius behind 10:1 gearbox, mounted in reverse
+        let mut axis = default_config().left;
+        axis.mechanical = MechanicalConfig {
+            gear_ratio: 10.0,
+            invert: true,
+            linear_scale: 0.05,
+            ..Default::default()
+        };
+        motion.apply_config(&axis, 3000.0, 300.0, 3000.0);
+
+        motion.push_cmd(MotorCommand::VelocityCommand(1.0)).unwrap();
+        run_cycles(&mut motion, 10);
+        // 1 m/s is 20 rad/s at the wheel and 200 rad/s at the motor
+        let motor_rpm = motion.motor.get_act_velocity_in_rpm();
+        assert!((motor_rpm + rad_s_to_rpm(200.0)).abs() < 1e-2);
+        assert_actual_vel(&motion, 1.0);
+
+        motion.push_cmd(MotorCommand::Halt).unwrap();
+        run_cycles(&mut motion, 10);
+
+        // Move 1 m, the motor turns 200 rad backward
+        let start_pos = motion.motor.get_act_position_in_rad();
+        motion.push_cmd(pos_cmd(1.0)).unwrap();
+        run_cycles(&mut motion, 2000);
+
+        let data = motion.get_motor_process_data();
+        let motor_pos = motion.motor.get_act_position_in_rad();
+        assert_eq!(data.control_mode_display, ControlMode::Position);
+        assert!((data.actual_pos - motor_pos / -200.0).abs() < 1e-4);
+        assert!((motor_pos - start_pos + 200.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_velocity_following_error_should_latch_fault_until_reset() {
+        let mut motion = create_motion();
+        let mut axis = default_config().left;
+        axis.following_error = FollowingErrorConfig {
+            vel_window: 10.0,
+            vel_filter_ms: 50,
+            ..Default::default()
+        };
+        motion.apply_config(&axis, 3000.0, 300.0, 3000.0);
+
+        motion.motor.jammed = true;
+        motion
+            .push_cmd(MotorCommand::VelocityCommand(50.0))
+            .unwrap();
+        run_cycles(&mut motion, 10);
+        assert_eq!(motion.fault(), None);
+
+        run_cycles(&mut motion, 2);
+        let data = motion.get_motor_process_data();
+        assert_eq!(data.control_mode_display, ControlMode::Fault);
+        assert_eq!(data.fault, Some(FaultReason::VelocityFollowingError));
+
+        // Motion commands are rejected and the motor is stopped until the fault is reset
+        motion.motor.jammed = false;
+        let cmd = MotorCommand::VelocityCommand(20.0);
+        assert_eq!(motion.push_cmd(cmd), Err(cmd));
+        assert_eq!(motion.push_cmd(MotorCommand::Halt), Ok(()));
+        run_cycles(&mut motion, 10);
+        assert_eq!(motion.get_motor_process_data().actual_vel, 0.0);
+        assert_eq!(motion.fault(), Some(FaultReason::VelocityFollowingError));
+
+        motion.push_cmd(MotorCommand::ResetFault).unwrap();
+        assert_eq!(motion.fault(), None);
+        assert_eq!(
+            motion.get_motor_process_data().control_mode_display,
+            ControlMode::StandStill
+        );
+
+        motion.push_cmd(cmd).unwrap();
+        run_cycles(&mut motion, 10);
+        assert_actual_vel(&motion, 20.0);
+    }
+
+    #[test]
+    fn test_position_following_error_should_abort_interpolation() {
+        let mut motion = create_motion();
+        let mut axis = default_config().left;
+        axis.following_error = FollowingErrorConfig {
+            pos_window: 1.0,
+            pos_filter_ms: 20,
+            ..Default::default()
+        };
+        motion.apply_config(&axis, 3000.0, 300.0, 3000.0);
+
+        motion.motor.jammed = true;
+        motion.push_cmd(pos_cmd(20.0)).unwrap();
+        motion.push_cmd(pos_cmd(10.0)).unwrap();
+        run_cycles(&mut motion, 500);
+
+        let data = motion.get_motor_process_data();
+        assert_eq!(data.fault, Some(FaultReason::PositionFollowingError));
+        assert_eq!(data.intp_vel, 0.0);
+        assert!(motion.cmd_queue.is_empty());
+        assert_eq!(
+            motion.s_curve_intper.get_intp_status(),
+            InterpolationStatus::Done
+        );
+    }
+
+    #[test]
+    fn test_drive_fault_should_stop_axis_and_be_cleared_by_reset() {
+        let mut motion = create_motion();
+        motion
+            .push_cmd(MotorCommand::VelocityCommand(50.0))
+            .unwrap();
+        run_cycles(&mut motion, 10);
+
+        motion.motor.drive_fault = Some(FaultReason::Stall);
+        run_cycles(&mut motion, 1);
+        let data = motion.get_motor_process_data();
+        assert_eq!(data.control_mode_display, ControlMode::Fault);
+        assert_eq!(data.fault, Some(FaultReason::Stall));
+
+        motion.push_cmd(MotorCommand::ResetFault).unwrap();
+        assert_eq!(motion.motor.drive_fault, None);
+        assert_eq!(motion.fault(), None);
+    }
+
+    fn seq_cmd(id: u32, cmd: MotorCommand) -> SequencedCommand {
+        SequencedCommand { id, cmd }
+    }
+
+    fn take_events(motion: &mut Motion<MockMotor, 4>) -> Vec<DeviceEventKind> {
+        core::iter::from_fn(|| motion.take_event()).collect()
+    }
+
+    #[test]
+    fn test_take_event_should_report_command_progress_by_sequence_id() {
+        let mut motion = create_motion();
+        motion.push_cmd(seq_cmd(1, pos_cmd(20.0))).unwrap();
+        motion.push_cmd(seq_cmd(2, pos_cmd(-10.0))).unwrap();
+        run_cycles(&mut motion, 10);
+        assert_eq!(motion.get_motor_process_data().active_cmd_id, Some(1));
+
+        run_cycles(&mut motion, 2000);
+        assert_eq!(motion.get_motor_process_data().active_cmd_id, None);
+        assert_eq!(
+            take_events(&mut motion),
+            [
+                DeviceEventKind::MotionStarted(1),
+                DeviceEventKind::MotionComplete(1),
+                DeviceEventKind::MotionStarted(2),
+                DeviceEventKind::MotionComplete(2),
+            ]
+        );
+
+        // The running command and the pending commands are aborted by halt
+        motion.push_cmd(seq_cmd(3, pos_cmd(20.0))).unwrap();
+        motion.push_cmd(seq_cmd(4, pos_cmd(20.0))).unwrap();
+        run_cycles(&mut motion, 10);
+        motion.push_cmd(seq_cmd(5, MotorCommand::Halt)).unwrap();
+        run_cycles(&mut motion, 2000);
+        assert_eq!(
+            take_events(&mut motion),
+            [
+                DeviceEventKind::MotionStarted(3),
+                DeviceEventKind::MotionAborted(4),
+                DeviceEventKind::MotionAborted(3),
+                DeviceEventKind::MotionStarted(5),
+                DeviceEventKind::MotionComplete(5),
+            ]
+        );
+        assert_eq!(
+            motion.get_motor_process_data().control_mode_display,
+            ControlMode::StandStill
+        );
+    }
+
+    #[test]
+    fn test_take_event_should_report_fault_changes() {
+        let mut motion = create_motion();
+        motion
+            .push_cmd(seq_cmd(1, MotorCommand::VelocityCommand(50.0)))
+            .unwrap();
+        motion
+            .push_cmd(seq_cmd(2, MotorCommand::VelocityCommand(80.0)))
+            .unwrap();
+        motion.motor.drive_fault = Some(FaultReason::Runaway);
+        run_cycles(&mut motion, 10);
+        motion
+            .push_cmd(seq_cmd(3, MotorCommand::ResetFault))
+            .unwrap();
+        assert_eq!(
+            take_events(&mut motion),
+            [
+                DeviceEventKind::MotionStarted(1),
+                DeviceEventKind::MotionAborted(1),
+                DeviceEventKind::MotionAborted(2),
+                DeviceEventKind::FaultRaised(FaultReason::Runaway),
+                DeviceEventKind::FaultCleared,
+                DeviceEventKind::MotionStarted(3),
+                DeviceEventKind::MotionComplete(3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_command_rejected_by_fault_should_be_aborted() {
+        let mut motion = create_motion();
+        motion.motor.drive_fault = Some(FaultReason::Stall);
+        run_cycles(&mut motion, 1);
+        take_events(&mut motion);
+
+        assert_eq!(
+            motion.push_cmd(seq_cmd(1, MotorCommand::VelocityCommand(50.0))),
+            Err(MotorCommand::VelocityCommand(50.0))
+        );
+        assert_eq!(
+            take_events(&mut motion),
+            [DeviceEventKind::MotionAborted(1)]
+        );
+    }
+
+    #[test]
+    fn test_dwell_should_pause_queue_between_commands() {
+        let mut motion = create_motion();
+        motion
+            .push_cmd(seq_cmd(1, MotorCommand::VelocityCommand(50.0)))
+            .unwrap();
+        motion
+            .push_cmd(seq_cmd(2, MotorCommand::Dwell(50)))
+            .unwrap();
+        motion
+            .push_cmd(seq_cmd(3, MotorCommand::VelocityCommand(-50.0)))
+            .unwrap();
+
+        // The velocity is kept for 10 cycles after it is reached
+        run_cycles(&mut motion, 8);
+        assert_actual_vel(&motion, 50.0);
+        assert_eq!(motion.get_motor_process_data().active_cmd_id, Some(2));
+
+        run_cycles(&mut motion, 10);
+        assert_actual_vel(&motion, -50.0);
+        assert_eq!(
+            take_events(&mut motion),
+            [
+                DeviceEventKind::MotionStarted(1),
+                DeviceEventKind::MotionComplete(1),
+                DeviceEventKind::MotionStarted(2),
+                DeviceEventKind::MotionComplete(2),
+                DeviceEventKind::MotionStarted(3),
+                DeviceEventKind::MotionComplete(3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wait_for_other_axis_should_release_both_axes_in_same_cycle() {
+        let mut left = create_motion();
+        let mut right = create_motion();
+        left.push_cmd(MotorCommand::WaitForOtherAxis).unwrap();
+        left.push_cmd(MotorCommand::Marker(1)).unwrap();
+        right.push_cmd(pos_cmd(5.0)).unwrap();
+        right.push_cmd(MotorCommand::WaitForOtherAxis).unwrap();
+        right.push_cmd(MotorCommand::Marker(2)).unwrap();
+
+        let mut marker_cycles = [None; 2];
+        for cycle in 0..2000 {
+            sync_axes(&mut left, &mut right);
+            left.run();
+            right.run();
+
+            for (motion, marker_cycle) in
+                [&mut left, &mut right].into_iter().zip(&mut marker_cycles)
+            {
+                while let Some(event) = motion.take_event() {
+                    if let DeviceEventKind::MarkerReached(_) = event {
+                        *marker_cycle = Some(cycle);
+                    }
+                }
+            }
+        }
+
+        // The left axis waits until the position command of the right axis is done
+        assert!(marker_cycles[0].is_some_and(|x| x > 10));
+        assert_eq!(marker_cycles[0], marker_cycles[1]);
+        assert!(left.is_done() && right.is_done());
+    }
+}
@@ -0,0 +1,370 @@
+#[cfg(feature = "debug-motor")]
+use defmt::debug;
+
+use protocol::{DriveProtectionConfig, FaultReason, VelocityEstimator};
+
+use crate::encoder::Encoder;
+use crate::hal::{BlockingDelay, DigitalOutput, EncoderInput, MotorDriver, PwmOutput};
+use crate::pid::Pid;
+
+pub struct BldcMotor24H<E, P, O, D>
+where
+    E: EncoderInput,
+    P: PwmOutput,
+    O: DigitalOutput,
+    D: BlockingDelay,
+{
+    pub encoder: Encoder<E>,
+    pub pid: Pid,
+    pwm_channel: P,
+    dir_pin: O,
+    break_pin: O,
+    delay: D,
+    period_s: f32,
+    break_applied: bool,
+    target_velocity_rpm: f32,
+    protection: DriveProtectionConfig,
+    vel_limit_rpm: f32,
+    stall_time_s: f32,
+    runaway_time_s: f32,
+    drive_fault: Option<FaultReason>,
+}
+
+impl<E, P, O, D> BldcMotor24H<E, P, O, D>
+where
+    E: EncoderInput,
+    P: PwmOutput,
+    O: DigitalOutput,
+    D: BlockingDelay,
+{
+    // The PWM output should be inverted: 24H motor, 0% duty: full speed, 100% duty: 0 speed
+    pub fn new(
+        encoder: Encoder<E>,
+        pwm_channel: P,
+        dir_pin: O,
+        break_pin: O,
+        delay: D,
+        pid: Pid,
+        period_s: f32,
+    ) -> Self {
+        Self {
+            encoder,
+            pid,
+            pwm_channel,
+            dir_pin,
+            break_pin,
+            delay,
+            period_s,
+            break_applied: false,
+            target_velocity_rpm: 0.0,
+            protection: DriveProtectionConfig::default(),
+            vel_limit_rpm: 0.0,
+            stall_time_s: 0.0,
+            runaway_time_s: 0.0,
+            drive_fault: None,
+        }
+    }
+
+    pub fn break_on(&mut self) {
+        self.break_pin.set_low();
+        self.dir_pin.set_low();
+        self.delay.delay_us(2);
+        self.break_pin.set_high();
+        self.dir_pin.set_high();
+    }
+
+    // Stall: the effort is saturated but the motor doesn't turn. Runaway: the motor turns
+    // against the set point or over the limit, the velocity loop pushes harder in the
+    // wrong direction when the encoder pair is swapped
+    fn check_drive_protection(&mut self, control_effort: f32) {
+        let config = self.protection;
+        let act_vel = self.encoder.get_act_velocity_in_rpm();
+        let target_vel = self.target_velocity_rpm;
+
+        let is_stalled = target_vel != 0.0
+            && control_effort.abs() >= config.stall_effort
+            && act_vel.abs() <= config.stall_vel;
+        let is_reversed = target_vel * act_vel < 0.0 && act_vel.abs() > config.runaway_margin;
+        let is_overspeed = act_vel.abs() > self.vel_limit_rpm + config.runaway_margin;
+
+        let exceeded_time = |time_s: f32, time_ms: u16, exceeded: bool| {
+            if time_ms > 0 && exceeded {
+                time_s + self.period_s
+            } else {
+                0.0
+            }
+        };
+        self.stall_time_s = exceeded_time(self.stall_time_s, config.stall_time_ms, is_stalled);
+        self.runaway_time_s = exceeded_time(
+            self.runaway_time_s,
+            config.runaway_time_ms,
+            is_reversed || is_overspeed,
+        );
+
+        let reason = if self.stall_time_s * 1000.0 > config.stall_time_ms as f32 {
+            FaultReason::Stall
+        } else if self.runaway_time_s * 1000.0 > config.runaway_time_ms as f32 {
+            FaultReason::Runaway
+        } else {
+            return;
+        };
+
+        self.drive_fault = Some(reason);
+        self.pid.clear_errors();
+        self.stall_time_s = 0.0;
+        self.runaway_time_s = 0.0;
+        self.pwm_channel.set_duty_cycle_percent(0);
+        self.break_applied = true;
+        self.break_on();
+    }
+}
+
+impl<E, P, O, D> MotorDriver for BldcMotor24H<E, P, O, D>
+where
+    E: EncoderInput,
+    P: PwmOutput,
+    O: DigitalOutput,
+    D: BlockingDelay,
+{
+    fn set_target_velocity(&mut self, target_velocity_rpm: f32) {
+        self.target_velocity_rpm = target_velocity_rpm;
+        self.pid.set_target_velocity(target_velocity_rpm);
+    }
+
+    fn set_velocity_estimator(&mut self, estimator: VelocityEstimator) {
+        self.encoder.set_velocity_estimator(estimator);
+    }
+
+    fn set_counts_per_rev(&mut self, counts_per_rev: u32) {
+        self.encoder.set_counts_per_rev(counts_per_rev);
+    }
+
+    fn set_drive_protection(&mut self, protection: DriveProtectionConfig, vel_limit_rpm: f32) {
+        self.protection = protection;
+        self.vel_limit_rpm = vel_limit_rpm;
+        self.stall_time_s = 0.0;
+        self.runaway_time_s = 0.0;
+    }
+
+    fn get_period_s(&self) -> f32 {
+        self.period_s
+    }
+
+    fn run_pid_velocity_control(&mut self) {
+        self.encoder.update_act_velocity_in_rpm(self.period_s);
+
+        // The output stays cut and the loop is held until the fault is cleared, so the
+        // integral doesn't wind up
+        if self.drive_fault.is_some() {
+            self.pwm_channel.set_duty_cycle_percent(0);
+            return;
+        }
+
+        #[cfg(feature = "debug-motor")]
+        debug!(
+            "{}, {}",
+            self.encoder.get_act_velocity_in_rpm(),
+            self.encoder.get_enc_count()
+        );
+
+        let control_effort: f32 = self
+            .pid
+            .run(self.encoder.get_act_velocity_in_rpm(), self.period_s);
+        let dir = if control_effort >= 0.0 { 1.0 } else { -1.0 };
+
+        let mut duty_cycle_percent: u8 = (control_effort * dir * 100.0) as u8;
+        if dir < 0.0 {
+            self.dir_pin.set_high();
+        } else {
+            self.dir_pin.set_low();
+        }
+
+        if self.target_velocity_rpm == 0.0 {
+            if !self.break_applied {
+                self.break_applied = true;
+                self.break_on();
+            }
+            duty_cycle_percent = 0;
+        } else {
+            self.break_pin.set_high();
+            self.break_applied = false;
+        }
+
+        self.pwm_channel.set_duty_cycle_percent(duty_cycle_percent);
+
+        self.check_drive_protection(control_effort);
+    }
+
+    fn drive_fault(&self) -> Option<FaultReason> {
+        self.drive_fault
+    }
+
+    fn clear_drive_fault(&mut self) {
+        self.drive_fault = None;
+        // The loop restarts without the integral wound up before the fault
+        self.pid.clear_errors();
+    }
+
+    fn get_act_position_in_rad(&self) -> f32 {
+        self.encoder.get_act_position_in_rad()
+    }
+
+    fn get_act_velocity_in_rpm(&self) -> f32 {
+        self.encoder.get_act_velocity_in_rpm()
+    }
+
+    fn get_act_acceleration_in_rad_s2(&self) -> f32 {
+        self.encoder.get_act_acceleration_in_rad_s2()
+    }
+
+    fn pid(&self) -> &Pid {
+        &self.pid
+    }
+
+    fn pid_mut(&mut self) -> &mut Pid {
+        &mut self.pid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockDelay, MockEncoderInput, MockPin, MockPwm};
+
+    const PROTECTION: DriveProtectionConfig = DriveProtectionConfig {
+        stall_effort: 0.9,
+        stall_vel: 30.0,
+        stall_time_ms: 300,
+        runaway_margin: 300.0,
+        runaway_time_ms: 500,
+    };
+
+    fn create_motor(
+        enc: MockEncoderInput,
+    ) -> (
+        BldcMotor24H<MockEncoderInput, MockPwm, MockPin, MockDelay>,
+        MockPwm,
+        MockPin,
+        MockPin,
+    ) {
+        let pwm = MockPwm::new();
+        let dir_pin = MockPin::new();
+        let break_pin = MockPin::new();
+        let motor = BldcMotor24H::new(
+            Encoder::new(enc, 400),
+            pwm.clone(),
+            dir_pin.clone(),
+            break_pin.clone(),
+            MockDelay,
+            Pid::new(0.001, 0.0, 0.0, 1.0),
+            0.005,
+        );
+
+        (motor, pwm, dir_pin, break_pin)
+    }
+
+    #[test]
+    fn test_run_pid_velocity_control_should_set_direction_and_duty_from_control_effort() {
+        let (mut motor, pwm, dir_pin, break_pin) = create_motor(MockEncoderInput::new());
+
+        motor.set_target_velocity(-500.0);
+        motor.run_pid_velocity_control();
+        assert_eq!(pwm.duty(), 50);
+        assert!(dir_pin.is_high());
+        assert!(break_pin.is_high());
+
+        motor.set_target_velocity(500.0);
+        motor.run_pid_velocity_control();
+        assert_eq!(pwm.duty(), 50);
+        assert!(!dir_pin.is_high());
+    }
+
+    #[test]
+    fn test_run_pid_velocity_control_should_pulse_brake_once_when_target_is_zero() {
+        let (mut motor, pwm, _dir_pin, break_pin) = create_motor(MockEncoderInput::new());
+
+        motor.set_target_velocity(500.0);
+        motor.run_pid_velocity_control();
+
+        motor.set_target_velocity(0.0);
+        motor.run_pid_velocity_control();
+        motor.run_pid_velocity_control();
+        assert_eq!(pwm.duty(), 0);
+        assert_eq!(break_pin.falling_edges(), 1);
+    }
+
+    #[test]
+    fn test_stall_should_cut_output_and_apply_brake_until_cleared() {
+        let (mut motor, pwm, _dir_pin, break_pin) = create_motor(MockEncoderInput::new());
+        motor.set_drive_protection(PROTECTION, 3000.0);
+
+        // Saturated effort, but the encoder doesn't move
+        motor.set_target_velocity(1000.0);
+        for _ in 0..50 {
+            motor.run_pid_velocity_control();
+        }
+        assert_eq!(motor.drive_fault(), None);
+        assert_eq!(pwm.duty(), 100);
+
+        for _ in 0..20 {
+            motor.run_pid_velocity_control();
+        }
+        assert_eq!(motor.drive_fault(), Some(FaultReason::Stall));
+        assert_eq!(pwm.duty(), 0);
+        assert_eq!(break_pin.falling_edges(), 1);
+
+        motor.clear_drive_fault();
+        motor.run_pid_velocity_control();
+        assert_eq!(motor.drive_fault(), None);
+        assert_eq!(pwm.duty(), 100);
+    }
+
+    #[test]
+    fn test_drive_fault_should_reset_pid_integral() {
+        let (mut motor, pwm, _dir_pin, _break_pin) = create_motor(MockEncoderInput::new());
+        motor.set_drive_protection(PROTECTION, 3000.0);
+        motor.pid_mut().set_gains(0.001, 0.01, 0.0);
+
+        // The integral winds up while the wheel is stalled
+        motor.set_target_velocity(1000.0);
+        while motor.drive_fault().is_none() {
+            motor.run_pid_velocity_control();
+        }
+        assert_eq!(motor.pid().get_error(), 0.0);
+
+        // kp * 100 + ki * 100 * 0.005 = 0.105, the output is saturated if the integral is kept
+        motor.clear_drive_fault();
+        motor.set_target_velocity(100.0);
+        motor.run_pid_velocity_control();
+        assert_eq!(pwm.duty(), 10);
+    }
+
+    #[test]
+    fn test_runaway_should_latch_fault_when_velocity_is_against_command() {
+        let enc = MockEncoderInput::new();
+        let (mut motor, pwm, _dir_pin, break_pin) = create_motor(enc.clone());
+        motor.set_drive_protection(PROTECTION, 3000.0);
+
+        // Swapped encoder pair: the motor is driven forward but the count goes backward,
+        // -20 counts per period is -600 rpm
+        motor.set_target_velocity(1000.0);
+        for _ in 0..90 {
+            enc.add(-20);
+            motor.run_pid_velocity_control();
+        }
+        assert_eq!(motor.drive_fault(), None);
+
+        for _ in 0..20 {
+            enc.add(-20);
+            motor.run_pid_velocity_control();
+        }
+        assert_eq!(motor.drive_fault(), Some(FaultReason::Runaway));
+        assert_eq!(pwm.duty(), 0);
+        assert_eq!(break_pin.falling_edges(), 1);
+
+        // The output stays cut while the fault is latched
+        enc.add(-20);
+        motor.run_pid_velocity_control();
+        assert_eq!(pwm.duty(), 0);
+    }
+}
@@ -0,0 +1,248 @@
+use core::f32;
+
+use num_traits::Float;
+use protocol::{DeviceConfig, MechanicalConfig, Odometry, Pose};
+
+use crate::rpm_to_rad_s;
+
+// The latest gyro rate is used until it is older than the timeout, it is longer than the
+// sample period of MPU6050
+const GYRO_TIMEOUT_S: f32 = 0.1;
+// The base is stationary when the wheels don't turn and the gyro rate is close to the bias
+// for the holding time, unit: m/s, rad/s, s
+const STATIONARY_WHEEL_VEL: f32 = 0.001;
+const STATIONARY_GYRO_RATE: f32 = 0.1;
+const STATIONARY_TIME_S: f32 = 0.5;
+// Time constant of the low-pass filter that estimates the gyro bias
+const GYRO_BIAS_TIME_CONSTANT_S: f32 = 2.0;
+
+struct GyroSample {
+    rate: f32,
+    age_s: f32,
+}
+
+// Differential-drive odometry of the two-wheel base, it is updated with the motor
+// positions after the motors run in every control cycle. The heading change is fused
+// with MPU6050 gyro, so the heading is kept when the wheels slip
+pub struct WheelOdometry {
+    period_s: f32,
+    odometry: Odometry,
+    // Motor positions of the previous update, unit: rad
+    prev_motor_pos: Option<[f32; 2]>,
+    gyro: Option<GyroSample>,
+    stationary_time_s: f32,
+}
+
+impl WheelOdometry {
+    pub fn new(period_s: f32) -> Self {
+        Self {
+            period_s,
+            odometry: Odometry::default(),
+            prev_motor_pos: None,
+            gyro: None,
+            stationary_time_s: 0.0,
+        }
+    }
+
+    pub fn odometry(&self) -> Odometry {
+        self.odometry
+    }
+
+    // The next update is integrated from the given pose
+    pub fn reset_pose(&mut self, pose: Pose) {
+        self.odometry.pose = Pose {
+            theta: wrap_angle(pose.theta),
+            ..pose
+        };
+    }
+
+    // Gyro Z rate of MPU6050, counterclockwise is positive, unit: rad/s
+    pub fn set_gyro_rate(&mut self, rate: f32) {
+        self.gyro = Some(GyroSample { rate, age_s: 0.0 });
+    }
+
+    // Positions (rad) and velocities (rpm) of the left and right motors. Only the change of
+    // positions is used, so the mechanical configuration can be changed between updates
+    pub fn update(&mut self, motor_pos: [f32; 2], motor_vel: [f32; 2], config: &DeviceConfig) {
+        let base = &config.base;
+        let mechanical = [&config.left.mechanical, &config.right.mechanical];
+        // Motor-side rad to the travel of the wheel, forward is positive
+        let to_wheel = |x: f32, mechanical: &MechanicalConfig| {
+            let travel = x / mechanical.gear_ratio * base.wheel_radius;
+            if mechanical.invert {
+                -travel
+            } else {
+                travel
+            }
+        };
+
+        // The first update only records the positions
+        let prev_motor_pos = self.prev_motor_pos.replace(motor_pos).unwrap_or(motor_pos);
+        let [left_dist, right_dist] =
+            [0, 1].map(|i| to_wheel(motor_pos[i] - prev_motor_pos[i], mechanical[i]));
+        let [left_vel, right_vel] =
+            [0, 1].map(|i| to_wheel(rpm_to_rad_s(motor_vel[i]), mechanical[i]));
+
+        let dist = (left_dist + right_dist) / 2.0;
+        let wheel_delta_theta = (right_dist - left_dist) / base.track_width;
+        let wheel_angular = (right_vel - left_vel) / base.track_width;
+        let is_wheel_stopped =
+            left_vel.abs() < STATIONARY_WHEEL_VEL && right_vel.abs() < STATIONARY_WHEEL_VEL;
+        let gyro_rate = self.update_gyro(is_wheel_stopped);
+
+        // The gyro noise is not integrated when the base is stationary
+        let gyro_weight = match gyro_rate {
+            Some(_) if self.stationary_time_s < STATIONARY_TIME_S => base.gyro_weight,
+            _ => 0.0,
+        };
+        let gyro_fused = gyro_rate.is_some() && base.gyro_weight > 0.0;
+        let gyro_rate = gyro_rate.unwrap_or(0.0);
+        let delta_theta =
+            gyro_weight * gyro_rate * self.period_s + (1.0 - gyro_weight) * wheel_delta_theta;
+
+        // The heading in the middle of the cycle is used, so the error of arcs is small
+        let pose = &mut self.odometry.pose;
+        let heading = pose.theta + delta_theta / 2.0;
+        pose.x += dist * Float::cos(heading);
+        pose.y += dist * Float::sin(heading);
+        pose.theta = wrap_angle(pose.theta + delta_theta);
+
+        self.odometry.linear = (left_vel + right_vel) / 2.0;
+        self.odometry.angular = gyro_weight * gyro_rate + (1.0 - gyro_weight) * wheel_angular;
+        self.odometry.gyro_fused = gyro_fused;
+    }
+
+    // Age the gyro sample and estimate the bias when the base is stationary, the rate
+    // without bias is returned if the sample is not too old
+    fn update_gyro(&mut self, is_wheel_stopped: bool) -> Option<f32> {
+        let gyro = self.gyro.as_mut()?;
+        gyro.age_s += self.period_s;
+        if gyro.age_s > GYRO_TIMEOUT_S {
+            self.gyro = None;
+            self.stationary_time_s = 0.0;
+            return None;
+        }
+
+        let bias = &mut self.odometry.gyro_bias;
+        if is_wheel_stopped && (gyro.rate - *bias).abs() < STATIONARY_GYRO_RATE {
+            self.stationary_time_s += self.period_s;
+        } else {
+            self.stationary_time_s = 0.0;
+        }
+        if self.stationary_time_s >= STATIONARY_TIME_S {
+            *bias += (gyro.rate - *bias) * self.period_s / GYRO_BIAS_TIME_CONSTANT_S;
+        }
+
+        Some(gyro.rate - *bias)
+    }
+}
+
+// Wrap the angle to -pi ~ pi
+pub(crate) fn wrap_angle(x: f32) -> f32 {
+    Float::atan2(Float::sin(x), Float::cos(x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::default_config;
+    use crate::rpm_to_rad_s;
+    use core::f32::consts::PI;
+
+    const PERIOD_S: f32 = 0.005;
+
+    #[test]
+    fn test_update_should_integrate_straight_line_and_turn() {
+        let mut config = default_config();
+        config.right.mechanical.invert = true;
+        let base = config.base;
+        let mut odometry = WheelOdometry::new(PERIOD_S);
+        odometry.update([10.0, -10.0], [0.0, 0.0], &config);
+        assert_eq!(odometry.odometry(), Odometry::default());
+
+        // One wheel revolution forward in 100 updates
+        for i in 1..=100 {
+            let pos = 10.0 + 2.0 * PI * i as f32 / 100.0;
+            odometry.update([pos, -pos], [60.0, -60.0], &config);
+        }
+        let data = odometry.odometry();
+        assert!((data.pose.x - 2.0 * PI * base.wheel_radius).abs() < 1e-4);
+        assert!(data.pose.y.abs() < 1e-6);
+        assert!((data.linear - 2.0 * PI * base.wheel_radius).abs() < 1e-4);
+        assert_eq!(data.angular, 0.0);
+
+        // Spin 90 degree in place, then move forward along y axis
+        odometry.reset_pose(Pose::default());
+        let spin = PI / 2.0 * base.track_width / 2.0 / base.wheel_radius;
+        let [left, right] = [10.0 + 2.0 * PI - spin, -(10.0 + 2.0 * PI + spin)];
+        odometry.update([left, right], [0.0, 0.0], &config);
+        odometry.update([left + 1.0, right - 1.0], [0.0, 0.0], &config);
+        let pose = odometry.odometry().pose;
+        assert!((pose.theta - PI / 2.0).abs() < 1e-4);
+        assert!(pose.x.abs() < 1e-4);
+        assert!((pose.y - base.wheel_radius).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_reset_pose_should_wrap_heading() {
+        let config = default_config();
+        let mut odometry = WheelOdometry::new(PERIOD_S);
+        odometry.reset_pose(Pose {
+            x: 1.0,
+            y: -2.0,
+            theta: 3.0 * PI / 2.0,
+        });
+        odometry.update([0.0, 0.0], [0.0, 0.0], &config);
+
+        let pose = odometry.odometry().pose;
+        assert_eq!((pose.x, pose.y), (1.0, -2.0));
+        assert!((pose.theta + PI / 2.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_gyro_should_keep_heading_when_wheels_slip() {
+        let config = default_config();
+        let mut odometry = WheelOdometry::new(PERIOD_S);
+        odometry.update([0.0, 0.0], [0.0, 0.0], &config);
+
+        // The left wheel spins on the floor for 1 s, the base doesn't turn
+        let slip_rpm = 60.0;
+        for i in 1..=200 {
+            odometry.set_gyro_rate(0.0);
+            let pos = rpm_to_rad_s(slip_rpm) * PERIOD_S * i as f32;
+            odometry.update([pos, 0.0], [slip_rpm, 0.0], &config);
+        }
+        let data = odometry.odometry();
+        let wheel_theta = -2.0 * PI * config.base.wheel_radius / config.base.track_width;
+        assert!(data.gyro_fused);
+        assert!((data.pose.theta - (1.0 - config.base.gyro_weight) * wheel_theta).abs() < 1e-4);
+
+        // The wheels are used when gyro data is not received
+        for _ in 0..40 {
+            odometry.update([0.0, 0.0], [0.0, 0.0], &config);
+        }
+        assert!(!odometry.odometry().gyro_fused);
+    }
+
+    #[test]
+    fn test_gyro_bias_should_be_estimated_when_stationary() {
+        let config = default_config();
+        let mut odometry = WheelOdometry::new(PERIOD_S);
+        for _ in 0..4000 {
+            odometry.set_gyro_rate(0.02);
+            odometry.update([0.0, 0.0], [0.0, 0.0], &config);
+        }
+        let data = odometry.odometry();
+        assert!((data.gyro_bias - 0.02).abs() < 1e-3);
+        assert!(data.pose.theta.abs() < 0.02 * STATIONARY_TIME_S);
+
+        // The base turns in place, only the bias is removed from the gyro rate
+        for _ in 0..200 {
+            odometry.set_gyro_rate(1.02);
+            odometry.update([0.0, 0.0], [0.0, 0.0], &config);
+        }
+        let data = odometry.odometry();
+        assert!((data.angular - config.base.gyro_weight).abs() < 1e-2);
+        assert!((data.gyro_bias - 0.02).abs() < 1e-3);
+    }
+}
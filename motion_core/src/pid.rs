@@ -0,0 +1,436 @@
+use core::f32;
+
+use num_traits::Float;
+use protocol::TuningRule;
+
+// Maximum number of oscillation cycles that can be averaged in auto-tuning
+pub const MAX_AUTOTUNE_CYCLES: usize = 16;
+// Minimum number of oscillation cycles, the first cycle is discarded because it
+// contains the transient response when the relay is started
+const MIN_AUTOTUNE_CYCLES: usize = 2;
+// The run is rejected if the coefficient of variation (std / mean) of the measured
+// amplitudes or periods is larger than this value
+const AUTOTUNE_MAX_VARIATION: f32 = 0.15;
+
+pub struct Pid {
+    kp: f32,
+    ki: f32,
+    kd: f32,
+    // set point (target velocity in RPM)
+    set_point: f32,
+    error_curr: f32,
+    error_prev: f32,
+    error_sum: f32,
+    output_limit: f32,
+    // Auto-tuning state. None: not in tuning mode, Some: in tuning mode
+    auto_tune: Option<TuningState>,
+    // Result of the last finished auto-tuning run, it is cleared when it is taken
+    auto_tune_result: Option<AutoTuneResult>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AutoTuneResult {
+    // The gains calculated from the relay test are applied: (kp, ki, kd)
+    Applied(f32, f32, f32),
+    // The oscillation is not consistent, the gains are not changed
+    Rejected,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AutoTuneError {
+    // Auto-tuning is already running
+    Running,
+    // The relay output is zero
+    InvalidOutput,
+    // The number of cycles is out of range
+    InvalidCycles,
+}
+
+#[derive(Copy, Clone)]
+pub struct TuningState {
+    // Configuration for the tuning process
+    output_high: f32,
+    output_low: f32,
+    hysteresis: f32,
+    cycles: usize,
+    rule: TuningRule,
+
+    // Internal state for detecting oscillations
+    // pv: process variable
+    relay_high: bool,
+    started: bool,
+    time_since_cycle_start: f32,
+    pv_max: f32,
+    pv_min: f32,
+
+    // Storage for measured oscillation characteristics
+    peak_amplitudes: [f32; MAX_AUTOTUNE_CYCLES],
+    peak_periods: [f32; MAX_AUTOTUNE_CYCLES],
+    peak_count: usize,
+}
+
+impl Pid {
+    pub fn new(kp: f32, ki: f32, kd: f32, output_limit: f32) -> Self {
+        Self {
+            kp,
+            ki,
+            kd,
+            set_point: 0.0,
+            error_curr: 0.0,
+            error_prev: 0.0,
+            error_sum: 0.0,
+            output_limit,
+            auto_tune: None,
+            auto_tune_result: None,
+        }
+    }
+
+    pub fn set_gains(&mut self, kp: f32, ki: f32, kd: f32) {
+        self.kp = kp;
+        self.ki = ki;
+        self.kd = kd;
+    }
+
+    pub fn is_autotune_running(&self) -> bool {
+        self.auto_tune.is_some()
+    }
+
+    pub fn take_autotune_result(&mut self) -> Option<AutoTuneResult> {
+        self.auto_tune_result.take()
+    }
+
+    pub fn start_autotune(
+        &mut self,
+        output_high: f32,
+        output_low: f32,
+        hysteresis: f32,
+        cycles: usize,
+        rule: TuningRule,
+    ) -> Result<(), AutoTuneError> {
+        if self.is_autotune_running() {
+            return Err(AutoTuneError::Running);
+        }
+
+        // If any of the parameters are zero, then we ignore the auto tune request
+        if output_high == 0.0 || output_low == 0.0 {
+            return Err(AutoTuneError::InvalidOutput);
+        }
+        if !is_valid_autotune_cycles(cycles) {
+            return Err(AutoTuneError::InvalidCycles);
+        }
+
+        // One extra cycle is measured because the first one is discarded
+        let cycles = cycles + 1;
+
+        self.auto_tune = Some(TuningState {
+            output_high,
+            output_low,
+            hysteresis: hysteresis.abs(),
+            cycles,
+            rule,
+            relay_high: true,
+            started: false,
+            time_since_cycle_start: 0.0,
+            pv_max: f32::MIN,
+            pv_min: f32::MAX,
+            peak_amplitudes: [0.0; MAX_AUTOTUNE_CYCLES],
+            peak_periods: [0.0; MAX_AUTOTUNE_CYCLES],
+            peak_count: 0,
+        });
+        self.auto_tune_result = None;
+        Ok(())
+    }
+
+    pub fn cancel_autotune(&mut self) {
+        self.auto_tune = None;
+        self.reset();
+    }
+
+    pub(crate) fn set_target_velocity(&mut self, target_velocity_rpm: f32) {
+        self.set_point = target_velocity_rpm;
+    }
+
+    pub(crate) fn get_error(&self) -> f32 {
+        self.error_curr
+    }
+
+    pub(crate) fn run(&mut self, act_velocity_rpm: f32, dt: f32) -> f32 {
+        let mut control_effort;
+
+        if let Some(mut tuning_state) = self.auto_tune {
+            // If in auto-tuning mode, handle the tuning logic (relay method)
+
+            // Determine relay output, the relay keeps its state while the process variable
+            // is inside the hysteresis band, this prevents chattering on noisy velocity
+            let relay_high_prev = tuning_state.relay_high;
+            if act_velocity_rpm > self.set_point + tuning_state.hysteresis {
+                tuning_state.relay_high = false;
+            } else if act_velocity_rpm < self.set_point - tuning_state.hysteresis {
+                tuning_state.relay_high = true;
+            }
+
+            control_effort = if tuning_state.relay_high {
+                tuning_state.output_high
+            } else {
+                tuning_state.output_low
+            };
+
+            // Track the peaks of process variable in current cycle
+            tuning_state.pv_max = tuning_state.pv_max.max(act_velocity_rpm);
+            tuning_state.pv_min = tuning_state.pv_min.min(act_velocity_rpm);
+            tuning_state.time_since_cycle_start += dt;
+
+            // A cycle starts and ends when the relay is switched from low to high
+            if !relay_high_prev && tuning_state.relay_high {
+                if tuning_state.started {
+                    let period = tuning_state.time_since_cycle_start;
+                    let amplitude = (tuning_state.pv_max - tuning_state.pv_min) / 2.0;
+
+                    // Store the measurements
+                    if period > 0.0 && amplitude > 0.0 {
+                        let index = tuning_state.peak_count;
+                        tuning_state.peak_periods[index] = period;
+                        tuning_state.peak_amplitudes[index] = amplitude;
+                        tuning_state.peak_count += 1;
+                    }
+                }
+
+                // Reset for the next cycle
+                tuning_state.started = true;
+                tuning_state.time_since_cycle_start = 0.0;
+                tuning_state.pv_max = act_velocity_rpm;
+                tuning_state.pv_min = act_velocity_rpm;
+            }
+
+            // Check if there is enough data to calculate the tuning parameters
+            if tuning_state.peak_count >= tuning_state.cycles {
+                self.auto_tune_result = Some(self.finish_autotune(&tuning_state));
+
+                // Reset the controller and exit tuning mode
+                control_effort = 0.0;
+                self.auto_tune = None;
+                self.reset();
+            } else {
+                self.auto_tune = Some(tuning_state);
+            }
+        } else {
+            // Normal PID control logic
+            self.error_prev = self.error_curr;
+            self.error_curr = self.set_point - act_velocity_rpm;
+            self.error_sum += self.error_curr * dt;
+
+            // warn!("Set point: {}, Actual: {}, Error: {}", self.set_point, act_velocity_rpm, self.error_curr);
+            control_effort = self.kp * self.error_curr
+                + self.ki * self.error_sum
+                + self.kd * (self.error_curr - self.error_prev) / dt;
+        }
+
+        if control_effort > self.output_limit {
+            control_effort = self.output_limit;
+        } else if control_effort < -self.output_limit {
+            control_effort = -self.output_limit;
+        }
+
+        control_effort
+    }
+
+    fn finish_autotune(&mut self, tuning_state: &TuningState) -> AutoTuneResult {
+        // The first cycle contains the transient response, so it is not used
+        let count = tuning_state.peak_count;
+        let periods = &tuning_state.peak_periods[1..count];
+        let amplitudes = &tuning_state.peak_amplitudes[1..count];
+
+        // Calculate average period (Tu) and amplitude (a), reject the run if the
+        // oscillation is not stable enough
+        let (avg_period, period_variation) = mean_and_variation(periods);
+        let (avg_amplitude, amplitude_variation) = mean_and_variation(amplitudes);
+        if period_variation > AUTOTUNE_MAX_VARIATION || amplitude_variation > AUTOTUNE_MAX_VARIATION
+        {
+            return AutoTuneResult::Rejected;
+        }
+
+        let tu = avg_period;
+        let a = avg_amplitude;
+        let eps = tuning_state.hysteresis;
+        let d = (tuning_state.output_high - tuning_state.output_low) / 2.0;
+        if a <= eps {
+            return AutoTuneResult::Rejected;
+        }
+
+        // Calculate Ultimate Gain (Ku) using the describing function method of a relay
+        // with hysteresis (eps == 0 gives the ideal relay: 4d / (pi * a))
+        let ku = (4.0 * d) / (f32::consts::PI * Float::sqrt(a * a - eps * eps));
+
+        // Calculate gains with selected tuning rule, (kp, ki, kd) is derived from
+        // kp = c_p * ku, ti = c_i * tu, td = c_d * tu
+        let (kp, ki, kd) = match tuning_state.rule {
+            TuningRule::ZieglerNichols => (0.6 * ku, (1.2 * ku) / tu, 0.075 * ku * tu),
+            TuningRule::TyreusLuyben => (0.4545 * ku, (0.2066 * ku) / tu, 0.0721 * ku * tu),
+            TuningRule::PessenIntegral => (0.7 * ku, (1.75 * ku) / tu, 0.105 * ku * tu),
+            TuningRule::SomeOvershoot => (0.33 * ku, (0.66 * ku) / tu, 0.11 * ku * tu),
+            TuningRule::NoOvershoot => (0.2 * ku, (0.4 * ku) / tu, 0.066 * ku * tu),
+            TuningRule::PiOnly => (0.45 * ku, (0.54 * ku) / tu, 0.0),
+        };
+
+        self.kp = kp;
+        self.ki = ki;
+        self.kd = kd;
+
+        AutoTuneResult::Applied(kp, ki, kd)
+    }
+
+    fn reset(&mut self) {
+        self.set_point = 0.0;
+        self.clear_errors();
+    }
+
+    // The integral and the derivative start from zero, the set point is kept
+    pub(crate) fn clear_errors(&mut self) {
+        self.error_curr = 0.0;
+        self.error_prev = 0.0;
+        self.error_sum = 0.0;
+    }
+}
+
+// One extra cycle is measured because the first one is discarded, so the number of
+// cycles is less than `MAX_AUTOTUNE_CYCLES`
+pub fn is_valid_autotune_cycles(cycles: usize) -> bool {
+    (MIN_AUTOTUNE_CYCLES..MAX_AUTOTUNE_CYCLES).contains(&cycles)
+}
+
+// Return mean and coefficient of variation (std / mean) of the data
+fn mean_and_variation(data: &[f32]) -> (f32, f32) {
+    let len = data.len() as f32;
+    let mean = data.iter().sum::<f32>() / len;
+    let variance = data.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / len;
+
+    (mean, Float::sqrt(variance) / mean.abs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.005;
+
+    // Feed the relay with a synthetic oscillation around the set point, the amplitude
+    // of each cycle is given by `amplitude(cycle)` and `noise()` is added to each sample
+    fn run_oscillation(
+        pid: &mut Pid,
+        period: f32,
+        amplitude: impl Fn(usize) -> f32,
+        mut noise: impl FnMut() -> f32,
+    ) {
+        let samples_per_cycle = (period / DT) as usize;
+        for i in 0..samples_per_cycle * 32 {
+            if !pid.is_autotune_running() {
+                break;
+            }
+
+            let t = i as f32 * DT;
+            let a = amplitude(i / samples_per_cycle);
+            pid.run(a * (2.0 * f32::consts::PI * t / period).sin() + noise(), DT);
+        }
+    }
+
+    // Deterministic noise uniformly distributed in [-amplitude, amplitude] (xorshift)
+    fn uniform_noise(amplitude: f32) -> impl FnMut() -> f32 {
+        let mut state = 0x2545_f491_u32;
+        move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            amplitude * (2.0 * state as f32 / u32::MAX as f32 - 1.0)
+        }
+    }
+
+    #[test]
+    fn test_autotune_should_apply_gains_from_stable_oscillation() {
+        let mut pid = Pid::new(0.0, 0.0, 0.0, 1.0);
+        pid.set_target_velocity(0.0);
+        pid.start_autotune(0.5, -0.5, 0.0, 8, TuningRule::ZieglerNichols)
+            .unwrap();
+
+        run_oscillation(&mut pid, 0.2, |_| 100.0, || 0.0);
+        assert!(!pid.is_autotune_running());
+
+        // Ku = 4d / (pi * a), Tu = 0.2
+        let ku = 4.0 * 0.5 / (f32::consts::PI * 100.0);
+        let tu = 0.2;
+        let Some(AutoTuneResult::Applied(kp, ki, kd)) = pid.take_autotune_result() else {
+            panic!("auto-tuning should be applied");
+        };
+        assert!((kp - 0.6 * ku).abs() / (0.6 * ku) < 0.05);
+        assert!((ki - 1.2 * ku / tu).abs() / (1.2 * ku / tu) < 0.05);
+        assert!((kd - 0.075 * ku * tu).abs() / (0.075 * ku * tu) < 0.05);
+        assert_eq!(pid.take_autotune_result(), None);
+    }
+
+    #[test]
+    fn test_autotune_should_reject_inconsistent_oscillation() {
+        let mut pid = Pid::new(0.1, 0.2, 0.3, 1.0);
+        pid.set_target_velocity(0.0);
+        pid.start_autotune(0.5, -0.5, 0.0, 8, TuningRule::ZieglerNichols)
+            .unwrap();
+
+        // The oscillation keeps growing, so the measured amplitudes are not consistent
+        run_oscillation(&mut pid, 0.2, |cycle| 50.0 * (cycle + 1) as f32, || 0.0);
+        assert!(!pid.is_autotune_running());
+        assert_eq!(pid.take_autotune_result(), Some(AutoTuneResult::Rejected));
+        assert_eq!((pid.kp, pid.ki, pid.kd), (0.1, 0.2, 0.3));
+    }
+
+    #[test]
+    fn test_autotune_hysteresis_should_suppress_chattering_on_noisy_velocity() {
+        // The relay chatters around the set point without hysteresis, the short cycles
+        // make the measured periods inconsistent
+        let mut pid = Pid::new(0.1, 0.2, 0.3, 1.0);
+        pid.set_target_velocity(0.0);
+        pid.start_autotune(0.5, -0.5, 0.0, 8, TuningRule::ZieglerNichols)
+            .unwrap();
+        run_oscillation(&mut pid, 0.2, |_| 100.0, uniform_noise(50.0));
+        assert!(!pid.is_autotune_running());
+        assert_eq!(pid.take_autotune_result(), Some(AutoTuneResult::Rejected));
+
+        // The hysteresis band is wider than the noise, so the relay switches once per
+        // half period
+        pid.set_target_velocity(0.0);
+        pid.start_autotune(0.5, -0.5, 60.0, 8, TuningRule::ZieglerNichols)
+            .unwrap();
+        run_oscillation(&mut pid, 0.2, |_| 100.0, uniform_noise(50.0));
+        assert!(!pid.is_autotune_running());
+        let Some(AutoTuneResult::Applied(kp, ki, _)) = pid.take_autotune_result() else {
+            panic!("auto-tuning should be applied");
+        };
+
+        // Tu = 2 * kp / ki with Ziegler-Nichols rule
+        let tu = 2.0 * kp / ki;
+        assert!((tu - 0.2).abs() / 0.2 < 0.05, "{tu}");
+    }
+
+    #[test]
+    fn test_autotune_should_reject_invalid_cycles() {
+        let mut pid = Pid::new(0.1, 0.2, 0.3, 1.0);
+        for cycles in [0, 1, MAX_AUTOTUNE_CYCLES] {
+            assert_eq!(
+                pid.start_autotune(0.5, -0.5, 0.0, cycles, TuningRule::ZieglerNichols),
+                Err(AutoTuneError::InvalidCycles)
+            );
+            assert!(!pid.is_autotune_running());
+        }
+
+        pid.start_autotune(
+            0.5,
+            -0.5,
+            0.0,
+            MAX_AUTOTUNE_CYCLES - 1,
+            TuningRule::ZieglerNichols,
+        )
+        .unwrap();
+        assert!(pid.is_autotune_running());
+        assert_eq!(
+            pid.start_autotune(0.5, -0.5, 0.0, 8, TuningRule::ZieglerNichols),
+            Err(AutoTuneError::Running)
+        );
+    }
+}
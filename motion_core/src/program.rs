@@ -0,0 +1,330 @@
+use protocol::{
+    MotionProgram, MotorCommand, ProgramError, ProgramStatus, ProgramStep, SequencedCommand,
+    MOTION_PROGRAM_SIZE,
+};
+
+use crate::hal::MotorDriver;
+use crate::motion::Motion;
+
+// Sequence ids of the commands pushed by the program, the lowest byte is the step, so the
+// running step is decided by the active command of motion
+pub const PROGRAM_CMD_ID: u32 = 0xFFFF_FF00;
+
+pub fn is_valid_program(program: &MotionProgram) -> bool {
+    let len = program.len as usize;
+    if len == 0 || len > MOTION_PROGRAM_SIZE {
+        return false;
+    }
+
+    program
+        .steps()
+        .iter()
+        .enumerate()
+        .all(|(i, step)| match *step {
+            ProgramStep::Position(x) => {
+                x.displacement.is_finite()
+                    && x.vel_max.is_finite()
+                    && x.vel_max > 0.0
+                    && x.vel_end.is_finite()
+            }
+            ProgramStep::Velocity(x) => x.is_finite(),
+            ProgramStep::Dwell(_) => true,
+            // Only backward jumps are allowed, so the loop always runs the previous steps
+            ProgramStep::Loop { target, .. } => (target as usize) < i,
+        })
+}
+
+struct RunState {
+    step: usize,
+    repeat: bool,
+    cycles: u32,
+    // Number of jumps done by each `Loop` step
+    jumps: [u16; MOTION_PROGRAM_SIZE],
+    // Remaining time of the running `Dwell` step
+    dwell_left_s: Option<f32>,
+}
+
+// Runs the program stored in RAM, it feeds the command queue of motion in every control
+// cycle, so the program keeps running when the host is disconnected
+#[derive(Default)]
+pub struct ProgramExecutor {
+    program: Option<MotionProgram>,
+    state: Option<RunState>,
+}
+
+impl ProgramExecutor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(&mut self, program: MotionProgram) -> Result<(), ProgramError> {
+        if !is_valid_program(&program) {
+            return Err(ProgramError::InvalidProgram);
+        }
+        if self.is_running() {
+            return Err(ProgramError::Running);
+        }
+
+        self.program = Some(program);
+        Ok(())
+    }
+
+    pub fn program(&self) -> Option<&MotionProgram> {
+        self.program.as_ref()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state.is_some()
+    }
+
+    // The program starts from the first step, it is restarted if it is running
+    pub fn start<D: MotorDriver, const N: usize>(
+        &mut self,
+        motion: &Motion<D, N>,
+        repeat: bool,
+    ) -> Result<(), ProgramError> {
+        if self.program.is_none() {
+            return Err(ProgramError::NoProgram);
+        }
+        if motion.fault().is_some() {
+            return Err(ProgramError::Fault);
+        }
+
+        self.state = Some(RunState {
+            step: 0,
+            repeat,
+            cycles: 0,
+            jumps: [0; MOTION_PROGRAM_SIZE],
+            dwell_left_s: None,
+        });
+        Ok(())
+    }
+
+    // Stop the program and halt the motor, the commands pushed by the program are aborted
+    pub fn stop<D: MotorDriver, const N: usize>(&mut self, motion: &mut Motion<D, N>) {
+        if self.state.take().is_some() {
+            let _ = motion.push_cmd(MotorCommand::Halt);
+        }
+    }
+
+    // The program is stopped by other commands, ex: `Halt` from host
+    pub fn cancel(&mut self) {
+        self.state = None;
+    }
+
+    pub fn status<D: MotorDriver, const N: usize>(
+        &self,
+        motion: &Motion<D, N>,
+    ) -> Option<ProgramStatus> {
+        let state = self.state.as_ref()?;
+        let step = match motion.active_cmd_id() {
+            Some(id) if id & PROGRAM_CMD_ID == PROGRAM_CMD_ID => (id & 0xFF) as u8,
+            _ => state.step.min(MOTION_PROGRAM_SIZE - 1) as u8,
+        };
+
+        Some(ProgramStatus {
+            step,
+            cycles: state.cycles,
+        })
+    }
+
+    // It is called before `Motion::run`, the steps are pushed until the queue is full or
+    // a `Dwell` step waits for the previous steps
+    pub fn run<D: MotorDriver, const N: usize>(&mut self, motion: &mut Motion<D, N>) {
+        let (Some(program), Some(state)) = (self.program.as_ref(), self.state.as_mut()) else {
+            return;
+        };
+
+        // The axis is already stopped by the fault
+        if motion.fault().is_some() {
+            self.state = None;
+            return;
+        }
+
+        let steps = program.steps();
+        let period_s = motion.motor.get_period_s();
+        let mut finished = false;
+
+        // A loop without motion steps is run once per cycle instead of blocking the task
+        for _ in 0..=MOTION_PROGRAM_SIZE {
+            let Some(step) = steps.get(state.step) else {
+                if state.repeat {
+                    state.step = 0;
+                    state.cycles = state.cycles.wrapping_add(1);
+                    state.jumps = [0; MOTION_PROGRAM_SIZE];
+                    continue;
+                }
+
+                finished = motion.is_done();
+                break;
+            };
+
+            match *step {
+                ProgramStep::Position(x) => {
+                    if !push_step(motion, state.step, MotorCommand::PositionCommand(x)) {
+                        break;
+                    }
+                    state.step += 1;
+                }
+                ProgramStep::Velocity(x) => {
+                    if !push_step(motion, state.step, MotorCommand::VelocityCommand(x)) {
+                        break;
+                    }
+                    state.step += 1;
+                }
+                ProgramStep::Dwell(ms) => {
+                    if !motion.is_done() {
+                        break;
+                    }
+
+                    let dwell_left_s = state.dwell_left_s.get_or_insert(ms as f32 / 1000.0);
+                    if *dwell_left_s > 0.0 {
+                        *dwell_left_s -= period_s;
+                        break;
+                    }
+                    state.dwell_left_s = None;
+                    state.step += 1;
+                }
+                ProgramStep::Loop { target, count } => {
+                    let jumps = &mut state.jumps[state.step];
+                    if count == 0 || *jumps < count {
+                        *jumps = jumps.saturating_add(1);
+                        state.step = target as usize;
+                    } else {
+                        // Reset for the next time the loop is entered, ex: nested loop
+                        *jumps = 0;
+                        state.step += 1;
+                    }
+                }
+            }
+        }
+
+        if finished {
+            self.state = None;
+        }
+    }
+}
+
+fn push_step<D: MotorDriver, const N: usize>(
+    motion: &mut Motion<D, N>,
+    step: usize,
+    cmd: MotorCommand,
+) -> bool {
+    if motion.is_queue_full() {
+        return false;
+    }
+
+    let id = PROGRAM_CMD_ID | step as u32;
+    motion.push_cmd(SequencedCommand { id, cmd }).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockMotor;
+    use crate::rpm_to_rad_s;
+    use protocol::PositionCommand;
+    use s_curve::SCurveInterpolator;
+
+    const PERIOD_S: f32 = 0.005;
+
+    fn create_motion() -> Motion<MockMotor, 4> {
+        let s_curve_intper = SCurveInterpolator::new(rpm_to_rad_s(3000.0), 300.0, 3000.0, PERIOD_S);
+        Motion::new(s_curve_intper, MockMotor::new(PERIOD_S))
+    }
+
+    fn run_cycles(
+        executor: &mut ProgramExecutor,
+        motion: &mut Motion<MockMotor, 4>,
+        cycles: usize,
+    ) {
+        for _ in 0..cycles {
+            executor.run(motion);
+            motion.run();
+        }
+    }
+
+    fn pos_step(displacement: f32) -> ProgramStep {
+        ProgramStep::Position(PositionCommand {
+            displacement,
+            vel_max: 100.0,
+            vel_end: 0.0,
+        })
+    }
+
+    #[test]
+    fn test_load_should_reject_invalid_program() {
+        let mut executor = ProgramExecutor::new();
+        let forward_jump = ProgramStep::Loop {
+            target: 1,
+            count: 1,
+        };
+        let program = MotionProgram::new("bad", &[pos_step(1.0), forward_jump]);
+        assert_eq!(executor.load(program), Err(ProgramError::InvalidProgram));
+        assert_eq!(
+            executor.load(MotionProgram::new("empty", &[])),
+            Err(ProgramError::InvalidProgram)
+        );
+        assert!(executor.program().is_none());
+    }
+
+    #[test]
+    fn test_run_should_repeat_loop_and_finish() {
+        let mut motion = create_motion();
+        let mut executor = ProgramExecutor::new();
+        let steps = [
+            pos_step(5.0),
+            pos_step(-5.0),
+            ProgramStep::Loop {
+                target: 0,
+                count: 2,
+            },
+            pos_step(2.0),
+        ];
+        executor
+            .load(MotionProgram::new("back-forth", &steps))
+            .unwrap();
+        executor.start(&motion, false).unwrap();
+
+        run_cycles(&mut executor, &mut motion, 10);
+        assert_eq!(executor.status(&motion).map(|x| x.step), Some(0));
+
+        run_cycles(&mut executor, &mut motion, 5000);
+        assert!(!executor.is_running());
+        assert!(motion.is_done());
+        let data = motion.get_motor_process_data();
+        assert!((data.actual_pos - 2.0).abs() < 0.2);
+    }
+
+    #[test]
+    fn test_dwell_should_hold_velocity_and_loop_until_stopped() {
+        let mut motion = create_motion();
+        let mut executor = ProgramExecutor::new();
+        let steps = [
+            ProgramStep::Velocity(60.0),
+            ProgramStep::Dwell(100),
+            ProgramStep::Velocity(-60.0),
+            ProgramStep::Dwell(100),
+        ];
+        executor
+            .load(MotionProgram::new("endurance", &steps))
+            .unwrap();
+        executor.start(&motion, true).unwrap();
+
+        // Velocity is kept during the dwell time (20 cycles)
+        run_cycles(&mut executor, &mut motion, 10);
+        assert_eq!(executor.status(&motion).map(|x| x.step), Some(1));
+        assert!((motion.get_motor_process_data().actual_vel - 60.0).abs() < 1e-4);
+
+        run_cycles(&mut executor, &mut motion, 20);
+        assert!((motion.get_motor_process_data().actual_vel + 60.0).abs() < 1e-4);
+
+        run_cycles(&mut executor, &mut motion, 100);
+        assert!(executor.status(&motion).is_some_and(|x| x.cycles >= 2));
+
+        executor.stop(&mut motion);
+        run_cycles(&mut executor, &mut motion, 100);
+        assert!(executor.status(&motion).is_none());
+        assert_eq!(motion.get_motor_process_data().actual_vel, 0.0);
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+
+use motion_core::hal::{BlockingDelay, DigitalOutput, EdgeTiming, EncoderInput, PwmOutput};
+
+use crate::plant::DriverInput;
+
+// Signals between the simulated hardware and the plant. The hardware is moved into the
+// motor, so the signals are shared with the simulator through `Arc`, and the simulator
+// can be moved between the threads of the emulator
+#[derive(Clone, Default)]
+pub struct SimBus {
+    input: Arc<Mutex<DriverInput>>,
+    encoder_count: Arc<AtomicU16>,
+    edge_timing: Arc<Mutex<Option<EdgeTiming>>>,
+}
+
+impl SimBus {
+    // Get the signals driven in the last control period, the brake pulse is cleared
+    // after it is taken
+    pub fn take_input(&self) -> DriverInput {
+        let mut input = self.input.lock().unwrap();
+        let taken = *input;
+        input.brake_pulsed = false;
+        taken
+    }
+
+    pub fn set_encoder_count(&self, count: u16) {
+        self.encoder_count.store(count, Ordering::Relaxed);
+    }
+
+    pub fn set_edge_timing(&self, edge_timing: EdgeTiming) {
+        *self.edge_timing.lock().unwrap() = Some(edge_timing);
+    }
+}
+
+pub struct SimEncoderInput {
+    bus: SimBus,
+}
+
+impl SimEncoderInput {
+    pub fn new(bus: SimBus) -> Self {
+        Self { bus }
+    }
+}
+
+impl EncoderInput for SimEncoderInput {
+    fn count(&mut self) -> u16 {
+        self.bus.encoder_count.load(Ordering::Relaxed)
+    }
+
+    fn edge_timing(&mut self) -> Option<EdgeTiming> {
+        *self.bus.edge_timing.lock().unwrap()
+    }
+}
+
+// The PWM channel in firmware is configured as active low, so the duty cycle seen on
+// the pin is inverted
+pub struct SimPwm {
+    bus: SimBus,
+}
+
+impl SimPwm {
+    pub fn new(bus: SimBus) -> Self {
+        Self { bus }
+    }
+}
+
+impl PwmOutput for SimPwm {
+    fn set_duty_cycle_percent(&mut self, percent: u8) {
+        self.bus.input.lock().unwrap().pwm_pin_duty = 100 - percent.min(100);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PinRole {
+    Direction,
+    Brake,
+}
+
+pub struct SimPin {
+    bus: SimBus,
+    role: PinRole,
+}
+
+impl SimPin {
+    pub fn new(bus: SimBus, role: PinRole) -> Self {
+        Self { bus, role }
+    }
+}
+
+impl DigitalOutput for SimPin {
+    fn set_high(&mut self) {
+        let mut input = self.bus.input.lock().unwrap();
+        match self.role {
+            PinRole::Direction => input.dir_high = true,
+            PinRole::Brake => input.brake_low = false,
+        }
+    }
+
+    fn set_low(&mut self) {
+        let mut input = self.bus.input.lock().unwrap();
+        match self.role {
+            PinRole::Direction => input.dir_high = false,
+            PinRole::Brake => {
+                // The brake pulse is much shorter than the control period, remember it
+                // so it is applied in the next step
+                input.brake_low = true;
+                input.brake_pulsed = true;
+            }
+        }
+    }
+}
+
+// The plant is not advanced during the delay, the delay is only used for short pulses
+pub struct SimDelay;
+
+impl BlockingDelay for SimDelay {
+    fn delay_us(&mut self, _us: u32) {}
+}
@@ -0,0 +1,336 @@
+pub mod hardware;
+pub mod pendulum;
+pub mod plant;
+
+use motion_core::encoder::Encoder;
+use motion_core::motion::Motion;
+use motion_core::motor::BldcMotor24H;
+use motion_core::pid::Pid;
+use s_curve::SCurveInterpolator;
+
+use crate::hardware::{PinRole, SimBus, SimDelay, SimEncoderInput, SimPin, SimPwm};
+use crate::plant::{Plant, PlantParams};
+
+// Same control period and queue size as the firmware
+pub const PERIOD_S: f32 = 0.005;
+pub const SIM_CMD_QUEUE_SIZE: usize = 32;
+
+pub type SimMotor = BldcMotor24H<SimEncoderInput, SimPwm, SimPin, SimDelay>;
+pub type SimMotion = Motion<SimMotor, SIM_CMD_QUEUE_SIZE>;
+
+// Closed-loop simulation of one wheel: the motion controller from `motion_core` drives
+// the simulated hardware, and the plant is advanced by `PERIOD_S` after each control cycle
+pub struct Simulator {
+    motion: SimMotion,
+    plant: Plant,
+    bus: SimBus,
+    time_s: f32,
+}
+
+impl Simulator {
+    pub fn new(params: PlantParams, pid: Pid, s_curve_intper: SCurveInterpolator) -> Self {
+        let bus = SimBus::default();
+        let motor = BldcMotor24H::new(
+            Encoder::new(
+                SimEncoderInput::new(bus.clone()),
+                params.counts_per_rev as u32,
+            ),
+            SimPwm::new(bus.clone()),
+            SimPin::new(bus.clone(), PinRole::Direction),
+            SimPin::new(bus.clone(), PinRole::Brake),
+            SimDelay,
+            pid,
+            PERIOD_S,
+        );
+
+        Self {
+            motion: Motion::new(s_curve_intper, motor),
+            plant: Plant::new(params),
+            bus,
+            time_s: 0.0,
+        }
+    }
+
+    pub fn motion(&self) -> &SimMotion {
+        &self.motion
+    }
+
+    pub fn motion_mut(&mut self) -> &mut SimMotion {
+        &mut self.motion
+    }
+
+    pub fn plant(&self) -> &Plant {
+        &self.plant
+    }
+
+    pub fn time_s(&self) -> f32 {
+        self.time_s
+    }
+
+    // Run one control cycle
+    pub fn step(&mut self) {
+        self.motion.run();
+
+        let input = self.bus.take_input();
+        self.plant.step(&input, PERIOD_S);
+        self.bus.set_encoder_count(self.plant.encoder_count());
+        self.bus.set_edge_timing(self.plant.encoder_edge_timing());
+        self.time_s += PERIOD_S;
+    }
+
+    pub fn run_for(&mut self, duration_s: f32) {
+        for _ in 0..(duration_s / PERIOD_S).round() as usize {
+            self.step();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use motion_core::config::default_config;
+    use motion_core::hal::MotorDriver;
+    use motion_core::pid::AutoTuneResult;
+    use motion_core::{rad_s_to_rpm, rpm_to_rad_s};
+    use protocol::{
+        AutoTuneCommand, ControlMode, FaultReason, MotorCommand, PositionCommand, TuningRule,
+        VelocityEstimator,
+    };
+
+    fn create_simulator() -> Simulator {
+        let config = default_config();
+        Simulator::new(
+            PlantParams::default(),
+            Pid::new(
+                config.left.pid.kp,
+                config.left.pid.ki,
+                config.left.pid.kd,
+                1.0,
+            ),
+            SCurveInterpolator::new(
+                rpm_to_rad_s(config.vel_limit),
+                config.acc_limit,
+                config.jerk_limit,
+                PERIOD_S,
+            ),
+        )
+    }
+
+    // Average velocity (rpm) measured by the controller in the given duration
+    fn average_velocity(sim: &mut Simulator, duration_s: f32) -> f32 {
+        let steps = (duration_s / PERIOD_S).round() as usize;
+        let mut sum = 0.0;
+        for _ in 0..steps {
+            sim.step();
+            sum += rad_s_to_rpm(sim.motion().get_motor_process_data().actual_vel);
+        }
+
+        sum / steps as f32
+    }
+
+    #[test]
+    fn test_velocity_command_should_track_target_velocity() {
+        let mut sim = create_simulator();
+        sim.motion_mut()
+            .push_cmd(MotorCommand::VelocityCommand(rpm_to_rad_s(1500.0)))
+            .unwrap();
+
+        sim.run_for(1.5);
+        let velocity = average_velocity(&mut sim, 0.5);
+        assert!((velocity - 1500.0).abs() < 15.0, "{velocity}");
+
+        sim.motion_mut()
+            .push_cmd(MotorCommand::VelocityCommand(rpm_to_rad_s(-800.0)))
+            .unwrap();
+
+        sim.run_for(1.5);
+        let velocity = average_velocity(&mut sim, 0.5);
+        assert!((velocity + 800.0).abs() < 15.0, "{velocity}");
+    }
+
+    #[test]
+    fn test_mt_estimator_should_track_low_velocity() {
+        let mut sim = create_simulator();
+        sim.motion_mut()
+            .motor
+            .set_velocity_estimator(VelocityEstimator::MT);
+        sim.motion_mut()
+            .push_cmd(MotorCommand::VelocityCommand(rpm_to_rad_s(20.0)))
+            .unwrap();
+
+        sim.run_for(1.5);
+        let velocity = average_velocity(&mut sim, 1.0);
+        assert!((velocity - 20.0).abs() < 1.0, "{velocity}");
+
+        // The wheel should turn smoothly instead of jerking between 0 and a count per period
+        let mut max_err: f32 = 0.0;
+        for _ in 0..200 {
+            sim.step();
+            max_err = max_err.max((sim.plant().velocity() - rpm_to_rad_s(20.0)).abs());
+        }
+        assert!(max_err < rpm_to_rad_s(5.0), "{max_err}");
+    }
+
+    #[test]
+    fn test_tracking_observer_should_publish_acceleration() {
+        let mut sim = create_simulator();
+        sim.motion_mut()
+            .motor
+            .set_velocity_estimator(VelocityEstimator::TrackingObserver(10.0));
+        // Slow ramp, so the velocity loop follows the interpolator
+        sim.motion_mut()
+            .s_curve_intper
+            .set_constraint(rpm_to_rad_s(3000.0), 200.0, 2000.0);
+        sim.motion_mut()
+            .push_cmd(MotorCommand::PositionCommand(PositionCommand {
+                displacement: 2000.0,
+                vel_max: rpm_to_rad_s(3000.0),
+                vel_end: 0.0,
+            }))
+            .unwrap();
+
+        sim.run_for(1.0);
+        let data = sim.motion().get_motor_process_data();
+        assert_eq!(data.intp_acc, 200.0);
+        assert!((data.actual_acc - 200.0).abs() < 10.0, "{}", data.actual_acc);
+
+        // The acceleration settles to 0 at constant velocity
+        sim.run_for(2.0);
+        let data = sim.motion().get_motor_process_data();
+        assert_eq!(data.intp_acc, 0.0);
+        assert!(data.actual_acc.abs() < 20.0, "{}", data.actual_acc);
+    }
+
+    #[test]
+    fn test_default_following_error_should_only_trip_on_jammed_wheel() {
+        let config = default_config();
+        let apply_config = |sim: &mut Simulator| {
+            sim.motion_mut().apply_config(
+                &config.left,
+                config.vel_limit,
+                config.acc_limit,
+                config.jerk_limit,
+            )
+        };
+
+        // Large velocity steps are followed without fault
+        let mut sim = create_simulator();
+        apply_config(&mut sim);
+        for vel in [3000.0, -3000.0, 0.0] {
+            sim.motion_mut()
+                .push_cmd(MotorCommand::VelocityCommand(rpm_to_rad_s(vel)))
+                .unwrap();
+            sim.run_for(1.5);
+        }
+        assert_eq!(sim.motion().fault(), None);
+
+        // The friction is larger than the stall torque, so the wheel can't move
+        let mut sim = Simulator::new(
+            PlantParams {
+                coulomb_friction: 1.0,
+                ..Default::default()
+            },
+            Pid::new(
+                config.left.pid.kp,
+                config.left.pid.ki,
+                config.left.pid.kd,
+                1.0,
+            ),
+            SCurveInterpolator::new(
+                rpm_to_rad_s(config.vel_limit),
+                config.acc_limit,
+                config.jerk_limit,
+                PERIOD_S,
+            ),
+        );
+        apply_config(&mut sim);
+        sim.motion_mut()
+            .push_cmd(MotorCommand::VelocityCommand(rpm_to_rad_s(1500.0)))
+            .unwrap();
+        sim.run_for(1.0);
+
+        let data = sim.motion().get_motor_process_data();
+        assert_eq!(data.control_mode_display, ControlMode::Fault);
+        assert_eq!(data.fault, Some(FaultReason::VelocityFollowingError));
+        assert!(sim.plant().current().abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_position_command_should_move_wheel_to_target() {
+        let mut sim = create_simulator();
+        let displacement = 20.0 * std::f32::consts::PI;
+        sim.motion_mut()
+            .push_cmd(MotorCommand::PositionCommand(PositionCommand {
+                displacement,
+                vel_max: rpm_to_rad_s(2000.0),
+                vel_end: 0.0,
+            }))
+            .unwrap();
+
+        sim.run_for(4.0);
+
+        // There is no position control loop yet (see TODOS in README), the lag of velocity
+        // control loop is left as position error when the interpolation is done
+        let data = sim.motion().get_motor_process_data();
+        assert_eq!(data.control_mode_display, ControlMode::Position);
+        assert!((data.intp_pos - displacement).abs() < 1.0);
+        assert!((sim.plant().position() - displacement).abs() < 0.05 * displacement);
+        assert_eq!(sim.plant().velocity(), 0.0);
+    }
+
+    #[test]
+    fn test_halt_should_stop_motor_and_enter_standstill() {
+        let mut sim = create_simulator();
+        sim.motion_mut()
+            .push_cmd(MotorCommand::VelocityCommand(rpm_to_rad_s(2000.0)))
+            .unwrap();
+        sim.run_for(1.5);
+
+        sim.motion_mut().push_cmd(MotorCommand::Halt).unwrap();
+        sim.run_for(1.0);
+
+        let data = sim.motion().get_motor_process_data();
+        assert_eq!(data.control_mode_display, ControlMode::StandStill);
+        assert_eq!(sim.plant().velocity(), 0.0);
+
+        // The wheel should not move after it stands still
+        let position = sim.plant().position();
+        sim.run_for(1.0);
+        assert_eq!(sim.plant().position(), position);
+    }
+
+    #[test]
+    fn test_autotune_should_find_gains_that_track_target_velocity() {
+        let mut sim = create_simulator();
+        sim.motion_mut()
+            .push_cmd(MotorCommand::AutoTuneCommand(AutoTuneCommand {
+                set_point: 1000.0,
+                output_limit: 0.4,
+                hysteresis: 60.0,
+                cycles: 8,
+                rule: TuningRule::NoOvershoot,
+                start: true,
+            }))
+            .unwrap();
+
+        let mut result = None;
+        for _ in 0..(10.0 / PERIOD_S) as usize {
+            sim.step();
+            result = sim.motion_mut().take_autotune_result();
+            if result.is_some() {
+                break;
+            }
+        }
+        assert!(
+            matches!(result, Some(AutoTuneResult::Applied(..))),
+            "{result:?}"
+        );
+
+        sim.motion_mut()
+            .push_cmd(MotorCommand::VelocityCommand(rpm_to_rad_s(1500.0)))
+            .unwrap();
+        sim.run_for(2.0);
+        let velocity = average_velocity(&mut sim, 0.5);
+        assert!((velocity - 1500.0).abs() < 15.0, "{velocity}");
+    }
+}
@@ -0,0 +1,211 @@
+use protocol::Mpu6050MotionData;
+
+const GRAVITY: f32 = 9.81;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PendulumParams {
+    // Distance from the wheel axle to the center of percussion of the chassis (m), it is
+    // (I + m d^2) / (m d) for a chassis with inertia I about its center of mass at height d
+    pub length: f32,
+    // Viscous damping of the pitch (1/s)
+    pub damping: f32,
+    // The chassis lies on the floor at this pitch (rad)
+    pub max_pitch: f32,
+}
+
+impl Default for PendulumParams {
+    fn default() -> Self {
+        Self {
+            length: 0.3,
+            damping: 0.1,
+            max_pitch: std::f32::consts::FRAC_PI_2,
+        }
+    }
+}
+
+// Chassis of the two-wheel balancing robot as an inverted pendulum on the wheel axle. The
+// acceleration of the base is the input, and the torque of the chassis on the wheels is
+// not fed back to the motors, the plants of the wheels already carry a share of the mass:
+//
+//   l pitch'' = g sin(pitch) - a cos(pitch) - l c pitch'
+//
+// The pitch is positive when the chassis leans forward
+#[derive(Clone, Debug)]
+pub struct Pendulum {
+    params: PendulumParams,
+    pitch: f32,
+    pitch_rate: f32,
+    // Velocity (m/s) of the base in the previous step and the acceleration from it
+    prev_vel: Option<f32>,
+    acc: f32,
+}
+
+impl Pendulum {
+    // Step size used to integrate the model
+    const INTEGRATION_STEP_S: f32 = 1.0e-4;
+
+    pub fn new(params: PendulumParams) -> Self {
+        Self {
+            params,
+            pitch: 0.0,
+            pitch_rate: 0.0,
+            prev_vel: None,
+            acc: 0.0,
+        }
+    }
+
+    // unit: rad
+    pub fn pitch(&self) -> f32 {
+        self.pitch
+    }
+
+    // unit: rad/s
+    pub fn pitch_rate(&self) -> f32 {
+        self.pitch_rate
+    }
+
+    pub fn is_fallen(&self) -> bool {
+        self.pitch.abs() >= self.params.max_pitch
+    }
+
+    // Tilt the chassis, ex: a push at the beginning of the test
+    pub fn set_pitch(&mut self, pitch: f32, pitch_rate: f32) {
+        self.pitch = pitch;
+        self.pitch_rate = pitch_rate;
+    }
+
+    // Advance the model with the forward velocity (m/s) of the base at the end of the step
+    pub fn step(&mut self, base_vel: f32, dt: f32) {
+        let prev_vel = self.prev_vel.replace(base_vel).unwrap_or(base_vel);
+        self.acc = (base_vel - prev_vel) / dt;
+
+        let params = &self.params;
+        let steps = (dt / Self::INTEGRATION_STEP_S).round().max(1.0) as usize;
+        let h = dt / steps as f32;
+        for _ in 0..steps {
+            if self.is_fallen() {
+                break;
+            }
+
+            let pitch_acc = (GRAVITY * self.pitch.sin() - self.acc * self.pitch.cos())
+                / params.length
+                - params.damping * self.pitch_rate;
+            self.pitch_rate += pitch_acc * h;
+            self.pitch += self.pitch_rate * h;
+        }
+
+        if self.is_fallen() {
+            self.pitch = self.pitch.clamp(-params.max_pitch, params.max_pitch);
+            self.pitch_rate = 0.0;
+        }
+    }
+
+    // MPU6050 on the chassis, it measures gravity and the acceleration of the base.
+    // Acceleration in g and angular rate in deg/s, the yaw rate is given by the wheels
+    pub fn imu_data(&self, yaw_rate: f32) -> Mpu6050MotionData {
+        let acc = self.acc / GRAVITY;
+        let (sin, cos) = self.pitch.sin_cos();
+        Mpu6050MotionData {
+            acc_x: acc * cos - sin,
+            acc_z: acc * sin + cos,
+            g_y: self.pitch_rate.to_degrees(),
+            g_z: yaw_rate.to_degrees(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plant::PlantParams;
+    use crate::{Simulator, PERIOD_S};
+    use motion_core::balance::BalanceController;
+    use motion_core::config::default_config;
+    use motion_core::pid::Pid;
+    use motion_core::rpm_to_rad_s;
+    use protocol::{BaseTwistCommand, DeviceConfig, Odometry};
+    use s_curve::SCurveInterpolator;
+
+    fn create_simulator(config: &DeviceConfig) -> Simulator {
+        let pid = &config.left.pid;
+        let mut sim = Simulator::new(
+            PlantParams::default(),
+            Pid::new(pid.kp, pid.ki, pid.kd, 1.0),
+            SCurveInterpolator::new(
+                rpm_to_rad_s(config.vel_limit),
+                config.acc_limit,
+                config.jerk_limit,
+                PERIOD_S,
+            ),
+        );
+        sim.motion_mut().apply_config(
+            &config.left,
+            config.vel_limit,
+            config.acc_limit,
+            config.jerk_limit,
+        );
+        sim
+    }
+
+    #[test]
+    fn test_pendulum_should_fall_without_control() {
+        let mut pendulum = Pendulum::new(PendulumParams::default());
+        pendulum.set_pitch(0.01, 0.0);
+        for _ in 0..400 {
+            pendulum.step(0.0, PERIOD_S);
+        }
+        assert!(pendulum.is_fallen());
+        assert_eq!(pendulum.pitch(), PendulumParams::default().max_pitch);
+
+        // The chassis leans backward when the base accelerates forward
+        let mut pendulum = Pendulum::new(PendulumParams::default());
+        pendulum.step(0.0, PERIOD_S);
+        pendulum.step(0.01, PERIOD_S);
+        assert!(pendulum.pitch_rate() < 0.0);
+        assert!(pendulum.imu_data(0.0).acc_x > 0.0);
+    }
+
+    #[test]
+    fn test_balance_controller_should_keep_chassis_upright() {
+        // Balancing needs a stiffer velocity loop of the wheels than the default gains
+        let mut config = default_config();
+        let pid = &mut config.left.pid;
+        (pid.kp, pid.ki, pid.kd) = (pid.kp * 10.0, pid.ki * 10.0, pid.kd * 10.0);
+        let mut left = create_simulator(&config);
+        let mut right = create_simulator(&config);
+        let mut pendulum = Pendulum::new(PendulumParams::default());
+        let mut balance = BalanceController::new(PERIOD_S);
+
+        // The chassis is pushed at the beginning, then the base moves forward
+        pendulum.set_pitch(0.1, 0.0);
+        balance.set_imu_data(pendulum.imu_data(0.0));
+        balance.start(BaseTwistCommand::default(), &Odometry::default());
+        let mut vels = Vec::new();
+        for i in 0..3000 {
+            if i == 1000 {
+                let twist = BaseTwistCommand {
+                    linear: 0.3,
+                    angular: 0.0,
+                };
+                balance.start(twist, &Odometry::default());
+            }
+
+            balance.set_imu_data(pendulum.imu_data(0.0));
+            assert_eq!(
+                balance.run(&config, left.motion_mut(), right.motion_mut()),
+                None
+            );
+            left.step();
+            right.step();
+            let wheel_vel = (left.plant().velocity() + right.plant().velocity()) / 2.0;
+            pendulum.step(wheel_vel * config.base.wheel_radius, PERIOD_S);
+            if i >= 2000 {
+                vels.push(wheel_vel * config.base.wheel_radius);
+            }
+        }
+        assert!(pendulum.pitch().abs() < 0.1);
+        let avg_vel = vels.iter().sum::<f32>() / vels.len() as f32;
+        assert!((avg_vel - 0.3).abs() < 0.05);
+    }
+}
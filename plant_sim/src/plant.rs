@@ -0,0 +1,245 @@
+use std::f32::consts::PI;
+
+use motion_core::hal::EdgeTiming;
+
+// Physical parameters of motor and wheel. The default values are rough estimations of
+// Nidec 24H (24V, ~4000 rpm without load) with the wheel and a share of the robot mass
+#[derive(Clone, Copy, Debug)]
+pub struct PlantParams {
+    // Supply voltage (V)
+    pub supply_voltage: f32,
+    // Winding resistance (ohm) and inductance (H)
+    pub resistance: f32,
+    pub inductance: f32,
+    // Back-EMF constant (V / (rad/s)), it is also used as torque constant (Nm / A)
+    pub k_emf: f32,
+    // Inertia of rotor and wheel (kg m^2)
+    pub inertia: f32,
+    // Viscous friction (Nm / (rad/s)) and Coulomb friction (Nm)
+    pub viscous_friction: f32,
+    pub coulomb_friction: f32,
+    // Torque applied by the brake of the inbuilt driver (Nm)
+    pub brake_torque: f32,
+    // Encoder counts per revolution (after quadrature decoding)
+    pub counts_per_rev: u16,
+}
+
+impl Default for PlantParams {
+    fn default() -> Self {
+        Self {
+            supply_voltage: 24.0,
+            resistance: 2.0,
+            inductance: 0.001,
+            k_emf: 24.0 / (4000.0 * 2.0 * PI / 60.0),
+            inertia: 2.0e-4,
+            viscous_friction: 2.0e-6,
+            coulomb_friction: 2.0e-3,
+            brake_torque: 0.05,
+            counts_per_rev: 400,
+        }
+    }
+}
+
+// Signals that are driven by the controller
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DriverInput {
+    // Duty cycle (%) on the PWM pin. The driver of 24H is active low:
+    // 0% duty: full speed, 100% duty: 0 speed
+    pub pwm_pin_duty: u8,
+    // Direction pin, low: positive direction, high: negative direction
+    pub dir_high: bool,
+    // The brake is applied when the pin is low or a pulse is seen in the last step
+    pub brake_low: bool,
+    pub brake_pulsed: bool,
+}
+
+impl Default for DriverInput {
+    fn default() -> Self {
+        Self {
+            pwm_pin_duty: 100,
+            dir_high: true,
+            brake_low: false,
+            brake_pulsed: false,
+        }
+    }
+}
+
+// First order electrical and mechanical model of motor:
+//
+//   L di/dt = u - R i - k_emf w
+//   J dw/dt = k_emf i - b w - friction - brake
+#[derive(Clone, Debug)]
+pub struct Plant {
+    params: PlantParams,
+    current: f32,
+    velocity: f32,
+    position: f32,
+    // Simulated time and the time when the encoder count changed last time (s)
+    time_s: f64,
+    last_edge_time_s: f64,
+}
+
+impl Plant {
+    // Step size used to integrate the model, it is much smaller than the electrical time
+    // constant (L / R) to keep forward Euler stable
+    const INTEGRATION_STEP_S: f32 = 1.0e-5;
+
+    pub fn new(params: PlantParams) -> Self {
+        Self {
+            params,
+            current: 0.0,
+            velocity: 0.0,
+            position: 0.0,
+            time_s: 0.0,
+            last_edge_time_s: 0.0,
+        }
+    }
+
+    pub fn params(&self) -> &PlantParams {
+        &self.params
+    }
+
+    // Velocity of the shaft (rad/s)
+    pub fn velocity(&self) -> f32 {
+        self.velocity
+    }
+
+    // Position of the shaft (rad)
+    pub fn position(&self) -> f32 {
+        self.position
+    }
+
+    pub fn current(&self) -> f32 {
+        self.current
+    }
+
+    // Raw encoder count, the count is quantized and wraps around like the QEI counter
+    pub fn encoder_count(&self) -> u16 {
+        self.counts() as u16
+    }
+
+    // Time of the latest encoder edge and the current time (us), they are what a timer
+    // capture channel sees, so they wrap around like a 32-bit timer
+    pub fn encoder_edge_timing(&self) -> EdgeTiming {
+        EdgeTiming {
+            last_edge_us: (self.last_edge_time_s * 1.0e6) as u64 as u32,
+            sample_us: (self.time_s * 1.0e6) as u64 as u32,
+        }
+    }
+
+    pub fn step(&mut self, input: &DriverInput, dt: f32) {
+        let p = self.params;
+
+        let speed_ratio = 1.0 - input.pwm_pin_duty.min(100) as f32 / 100.0;
+        let dir = if input.dir_high { -1.0 } else { 1.0 };
+        let brake = input.brake_low || input.brake_pulsed;
+        // The driver stops powering the winding when the brake is applied
+        let voltage = if brake {
+            0.0
+        } else {
+            dir * speed_ratio * p.supply_voltage
+        };
+
+        let steps = (dt / Self::INTEGRATION_STEP_S).ceil().max(1.0) as usize;
+        let h = dt / steps as f32;
+        for _ in 0..steps {
+            let prev_counts = self.counts();
+
+            let di =
+                (voltage - p.resistance * self.current - p.k_emf * self.velocity) / p.inductance;
+            self.current += di * h;
+
+            let drive_torque = p.k_emf * self.current - p.viscous_friction * self.velocity;
+            let resist_torque = p.coulomb_friction + if brake { p.brake_torque } else { 0.0 };
+            self.velocity =
+                Self::apply_friction(self.velocity, drive_torque, resist_torque, p.inertia, h);
+            self.position += self.velocity * h;
+
+            self.time_s += h as f64;
+            if self.counts() != prev_counts {
+                self.last_edge_time_s = self.time_s;
+            }
+        }
+    }
+
+    fn counts(&self) -> i64 {
+        (self.position / (2.0 * PI) * self.params.counts_per_rev as f32).floor() as i64
+    }
+
+    // Integrate velocity with a friction torque that opposes motion, the wheel sticks
+    // when it stands still and the drive torque can't overcome the friction
+    fn apply_friction(
+        velocity: f32,
+        drive_torque: f32,
+        friction: f32,
+        inertia: f32,
+        h: f32,
+    ) -> f32 {
+        if velocity == 0.0 && drive_torque.abs() <= friction {
+            return 0.0;
+        }
+
+        let direction = if velocity != 0.0 {
+            velocity.signum()
+        } else {
+            drive_torque.signum()
+        };
+        let next = velocity + (drive_torque - direction * friction) / inertia * h;
+
+        // Friction can stop the wheel but it can't reverse it
+        if next * direction < 0.0 {
+            0.0
+        } else {
+            next
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(plant: &mut Plant, input: &DriverInput, duration_s: f32) {
+        for _ in 0..(duration_s / 0.005) as usize {
+            plant.step(input, 0.005);
+        }
+    }
+
+    #[test]
+    fn test_step_should_reach_no_load_speed_with_inverted_pwm() {
+        let mut plant = Plant::new(PlantParams::default());
+        let input = DriverInput {
+            pwm_pin_duty: 0,
+            dir_high: false,
+            ..Default::default()
+        };
+        run(&mut plant, &input, 2.0);
+
+        // Friction reduces the no-load speed a little
+        let rpm = plant.velocity() * 60.0 / (2.0 * PI);
+        assert!(rpm > 3800.0 && rpm < 4000.0, "{rpm}");
+
+        // 100% duty on the pin stops the motor, and it stays still because of friction
+        let input = DriverInput {
+            pwm_pin_duty: 100,
+            ..input
+        };
+        run(&mut plant, &input, 2.0);
+        assert_eq!(plant.velocity(), 0.0);
+    }
+
+    #[test]
+    fn test_encoder_count_should_be_quantized_and_wrap_around() {
+        let mut plant = Plant::new(PlantParams::default());
+        let input = DriverInput {
+            pwm_pin_duty: 50,
+            dir_high: true,
+            ..Default::default()
+        };
+        plant.step(&input, 0.5);
+
+        let counts = plant.position() / (2.0 * PI) * 400.0;
+        assert!(counts < 0.0);
+        assert_eq!(plant.encoder_count(), counts.floor() as i64 as u16);
+    }
+}
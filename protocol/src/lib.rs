@@ -1,18 +1,48 @@
 #![cfg_attr(not(feature = "use-std"), no_std)]
 
-use postcard_rpc::{endpoints, topics, TopicDirection};
+use postcard_rpc::{TopicDirection, endpoints, topics};
 use postcard_schema::Schema;
 use serde::{Deserialize, Serialize};
 
 pub type CommandSetResult = Result<(), CommandError>;
+pub type ConfigSetResult = Result<(), ConfigError>;
+// Number of commands appended to each chunk, in the order of the request
+pub type AppendResult = Result<[u8; 2], CommandError>;
+pub type ProgramResult = Result<(), ProgramError>;
+pub type CalibrationResult = Result<ImuCalibration, CalibrationError>;
+
+// Maximum number of position commands in `PositionChunk`, a request with 2 full chunks
+// needs to fit in the receive buffer of the board
+pub const POSITION_CHUNK_SIZE: usize = 8;
+
+// Maximum number of steps in `MotionProgram` and length of its name
+pub const MOTION_PROGRAM_SIZE: usize = 32;
+pub const PROGRAM_NAME_SIZE: usize = 16;
+
+// Version of `DeviceConfig` layout, it needs to be increased when `DeviceConfig` is
+// changed, so the record stored with previous layout will not be loaded
+pub const DEVICE_CONFIG_VERSION: u16 = 12;
 
 endpoints! {
     list = ENDPOINT_LIST;
     omit_std = true;
-    | EndpointTy                  | RequestTy                     | ResponseTy              | Path               |
-    | ----------                  | ----------                    | ----------              | ----------         |
-    | SetMotorCommandEndPoint     | (MotorId, MotorCommand)       | CommandSetResult        | "motor_cmd/set"    |
-    | SetMotorCommandsEndPoint    | [(MotorId, MotorCommand); 2]  | CommandSetResult        | "motor_cmds/set"   |
+    | EndpointTy                  | RequestTy                         | ResponseTy              | Path               |
+    | ----------                  | ----------                        | ----------              | ----------         |
+    | SetMotorCommandEndPoint     | (MotorId, SequencedCommand)       | CommandSetResult        | "motor_cmd/set"    |
+    | SetMotorCommandsEndPoint    | [(MotorId, SequencedCommand); 2]  | CommandSetResult        | "motor_cmds/set"   |
+    | AppendPositionsEndPoint     | [(MotorId, PositionChunk); 2]     | AppendResult            | "positions/append" |
+    | UploadProgramEndPoint       | (MotorId, MotionProgram)          | ProgramResult           | "program/upload"   |
+    | ControlProgramEndPoint      | (MotorId, ProgramControl)         | ProgramResult           | "program/control"  |
+    | SetBaseTwistEndPoint        | BaseTwistCommand                  | CommandSetResult        | "base/twist"       |
+    | ResetPoseEndPoint           | Pose                              | ()                      | "base/pose/reset"  |
+    | SetHeadingCommandEndPoint   | HeadingCommand                    | CommandSetResult        | "base/heading"     |
+    | SetBalanceCommandEndPoint   | BaseTwistCommand                  | CommandSetResult        | "base/balance"     |
+    | GetConfigEndPoint           | ()                                | DeviceConfig            | "config/get"       |
+    | SetConfigEndPoint           | DeviceConfig                      | ConfigSetResult         | "config/set"       |
+    | SaveConfigEndPoint          | ()                                | ConfigSetResult         | "config/save"      |
+    | ResetConfigEndPoint         | ()                                | ConfigSetResult         | "config/reset"     |
+    | CalibrateImuEndPoint        | CalibrateImuRequest               | CalibrationResult       | "imu/calibrate"    |
+    | SetImuConfigEndPoint        | ImuConfig                         | ConfigSetResult         | "imu/config"       |
 }
 
 topics! {
@@ -23,7 +53,6 @@ topics! {
     | ----------                  | ----------                    | ----------    |
 }
 
-
 topics! {
     list = TOPICS_OUT_LIST;
     direction = TopicDirection::ToClient;
@@ -32,9 +61,10 @@ topics! {
     | ----------                  | ----------                          | ----------      | ----------         |
     | MotorProcessDataTopic       | [(MotorId, MotorProcessData); 2]    | "motor/data"    |                    |
     | Mpu6050MotionDataTopic      | Mpu6050MotionData                   | "mpu6050/data"  |                    |
+    | DeviceEventTopic            | DeviceEvent                         | "device/event"  |                    |
+    | OdometryTopic               | Odometry                            | "base/odometry" |                    |
 }
 
-
 #[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
 pub enum ControlMode {
     Position,
@@ -42,12 +72,291 @@ pub enum ControlMode {
     Velocity,
     StandStill,
     Pid,
+    // The axis is stopped because of a fault, it is latched until `ResetFault` is received
+    Fault,
+    // The velocity is set by the heading controller of the base, both wheels are in this
+    // mode until a command is sent to one of them
+    Heading,
+    // The velocity is set by the balance controller, it keeps the chassis upright on both
+    // wheels
+    Balance,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum FaultReason {
+    // The gap between interpolated and actual position stays outside the window
+    PositionFollowingError,
+    // The gap between velocity set point and actual velocity stays outside the window
+    VelocityFollowingError,
+    // The control effort is high but the motor doesn't turn
+    Stall,
+    // The motor turns against the set point or faster than the limit, ex: swapped encoder
+    // pair
+    Runaway,
 }
 
 #[derive(Serialize, Deserialize, Schema, Debug, PartialEq)]
 pub enum CommandError {
     // The motor id is set as bits
     BufferFull(u8),
+    // The axis is in fault state, only `Halt` and `ResetFault` are accepted. The motor id
+    // is set as bits
+    Fault(u8),
+    // Some values of the command are out of range, ex: the cycles of auto-tuning. The
+    // motor id is set as bits
+    InvalidCommand(u8),
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq)]
+pub enum ConfigError {
+    // Some values in the configuration are out of range
+    InvalidConfig,
+    // Failed to write or erase the configuration in flash
+    FlashError,
+    // The motors are moving, the configuration is saved or reset only when both axes are
+    // at rest, since writing flash stalls the CPU and the motion task
+    NotAtRest,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq)]
+pub enum CalibrationError {
+    // The motors are moving, the board needs to stay still during the calibration
+    NotAtRest,
+    // Failed to access MPU6050 through I2C
+    I2cError,
+    // The offsets are applied but failed to be written in flash
+    FlashError,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq)]
+pub enum ProgramError {
+    // The steps are out of range, ex: a loop jumps forward
+    InvalidProgram,
+    // There is no program uploaded to the motor
+    NoProgram,
+    // The program of the motor is running, it needs to be stopped before uploading
+    Running,
+    // The axis is in fault state, the program can't be started
+    Fault,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct PidGains {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+}
+
+// Method used to estimate velocity from encoder counts
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub enum VelocityEstimator {
+    // Count difference in one control period, the resolution is 30 rpm with 400 counts/rev
+    // and 5 ms period
+    #[default]
+    Difference,
+    // Count difference over the last N control periods
+    MovingWindow(u8),
+    // M/T method, counts are divided by the time between encoder edges captured by timer.
+    // The moving window is used if the encoder input can't capture edge timing. The board
+    // rejects it because its encoder timers run in QEI mode without input capture
+    MT,
+    // PLL tracking observer, the value is the bandwidth (unit: Hz). It also estimates
+    // acceleration, which is published as `MotorProcessData::actual_acc`
+    TrackingObserver(f32),
+}
+
+// Mechanical configuration of an axis. Positions and velocities of commands and
+// `MotorProcessData` are in axis units: output radians multiplied by `linear_scale`, and
+// axis units per second, so they are output rad and rad/s when `linear_scale` is 1.0.
+// Motion limits and auto-tune commands stay on the motor side
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct MechanicalConfig {
+    // Encoder lines per motor revolution, the counts per revolution seen by the counter
+    // is `counts_per_rev * quadrature_multiplier`
+    pub counts_per_rev: u16,
+    // 1, 2 or 4, decided by the edges counted by the quadrature decoder
+    pub quadrature_multiplier: u8,
+    // Motor revolutions per output revolution
+    pub gear_ratio: f32,
+    // Reverse the positive direction of the axis
+    pub invert: bool,
+    // Axis units per output radian, ex: wheel radius (m) to command positions in metres
+    pub linear_scale: f32,
+}
+
+impl Default for MechanicalConfig {
+    fn default() -> Self {
+        Self {
+            counts_per_rev: 100,
+            quadrature_multiplier: 4,
+            gear_ratio: 1.0,
+            invert: false,
+            linear_scale: 1.0,
+        }
+    }
+}
+
+// Following error limits of an axis, the windows are in axis units. The axis faults when
+// the error stays outside the window longer than the filter time, a window of 0 disables
+// the check
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct FollowingErrorConfig {
+    pub pos_window: f32,
+    pub pos_filter_ms: u16,
+    pub vel_window: f32,
+    pub vel_filter_ms: u16,
+}
+
+// Protection of motor drive, the PWM is cut and the brake is applied when the motor stalls
+// or runs away. The velocities are motor-side rpm, and a time of 0 disables the check
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct DriveProtectionConfig {
+    // Stall: the control effort (0.0 ~ 1.0) is above `stall_effort` while the speed is
+    // below `stall_vel`
+    pub stall_effort: f32,
+    pub stall_vel: f32,
+    pub stall_time_ms: u16,
+    // Runaway: the velocity is opposite to the set point, or the speed is above the
+    // velocity limit, by more than `runaway_margin`
+    pub runaway_margin: f32,
+    pub runaway_time_ms: u16,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct AxisConfig {
+    pub pid: PidGains,
+    pub vel_estimator: VelocityEstimator,
+    pub mechanical: MechanicalConfig,
+    pub following_error: FollowingErrorConfig,
+    pub drive_protection: DriveProtectionConfig,
+}
+
+// Geometry of the two-wheel base, it is used to convert `BaseTwistCommand` to wheel
+// velocities, unit: m. The heading controller settings are in rad
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct BaseConfig {
+    pub wheel_radius: f32,
+    // Distance between the contact points of the wheels
+    pub track_width: f32,
+    // Weight (0.0 ~ 1.0) of MPU6050 gyro in the heading change of odometry, the rest is
+    // from the wheels. 0.0 disables the gyro
+    pub gyro_weight: f32,
+    // Yaw rate (rad/s) per rad of heading error
+    pub heading_kp: f32,
+    // Limits of the angular motion of `HeadingCommand::RotateTo`, unit: rad/s^2, rad/s^3
+    pub angular_acc_limit: f32,
+    pub angular_jerk_limit: f32,
+}
+
+// Gains and limits of the balance controller. The wheels are accelerated by the pitch,
+// pitch rate and the error of base velocity, unit: m/s^2 per rad, rad/s and m/s. The
+// pitch is positive when the chassis leans forward
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct BalanceConfig {
+    pub kp_pitch: f32,
+    pub kd_pitch: f32,
+    pub kp_vel: f32,
+    // Pitch of the balance point, the center of mass is not above the wheel axle, unit: rad
+    pub pitch_offset: f32,
+    // The wheels are halted when the pitch is beyond the angle, unit: rad
+    pub max_tilt: f32,
+    // Time constant of the complementary filter, the gyro drift is corrected by the
+    // accelerometer slower than it, unit: s
+    pub filter_time_constant: f32,
+}
+
+// Configuration of MPU6050, it is applied right away
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct ImuConfig {
+    // Output rate of the DMP orientation, it is changed with the sample rate divider of
+    // MPU6050, unit: Hz
+    pub dmp_rate_hz: u16,
+    // Rate that the data is read and published, MPU6050 samples at the faster of it and
+    // `dmp_rate_hz`. The DMP only works up to 200 Hz, so the orientation is `None` when
+    // MPU6050 samples faster, unit: Hz
+    pub sample_rate_hz: u16,
+    pub accel_range: AccelRange,
+    pub gyro_range: GyroRange,
+    pub dlpf: DlpfBandwidth,
+}
+
+// Full-scale range of the accelerometer, unit: g
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub enum AccelRange {
+    #[default]
+    G2,
+    G4,
+    G8,
+    G16,
+}
+
+// Full-scale range of the gyro, unit: deg/s
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub enum GyroRange {
+    Deg250,
+    Deg500,
+    Deg1000,
+    #[default]
+    Deg2000,
+}
+
+// Bandwidth of the digital low-pass filter of MPU6050 (accel/gyro), the filter is always
+// on, so the sensors are sampled at 1 kHz
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub enum DlpfBandwidth {
+    Hz184,
+    Hz94,
+    #[default]
+    Hz44,
+    Hz21,
+    Hz10,
+    Hz5,
+}
+
+// Axis and sign of gravity when the board rests during the calibration, same as
+// `ReferenceGravity` of `mpu6050-dmp`. `Zero` is used when the gravity is not measured
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub enum ReferenceGravity {
+    Zero,
+    XN,
+    XP,
+    YN,
+    YP,
+    #[default]
+    ZN,
+    ZP,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct CalibrateImuRequest {
+    pub gravity: ReferenceGravity,
+    // The offsets are written to `DeviceConfig` and saved in flash for the next boot,
+    // otherwise they are only applied until reboot
+    pub persist: bool,
+}
+
+// MPU6050 offsets found by the calibration, (x, y, z)
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct ImuCalibration {
+    pub accel: (i16, i16, i16),
+    pub gyro: (i16, i16, i16),
+}
+
+// Configuration of the board, it is loaded from flash when the board boots
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct DeviceConfig {
+    pub left: AxisConfig,
+    pub right: AxisConfig,
+    // Limits of position interpolation, unit: rpm, rad/s^2, rad/s^3
+    pub vel_limit: f32,
+    pub acc_limit: f32,
+    pub jerk_limit: f32,
+    // MPU6050 offsets obtained from the calibration process, (x, y, z)
+    pub accel_calibration: (i16, i16, i16),
+    pub gyro_calibration: (i16, i16, i16),
+    pub base: BaseConfig,
+    pub balance: BalanceConfig,
+    pub imu: ImuConfig,
 }
 
 #[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
@@ -57,10 +366,77 @@ pub struct PositionCommand {
     pub vel_end: f32,
 }
 
+// Velocity of the two-wheel base, the board converts it to velocity commands of both
+// wheels in the same control cycle
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct BaseTwistCommand {
+    // Forward velocity, unit: m/s
+    pub linear: f32,
+    // Yaw rate, counterclockwise is positive, unit: rad/s
+    pub angular: f32,
+}
+
+// Commands of the heading controller, the heading is in the odometry frame, unit: m/s, rad,
+// rad/s
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum HeadingCommand {
+    // Drive straight and hold the heading, the current heading is held if it is `None`
+    Hold { linear: f32, heading: Option<f32> },
+    // Rotate in place to the heading with S-curve limited angular motion, then the heading
+    // is held
+    RotateTo { heading: f32, vel_max: f32 },
+}
+
+// Pose of the two-wheel base in the odometry frame, the frame is decided by the pose given
+// to `ResetPoseEndPoint`. unit: m, rad
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct Pose {
+    pub x: f32,
+    pub y: f32,
+    // Heading, counterclockwise is positive, it is wrapped to -pi ~ pi
+    pub theta: f32,
+}
+
+// Wheel odometry integrated by the board in every control cycle
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct Odometry {
+    pub pose: Pose,
+    // Body velocities, unit: m/s, rad/s
+    pub linear: f32,
+    pub angular: f32,
+    // Gyro Z bias estimated when the base is stationary, unit: rad/s
+    pub gyro_bias: f32,
+    // The heading is fused with gyro, it is false when the gyro data is not received
+    pub gyro_fused: bool,
+    // Target heading minus the heading, it is `None` when the heading controller is off
+    pub heading_error: Option<f32>,
+    // Pitch of the chassis estimated from MPU6050, forward is positive, unit: rad
+    pub pitch: f32,
+}
+
+// Tuning rules used to convert ultimate gain (Ku) and ultimate period (Tu) from the
+// relay test into PID gains
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub enum TuningRule {
+    ZieglerNichols,
+    TyreusLuyben,
+    PessenIntegral,
+    SomeOvershoot,
+    #[default]
+    NoOvershoot,
+    PiOnly,
+}
+
 #[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
 pub struct AutoTuneCommand {
     pub set_point: f32,
     pub output_limit: f32,
+    // Hysteresis band around `set_point` (unit: rpm), the relay only switches when the
+    // velocity leaves the band
+    pub hysteresis: f32,
+    // Number of oscillation cycles that are averaged to get Ku and Tu
+    pub cycles: u8,
+    pub rule: TuningRule,
     pub start: bool,
 }
 
@@ -77,6 +453,137 @@ pub enum MotorCommand {
     VelocityCommand(f32),
     PositionCommand(PositionCommand),
     AutoTuneCommand(AutoTuneCommand),
+    // Clear the latched fault, the axis enters `StandStill`
+    ResetFault,
+    // Wait for the time (unit: ms) after the previous commands are done
+    Dwell(u32),
+    // Wait until the other axis also reaches `WaitForOtherAxis`, both axes take their next
+    // commands in the same control cycle
+    WaitForOtherAxis,
+    // Report `DeviceEventKind::MarkerReached` when the previous commands are done
+    Marker(u32),
+}
+
+// Motor command with a sequence id assigned by the host, the id is reported in
+// `MotorProcessData` and the motion events, so the host can follow the command
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct SequencedCommand {
+    pub id: u32,
+    pub cmd: MotorCommand,
+}
+
+// The command is not followed by the host, the id is 0
+impl From<MotorCommand> for SequencedCommand {
+    fn from(cmd: MotorCommand) -> Self {
+        Self { id: 0, cmd }
+    }
+}
+
+// Position commands that are appended to the command queue of a motor without the other
+// commands in between, the board appends as many commands as the queue can hold and the
+// rest are sent again by the host. The command `i` gets sequence id `first_id + i`
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct PositionChunk {
+    pub first_id: u32,
+    pub len: u8,
+    pub cmds: [PositionCommand; POSITION_CHUNK_SIZE],
+}
+
+impl PositionChunk {
+    pub fn new(first_id: u32, cmds: &[PositionCommand]) -> Self {
+        let len = cmds.len().min(POSITION_CHUNK_SIZE);
+        let mut chunk = Self {
+            first_id,
+            len: len as u8,
+            ..Default::default()
+        };
+        chunk.cmds[..len].copy_from_slice(&cmds[..len]);
+        chunk
+    }
+
+    pub fn sequenced_cmds(&self) -> impl Iterator<Item = SequencedCommand> + '_ {
+        let len = (self.len as usize).min(POSITION_CHUNK_SIZE);
+        self.cmds[..len]
+            .iter()
+            .enumerate()
+            .map(|(i, x)| SequencedCommand {
+                id: self.first_id.wrapping_add(i as u32),
+                cmd: MotorCommand::PositionCommand(*x),
+            })
+    }
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum ProgramStep {
+    Position(PositionCommand),
+    // The velocity step is done when the velocity is reached, it is followed by `Dwell`
+    // to keep the velocity for a while
+    Velocity(f32),
+    // Wait until the previous steps are done, then wait for the time (unit: ms)
+    Dwell(u32),
+    // Jump back to `target` step `count` times, the loop never ends if `count` is 0
+    Loop { target: u8, count: u16 },
+}
+
+impl Default for ProgramStep {
+    fn default() -> Self {
+        Self::Dwell(0)
+    }
+}
+
+// Program of a motor that is stored in the board, it is run by the board without the
+// host sending commands
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct MotionProgram {
+    // UTF-8 name, padded with 0
+    pub name: [u8; PROGRAM_NAME_SIZE],
+    pub len: u8,
+    pub steps: [ProgramStep; MOTION_PROGRAM_SIZE],
+}
+
+impl MotionProgram {
+    // The name and steps are truncated if they are too long
+    pub fn new(name: &str, steps: &[ProgramStep]) -> Self {
+        let mut program = Self::default();
+        let name_len = name.len().min(PROGRAM_NAME_SIZE);
+        program.name[..name_len].copy_from_slice(&name.as_bytes()[..name_len]);
+
+        let len = steps.len().min(MOTION_PROGRAM_SIZE);
+        program.len = len as u8;
+        program.steps[..len].copy_from_slice(&steps[..len]);
+        program
+    }
+
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|x| *x == 0)
+            .unwrap_or(PROGRAM_NAME_SIZE);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+
+    pub fn steps(&self) -> &[ProgramStep] {
+        &self.steps[..(self.len as usize).min(MOTION_PROGRAM_SIZE)]
+    }
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum ProgramControl {
+    // Run the program once
+    Start,
+    // Run the program again and again until it is stopped
+    Loop,
+    // Stop the program and halt the motor
+    Stop,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct ProgramStatus {
+    // Step that is running
+    pub step: u8,
+    // Number of finished runs when the program is looped
+    pub cycles: u32,
 }
 
 #[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
@@ -84,13 +591,30 @@ pub struct MotorProcessData {
     pub control_mode_display: ControlMode,
     pub actual_pos: f32,
     pub actual_vel: f32,
+    // Estimated by the tracking observer, it is 0 for the other velocity estimators
+    pub actual_acc: f32,
     pub intp_pos: f32,
     pub intp_vel: f32,
     pub intp_acc: f32,
     pub intp_jerk: f32,
+    pub fault: Option<FaultReason>,
+    // Sequence id of the command that is running, it is `None` when the command is done
+    pub active_cmd_id: Option<u32>,
+    // Credits of the command queue: free entries and number of commands received (wrapping).
+    // The commands sent by the host that are not received yet take the free entries, so the
+    // host can send `queue_free - (sent - received_cmds)` commands without `BufferFull`
+    pub queue_free: u8,
+    pub received_cmds: u32,
+    // Status of the program stored in the board, it is `None` when the program is not running
+    pub program: Option<ProgramStatus>,
+    // Time of the control cycle since boot, unit: us
+    pub timestamp_us: u64,
+    // Counter of the control cycle (wrapping), the data of some cycles is not published
+    // when the link is busy, so the host finds them by the gap of the counter
+    pub cycle: u32,
 }
 
-#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Default)]
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
 pub struct Mpu6050MotionData {
     pub acc_x: f32,
     pub acc_y: f32,
@@ -98,11 +622,91 @@ pub struct Mpu6050MotionData {
     pub g_x: f32,
     pub g_y: f32,
     pub g_z: f32,
+    // The latest orientation from the DMP, it is `None` until the first packet is read
+    pub orientation: Option<Orientation>,
+    // The values are 0 when MPU6050 is not healthy
+    pub health: ImuHealth,
+    // Time of the read since boot, unit: us. It is on the same clock as the timestamp of
+    // `MotorProcessData`, so the data can be aligned
+    pub timestamp_us: u64,
+    // Counter of the samples (wrapping), it increases in every sample period even if the
+    // read fails
+    pub sample: u32,
+}
+
+// Health of MPU6050, the motors are controlled regardless of it
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub enum ImuHealth {
+    #[default]
+    Ok,
+    // The data can't be read through I2C, MPU6050 is initialized again after the bus is
+    // recovered when the error persists
+    Error,
+    // MPU6050 doesn't respond to the initialization, it is retried with backoff
+    Absent,
+}
+
+// Unit quaternion of the orientation
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct Quaternion {
+    pub w: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+// Orientation of MPU6050 from the DMP quaternion, the angles are in yaw-pitch-roll (ZYX)
+// order, unit: rad. The pitch is positive when +x of MPU6050 points down
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy, Default)]
+pub struct Orientation {
+    pub quaternion: Quaternion,
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub enum DeviceEventKind {
+    // The connection is lost, the motors are halted
+    ConnectionLost,
+    FaultRaised(FaultReason),
+    FaultCleared,
+    // The command with the sequence id is taken from the queue and applied
+    MotionStarted(u32),
+    // The command with the sequence id is done: the position is reached, the velocity
+    // reaches the set point, auto-tuning or halt is finished
+    MotionComplete(u32),
+    // The command with the sequence id is replaced by the next command, or it is
+    // dropped by halt or fault before it is done
+    MotionAborted(u32),
+    // The new gains, it is `None` if the run is rejected
+    AutoTuneFinished(Option<PidGains>),
+    // The health of MPU6050 is changed, ex: the data can't be read through I2C
+    ImuHealthChanged(ImuHealth),
+    // A motion command is rejected because the queue is full
+    QueueOverflow,
+    // `MotorCommand::Marker` is reached
+    MarkerReached(u32),
+    // The balance controller halts the wheels, the chassis tilts beyond
+    // `BalanceConfig::max_tilt` or MPU6050 data is not received
+    BalanceCutoff,
+}
+
+// Event reported by the device, `motor` is `None` for the events of the board
+#[derive(Serialize, Deserialize, Schema, Debug, PartialEq, Clone, Copy)]
+pub struct DeviceEvent {
+    // Time since the device boots, unit: ms
+    pub timestamp_ms: u32,
+    pub motor: Option<MotorId>,
+    pub kind: DeviceEventKind,
 }
 
 #[cfg(feature = "use-std")]
 mod display_impl {
-    use super::ControlMode;
+    use super::{
+        CalibrationError, ControlMode, DeviceEventKind, FaultReason, ImuHealth, ReferenceGravity,
+        TuningRule, VelocityEstimator,
+    };
     use std::fmt::Display;
 
     impl Display for ControlMode {
@@ -112,7 +716,106 @@ mod display_impl {
                 ControlMode::Velocity => write!(f, "Velocity"),
                 ControlMode::StandStill => write!(f, "StandStill"),
                 ControlMode::Pid => write!(f, "Pid"),
+                ControlMode::Fault => write!(f, "Fault"),
+                ControlMode::Heading => write!(f, "Heading"),
+                ControlMode::Balance => write!(f, "Balance"),
+            }
+        }
+    }
+
+    impl Display for FaultReason {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                FaultReason::PositionFollowingError => write!(f, "PositionFollowingError"),
+                FaultReason::VelocityFollowingError => write!(f, "VelocityFollowingError"),
+                FaultReason::Stall => write!(f, "Stall"),
+                FaultReason::Runaway => write!(f, "Runaway"),
+            }
+        }
+    }
+
+    impl Display for DeviceEventKind {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                DeviceEventKind::ConnectionLost => write!(f, "Connection lost, motors halted"),
+                DeviceEventKind::FaultRaised(reason) => write!(f, "Fault raised: {reason}"),
+                DeviceEventKind::FaultCleared => write!(f, "Fault cleared"),
+                DeviceEventKind::MotionStarted(id) => write!(f, "Motion {id} started"),
+                DeviceEventKind::MotionComplete(id) => write!(f, "Motion {id} complete"),
+                DeviceEventKind::MotionAborted(id) => write!(f, "Motion {id} aborted"),
+                DeviceEventKind::AutoTuneFinished(Some(gains)) => write!(
+                    f,
+                    "Auto-tune finished, kp: {}, ki: {}, kd: {}",
+                    gains.kp, gains.ki, gains.kd
+                ),
+                DeviceEventKind::AutoTuneFinished(None) => {
+                    write!(f, "Auto-tune rejected, oscillation is not consistent")
+                }
+                DeviceEventKind::ImuHealthChanged(x) => write!(f, "MPU6050 health: {x}"),
+                DeviceEventKind::QueueOverflow => write!(f, "Command queue overflow"),
+                DeviceEventKind::MarkerReached(x) => write!(f, "Marker {x} reached"),
+                DeviceEventKind::BalanceCutoff => write!(f, "Balance cutoff, wheels halted"),
+            }
+        }
+    }
+
+    impl Display for TuningRule {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                TuningRule::ZieglerNichols => write!(f, "ZieglerNichols"),
+                TuningRule::TyreusLuyben => write!(f, "TyreusLuyben"),
+                TuningRule::PessenIntegral => write!(f, "PessenIntegral"),
+                TuningRule::SomeOvershoot => write!(f, "SomeOvershoot"),
+                TuningRule::NoOvershoot => write!(f, "NoOvershoot"),
+                TuningRule::PiOnly => write!(f, "PiOnly"),
+            }
+        }
+    }
+
+    impl Display for ImuHealth {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ImuHealth::Ok => write!(f, "Ok"),
+                ImuHealth::Error => write!(f, "I2C error"),
+                ImuHealth::Absent => write!(f, "Absent"),
+            }
+        }
+    }
+
+    impl Display for ReferenceGravity {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ReferenceGravity::Zero => write!(f, "Zero"),
+                ReferenceGravity::XN => write!(f, "-X"),
+                ReferenceGravity::XP => write!(f, "+X"),
+                ReferenceGravity::YN => write!(f, "-Y"),
+                ReferenceGravity::YP => write!(f, "+Y"),
+                ReferenceGravity::ZN => write!(f, "-Z"),
+                ReferenceGravity::ZP => write!(f, "+Z"),
             }
         }
     }
-}
\ No newline at end of file
+
+    impl Display for CalibrationError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                CalibrationError::NotAtRest => write!(f, "Motors are moving"),
+                CalibrationError::I2cError => write!(f, "MPU6050 I2C error"),
+                CalibrationError::FlashError => write!(f, "Failed to save offsets in flash"),
+            }
+        }
+    }
+
+    impl Display for VelocityEstimator {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                VelocityEstimator::Difference => write!(f, "Difference"),
+                VelocityEstimator::MovingWindow(periods) => write!(f, "MovingWindow({periods})"),
+                VelocityEstimator::MT => write!(f, "MT"),
+                VelocityEstimator::TrackingObserver(bandwidth) => {
+                    write!(f, "TrackingObserver({bandwidth} Hz)")
+                }
+            }
+        }
+    }
+}
@@ -92,6 +92,13 @@ impl SCurveInterpolator {
         }
     }
 
+    pub fn set_constraint(&mut self, vel_limit: f32, acc_limit: f32, jerk_limit: f32) {
+        // The new constraint is used when the next target is set
+        self.motion_constraint.vel_limit = vel_limit;
+        self.motion_constraint.acc_limit = acc_limit;
+        self.motion_constraint.jerk_limit = jerk_limit;
+    }
+
     pub fn get_intp_data(&self) -> InterpolationDataOutput {
         let dir = self.target_data.dir;
         InterpolationDataOutput {
@@ -324,6 +331,18 @@ impl SCurveInterpolator {
         );
     }
 
+    // Stop interpolation immediately and keep the current position, the axis is not
+    // decelerated. It is used for quick stop when the axis can't follow the interpolation
+    pub fn abort(&mut self) {
+        self.intp_data.vel = 0.0;
+        self.intp_data.acc = 0.0;
+        self.intp_data.jerk = 0.0;
+        self.intp_data.dec_start_period = usize::MIN;
+        self.intp_data.dec_right_away = false;
+        self.intp_data.pos_end = self.target_data.dir * self.intp_data.pos;
+        self.intp_status = InterpolationStatus::Done;
+    }
+
     fn calculate_dec_distance(&mut self) {
         if self.intp_data.vel < self.target_data.vel_end {
             // In deceleration segment, we expect the intp vel is greater than or
@@ -582,4 +601,27 @@ mod tests {
         assert_eq!(length_check, true);
         assert_eq!(vel_check, true);
     }
+
+    #[test]
+    fn test_abort_should_keep_position_and_allow_next_target() {
+        for dir in [1.0_f32, -1.0] {
+            let mut scurve = SCurveInterpolator::new(10.0, 10.0, 30.0, T);
+            scurve.set_target(0.0, dir * 20.0, 0.0, 0.0, 5.0);
+            for _ in 0..1000 {
+                scurve.interpolate();
+            }
+
+            scurve.abort();
+            let abort_pos = scurve.get_intp_data().pos;
+            assert_eq!(scurve.get_intp_status(), InterpolationStatus::Done);
+            assert_eq!(scurve.get_intp_data().vel, 0.0);
+
+            // The next target starts from the aborted position
+            scurve.set_target(0.0, dir * 5.0, 0.0, 0.0, 5.0);
+            while scurve.get_intp_status() != InterpolationStatus::Done {
+                scurve.interpolate();
+            }
+            assert!((scurve.get_intp_data().pos - (abort_pos + dir * 5.0)).abs() < 1e-2);
+        }
+    }
 }
@@ -8,7 +8,9 @@ use tokio::select;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::{mpsc, watch};
 
+use crate::ConnectionTarget;
 use host::client::{Client, ClientError};
+use host::telemetry::{ImuHistory, SampleCounter};
 use protocol::*;
 
 struct MotorCommandActor {
@@ -16,10 +18,47 @@ struct MotorCommandActor {
     halt_command_recv: mpsc::Receiver<()>,
     command_queue_send_internal: UnboundedSender<MotorCommand>,
     command_queue_recv: mpsc::UnboundedReceiver<MotorCommand>,
+    data_recv: watch::Receiver<MotorProcessData>,
     cancel_actor_recv: watch::Receiver<bool>,
     task_err_send: watch::Sender<Result<(), String>>,
 }
 
+// Credits of the command queue in target board. The commands sent by this actor but not
+// received by the board yet take the free entries reported in process data
+#[derive(Default)]
+struct QueueCredits {
+    // Number of sent commands, it starts from the received commands in the first process
+    // data, so it is `None` until the process data is received
+    sent_cmds: Option<u32>,
+}
+
+impl QueueCredits {
+    fn sync(&mut self, data: &MotorProcessData) {
+        match self.sent_cmds {
+            None => self.sent_cmds = Some(data.received_cmds),
+            // The board received more commands than this actor sent, ex: another host also
+            // sends commands, the count is followed again
+            Some(x) if data.received_cmds.wrapping_sub(x) as i32 > 0 => {
+                self.sent_cmds = Some(data.received_cmds);
+            }
+            _ => (),
+        }
+    }
+
+    fn available(&self, data: &MotorProcessData) -> u32 {
+        match self.sent_cmds {
+            Some(x) => (data.queue_free as u32).saturating_sub(x.wrapping_sub(data.received_cmds)),
+            None => 0,
+        }
+    }
+
+    fn consume(&mut self) {
+        if let Some(x) = self.sent_cmds.as_mut() {
+            *x = x.wrapping_add(1);
+        }
+    }
+}
+
 impl MotorCommandActor {
     async fn run(&mut self) {
         let result = self.run_internal().await;
@@ -36,6 +75,10 @@ impl MotorCommandActor {
         // This queue is used to store the command from `command_queue`, and it will
         // be used when sending position commands.
         let mut internal_command_cache = VecDeque::<MotorCommand>::new();
+        let mut credits = QueueCredits::default();
+        // The command is rejected even if there are credits, it is sent again after the
+        // next process data is received instead of being re-sent right away
+        let mut rejected = false;
 
         let _id = self
             .client
@@ -44,6 +87,15 @@ impl MotorCommandActor {
             .map_err(|_x| ClientError::Comms(HostErr::Closed))?;
 
         loop {
+            // `Halt` and `ResetFault` are not queued in the board, so they are sent without
+            // credits
+            let can_send = match internal_command_cache.front() {
+                Some(MotorCommand::Halt | MotorCommand::ResetFault) => true,
+                Some(_) => !rejected && credits.available(&self.data_recv.borrow()) > 0,
+                None => false,
+            };
+            let wait_credits = !can_send && !internal_command_cache.is_empty();
+
             select! {
                 biased;
 
@@ -69,6 +121,15 @@ impl MotorCommandActor {
                     }
                     internal_command_cache.push_back(motor_command);
                 },
+                // The credits are updated by the process data of every control cycle
+                res = self.data_recv.changed(), if wait_credits => {
+                    if res.is_err() {
+                        error!("process_motor_command(), process data is closed");
+                        break Err(ClientError::Comms(HostErr::Closed));
+                    }
+                    credits.sync(&self.data_recv.borrow_and_update());
+                    rejected = false;
+                },
                 result = async {
                     if let Some(command) = internal_command_cache.front() {
                         self
@@ -78,24 +139,35 @@ impl MotorCommandActor {
                     }
 
                     Ok(())
-                } => {
+                }, if can_send => {
                     match result {
                             Ok(_) => {
                                 // If the command is sent successfully, pop it from the queue
                                 internal_command_cache.pop_front();
+                                credits.consume();
                             }
                             Err(e) => match e {
                                 ClientError::Comms(e) => {
                                     error!("process_motor_command(), unexpected error: {e:?}");
                                     break Err(ClientError::Comms(e));
                                 },
-                                // Currently, the other error is CommandError::BufferFull which will be raised
-                                // when the position command queue in the target board is full. Here, the error
-                                // is ignored, so it stays in `internal_command_cache` and the async block will
-                                // try to re-send it. (Improvement? create a data field called `buffer_full`,
-                                // wait until it is set to false and re-send the command after that to prevent
-                                // repeatedly sending the same command)
-                                _ => (),
+                                // The command is not accepted by retrying, drop it
+                                ClientError::Endpoint(CommandError::InvalidCommand(_)) => {
+                                    warn!("process_motor_command(), invalid command: {:?}", internal_command_cache.pop_front());
+                                }
+                                // The motor is stopped and the commands are rejected until the fault is
+                                // cleared, the cached commands are dropped instead of being sent again
+                                ClientError::Endpoint(CommandError::Fault(_)) => {
+                                    warn!("process_motor_command(), fault, drop {} commands", internal_command_cache.len());
+                                    internal_command_cache.clear();
+                                }
+                                // The other error is `CommandError::BufferFull`, the command stays in
+                                // `internal_command_cache` and it is sent again after the next process
+                                // data is received
+                                ClientError::Endpoint(e) => {
+                                    debug!("process_motor_command(), rejected: {e:?}");
+                                    rejected = true;
+                                }
                             },
                         }
                 }
@@ -104,9 +176,29 @@ impl MotorCommandActor {
     }
 }
 
+// The graph takes the samples in every frame, the samples are dropped when it can't keep up
+const SAMPLE_QUEUE_SIZE: usize = 256;
+// MPU6050 can be sampled at 1 kHz, it is kept for a few control cycles
+const IMU_HISTORY_SIZE: usize = 64;
+
+// Process data of the left motor in one control cycle, and the MPU6050 sample read closest
+// to it. The number of control cycles dropped before it is counted by the cycle counter
+#[derive(Default, Clone, Copy)]
+pub struct TelemetrySample {
+    pub motor_data: MotorProcessData,
+    pub mpu6050_data: Mpu6050MotionData,
+    pub dropped: u32,
+}
+
 struct MotorDataActor {
     client: Arc<Client>,
     data_send: watch::Sender<MotorProcessData>,
+    sample_send: mpsc::Sender<TelemetrySample>,
+    cycle_counter: SampleCounter,
+    imu_counter: SampleCounter,
+    imu_history: ImuHistory,
+    odometry_send: watch::Sender<Odometry>,
+    event_send: mpsc::UnboundedSender<DeviceEvent>,
     cancel_actor_recv: watch::Receiver<bool>,
     task_err_send: watch::Sender<Result<(), String>>,
 }
@@ -131,12 +223,11 @@ impl MotorDataActor {
             .await
             .map_err(|_x| ClientError::Comms(HostErr::Closed))?;
 
-        let mut mpu6050_data_sub = self
-            .client
-            .client
-            .subscribe_multi::<protocol::Mpu6050MotionDataTopic>(8)
-            .await
-            .map_err(|_x| ClientError::Comms(HostErr::Closed))?;
+        let mut mpu6050_data_sub = self.client.subscribe_mpu6050(8).await?;
+
+        let mut odometry_sub = self.client.subscribe_odometry(8).await?;
+
+        let mut event_sub = self.client.subscribe_events(8).await?;
 
         // Check `ping` to make sure the device is connected
         let _id = self.client.ping(0).await?;
@@ -155,6 +246,7 @@ impl MotorDataActor {
                     match res {
                         Ok(data) => {
                             if data[0].0 == MotorId::Left {
+                                self.send_sample(data[0].1);
                                 if let Err(e) = self.data_send.send(data[0].1) {
                                     error!("process_motor_data(), failed to send data: {e}");
                                     // I borrow the error type from HostError (it might be a bad idea, and this can be
@@ -180,17 +272,73 @@ impl MotorDataActor {
                 },
                 res = mpu6050_data_sub.recv() => {
                     match res {
-                        Ok(_data) => {
-                            // debug!("{:?}", data);
+                        Ok(data) => {
+                            if let Some(dropped) = self.imu_counter.update(data.sample) {
+                                if dropped > 0 {
+                                    warn!("process_mpu6050_data(), dropped: {dropped}");
+                                }
+                                self.imu_history.push(data);
+                            }
                         },
                         _ => (),
                     }
+                },
+                res = odometry_sub.recv() => {
+                    match res {
+                        Ok(odometry) => {
+                            // The receiver is held by `Communication`
+                            let _ = self.odometry_send.send(odometry);
+                        },
+                        Err(MultiSubRxError::Lagged(x)) => {
+                            warn!("process_odometry(), lag: {x}");
+                        },
+                        // Closed connection is handled by motor data subscription
+                        Err(MultiSubRxError::IoClosed) => (),
+                    }
+                },
+                res = event_sub.recv() => {
+                    match res {
+                        Ok(event) => {
+                            // The receiver is held by `Communication`, the event is dropped
+                            // when it is stopped
+                            let _ = self.event_send.send(event);
+                        },
+                        Err(MultiSubRxError::Lagged(x)) => {
+                            warn!("process_device_event(), lag: {x}");
+                        },
+                        // Closed connection is handled by motor data subscription
+                        Err(MultiSubRxError::IoClosed) => (),
+                    }
                 }
             }
         }
     }
 }
 
+impl MotorDataActor {
+    // Each control cycle is sent to the graph once, the board publishes the latest data,
+    // so the same cycle can be received again
+    fn send_sample(&mut self, motor_data: MotorProcessData) {
+        let Some(dropped) = self.cycle_counter.update(motor_data.cycle) else {
+            return;
+        };
+        if dropped > 0 {
+            warn!("process_motor_data(), dropped: {dropped}");
+        }
+
+        let mpu6050_data = self
+            .imu_history
+            .nearest(motor_data.timestamp_us)
+            .copied()
+            .unwrap_or_default();
+        let _ = self.sample_send.try_send(TelemetrySample {
+            motor_data,
+            mpu6050_data,
+            dropped,
+        });
+    }
+}
+
 impl Drop for Communication {
     fn drop(&mut self) {
         debug!("Communication actor is dropped");
@@ -199,30 +347,49 @@ impl Drop for Communication {
 }
 
 pub struct Communication {
+    client: Arc<Client>,
     halt_command_send: mpsc::Sender<()>,
     command_queue_send: mpsc::UnboundedSender<MotorCommand>,
     data_recv: watch::Receiver<MotorProcessData>,
+    sample_recv: mpsc::Receiver<TelemetrySample>,
+    odometry_recv: watch::Receiver<Odometry>,
+    event_recv: mpsc::UnboundedReceiver<DeviceEvent>,
     cancel_actor_send: watch::Sender<bool>,
     command_actor_err_recv: watch::Receiver<Result<(), String>>,
     data_actor_err_recv: watch::Receiver<Result<(), String>>,
     prev_command: Option<MotorCommand>,
+    prev_balance_command: Option<BaseTwistCommand>,
+    config_send: watch::Sender<Option<DeviceConfig>>,
+    config_recv: watch::Receiver<Option<DeviceConfig>>,
+    imu_calibration_send: watch::Sender<Option<Result<ImuCalibration, String>>>,
+    imu_calibration_recv: watch::Receiver<Option<Result<ImuCalibration, String>>>,
 }
 
 impl Communication {
-    pub fn new(port_name: &str) -> Result<Self, String> {
-        let client = Arc::new(Client::new(port_name)?);
+    pub fn new(target: &ConnectionTarget) -> Result<Self, String> {
+        let client = match target {
+            ConnectionTarget::Usb(port_name) => Client::new(port_name)?,
+            ConnectionTarget::Emulator(addr) => Client::new_tcp(addr)?,
+        };
+        let client = Arc::new(client);
         let (halt_command_send, halt_command_recv) = mpsc::channel::<()>(1);
         let (command_queue_send, command_queue_recv) = mpsc::unbounded_channel::<MotorCommand>();
         let (data_send, data_recv) = watch::channel(MotorProcessData::default());
+        let (sample_send, sample_recv) = mpsc::channel(SAMPLE_QUEUE_SIZE);
+        let (odometry_send, odometry_recv) = watch::channel(Odometry::default());
+        let (event_send, event_recv) = mpsc::unbounded_channel::<DeviceEvent>();
         let (cancel_actor_send, cancel_actor_recv) = watch::channel(false);
         let (command_actor_err_send, command_actor_err_recv) = watch::channel(Ok(()));
         let (data_actor_err_send, data_actor_err_recv) = watch::channel(Ok(()));
+        let (config_send, config_recv) = watch::channel(None);
+        let (imu_calibration_send, imu_calibration_recv) = watch::channel(None);
 
         let mut motor_command_actor = MotorCommandActor {
             client: client.clone(),
             halt_command_recv,
             command_queue_send_internal: command_queue_send.clone(),
             command_queue_recv,
+            data_recv: data_send.subscribe(),
             cancel_actor_recv: cancel_actor_recv.clone(),
             task_err_send: command_actor_err_send,
         };
@@ -230,6 +397,12 @@ impl Communication {
         let mut motor_data_actor = MotorDataActor {
             client: client.clone(),
             data_send,
+            sample_send,
+            cycle_counter: SampleCounter::new(),
+            imu_counter: SampleCounter::new(),
+            imu_history: ImuHistory::new(IMU_HISTORY_SIZE),
+            odometry_send,
+            event_send,
             cancel_actor_recv: cancel_actor_recv.clone(),
             task_err_send: data_actor_err_send,
         };
@@ -237,15 +410,27 @@ impl Communication {
         tokio::spawn(async move { motor_command_actor.run().await });
         tokio::spawn(async move { motor_data_actor.run().await });
 
-        Ok(Self {
+        let communication = Self {
+            client,
             halt_command_send,
             command_queue_send,
             data_recv,
+            sample_recv,
+            odometry_recv,
+            event_recv,
             cancel_actor_send,
             command_actor_err_recv,
             data_actor_err_recv,
             prev_command: None,
-        })
+            prev_balance_command: None,
+            config_send,
+            config_recv,
+            imu_calibration_send,
+            imu_calibration_recv,
+        };
+        communication.read_config();
+
+        Ok(communication)
     }
 
     pub fn stop(&self) -> Result<(), String> {
@@ -275,12 +460,127 @@ impl Communication {
             let _ = self.halt_command_send.try_send(());
         }
         self.prev_command = Some(data);
+        self.prev_balance_command = None;
+    }
+
+    // The balance command drives both wheels and it is not queued in the board, so it is
+    // sent right away instead of going through the motor command actor. The mode switch
+    // halts the motors and waits for it before the balance command is sent
+    pub fn send_balance_command(&mut self, twist: BaseTwistCommand) {
+        if self.prev_balance_command.is_some_and(|x| x == twist) {
+            return;
+        }
+
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            if let Err(e) = client.set_balance_cmd(twist).await {
+                error!("Failed to send balance command, {e:?}");
+            }
+        });
+        self.prev_balance_command = Some(twist);
+        self.prev_command = None;
+    }
+
+    // The other fields of the configuration are read from the device, so only the gains
+    // of balance controller are changed
+    pub fn set_balance_config(&self, balance: BalanceConfig) {
+        let client = self.client.clone();
+        let config_send = self.config_send.clone();
+        tokio::spawn(async move {
+            let result = async {
+                let config = client.get_config().await.map_err(|e| format!("{e:?}"))?;
+                let config = DeviceConfig { balance, ..config };
+                client
+                    .set_config(config)
+                    .await
+                    .map(|_| config)
+                    .map_err(|e| format!("{e:?}"))
+            };
+            match result.await {
+                Ok(config) => {
+                    let _ = config_send.send(Some(config));
+                }
+                Err(e) => error!("Failed to set balance config, {e}"),
+            }
+        });
+    }
+
+    // The configuration of the device when it is read or the balance gains are changed, it
+    // is `None` if it is not changed since the last call
+    pub fn take_config(&mut self) -> Option<DeviceConfig> {
+        if !self.config_recv.has_changed().unwrap_or(false) {
+            return None;
+        }
+        *self.config_recv.borrow_and_update()
+    }
+
+    // The board responds after the calibration is done, it takes a few seconds
+    pub fn calibrate_imu(&self, request: CalibrateImuRequest) {
+        let client = self.client.clone();
+        let imu_calibration_send = self.imu_calibration_send.clone();
+        tokio::spawn(async move {
+            let result = client
+                .calibrate_imu(request.gravity, request.persist)
+                .await
+                .map_err(|e| match e {
+                    ClientError::Endpoint(e) => e.to_string(),
+                    ClientError::Comms(e) => format!("{e:?}"),
+                });
+            if let Err(e) = &result {
+                error!("Failed to calibrate IMU, {e}");
+            }
+            let _ = imu_calibration_send.send(Some(result));
+        });
+    }
+
+    // The result of IMU calibration, it is `None` if no calibration is finished since the
+    // last call
+    pub fn take_imu_calibration(&mut self) -> Option<Result<ImuCalibration, String>> {
+        if !self.imu_calibration_recv.has_changed().unwrap_or(false) {
+            return None;
+        }
+        self.imu_calibration_recv.borrow_and_update().clone()
+    }
+
+    fn read_config(&self) {
+        let client = self.client.clone();
+        let config_send = self.config_send.clone();
+        tokio::spawn(async move {
+            match client.get_config().await {
+                Ok(config) => {
+                    let _ = config_send.send(Some(config));
+                }
+                Err(e) => error!("Failed to read config, {e:?}"),
+            }
+        });
     }
 
     pub fn get_motor_process_data(&self) -> MotorProcessData {
         *self.data_recv.borrow()
     }
 
+    // Samples received since the last call, in the order of control cycles
+    pub fn take_samples(&mut self) -> Vec<TelemetrySample> {
+        let mut samples = Vec::new();
+        while let Ok(sample) = self.sample_recv.try_recv() {
+            samples.push(sample);
+        }
+        samples
+    }
+
+    pub fn get_odometry(&self) -> Odometry {
+        *self.odometry_recv.borrow()
+    }
+
+    // Events received since the last call, in the order they are published
+    pub fn take_device_events(&mut self) -> Vec<DeviceEvent> {
+        let mut events = Vec::new();
+        while let Ok(event) = self.event_recv.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+
     pub fn get_motor_command_actor_err(&self) -> Result<(), String> {
         self.command_actor_err_recv.borrow().clone()
     }
@@ -2,7 +2,11 @@ use std::fmt::Display;
 
 use eframe::egui::Ui;
 
-use protocol::{AutoTuneCommand, ControlMode, MotorProcessData};
+use controller::communication::TelemetrySample;
+use protocol::{
+    AutoTuneCommand, BalanceConfig, BaseTwistCommand, CalibrateImuRequest, ControlMode,
+    DeviceConfig, DeviceEvent, ImuCalibration, Odometry,
+};
 
 pub mod controller;
 pub mod view;
@@ -29,23 +33,47 @@ pub enum ErrorType {
 
 #[derive(Default, Clone, Copy)]
 pub struct ProfileData {
+    // Time of the control cycle since the board boots, unit: s
+    time_s: f64,
+    // Number of control cycles dropped before this one
+    dropped: u32,
     intp_pos: f32,
     intp_vel: f32,
     intp_acc: f32,
     intp_jerk: f32,
     act_pos: f32,
     act_vel: f32,
+    heading_error: f32,
+    pitch: f32,
+    roll: f32,
+    imu_pitch: f32,
+    yaw: f32,
 }
 
 impl ProfileData {
-    pub fn from(motor_data: &MotorProcessData) -> Self {
+    // The heading error is 0 when the heading controller is off, and the orientation is 0
+    // until the DMP data is received
+    pub fn from(sample: &TelemetrySample, odometry: &Odometry) -> Self {
+        let TelemetrySample {
+            motor_data,
+            mpu6050_data,
+            dropped,
+        } = sample;
+        let orientation = mpu6050_data.orientation.unwrap_or_default();
         Self {
+            time_s: motor_data.timestamp_us as f64 / 1e6,
+            dropped: *dropped,
             intp_pos: motor_data.intp_pos,
             intp_vel: motor_data.intp_vel,
             intp_acc: motor_data.intp_acc,
             intp_jerk: motor_data.intp_jerk,
             act_pos: motor_data.actual_pos,
             act_vel: motor_data.actual_vel,
+            heading_error: odometry.heading_error.unwrap_or(0.0),
+            pitch: odometry.pitch,
+            roll: orientation.roll,
+            imu_pitch: orientation.pitch,
+            yaw: orientation.yaw,
         }
     }
 }
@@ -58,6 +86,11 @@ pub enum ProfileDataType {
     IntpJerk,
     ActPos,
     ActVel,
+    HeadingError,
+    Pitch,
+    Roll,
+    ImuPitch,
+    Yaw,
 }
 
 impl Display for ProfileDataType {
@@ -69,14 +102,27 @@ impl Display for ProfileDataType {
             ProfileDataType::IntpJerk => write!(f, "intp_jerk"),
             ProfileDataType::ActPos => write!(f, "act_pos"),
             ProfileDataType::ActVel => write!(f, "act_vel"),
+            ProfileDataType::HeadingError => write!(f, "heading_error"),
+            ProfileDataType::Pitch => write!(f, "pitch"),
+            ProfileDataType::Roll => write!(f, "roll"),
+            ProfileDataType::ImuPitch => write!(f, "imu_pitch"),
+            ProfileDataType::Yaw => write!(f, "yaw"),
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionTarget {
+    // The board connected through USB, identified by the product string
+    Usb(String),
+    // The emulator listening on a TCP address
+    Emulator(String),
+}
+
 #[derive(Debug)]
 pub enum ViewRequest {
-    // A request that wants to start connection with a port name from connection window
-    ConnectionStart(String),
+    // A request that wants to start connection with a target from connection window
+    ConnectionStart(ConnectionTarget),
     // A request that wants to stop connection from connection window
     ConnectionStop,
     // A request that wants to clear error from error window
@@ -91,6 +137,12 @@ pub enum ViewRequest {
     PositionControl(String),
     // A request that wants to start auto-tuning from command window
     AutoTuneControl(AutoTuneCommand),
+    // A request that wants to move the balancing robot from command window
+    BalanceControl(BaseTwistCommand),
+    // A request that wants to apply the gains of balance controller from command window
+    BalanceConfigSet(BalanceConfig),
+    // A request that wants to calibrate MPU6050 from command window
+    ImuCalibrate(CalibrateImuRequest),
 }
 
 #[derive(Clone)]
@@ -107,4 +159,11 @@ pub enum ViewEvent {
     InternalStopModeRequest(String),
     // Send motor profile data to profile window to draw the graph
     ProfileDataUpdate(ProfileData),
+    // Send the events received from the device in this frame to event log window, they
+    // are kept in order
+    DeviceEventsReceived(Vec<DeviceEvent>),
+    // Send the configuration read from the device to command window
+    DeviceConfigUpdate(DeviceConfig),
+    // Send the offsets or the error of IMU calibration to command window
+    ImuCalibrationUpdate(Result<ImuCalibration, String>),
 }
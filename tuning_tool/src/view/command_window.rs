@@ -1,31 +1,57 @@
-use eframe::egui::{Button, ScrollArea, Slider, TextEdit, Ui};
+use eframe::egui::{Button, ComboBox, ScrollArea, Slider, TextEdit, Ui};
 
 use crate::{DEFAULT_CONTROL_MODE, UiView, ViewEvent, ViewRequest};
-use protocol::{AutoTuneCommand, ControlMode};
+use motion_core::config::axis_velocity_resolution;
+use protocol::{
+    AutoTuneCommand, AxisConfig, BalanceConfig, BaseTwistCommand, CalibrateImuRequest,
+    ControlMode, ImuCalibration, ReferenceGravity, TuningRule,
+};
+
+const DEFAULT_AUTOTUNE_CYCLES: u8 = 8;
+// The motor is treated as stopped below this multiple of the velocity resolution, it is
+// 4.2 rad/s (40 rpm) with the default configuration
+const AUTOTUNE_STOP_VEL_MARGIN: f32 = 4.0 / 3.0;
 
 #[derive(Default)]
 pub(super) struct CommandWindow {
     curr_control_mode: ControlMode,
     request: Option<ViewRequest>,
-    // velocity command, unit: rpm
+    // velocity command, unit: axis unit/s (rad/s with the default configuration)
     curr_vel_cmd: f32,
     prev_vel_cmd: f32,
     // position command format: '(dist, vel, vel_end);'
     // Input data should be enclosed by parenthesis, and use ';' to indicate the
     // end of one command block, and the unit of each data is as follows:
     // 1. dist: rad
-    // 2. vel: rpm
-    // 3. vel_end: rpm, the end velocity of position command block, it is optional.
+    // 2. vel: axis unit/s (rad/s with the default configuration)
+    // 3. vel_end: axis unit/s, the end velocity of position command block, it is optional.
     //    If it is not given, the end velocity will be treated as 0
     pos_cmd: String,
     // auto tune command
     auto_tune_cmd: AutoTuneCommand,
+    // balance command, unit: m/s, rad/s
+    curr_balance_cmd: BaseTwistCommand,
+    prev_balance_cmd: BaseTwistCommand,
+    // gains of balance controller, they are read from the device after connection
+    balance_config: Option<BalanceConfig>,
+    // configuration of the left axis that runs auto tune, it is read with balance gains
+    left_axis_config: Option<AxisConfig>,
+    // time of the previous profile data, it is used to measure the control period, unit: s
+    prev_data_time_s: Option<f64>,
+    // IMU calibration, the board needs to stay still until the result is received
+    imu_calibration_cmd: CalibrateImuRequest,
+    is_calibrating_imu: bool,
+    imu_calibration_result: Option<Result<ImuCalibration, String>>,
 }
 
 impl CommandWindow {
     pub fn new() -> Self {
         Self {
             curr_control_mode: DEFAULT_CONTROL_MODE,
+            auto_tune_cmd: AutoTuneCommand {
+                cycles: DEFAULT_AUTOTUNE_CYCLES,
+                ..Default::default()
+            },
             ..Default::default()
         }
     }
@@ -46,7 +72,7 @@ impl CommandWindow {
 
     fn display_velocity_command_panel(&mut self, ui: &mut Ui) {
         ui.add(
-            Slider::new(&mut self.curr_vel_cmd, -3000.0..=3000.0).text("motor velocity cmd (rpm)"),
+            Slider::new(&mut self.curr_vel_cmd, -300.0..=300.0).text("axis velocity cmd (unit/s)"),
         );
 
         if self.curr_vel_cmd != self.prev_vel_cmd {
@@ -66,6 +92,28 @@ impl CommandWindow {
             );
         });
 
+        ui.columns(3, |columns| {
+            columns[0].add(
+                Slider::new(&mut self.auto_tune_cmd.hysteresis, 0.0..=200.0)
+                    .text("hysteresis (rpm)"),
+            );
+            columns[1].add(Slider::new(&mut self.auto_tune_cmd.cycles, 2..=15).text("cycles"));
+            ComboBox::new("tuning_rules", "tuning rule")
+                .selected_text(format!("{}", self.auto_tune_cmd.rule))
+                .show_ui(&mut columns[2], |ui| {
+                    for rule in [
+                        TuningRule::ZieglerNichols,
+                        TuningRule::TyreusLuyben,
+                        TuningRule::PessenIntegral,
+                        TuningRule::SomeOvershoot,
+                        TuningRule::NoOvershoot,
+                        TuningRule::PiOnly,
+                    ] {
+                        ui.selectable_value(&mut self.auto_tune_cmd.rule, rule, rule.to_string());
+                    }
+                });
+        });
+
         let text = if !self.auto_tune_cmd.start {
             "start"
         } else {
@@ -77,6 +125,90 @@ impl CommandWindow {
             self.request = Some(ViewRequest::AutoTuneControl(self.auto_tune_cmd.clone()));
         }
     }
+
+    fn display_balance_command_panel(&mut self, ui: &mut Ui) {
+        ui.columns(2, |columns| {
+            columns[0].add(
+                Slider::new(&mut self.curr_balance_cmd.linear, -0.5..=0.5)
+                    .text("linear velocity cmd (m/s)"),
+            );
+            columns[1].add(
+                Slider::new(&mut self.curr_balance_cmd.angular, -3.0..=3.0)
+                    .text("angular velocity cmd (rad/s)"),
+            );
+        });
+
+        if self.curr_balance_cmd != self.prev_balance_cmd {
+            self.prev_balance_cmd = self.curr_balance_cmd;
+            self.request = Some(ViewRequest::BalanceControl(self.curr_balance_cmd));
+        }
+
+        let Some(config) = self.balance_config.as_mut() else {
+            ui.label("Reading balance gains...");
+            return;
+        };
+        ui.columns(3, |columns| {
+            columns[0].add(Slider::new(&mut config.kp_pitch, 0.0..=100.0).text("kp pitch"));
+            columns[1].add(Slider::new(&mut config.kd_pitch, 0.0..=20.0).text("kd pitch"));
+            columns[2].add(Slider::new(&mut config.kp_vel, 0.0..=20.0).text("kp velocity"));
+        });
+        ui.columns(3, |columns| {
+            columns[0]
+                .add(Slider::new(&mut config.pitch_offset, -0.2..=0.2).text("pitch offset (rad)"));
+            columns[1].add(Slider::new(&mut config.max_tilt, 0.1..=1.5).text("max tilt (rad)"));
+            columns[2].add(
+                Slider::new(&mut config.filter_time_constant, 0.05..=5.0)
+                    .text("filter time constant (s)"),
+            );
+        });
+
+        if ui.button("Apply gains").clicked() {
+            self.request = Some(ViewRequest::BalanceConfigSet(*config));
+        }
+    }
+
+    fn display_imu_calibration_panel(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ComboBox::new("reference_gravity", "reference gravity")
+                .selected_text(self.imu_calibration_cmd.gravity.to_string())
+                .show_ui(ui, |ui| {
+                    for gravity in [
+                        ReferenceGravity::Zero,
+                        ReferenceGravity::XN,
+                        ReferenceGravity::XP,
+                        ReferenceGravity::YN,
+                        ReferenceGravity::YP,
+                        ReferenceGravity::ZN,
+                        ReferenceGravity::ZP,
+                    ] {
+                        ui.selectable_value(
+                            &mut self.imu_calibration_cmd.gravity,
+                            gravity,
+                            gravity.to_string(),
+                        );
+                    }
+                });
+            ui.checkbox(&mut self.imu_calibration_cmd.persist, "save to flash");
+
+            let button = Button::new("Calibrate IMU");
+            if ui.add_enabled(!self.is_calibrating_imu, button).clicked() {
+                self.is_calibrating_imu = true;
+                self.request = Some(ViewRequest::ImuCalibrate(self.imu_calibration_cmd));
+            }
+        });
+
+        if self.is_calibrating_imu {
+            ui.label("Calibrating, keep the board still...");
+        } else if let Some(result) = &self.imu_calibration_result {
+            match result {
+                Ok(x) => ui.label(format!(
+                    "accel offsets: {:?}, gyro offsets: {:?}",
+                    x.accel, x.gyro
+                )),
+                Err(e) => ui.label(format!("Calibration failed, {e}")),
+            };
+        }
+    }
 }
 
 impl UiView for CommandWindow {
@@ -86,8 +218,12 @@ impl UiView for CommandWindow {
             ControlMode::Position => self.display_position_command_panel(ui),
             ControlMode::Velocity => self.display_velocity_command_panel(ui),
             ControlMode::Pid => self.display_autotune_command_panel(ui),
+            ControlMode::Balance => self.display_balance_command_panel(ui),
             _ => (),
         }
+
+        ui.separator();
+        self.display_imu_calibration_panel(ui);
     }
 
     fn take_request(&mut self) -> Option<ViewRequest> {
@@ -106,19 +242,42 @@ impl UiView for CommandWindow {
                 }
             }
             ViewEvent::ProfileDataUpdate(data) => {
-                // Turn off auto tune command when the motor is not moving.
-                // The target board runs motion task every 5ms, and 30 rpm is the minimum speed
-                // that can be detected by the encoder and this cycle time. The calculation is
-                // as follows:
-                // the 24H BLDC motor generates 400 pulses per rev, so:
-                // 30 rpm = 0.5 rev/s
-                // 0.5 rev/s * 400 pulses/rev = 200 pulses/s
-                // 200 pulses/s / 200 Hz = 1 pulse per cycle
-                // Here, I slightly increase the threshold to 40 rpm to prevent unstable behavior
-                if self.auto_tune_cmd.start && data.act_vel.abs() <= 40.0 {
+                // The data of dropped control cycles is missing, so the time difference is
+                // divided by the number of cycles
+                let period_s = self
+                    .prev_data_time_s
+                    .map(|x| ((data.time_s - x) / (data.dropped as f64 + 1.0)) as f32);
+                self.prev_data_time_s = Some(data.time_s);
+
+                // Turn off auto tune command when the motor is not moving. The velocity
+                // estimator can't tell the speed below its resolution from standstill, ex:
+                // 3.1 rad/s (30 rpm) is one count per 5 ms period with 400 counts/rev and
+                // `Difference` estimator, so the threshold is slightly higher to prevent
+                // unstable behavior
+                let stop_vel = match (self.left_axis_config, period_s) {
+                    (Some(axis), Some(period_s)) if period_s > 0.0 => {
+                        Some(axis_velocity_resolution(&axis, period_s) * AUTOTUNE_STOP_VEL_MARGIN)
+                    }
+                    _ => None,
+                };
+                if self.auto_tune_cmd.start && stop_vel.is_some_and(|x| data.act_vel.abs() <= x) {
                     self.auto_tune_cmd.start = false;
                 }
             }
+            ViewEvent::DeviceConfigUpdate(config) => {
+                self.balance_config = Some(config.balance);
+                self.left_axis_config = Some(config.left);
+            }
+            ViewEvent::ImuCalibrationUpdate(result) => {
+                self.is_calibrating_imu = false;
+                self.imu_calibration_result = Some(result);
+            }
+            ViewEvent::ConnectionStatusUpdate(false) => {
+                self.balance_config = None;
+                self.left_axis_config = None;
+                self.prev_data_time_s = None;
+                self.is_calibrating_imu = false;
+            }
             _ => (),
         }
     }
@@ -129,5 +288,7 @@ impl UiView for CommandWindow {
         self.prev_vel_cmd = 0.0;
         self.pos_cmd.clear();
         self.auto_tune_cmd.start = false;
+        self.curr_balance_cmd = BaseTwistCommand::default();
+        self.prev_balance_cmd = BaseTwistCommand::default();
     }
 }
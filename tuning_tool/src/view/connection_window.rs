@@ -3,7 +3,8 @@ use std::fmt::Display;
 use eframe::egui::{Button, ComboBox, Ui};
 use nusb;
 
-use crate::{UiView, ViewEvent, ViewRequest};
+use crate::{ConnectionTarget, UiView, ViewEvent, ViewRequest};
+use host::tcp::DEFAULT_EMULATOR_ADDR;
 
 #[derive(Default, PartialEq)]
 struct UsbInfo {
@@ -32,9 +33,42 @@ impl From<nusb::DeviceInfo> for UsbInfo {
     }
 }
 
+// The device that can be selected in connection window, the emulator is always listed, so
+// the UI can be used without the board
+#[derive(Default, PartialEq)]
+enum Device {
+    #[default]
+    None,
+    Usb(UsbInfo),
+    Emulator,
+}
+
+impl Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Device::None => write!(f, ""),
+            Device::Usb(usb_info) => write!(f, "{usb_info}"),
+            Device::Emulator => write!(f, "emulator, {DEFAULT_EMULATOR_ADDR}"),
+        }
+    }
+}
+
+impl Device {
+    fn target(&self) -> Option<ConnectionTarget> {
+        match self {
+            Device::None => None,
+            Device::Usb(usb_info) if usb_info.product_string.is_empty() => None,
+            Device::Usb(usb_info) => Some(ConnectionTarget::Usb(usb_info.product_string.clone())),
+            Device::Emulator => Some(ConnectionTarget::Emulator(
+                DEFAULT_EMULATOR_ADDR.to_string(),
+            )),
+        }
+    }
+}
+
 #[derive(Default)]
 pub(super) struct ConnectionWindow {
-    selected_usb: UsbInfo,
+    selected_device: Device,
     target: bool,
     curr: bool,
     request: Option<ViewRequest>,
@@ -54,28 +88,27 @@ impl UiView for ConnectionWindow {
 
         ui.heading("Connection setup");
         ui.horizontal_centered(|ui| {
-            ComboBox::new("usb", "usbs")
-                .selected_text(format!("{}", self.selected_usb))
+            ComboBox::new("usb", "devices")
+                .selected_text(format!("{}", self.selected_device))
                 .show_ui(ui, |ui| {
                     for device in devices {
-                        let usb_info = UsbInfo::from(device);
-                        let text = usb_info.to_string();
-                        ui.selectable_value(&mut self.selected_usb, usb_info, text);
+                        let device = Device::Usb(UsbInfo::from(device));
+                        let text = device.to_string();
+                        ui.selectable_value(&mut self.selected_device, device, text);
                     }
+
+                    let text = Device::Emulator.to_string();
+                    ui.selectable_value(&mut self.selected_device, Device::Emulator, text);
                 });
 
             let text_in_button = if self.curr { "Stop" } else { "Start" };
             let conn_button = Button::new(text_in_button);
 
-            if ui
-                .add_enabled(!self.selected_usb.product_string.is_empty(), conn_button)
-                .clicked()
-            {
+            let target = self.selected_device.target();
+            if ui.add_enabled(target.is_some(), conn_button).clicked() {
                 self.target = !self.curr;
                 if self.target {
-                    self.request = Some(ViewRequest::ConnectionStart(
-                        self.selected_usb.product_string.clone(),
-                    ));
+                    self.request = target.map(ViewRequest::ConnectionStart);
                 } else {
                     self.request = Some(ViewRequest::ConnectionStop);
                 }
@@ -46,6 +46,11 @@ impl UiView for ControlModeWindow {
                     ControlMode::Pid,
                     "Pid",
                 );
+                ui.selectable_value(
+                    &mut self.target_control_mode,
+                    ControlMode::Balance,
+                    "Balance",
+                );
             });
 
         // Check if we need to do mode switch
@@ -0,0 +1,81 @@
+use std::collections::VecDeque;
+
+use crate::{UiView, ViewEvent, ViewRequest};
+use eframe::egui::{self, RichText};
+use protocol::{DeviceEvent, DeviceEventKind, ImuHealth};
+
+const MAX_EVENT_LOG_SIZE: usize = 200;
+
+#[derive(Default)]
+pub(super) struct EventLogWindow {
+    events: VecDeque<DeviceEvent>,
+}
+
+impl EventLogWindow {
+    pub fn new() -> Self {
+        Self {
+            ..Default::default()
+        }
+    }
+}
+
+impl UiView for EventLogWindow {
+    fn show(&mut self, ui: &mut eframe::egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.heading("Events");
+            if ui.button("Clear").clicked() {
+                self.events.clear();
+            }
+        });
+
+        egui::ScrollArea::vertical()
+            .auto_shrink([false, false])
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                for event in &self.events {
+                    let motor = match event.motor {
+                        Some(id) => format!("{id:?}"),
+                        None => "Board".to_string(),
+                    };
+                    let text = RichText::new(format!(
+                        "{:>10.3} s  {motor:<6} {}",
+                        event.timestamp_ms as f32 / 1000.0,
+                        event.kind
+                    ))
+                    .monospace();
+
+                    match event.kind {
+                        DeviceEventKind::ConnectionLost
+                        | DeviceEventKind::FaultRaised(_)
+                        | DeviceEventKind::ImuHealthChanged(ImuHealth::Error | ImuHealth::Absent)
+                        | DeviceEventKind::QueueOverflow
+                        | DeviceEventKind::BalanceCutoff => {
+                            ui.label(text.color(ui.visuals().error_fg_color));
+                        }
+                        _ => {
+                            ui.label(text);
+                        }
+                    }
+                }
+            });
+    }
+
+    fn take_request(&mut self) -> Option<ViewRequest> {
+        None
+    }
+
+    fn handle_event(&mut self, event: ViewEvent) {
+        if let ViewEvent::DeviceEventsReceived(events) = event {
+            for event in events {
+                if self.events.len() == MAX_EVENT_LOG_SIZE {
+                    self.events.pop_front();
+                }
+                self.events.push_back(event);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        // The log is kept, so the events before an error can still be checked
+    }
+}
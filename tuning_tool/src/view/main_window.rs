@@ -3,7 +3,9 @@ use eframe::{
     egui::{self, Ui, Vec2},
 };
 
-use protocol::{AutoTuneCommand, ControlMode, MotorCommand, MotorProcessData, PositionCommand};
+use protocol::{
+    AutoTuneCommand, BaseTwistCommand, ControlMode, MotorCommand, MotorProcessData, PositionCommand,
+};
 
 use crate::{
     ErrorType, ProfileData, ViewEvent, ViewRequest,
@@ -46,6 +48,7 @@ pub struct TuningTool {
     // Others
     velocity_command: f32,
     auto_tune_command: Option<AutoTuneCommand>,
+    balance_command: BaseTwistCommand,
 }
 
 impl App for TuningTool {
@@ -78,6 +81,15 @@ impl App for TuningTool {
                 .show(ui);
         });
 
+        egui::TopBottomPanel::bottom("event_panel")
+            .resizable(true)
+            .default_height(120.0)
+            .show(ctx, |ui| {
+                self.window_wrapper
+                    .get_window(WindowType::EventLogWindow)
+                    .show(ui);
+            });
+
         egui::CentralPanel::default().show(ctx, |ui| {
             self.window_wrapper
                 .get_window(WindowType::ProfileWindow)
@@ -108,6 +120,20 @@ impl App for TuningTool {
         self.view_events.push(ViewEvent::ConnectionStatusUpdate(
             self.communication.is_some(),
         ));
+        if let Some(communication) = self.communication.as_mut() {
+            let events = communication.take_device_events();
+            if !events.is_empty() {
+                self.view_events.push(ViewEvent::DeviceEventsReceived(events));
+            }
+            if let Some(config) = communication.take_config() {
+                self.view_events
+                    .push(ViewEvent::DeviceConfigUpdate(config));
+            }
+            if let Some(result) = communication.take_imu_calibration() {
+                self.view_events
+                    .push(ViewEvent::ImuCalibrationUpdate(result));
+            }
+        }
         if let Some(motor_data) = self.get_motor_data() {
             // Run mode switch to decide current control mode
             let mode_switch_result = self.mode_switch.process(&motor_data);
@@ -123,8 +149,16 @@ impl App for TuningTool {
                 motor_data.control_mode_display,
             )));
 
-            self.view_events
-                .push(ViewEvent::ProfileDataUpdate(ProfileData::from(&motor_data)));
+            // Every control cycle received since the last frame is plotted
+            if let Some(communication) = self.communication.as_mut() {
+                let odometry = communication.get_odometry();
+                for sample in communication.take_samples() {
+                    self.view_events
+                        .push(ViewEvent::ProfileDataUpdate(ProfileData::from(
+                            &sample, &odometry,
+                        )));
+                }
+            }
 
             if let Ok(mode) = mode_switch_result {
                 // Send motor command when mode switch gives valud output mode
@@ -151,6 +185,7 @@ impl TuningTool {
 
             velocity_command: 0.0,
             auto_tune_command: None,
+            balance_command: BaseTwistCommand::default(),
         }
     }
 
@@ -160,6 +195,7 @@ impl TuningTool {
         self.velocity_command = 0.0;
         self.position_command_parser.reset();
         self.auto_tune_command.take();
+        self.balance_command = BaseTwistCommand::default();
         if communication_stopped {
             // Clear other data when communication is stopped
             self.communication.take();
@@ -212,6 +248,12 @@ impl TuningTool {
                     ));
                 }
             }
+            // The balance command keeps the chassis upright, the default command is sent
+            // to switch control mode
+            ControlMode::Balance => communication.send_balance_command(self.balance_command),
+            // The board enters fault by itself, and the heading controller drives both
+            // wheels, they can't be requested from the control mode window
+            ControlMode::Fault | ControlMode::Heading => (),
         }
     }
 
@@ -283,8 +325,8 @@ impl TuningTool {
             let a = self.window_wrapper.get_window(window_type).take_request();
             if let Some(request) = a {
                 match request {
-                    ViewRequest::ConnectionStart(port_name) => {
-                        match Communication::new(&port_name) {
+                    ViewRequest::ConnectionStart(target) => {
+                        match Communication::new(&target) {
                             Ok(comm) => {
                                 self.communication = Some(comm);
                                 self.view_events
@@ -334,6 +376,20 @@ impl TuningTool {
                         error!("process auto-tune command: {:?}", &x);
                         self.auto_tune_command = Some(x);
                     }
+                    ViewRequest::BalanceControl(twist) => {
+                        self.mode_switch.ignite(ControlMode::Balance);
+                        self.balance_command = twist;
+                    }
+                    ViewRequest::BalanceConfigSet(config) => {
+                        if let Some(communication) = self.communication.as_ref() {
+                            communication.set_balance_config(config);
+                        }
+                    }
+                    ViewRequest::ImuCalibrate(request) => {
+                        if let Some(communication) = self.communication.as_ref() {
+                            communication.calibrate_imu(request);
+                        }
+                    }
                     _ => (),
                 }
             }
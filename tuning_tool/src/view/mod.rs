@@ -2,6 +2,7 @@ pub(super) mod command_window;
 pub(super) mod connection_window;
 pub(super) mod control_mode_window;
 pub(super) mod error_window;
+pub(super) mod event_log_window;
 pub mod main_window;
 pub(super) mod profile_window;
 pub mod window_wrapper;
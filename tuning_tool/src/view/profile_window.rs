@@ -6,8 +6,10 @@ use egui_plot::{Legend, Line, Plot, PlotPoints};
 pub struct DataGraph {
     window_values: VecDeque<ProfileData>,
     window_size: usize,
-    data_flags: [(ProfileDataType, bool); 6],
+    data_flags: [(ProfileDataType, bool); 11],
     can_update: bool,
+    // Number of control cycles that are not received while the graph is updated
+    dropped_samples: u64,
 }
 
 impl DataGraph {
@@ -22,8 +24,14 @@ impl DataGraph {
                 (ProfileDataType::IntpJerk, false),
                 (ProfileDataType::ActPos, false),
                 (ProfileDataType::ActVel, false),
+                (ProfileDataType::HeadingError, false),
+                (ProfileDataType::Pitch, false),
+                (ProfileDataType::Roll, false),
+                (ProfileDataType::ImuPitch, false),
+                (ProfileDataType::Yaw, false),
             ],
             can_update: false,
+            dropped_samples: 0,
         }
     }
 
@@ -32,23 +40,30 @@ impl DataGraph {
             return;
         }
 
+        self.dropped_samples += data.dropped as u64;
         if self.window_values.len() == self.window_size {
             self.window_values.pop_front();
         }
         self.window_values.push_back(data);
     }
 
+    // The data is plotted against the time of the board, unit: s
     fn get_data(&self, get_data_type: ProfileDataType) -> PlotPoints {
-        let iter = self.window_values.iter().enumerate();
+        let iter = self.window_values.iter().map(|x| (x.time_s, x));
         match get_data_type {
-            ProfileDataType::IntpPos => iter.map(|(x, y)| [x as f64, y.intp_pos as f64]).collect(),
-            ProfileDataType::IntpVel => iter.map(|(x, y)| [x as f64, y.intp_vel as f64]).collect(),
-            ProfileDataType::IntpAcc => iter.map(|(x, y)| [x as f64, y.intp_acc as f64]).collect(),
-            ProfileDataType::IntpJerk => {
-                iter.map(|(x, y)| [x as f64, y.intp_jerk as f64]).collect()
+            ProfileDataType::IntpPos => iter.map(|(x, y)| [x, y.intp_pos as f64]).collect(),
+            ProfileDataType::IntpVel => iter.map(|(x, y)| [x, y.intp_vel as f64]).collect(),
+            ProfileDataType::IntpAcc => iter.map(|(x, y)| [x, y.intp_acc as f64]).collect(),
+            ProfileDataType::IntpJerk => iter.map(|(x, y)| [x, y.intp_jerk as f64]).collect(),
+            ProfileDataType::ActPos => iter.map(|(x, y)| [x, y.act_pos as f64]).collect(),
+            ProfileDataType::ActVel => iter.map(|(x, y)| [x, y.act_vel as f64]).collect(),
+            ProfileDataType::HeadingError => {
+                iter.map(|(x, y)| [x, y.heading_error as f64]).collect()
             }
-            ProfileDataType::ActPos => iter.map(|(x, y)| [x as f64, y.act_pos as f64]).collect(),
-            ProfileDataType::ActVel => iter.map(|(x, y)| [x as f64, y.act_vel as f64]).collect(),
+            ProfileDataType::Pitch => iter.map(|(x, y)| [x, y.pitch as f64]).collect(),
+            ProfileDataType::Roll => iter.map(|(x, y)| [x, y.roll as f64]).collect(),
+            ProfileDataType::ImuPitch => iter.map(|(x, y)| [x, y.imu_pitch as f64]).collect(),
+            ProfileDataType::Yaw => iter.map(|(x, y)| [x, y.yaw as f64]).collect(),
         }
     }
 }
@@ -82,6 +97,8 @@ impl UiView for DataGraph {
                 for item in self.data_flags.iter_mut() {
                     ui.checkbox(&mut item.1, item.0.to_string());
                 }
+
+                ui.label(format!("dropped: {}", self.dropped_samples));
             })
         });
     }
@@ -101,5 +118,6 @@ impl UiView for DataGraph {
 
     fn reset(&mut self) {
         self.window_values.clear();
+        self.dropped_samples = 0;
     }
 }
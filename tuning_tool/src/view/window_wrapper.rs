@@ -3,7 +3,7 @@ use crate::{
     view::{
         command_window::CommandWindow, connection_window::ConnectionWindow,
         control_mode_window::ControlModeWindow, error_window::ErrorWindow,
-        profile_window::DataGraph,
+        event_log_window::EventLogWindow, profile_window::DataGraph,
     },
 };
 use std::collections::HashMap;
@@ -16,6 +16,7 @@ pub enum WindowType {
     CommandWindow,
     ProfileWindow,
     ErrorWindow,
+    EventLogWindow,
 }
 
 pub struct WindowWrapper {
@@ -39,6 +40,7 @@ impl WindowWrapper {
             Box::new(DataGraph::new(DEFAULT_GRAPH_SIZE)),
         );
         window_map.insert(WindowType::ErrorWindow, Box::new(ErrorWindow::new()));
+        window_map.insert(WindowType::EventLogWindow, Box::new(EventLogWindow::new()));
 
         Self { window_map }
     }